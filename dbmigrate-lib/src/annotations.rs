@@ -0,0 +1,209 @@
+///! Settings declared in migration files with `-- dbmigrate:<name> <value>` comments
+use std::time::Duration;
+
+use drivers::Driver;
+use errors::{Result, ResultExt};
+use timeout::parse_duration;
+use version::VersionRequirement;
+
+pub const PREFIX: &'static str = "-- dbmigrate:";
+
+/// Annotations found in a SQL migration file
+#[derive(Debug, Default, PartialEq)]
+pub struct Annotations {
+    /// Queries that must return true for the migration to run,
+    /// from `-- dbmigrate:precondition SELECT ...` lines
+    pub preconditions: Vec<String>,
+    /// Queries that must return true after the migration ran, otherwise it is rolled back.
+    /// From `-- dbmigrate:assert SELECT ...` lines
+    pub assertions: Vec<String>,
+    /// Whether the migration destroys data and the database should be backed up before it runs,
+    /// from a `-- dbmigrate:destructive` line
+    pub destructive: bool,
+    /// Whether the migration can't be reverted, from a `-- dbmigrate:irreversible` line in its up file.
+    /// Its down file can then be left out
+    pub irreversible: bool,
+    /// Versions of the server the migration can run on, from `-- dbmigrate:requires-server >=8.0 <9` lines
+    pub requires_server: Vec<VersionRequirement>,
+    /// How long the migration may run before being cancelled, from a `-- dbmigrate:timeout 10m` line.
+    /// Overrides the timeout set on the migrator
+    pub timeout: Option<Duration>,
+    /// Whether the `ALTER TABLE` statements of the MySQL migration are run by an online schema change
+    /// tool instead of locking the tables, from a `-- dbmigrate:online` line
+    pub online: bool,
+    /// Whether the migration is independent from the other parallel ones around it, like an index creation,
+    /// so that `up --parallel` can run them at the same time. From a `-- dbmigrate:parallel` line
+    pub parallel: bool,
+    /// Tags to select or skip the migration with, from `-- dbmigrate:tags reporting,slow` lines
+    pub tags: Vec<String>,
+    /// Environments the migration runs in, it is skipped in the others. From `-- dbmigrate:environments dev, test`
+    pub environments: Vec<String>,
+    /// Expand migration whose changes the migration finishes, like dropping the column the expand one
+    /// replaced, from a `-- dbmigrate:contract 12` line. `up` stops before it and `contract` runs it
+    pub contract: Option<i32>,
+    /// How a Galera cluster runs the DDL statements of the migration, `TOI` on all the nodes at once
+    /// or `RSU` on the connected node only, from a `-- dbmigrate:osu-method rsu` line
+    pub osu_method: Option<String>,
+}
+
+impl Annotations {
+    /// Finds all the annotations in the content of a file.
+    /// Unknown annotations are an error to catch typos early
+    pub fn parse(content: &str) -> Result<Annotations> {
+        let mut annotations = Annotations::default();
+
+        for line in content.lines().map(|l| l.trim()) {
+            if !line.starts_with(PREFIX) {
+                continue;
+            }
+            let mut parts = line[PREFIX.len()..].splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().map(|v| v.trim()).unwrap_or("");
+
+            match name {
+                "precondition" => {
+                    if value.is_empty() {
+                        bail!("Empty precondition");
+                    }
+                    annotations.preconditions.push(value.to_owned());
+                },
+                "assert" => {
+                    if value.is_empty() {
+                        bail!("Empty assertion");
+                    }
+                    annotations.assertions.push(value.to_owned());
+                },
+                "destructive" => annotations.destructive = true,
+                "irreversible" => annotations.irreversible = true,
+                "online" => annotations.online = true,
+                "parallel" => annotations.parallel = true,
+                "requires-server" => {
+                    if value.is_empty() {
+                        bail!("Empty server requirement");
+                    }
+                    for requirement in split_list(value) {
+                        annotations.requires_server.push(requirement.parse()?);
+                    }
+                },
+                "environments" => {
+                    if value.is_empty() {
+                        bail!("Empty environments");
+                    }
+                    annotations.environments.extend(split_list(value).map(|e| e.to_owned()));
+                },
+                "tags" => {
+                    if value.is_empty() {
+                        bail!("Empty tags");
+                    }
+                    annotations.tags.extend(split_list(value).map(|t| t.to_owned()));
+                },
+                "timeout" => annotations.timeout = Some(parse_duration(value)?),
+                "contract" => {
+                    let expand = value.parse().chain_err(|| format!("Invalid expand migration number: {}", value))?;
+                    annotations.contract = Some(expand);
+                },
+                "osu-method" => {
+                    let method = value.to_uppercase();
+                    if method != "TOI" && method != "RSU" {
+                        bail!("Invalid OSU method {:?}, expected toi or rsu", value);
+                    }
+                    annotations.osu_method = Some(method);
+                },
+                _ => bail!("Unknown annotation: {}", name),
+            }
+        }
+
+        Ok(annotations)
+    }
+}
+
+// Values separated by commas and/or whitespace
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(|c: char| c.is_whitespace() || c == ',').filter(|v| !v.is_empty())
+}
+
+/// Runs a query that should return a single boolean value
+pub fn check_condition<D: Driver + ?Sized>(driver: &D, query: &str) -> Result<bool> {
+    let rows = driver.query(query).chain_err(|| format!("Failed to check: {}", query))?;
+    let value = rows.into_iter().next().and_then(|row| row.into_iter().next()).and_then(|v| v);
+    Ok(is_true(value))
+}
+
+/// Whether a value returned by `Driver::query` is true, which depends on how
+/// the database represents booleans
+pub fn is_true(value: Option<String>) -> bool {
+    match value {
+        Some(v) => {
+            let v = v.to_lowercase();
+            v == "true" || v == "t" || v == "1"
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Annotations;
+
+    #[test]
+    fn test_parse_preconditions_and_assertions() {
+        let content = "-- dbmigrate:precondition SELECT count(*) = 0 FROM users\n\
+            -- a regular comment\n\
+            DROP TABLE users;\n\
+            -- dbmigrate:assert SELECT count(*) = 0 FROM users_archive";
+        let annotations = Annotations::parse(content).unwrap();
+        assert_eq!(annotations.preconditions, vec!["SELECT count(*) = 0 FROM users"]);
+        assert_eq!(annotations.assertions, vec!["SELECT count(*) = 0 FROM users_archive"]);
+        assert!(!annotations.destructive);
+        assert!(Annotations::parse("-- dbmigrate:destructive\nDROP TABLE users;").unwrap().destructive);
+        assert!(Annotations::parse("-- dbmigrate:irreversible\nDELETE FROM users;").unwrap().irreversible);
+        assert!(Annotations::parse("-- dbmigrate:online\nALTER TABLE users DROP COLUMN login;").unwrap().online);
+        assert!(Annotations::parse("-- dbmigrate:parallel\nCREATE INDEX users_name ON users (name);").unwrap().parallel);
+    }
+
+    #[test]
+    fn test_parse_server_requirements() {
+        let annotations = Annotations::parse("-- dbmigrate:requires-server >=5.7, <8\nSELECT 1;").unwrap();
+        let requirements = annotations.requires_server.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(requirements, vec![">=5.7", "<8"]);
+        assert!(Annotations::parse("-- dbmigrate:requires-server mysql 8").is_err());
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let annotations = Annotations::parse("-- dbmigrate:tags reporting, slow\nCREATE INDEX ...;").unwrap();
+        assert_eq!(annotations.tags, vec!["reporting", "slow"]);
+        let annotations = Annotations::parse("-- dbmigrate:environments dev,test\nINSERT INTO users ...;").unwrap();
+        assert_eq!(annotations.environments, vec!["dev", "test"]);
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let annotations = Annotations::parse("-- dbmigrate:timeout 10m\nUPDATE users SET active = true;").unwrap();
+        assert_eq!(annotations.timeout, Some(Duration::from_secs(600)));
+        assert!(Annotations::parse("-- dbmigrate:timeout soon").is_err());
+    }
+
+    #[test]
+    fn test_parse_contract() {
+        let annotations = Annotations::parse("-- dbmigrate:contract 12
+ALTER TABLE users DROP COLUMN login;").unwrap();
+        assert_eq!(annotations.contract, Some(12));
+        assert!(Annotations::parse("-- dbmigrate:contract").is_err());
+    }
+
+    #[test]
+    fn test_parse_osu_method() {
+        let annotations = Annotations::parse("-- dbmigrate:osu-method rsu
+ALTER TABLE users ADD INDEX idx_email (email);").unwrap();
+        assert_eq!(annotations.osu_method, Some("RSU".to_owned()));
+        assert!(Annotations::parse("-- dbmigrate:osu-method nbo").is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_annotation() {
+        assert!(Annotations::parse("-- dbmigrate:precondtion SELECT true").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+///! Dumps of a database taken before risky migrations
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use dialect::strip_dialect;
+use drivers::sqlite_path;
+use errors::{redact_urls, Result, ResultExt};
+use history::utc_now;
+
+/// Backs up the database at the URL in `dir`, in a file named after the database and the current
+/// UTC time, and returns its path. Postgres and MySQL are dumped with `pg_dump` and `mysqldump`,
+/// which need to be installed, SQLite files are copied.
+pub fn backup(url: &str, dir: &Path) -> Result<PathBuf> {
+    let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    fs::create_dir_all(dir).chain_err(|| format!("Failed to create {}", dir.display()))?;
+
+    match parsed_url.scheme() {
+        "postgres" => {
+            let path = dir.join(backup_filename(database_name(&parsed_url), "sql", &utc_now()));
+            let (dbname, password) = take_password(&strip_dialect(url)?.0)?;
+            let mut cmd = Command::new("pg_dump");
+            cmd.arg("--dbname").arg(dbname).arg("--file").arg(&path);
+            // Keeps the password out of the process list
+            if let Some(password) = password {
+                cmd.env("PGPASSWORD", password);
+            }
+            run_dump(cmd, "pg_dump")?;
+            Ok(path)
+        },
+        "mysql" | "mariadb" | "tidb" => {
+            let path = dir.join(backup_filename(database_name(&parsed_url), "sql", &utc_now()));
+            let default_port = if parsed_url.scheme() == "tidb" { 4000 } else { 3306 };
+            let mut cmd = Command::new("mysqldump");
+            cmd.arg("--host").arg(parsed_url.host_str().unwrap_or("localhost"))
+                .arg("--port").arg(parsed_url.port().unwrap_or(default_port).to_string())
+                .arg("--user").arg(decode(parsed_url.username()))
+                .arg("--result-file").arg(&path)
+                .arg(database_name(&parsed_url));
+            // Keeps the password out of the process list
+            if let Some(password) = parsed_url.password() {
+                cmd.env("MYSQL_PWD", decode(password));
+            }
+            run_dump(cmd, "mysqldump")?;
+            Ok(path)
+        },
+        "sqlite" => {
+            let source = sqlite_path(url);
+            if !source.is_file() {
+                bail!("Can only back up SQLite databases stored in a file, not {}", redact_urls(url));
+            }
+            let name = source.file_stem().and_then(|s| s.to_str()).unwrap_or("database").to_owned();
+            let path = dir.join(backup_filename(&name, "sqlite3", &utc_now()));
+            fs::copy(&source, &path)
+                .chain_err(|| format!("Failed to copy {} to {}", source.display(), path.display()))?;
+            Ok(path)
+        },
+        _ => bail!("Invalid URL: {}", redact_urls(url)),
+    }
+}
+
+fn database_name(url: &Url) -> &str {
+    url.path().trim_left_matches('/')
+}
+
+// The URL without its password, and the decoded password
+fn take_password(url: &str) -> Result<(String, Option<String>)> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let password = parsed_url.password().map(decode);
+    parsed_url.set_password(None).map_err(|_| format!("Invalid URL: {}", redact_urls(url)))?;
+    Ok((parsed_url.into_string(), password))
+}
+
+/// Percent-decodes a user name or password taken from a URL
+pub fn decode(value: &str) -> String {
+    percent_decode(value.as_bytes()).decode_utf8_lossy().into_owned()
+}
+
+fn run_dump(mut cmd: Command, program: &str) -> Result<()> {
+    let status = cmd.status().chain_err(|| format!("Failed to run {}, is it installed?", program))?;
+    if !status.success() {
+        bail!("{} failed: {}", program, status);
+    }
+    Ok(())
+}
+
+// `name-YYYYMMDD-HHMMSS.extension` from a time formatted as in the history
+fn backup_filename(name: &str, extension: &str, time: &str) -> String {
+    let time = time.replace("-", "").replace(":", "").replace(" ", "-");
+    format!("{}-{}.{}", if name.is_empty() { "database" } else { name }, time, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_filename, take_password};
+
+    #[test]
+    fn test_backup_filename() {
+        assert_eq!(backup_filename("app", "sql", "2026-10-16 09:51:26"), "app-20261016-095126.sql");
+        assert_eq!(backup_filename("", "sql", "2026-10-16 09:51:26"), "database-20261016-095126.sql");
+    }
+
+    #[test]
+    fn test_take_password() {
+        assert_eq!(
+            take_password("postgres://app:p%40ss@db/app?sslmode=require").unwrap(),
+            ("postgres://app@db/app?sslmode=require".to_owned(), Some("p@ss".to_owned()))
+        );
+        assert_eq!(take_password("postgres://app@db/app").unwrap(), ("postgres://app@db/app".to_owned(), None));
+    }
+}
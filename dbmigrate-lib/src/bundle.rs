@@ -0,0 +1,265 @@
+///! Bundles listing the SHA-256 of every migration file, optionally signed with GPG so only
+///! migrations approved by a trusted key get applied
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+use errors::{Result, ResultExt};
+use files::Migrations;
+
+/// Name of the bundle file written in the migrations folder by default
+pub const BUNDLE_FILENAME: &'static str = "dbmigrate.bundle";
+
+/// The SHA-256 of the migration files, by filename. It is written in the format of `sha256sum`
+/// so it can also be checked with `sha256sum -c`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bundle {
+    /// Hexadecimal SHA-256 of the raw content of each file
+    pub files: BTreeMap<String, String>,
+}
+
+impl Bundle {
+    /// Hashes the up and down files of all the migrations
+    pub fn create(migrations: &Migrations) -> Result<Bundle> {
+        let mut files = BTreeMap::new();
+        for migration in migrations.values() {
+            for file in migration.up.iter().chain(migration.down.iter()) {
+                files.insert(file.filename.clone(), sha256(&file.path)?);
+            }
+        }
+        Ok(Bundle { files: files })
+    }
+
+    /// Parses a bundle written by `write`
+    pub fn parse(content: &str) -> Result<Bundle> {
+        let mut files = BTreeMap::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.splitn(2, "  ");
+            match (parts.next(), parts.next()) {
+                (Some(hash), Some(filename)) if hash.len() == 64 => {
+                    files.insert(filename.to_owned(), hash.to_owned());
+                },
+                _ => bail!("Invalid bundle line: {}", line),
+            }
+        }
+        Ok(Bundle { files: files })
+    }
+
+    /// Reads the bundle at the given path
+    pub fn read(path: &Path) -> Result<Bundle> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .chain_err(|| format!("Failed to read {}", path.display()))?;
+        Bundle::parse(&content)
+    }
+
+    /// Writes the bundle at the given path
+    pub fn write(&self, path: &Path) -> Result<()> {
+        File::create(path)
+            .and_then(|mut f| f.write_all(self.to_string().as_bytes()))
+            .chain_err(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Checks the migrations are exactly the ones of the bundle, none being added, removed or modified
+    pub fn check(&self, migrations: &Migrations) -> Result<()> {
+        let current = Bundle::create(migrations)?;
+        for (filename, hash) in &current.files {
+            match self.files.get(filename) {
+                Some(expected) if expected == hash => (),
+                Some(_) => bail!("{} was modified since it was bundled", filename),
+                None => bail!("{} isn't part of the bundle", filename),
+            }
+        }
+        if let Some(filename) = self.files.keys().find(|filename| !current.files.contains_key(*filename)) {
+            bail!("{} is part of the bundle but is missing", filename);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Bundle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (filename, hash) in &self.files {
+            writeln!(f, "{}  {}", hash, filename)?;
+        }
+        Ok(())
+    }
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).chain_err(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+/// Path of the detached signature of a bundle, next to it
+pub fn signature_path(bundle: &Path) -> PathBuf {
+    let mut path = bundle.as_os_str().to_owned();
+    path.push(".asc");
+    PathBuf::from(path)
+}
+
+/// Signs the bundle with `gpg`, which needs to be installed, writing an armored detached signature
+/// next to it. The default key of gpg is used unless one is given.
+pub fn sign(bundle: &Path, key: Option<&str>) -> Result<PathBuf> {
+    let signature = signature_path(bundle);
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--batch").arg("--yes").arg("--armor").arg("--detach-sign").arg("--output").arg(&signature);
+    if let Some(key) = key {
+        cmd.arg("--local-user").arg(key);
+    }
+    cmd.arg(bundle);
+    run_gpg(cmd)?;
+    Ok(signature)
+}
+
+/// Checks the signature of the bundle with `gpg`. When a keyring is given, only its keys are trusted,
+/// otherwise any key of the default keyring is. Signatures made by expired or revoked keys are refused
+pub fn verify_signature(bundle: &Path, keyring: Option<&Path>) -> Result<()> {
+    let signature = signature_path(bundle);
+    if !signature.is_file() {
+        bail!("The bundle has no signature, {} doesn't exist", signature.display());
+    }
+    let keyring = match keyring {
+        // gpg looks for keyrings without a slash in its home directory
+        Some(keyring) => Some(fs::canonicalize(keyring).chain_err(|| format!("Failed to find {}", keyring.display()))?),
+        None => None,
+    };
+    let gpg = || {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--batch");
+        if let Some(ref keyring) = keyring {
+            cmd.arg("--no-default-keyring").arg("--keyring").arg(keyring);
+        }
+        cmd
+    };
+
+    let trusted = match keyring {
+        Some(_) => {
+            let mut cmd = gpg();
+            cmd.arg("--with-colons").arg("--list-keys");
+            Some(fingerprints(&run_gpg(cmd).chain_err(|| "Failed to list the keys of the keyring")?))
+        },
+        None => None,
+    };
+    let mut cmd = gpg();
+    cmd.arg("--status-fd").arg("1").arg("--verify").arg(&signature).arg(bundle);
+    run_gpg(cmd)
+        .and_then(|status| check_status(&status, trusted.as_ref().map(Vec::as_slice)))
+        .chain_err(|| format!("Invalid signature for {}", bundle.display()))
+}
+
+// The fingerprints of the keys and subkeys listed by `gpg --with-colons`
+fn fingerprints(listing: &str) -> Vec<String> {
+    listing.lines()
+        .filter(|line| line.starts_with("fpr:"))
+        .filter_map(|line| line.split(':').nth(9))
+        .map(|fpr| fpr.to_owned())
+        .collect()
+}
+
+// gpg succeeds on signatures of expired or revoked keys and of keys from any keyring it reads, so
+// the status lines are checked for a valid signature of a trusted key and none of the problems.
+// VALIDSIG gives the fingerprint of the signing key then, last, the one of its primary key
+fn check_status(status: &str, trusted: Option<&[String]>) -> Result<()> {
+    let mut valid = false;
+    for line in status.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("[GNUPG:]") {
+            continue;
+        }
+        match fields.next() {
+            Some("VALIDSIG") => {
+                let fingerprints = fields.collect::<Vec<_>>();
+                let signer = fingerprints.first().cloned().unwrap_or("");
+                let primary = fingerprints.last().cloned().unwrap_or("");
+                match trusted {
+                    Some(trusted) if !trusted.iter().any(|fpr| fpr == signer || fpr == primary) => {
+                        bail!("The signing key {} isn't in the keyring", primary);
+                    },
+                    _ => valid = true,
+                }
+            },
+            Some("EXPKEYSIG") => bail!("The signing key has expired"),
+            Some("REVKEYSIG") => bail!("The signing key has been revoked"),
+            Some("EXPSIG") => bail!("The signature has expired"),
+            Some("BADSIG") | Some("ERRSIG") => bail!("The signature doesn't match"),
+            _ => (),
+        }
+    }
+    if !valid {
+        bail!("gpg didn't report a valid signature");
+    }
+    Ok(())
+}
+
+// Runs gpg, returning what it wrote on stdout
+fn run_gpg(mut cmd: Command) -> Result<String> {
+    let output = cmd.output().chain_err(|| "Failed to run gpg, is it installed?")?;
+    if !output.status.success() {
+        bail!("gpg failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    use super::{check_status, fingerprints, Bundle};
+    use files::read_migration_files;
+
+    #[test]
+    fn test_check_detects_modified_and_added_files() {
+        let dir = TempDir::new("migrations").unwrap();
+        for name in &["0001.users.up.sql", "0001.users.down.sql"] {
+            File::create(dir.path().join(name)).unwrap().write_all(b"SELECT 1;").unwrap();
+        }
+        let bundle = Bundle::create(&read_migration_files(dir.path()).unwrap()).unwrap();
+        assert_eq!(Bundle::parse(&bundle.to_string()).unwrap(), bundle);
+        assert!(bundle.check(&read_migration_files(dir.path()).unwrap()).is_ok());
+
+        File::create(dir.path().join("0001.users.up.sql")).unwrap().write_all(b"SELECT 2;").unwrap();
+        assert!(bundle.check(&read_migration_files(dir.path()).unwrap()).is_err());
+
+        File::create(dir.path().join("0001.users.up.sql")).unwrap().write_all(b"SELECT 1;").unwrap();
+        for name in &["0002.posts.up.sql", "0002.posts.down.sql"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        assert!(bundle.check(&read_migration_files(dir.path()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_check_status() {
+        let key = "D8E5A4C1B2F3E4D5C6B7A8F9E0D1C2B3A4F5E6D7";
+        let valid = format!(
+            "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG A4F5E6D7 Jane <jane@example.com>\n\
+            [GNUPG:] VALIDSIG {0} 2024-01-02 1704153600 0 4 0 1 10 00 {0}\n[GNUPG:] TRUST_UNDEFINED 0 pgp\n",
+            key
+        );
+        assert!(check_status(&valid, None).is_ok());
+        assert!(check_status(&valid, Some(&[key.to_owned()])).is_ok());
+        assert!(check_status(&valid, Some(&["0000000000000000000000000000000000000000".to_owned()])).is_err());
+
+        let expired = valid.replace("GOODSIG", "EXPKEYSIG");
+        assert!(check_status(&expired, None).is_err());
+        let revoked = valid.replace("GOODSIG", "REVKEYSIG");
+        assert!(check_status(&revoked, Some(&[key.to_owned()])).is_err());
+        assert!(check_status("[GNUPG:] NEWSIG\n[GNUPG:] BADSIG A4F5E6D7 Jane\n", None).is_err());
+        assert!(check_status("", None).is_err());
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let listing = "tru::1:1704153600:0:3:1:5\npub:-:255:22:B2F3E4D5C6B7A8F9:1704153600:::-:::scSC:::::ed25519:::0:\n\
+            fpr:::::::::D8E5A4C1B2F3E4D5C6B7A8F9E0D1C2B3A4F5E6D7:\nuid:-::::1704153600::ABC::Jane::::::::::0:\n";
+        assert_eq!(fingerprints(listing), vec!["D8E5A4C1B2F3E4D5C6B7A8F9E0D1C2B3A4F5E6D7"]);
+    }
+}
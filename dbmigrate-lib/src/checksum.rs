@@ -0,0 +1,110 @@
+///! Checksums of migration files, to notice migrations edited after being applied
+use flate2::Crc;
+
+use errors::Result;
+use files::HEADER_PREFIX;
+use statements::strip_line_ending;
+
+/// CRC32 of the content, in hexadecimal. Unless `strict`, line endings and trailing whitespace
+/// are normalized first so checking out the files with CRLF line endings doesn't change it,
+/// and the header `create_migration` writes is left out
+pub fn checksum(content: &str, strict: bool) -> String {
+    let mut crc = Crc::new();
+    if strict {
+        crc.update(content.as_bytes());
+    } else {
+        crc.update(normalize(content).as_bytes());
+    }
+    format!("{:08x}", crc.sum())
+}
+
+/// Same as `checksum` for content given line by line, their line endings included, so that
+/// the whole content is never in memory
+pub fn checksum_lines<I: Iterator<Item = Result<String>>>(lines: I, strict: bool) -> Result<String> {
+    let mut crc = Crc::new();
+    // Empty lines are only added once a line follows them, the trailing ones being trimmed
+    let mut newlines = 0;
+    let mut first = true;
+    let mut in_header = true;
+    for line in lines {
+        let line = line?;
+        if strict {
+            crc.update(line.as_bytes());
+            continue;
+        }
+        if in_header && line.starts_with(HEADER_PREFIX) {
+            continue;
+        }
+        in_header = false;
+        if !first {
+            newlines += 1;
+        }
+        first = false;
+        let line = strip_line_ending(&line).trim_right();
+        if !line.is_empty() {
+            crc.update("\n".repeat(newlines).as_bytes());
+            crc.update(line.as_bytes());
+            newlines = 0;
+        }
+    }
+    Ok(format!("{:08x}", crc.sum()))
+}
+
+fn normalize(content: &str) -> String {
+    let lines = content.lines()
+        .skip_while(|line| line.starts_with(HEADER_PREFIX))
+        .map(|line| line.trim_right())
+        .collect::<Vec<_>>();
+    lines.join("\n").trim_right().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, checksum_lines};
+
+    #[test]
+    fn test_line_endings_and_trailing_whitespace_are_ignored() {
+        let unix = "CREATE TABLE a (id INT);\nDROP TABLE b;\n";
+        let windows = "CREATE TABLE a (id INT);  \r\nDROP TABLE b;\r\n\r\n";
+        assert_eq!(checksum(unix, false), checksum(windows, false));
+        assert!(checksum(unix, true) != checksum(windows, true));
+    }
+
+    #[test]
+    fn test_header_is_ignored() {
+        let header = "-- dbmigrate-header: Migration: 0001.drop_a.up.sql\n\
+            -- dbmigrate-header: Author: Jane\nDROP TABLE a;\n";
+        assert_eq!(checksum(header, false), checksum("DROP TABLE a;", false));
+        assert!(checksum(header, true) != checksum("DROP TABLE a;", true));
+        let footer = "DROP TABLE a;\n-- dbmigrate-header: Author: Jane";
+        assert!(checksum(footer, false) != checksum("DROP TABLE a;", false));
+    }
+
+    #[test]
+    fn test_other_changes_are_detected() {
+        assert!(checksum("DROP TABLE a;", false) != checksum("DROP  TABLE a;", false));
+        assert!(checksum("DROP TABLE a;", false) != checksum("\nDROP TABLE a;", false));
+    }
+
+    #[test]
+    fn test_checksum_of_lines() {
+        let lines = |content: &str| {
+            let mut lines = content.split('\n').map(|line| format!("{}\n", line)).collect::<Vec<_>>();
+            let last = lines.pop().unwrap();
+            if last != "\n" {
+                lines.push(last.trim_right_matches('\n').to_owned());
+            }
+            lines
+        };
+        let contents = [
+            "\nCREATE TABLE a (id INT);  \r\n\r\nDROP TABLE b;\r\n\r\n", "DROP TABLE a;", "",
+            "-- dbmigrate-header: Date: now\n\nDROP TABLE a;",
+        ];
+        for content in &contents {
+            let lines = lines(content);
+            for &strict in &[false, true] {
+                assert_eq!(checksum_lines(lines.iter().map(|l| Ok(l.clone())), strict).unwrap(), checksum(content, strict));
+            }
+        }
+    }
+}
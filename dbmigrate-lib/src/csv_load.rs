@@ -0,0 +1,145 @@
+///! Loading CSV files into tables
+use std::path::{Path, PathBuf};
+
+use csv;
+use regex::Regex;
+
+use drivers::Driver;
+use errors::{Result, ResultExt};
+
+// How many rows go in a single INSERT
+const BATCH_SIZE: usize = 500;
+
+/// Content of a `.load` migration file: which CSV file to load into which table.
+/// It is made of `key = value` lines, for example:
+///
+/// ```text
+/// # Reference data
+/// table = countries
+/// file = countries.csv
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct LoadManifest {
+    /// Table the rows are inserted into
+    pub table: String,
+    /// The CSV file, relative to the manifest. Its first row names the columns
+    pub file: PathBuf,
+}
+
+impl LoadManifest {
+    /// Parses the content of a manifest
+    pub fn parse(content: &str) -> Result<LoadManifest> {
+        let mut table = None;
+        let mut file = None;
+
+        for line in content.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=').map(|p| p.trim());
+            match (parts.next(), parts.next()) {
+                (Some("table"), Some(value)) => table = Some(value.to_owned()),
+                (Some("file"), Some(value)) => file = Some(PathBuf::from(value)),
+                _ => bail!("Invalid manifest line: {}", line),
+            }
+        }
+
+        match (table, file) {
+            (Some(table), Some(file)) => {
+                check_identifier(&table)?;
+                Ok(LoadManifest { table: table, file: file })
+            },
+            _ => bail!("A manifest needs both a table and a file"),
+        }
+    }
+}
+
+// Names end up in queries as-is so only allow simple ones
+fn check_identifier(name: &str) -> Result<()> {
+    let re = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*(\.[a-zA-Z_][a-zA-Z0-9_]*)?$").unwrap();
+    if !re.is_match(name) {
+        bail!("{} is not a valid table or column name", name);
+    }
+    Ok(())
+}
+
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace("'", "''"))
+}
+
+/// Reads the column names from the first row of a CSV file
+pub fn read_columns(path: &Path) -> Result<Vec<String>> {
+    let mut reader = csv::Reader::from_path(path).chain_err(|| format!("Failed to open {:?}", path))?;
+    let columns = reader.headers()
+        .chain_err(|| format!("Failed to read the header of {:?}", path))?
+        .iter()
+        .map(|c| c.trim().to_owned())
+        .collect::<Vec<_>>();
+    for column in &columns {
+        check_identifier(column)?;
+    }
+    Ok(columns)
+}
+
+/// Builds the INSERT statements for the given rows, empty values being NULL
+fn insert_statements(table: &str, columns: &[String], rows: &[Vec<String>]) -> Vec<String> {
+    rows.chunks(BATCH_SIZE).map(|batch| {
+        let values = batch.iter()
+            .map(|row| {
+                let values = row.iter()
+                    .map(|v| if v.is_empty() { "NULL".to_owned() } else { quote(v) })
+                    .collect::<Vec<_>>();
+                format!("({})", values.join(", "))
+            })
+            .collect::<Vec<_>>();
+        format!("INSERT INTO {} ({}) VALUES {};", table, columns.join(", "), values.join(", "))
+    }).collect()
+}
+
+/// Loads a CSV file using batched INSERTs, for databases without a bulk loading command
+pub fn insert_csv_rows<D: Driver + ?Sized>(driver: &D, table: &str, path: &Path) -> Result<()> {
+    let columns = read_columns(path)?;
+    let mut reader = csv::Reader::from_path(path).chain_err(|| format!("Failed to open {:?}", path))?;
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record.chain_err(|| format!("Invalid CSV in {:?}", path))?;
+        rows.push(record.iter().map(|v| v.to_owned()).collect::<Vec<_>>());
+    }
+
+    for statement in insert_statements(table, &columns, &rows) {
+        driver.execute(&statement).chain_err(|| format!("Failed to load {:?} into {}", path, table))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{insert_statements, LoadManifest};
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = LoadManifest::parse("# Countries\ntable = countries\nfile = data/countries.csv\n").unwrap();
+        assert_eq!(manifest, LoadManifest { table: "countries".to_owned(), file: PathBuf::from("data/countries.csv") });
+    }
+
+    #[test]
+    fn test_parse_manifest_invalid_table() {
+        assert!(LoadManifest::parse("table = countries; DROP TABLE users\nfile = c.csv").is_err());
+        assert!(LoadManifest::parse("file = c.csv").is_err());
+    }
+
+    #[test]
+    fn test_insert_statements() {
+        let columns = vec!["code".to_owned(), "name".to_owned()];
+        let rows = vec![
+            vec!["FR".to_owned(), "France".to_owned()],
+            vec!["CI".to_owned(), "Côte d'Ivoire".to_owned()],
+            vec!["XX".to_owned(), "".to_owned()],
+        ];
+        assert_eq!(
+            insert_statements("countries", &columns, &rows),
+            vec!["INSERT INTO countries (code, name) VALUES ('FR', 'France'), ('CI', 'Côte d''Ivoire'), ('XX', NULL);"]
+        );
+    }
+}
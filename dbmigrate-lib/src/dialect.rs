@@ -0,0 +1,352 @@
+///! Databases derived from Postgres, selected with the `dialect` parameter of Postgres URLs, which
+///! need the driver to run some statements differently, and the `LintTarget`s `lint` checks migrations for:
+///! these dialects and MariaDB, a flavor of the MySQL driver
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+use url::Url;
+
+use drivers::{parse_flavor, Flavor};
+use errors::{redact_urls, Error, Result, ResultExt};
+use files::{MigrationFile, MigrationKind, Migrations};
+use statements::{split_statements, statement_starts};
+
+/// The flavour of Postgres the migrations are written for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// Postgres itself
+    Postgres,
+    /// TimescaleDB, whose extension statements need a session of their own
+    Timescale,
+    /// Greenplum, whose tables should say how they are distributed across segments
+    Greenplum,
+}
+
+impl Dialect {
+    /// All the names accepted by `from_str`
+    pub fn variants() -> [&'static str; 3] {
+        ["postgres", "timescale", "greenplum"]
+    }
+
+    /// The dialect given by the `dialect` parameter of the URL, Postgres without it
+    pub fn from_url(url: &str) -> Result<Dialect> {
+        strip_dialect(url).map(|(_, dialect)| dialect)
+    }
+
+    /// Splits a migration into the statements to run first, each in a new session, and the rest.
+    /// TimescaleDB needs `ALTER EXTENSION timescaledb UPDATE` to be the first statement of its
+    /// session, and the extension to exist before anything uses it. Only statements are matched,
+    /// not what comments, strings or function bodies contain
+    pub fn extension_statements(&self, migration: &str) -> (Vec<String>, String) {
+        if *self != Dialect::Timescale {
+            return (vec![], migration.to_owned());
+        }
+        let extension = Regex::new(r#"(?i)^(CREATE|ALTER)\s+EXTENSION\s+(IF\s+NOT\s+EXISTS\s+)?"?timescaledb\b"?[^;]*;"#)
+            .unwrap();
+        let mut statements = vec![];
+        let mut rest = String::new();
+        let mut end = 0;
+        for start in statement_starts(migration) {
+            if let Some(m) = extension.find(&migration[start..]) {
+                statements.push(m.as_str().to_owned());
+                rest.push_str(&migration[end..start]);
+                end = start + m.end();
+            }
+        }
+        rest.push_str(&migration[end..]);
+        (statements, rest)
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect::Postgres
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Dialect::Postgres => "postgres",
+            Dialect::Timescale => "timescale",
+            Dialect::Greenplum => "greenplum",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Dialect> {
+        match s.to_lowercase().as_str() {
+            "postgres" | "postgresql" => Ok(Dialect::Postgres),
+            "timescale" | "timescaledb" => Ok(Dialect::Timescale),
+            "greenplum" => Ok(Dialect::Greenplum),
+            _ => bail!("Unknown dialect {}, expected one of {}", s, Dialect::variants().join(", ")),
+        }
+    }
+}
+
+/// What `lint` checks the migrations against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintTarget {
+    /// Postgres or a database derived from it
+    Postgres(Dialect),
+    /// MariaDB, whatever its URL, as the MySQL driver finds it from the URL
+    Mariadb,
+    /// MySQL and its other flavors, which have nothing to check
+    Mysql,
+}
+
+impl LintTarget {
+    /// All the names accepted by `from_str`
+    pub fn variants() -> [&'static str; 5] {
+        ["postgres", "timescale", "greenplum", "mariadb", "mysql"]
+    }
+
+    /// The target of the database URL: the flavor of `mysql://`, `mariadb://` and `tidb://` URLs, like
+    /// the MySQL driver reads it, and the dialect of the others
+    pub fn from_url(url: &str) -> Result<LintTarget> {
+        let scheme = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?.scheme().to_owned();
+        match scheme.as_str() {
+            "mysql" | "mariadb" | "tidb" => match parse_flavor(url)?.1 {
+                Flavor::Mariadb => Ok(LintTarget::Mariadb),
+                _ => Ok(LintTarget::Mysql),
+            },
+            _ => Dialect::from_url(url).map(LintTarget::Postgres),
+        }
+    }
+}
+
+impl Default for LintTarget {
+    fn default() -> LintTarget {
+        LintTarget::Postgres(Dialect::default())
+    }
+}
+
+impl fmt::Display for LintTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LintTarget::Postgres(dialect) => write!(f, "{}", dialect),
+            LintTarget::Mariadb => write!(f, "mariadb"),
+            LintTarget::Mysql => write!(f, "mysql"),
+        }
+    }
+}
+
+impl FromStr for LintTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LintTarget> {
+        match s.to_lowercase().as_str() {
+            "mariadb" => Ok(LintTarget::Mariadb),
+            "mysql" => Ok(LintTarget::Mysql),
+            _ => s.parse().map(LintTarget::Postgres).chain_err(|| {
+                format!("Unknown lint target {}, expected one of {}", s, LintTarget::variants().join(", "))
+            }),
+        }
+    }
+}
+
+/// Takes the `dialect` parameter out of the URL, as the server would reject it
+pub fn strip_dialect(url: &str) -> Result<(String, Dialect)> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let mut dialect = Dialect::default();
+    let mut others = vec![];
+    for (key, value) in parsed_url.query_pairs().into_owned() {
+        match key.as_str() {
+            "dialect" => dialect = value.parse()?,
+            _ => others.push((key, value)),
+        }
+    }
+    if others.len() == parsed_url.query_pairs().count() {
+        return Ok((url.to_owned(), dialect));
+    }
+    if others.is_empty() {
+        parsed_url.set_query(None);
+    } else {
+        parsed_url.query_pairs_mut().clear().extend_pairs(others);
+        // Spaces are encoded as `+`, which neither the postgres crate nor libpq decode
+        let query = parsed_url.query().map(|q| q.replace('+', "%20"));
+        parsed_url.set_query(query.as_ref().map(String::as_str));
+    }
+    Ok((parsed_url.into_string(), dialect))
+}
+
+/// Something in a migration file the dialect won't handle well
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintWarning {
+    /// The file it was found in
+    pub filename: String,
+    /// What's wrong
+    pub message: String,
+}
+
+/// Checks the up migrations against the target, reading their content one at a time:
+///
+/// - Greenplum: `CREATE TABLE` statements need a `DISTRIBUTED BY`, `DISTRIBUTED RANDOMLY` or
+///   `DISTRIBUTED REPLICATED` clause, tables being distributed by their first column otherwise
+/// - TimescaleDB: TimescaleDB functions and options can't be used before the migration creating the extension
+/// - MariaDB: `ALTER TABLE` statements should set an `ALGORITHM`, MariaDB silently copying the whole table
+///   when it can't make the change instantly otherwise
+pub fn lint(migrations: &Migrations, target: LintTarget) -> Result<Vec<LintWarning>> {
+    match target {
+        LintTarget::Postgres(dialect) => lint_dialect(migrations, dialect),
+        LintTarget::Mariadb => lint_mariadb(migrations),
+        LintTarget::Mysql => Ok(vec![]),
+    }
+}
+
+fn lint_dialect(migrations: &Migrations, dialect: Dialect) -> Result<Vec<LintWarning>> {
+    let files = sql_up_files(migrations);
+    let mut warnings = vec![];
+    match dialect {
+        Dialect::Postgres => (),
+        Dialect::Greenplum => {
+            let create_table = Regex::new(r"(?i)^CREATE\s+(UNLOGGED\s+)?TABLE\s+(IF\s+NOT\s+EXISTS\s+)?([^\s(]+)").unwrap();
+            let distributed = Regex::new(r"(?i)\bDISTRIBUTED\s+(BY|RANDOMLY|REPLICATED)\b").unwrap();
+            for file in &files {
+                for statement in split_statements(&file.read_content()?, ';') {
+                    if let Some(captures) = create_table.captures(&statement) {
+                        if !distributed.is_match(&statement) {
+                            warnings.push(LintWarning {
+                                filename: file.filename.clone(),
+                                message: format!(
+                                    "{} has no DISTRIBUTED clause, Greenplum distributes it by its first column", &captures[3]
+                                ),
+                            });
+                        }
+                    }
+                }
+            }
+        },
+        Dialect::Timescale => {
+            let creates_extension = Regex::new(r#"(?i)\bCREATE\s+EXTENSION\s+(IF\s+NOT\s+EXISTS\s+)?"?timescaledb\b"#).unwrap();
+            let uses_timescale = Regex::new(
+                r"(?i)\b(create_hypertable|add_retention_policy|add_compression_policy|add_continuous_aggregate_policy|time_bucket)\s*\(|\btimescaledb\."
+            ).unwrap();
+            // Without a migration creating it, the extension was created some other way
+            let mut creating = None;
+            for file in &files {
+                if creates_extension.is_match(&file.read_content()?) {
+                    creating = Some(file);
+                    break;
+                }
+            }
+            if let Some(creating) = creating {
+                for file in files.iter().take_while(|file| file.number < creating.number) {
+                    if uses_timescale.is_match(&file.read_content()?) {
+                        warnings.push(LintWarning {
+                            filename: file.filename.clone(),
+                            message: format!("uses TimescaleDB before {} creates the extension", creating.filename),
+                        });
+                    }
+                }
+            }
+        },
+    }
+    Ok(warnings)
+}
+
+fn lint_mariadb(migrations: &Migrations) -> Result<Vec<LintWarning>> {
+    let alter_table = Regex::new(r"(?i)^ALTER\s+(ONLINE\s+|IGNORE\s+)*TABLE\s+(IF\s+EXISTS\s+)?(\S+)").unwrap();
+    let algorithm = Regex::new(r"(?i)\bALGORITHM\s*=").unwrap();
+    let mut warnings = vec![];
+    for file in sql_up_files(migrations) {
+        for statement in split_statements(&file.read_content()?, ';') {
+            if let Some(captures) = alter_table.captures(&statement) {
+                if !algorithm.is_match(&statement) {
+                    warnings.push(LintWarning {
+                        filename: file.filename.clone(),
+                        message: format!(
+                            "ALTER TABLE {} has no ALGORITHM, add ALGORITHM=INSTANT to fail rather than \
+                            copy the table if the change can't be made instantly", &captures[3]
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+fn sql_up_files(migrations: &Migrations) -> Vec<&MigrationFile> {
+    migrations.values().filter_map(|m| m.up.as_ref())
+        .filter(|file| file.kind == MigrationKind::Sql)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    use super::{lint, strip_dialect, Dialect, LintTarget};
+    use files::read_migration_files;
+
+    #[test]
+    fn test_dialects() {
+        assert_eq!(
+            strip_dialect("postgres://db/app?dialect=timescale&sslmode=require").unwrap(),
+            ("postgres://db/app?sslmode=require".to_owned(), Dialect::Timescale)
+        );
+        assert_eq!(Dialect::from_url("postgres://db/app").unwrap(), Dialect::Postgres);
+        assert!(Dialect::from_url("postgres://db/app?dialect=redshift").is_err());
+        assert!(Dialect::from_url("postgres://db/app?dialect=mariadb").is_err());
+        assert_eq!(LintTarget::from_url("mariadb://db/app").unwrap(), LintTarget::Mariadb);
+        assert_eq!(LintTarget::from_url("mysql://db/app?flavor=mariadb").unwrap(), LintTarget::Mariadb);
+        assert_eq!(LintTarget::from_url("mysql://db/app").unwrap(), LintTarget::Mysql);
+        assert_eq!(LintTarget::from_url("tidb://db/app").unwrap(), LintTarget::Mysql);
+        assert_eq!(
+            LintTarget::from_url("postgres://db/app?dialect=greenplum").unwrap(),
+            LintTarget::Postgres(Dialect::Greenplum)
+        );
+        assert_eq!("timescale".parse::<LintTarget>().unwrap(), LintTarget::Postgres(Dialect::Timescale));
+        assert_eq!("MariaDB".parse::<LintTarget>().unwrap(), LintTarget::Mariadb);
+        assert!("oracle".parse::<LintTarget>().is_err());
+
+        let migration = "CREATE TABLE metrics (time TIMESTAMPTZ NOT NULL, value DOUBLE PRECISION);\n\
+            ALTER EXTENSION timescaledb UPDATE;\n\
+            SELECT create_hypertable('metrics', 'time');\n";
+        let (first, rest) = Dialect::Timescale.extension_statements(migration);
+        assert_eq!(first, vec!["ALTER EXTENSION timescaledb UPDATE;"]);
+        assert!(!rest.contains("EXTENSION") && rest.contains("create_hypertable"));
+        assert_eq!(Dialect::Greenplum.extension_statements(migration), (vec![], migration.to_owned()));
+        let commented = "-- Run ALTER EXTENSION timescaledb UPDATE; by hand\n\
+            COMMENT ON TABLE metrics IS '\nCREATE EXTENSION timescaledb;';\n";
+        assert_eq!(Dialect::Timescale.extension_statements(commented), (vec![], commented.to_owned()));
+    }
+
+    #[test]
+    fn test_lint() {
+        let dir = TempDir::new("migrations").unwrap();
+        let files = [
+            ("0001.metrics.up.sql", "CREATE TABLE metrics (time TIMESTAMPTZ, value INT) DISTRIBUTED RANDOMLY;\n\
+                CREATE TABLE events (id INT);"),
+            ("0002.hypertable.up.sql", "SELECT create_hypertable('metrics', 'time');"),
+            ("0003.timescale.up.sql", "CREATE EXTENSION IF NOT EXISTS timescaledb;"),
+            ("0004.columns.up.sql", "ALTER TABLE users ADD COLUMN bio TEXT;\n\
+                ALTER TABLE users ADD COLUMN age INT, ALGORITHM=INSTANT;"),
+        ];
+        for &(name, content) in &files {
+            File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+            File::create(dir.path().join(name.replace(".up.", ".down."))).unwrap();
+        }
+        let migrations = read_migration_files(dir.path()).unwrap();
+
+        assert!(lint(&migrations, LintTarget::Postgres(Dialect::Postgres)).unwrap().is_empty());
+        let greenplum = lint(&migrations, LintTarget::Postgres(Dialect::Greenplum)).unwrap();
+        assert_eq!(greenplum.len(), 1);
+        assert!(greenplum[0].message.starts_with("events has no DISTRIBUTED clause"));
+        let timescale = lint(&migrations, LintTarget::Postgres(Dialect::Timescale)).unwrap();
+        assert_eq!(timescale.len(), 1);
+        assert_eq!(timescale[0].filename, "0002.hypertable.up.sql");
+        let mariadb = lint(&migrations, LintTarget::Mariadb).unwrap();
+        assert_eq!(mariadb.len(), 1);
+        assert!(mariadb[0].message.starts_with("ALTER TABLE users has no ALGORITHM"));
+        assert!(lint(&migrations, LintTarget::Mysql).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+///! Marker of a migration that failed partway, leaving the database in a state no migration number describes
+use drivers::Driver;
+use errors::{Result, ResultExt};
+
+/// Table holding the current migration number, in which the marker is kept
+pub const MIGRATION_TABLE: &'static str = "__dbmigrate_table";
+
+// Migration tables created before the marker existed lack its column, added by the given statement
+fn ensure_column<D: Driver + ?Sized>(driver: &D, table: &str, add_column: &str) -> Result<()> {
+    if driver.query(&format!("SELECT dirty FROM {} WHERE 1 = 0;", table)).is_err() {
+        driver.execute(add_column).chain_err(|| "Failed to add the dirty column to the migration table")?;
+    }
+    Ok(())
+}
+
+// The standard statement adding the column, which some databases spell differently
+fn add_column(table: &str) -> String {
+    format!("ALTER TABLE {} ADD COLUMN dirty INTEGER;", table)
+}
+
+/// The migration that left the database dirty according to the migration table, if one did
+pub fn select_dirty<D: Driver + ?Sized>(driver: &D, table: &str) -> Result<Option<i32>> {
+    select_dirty_with(driver, table, &add_column(table))
+}
+
+/// Same as `select_dirty`, for databases adding the column with another statement than
+/// `ALTER TABLE ... ADD COLUMN dirty INTEGER`
+pub fn select_dirty_with<D: Driver + ?Sized>(driver: &D, table: &str, add_column: &str) -> Result<Option<i32>> {
+    ensure_column(driver, table, add_column)?;
+    let rows = driver.query(&format!("SELECT dirty FROM {} WHERE id = 1;", table))?;
+    match rows.into_iter().next().and_then(|row| row.into_iter().next()).and_then(|v| v) {
+        Some(number) => number.parse().map(Some).chain_err(|| format!("Invalid dirty migration: {}", number)),
+        None => Ok(None),
+    }
+}
+
+/// Records the migration that left the database dirty in the migration table, `None` clearing it
+pub fn update_dirty<D: Driver + ?Sized>(driver: &D, table: &str, number: Option<i32>) -> Result<()> {
+    update_dirty_with(driver, table, &add_column(table), number)
+}
+
+/// Same as `update_dirty`, for databases adding the column with another statement
+pub fn update_dirty_with<D: Driver + ?Sized>(
+    driver: &D, table: &str, add_column: &str, number: Option<i32>
+) -> Result<()> {
+    ensure_column(driver, table, add_column)?;
+    driver.execute(&format!(
+        "UPDATE {} SET dirty = {} WHERE id = 1;", table, number.map_or("NULL".to_owned(), |n| n.to_string())
+    )).chain_err(|| "Failed to update the dirty marker")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::{select_dirty_with, update_dirty_with};
+    use drivers::Driver;
+    use errors::Result;
+
+    // A migration table lacking the dirty column until it is added
+    #[derive(Default)]
+    struct OldTableDriver {
+        has_column: Cell<bool>,
+        executed: RefCell<Vec<String>>,
+    }
+
+    impl Driver for OldTableDriver {
+        fn ensure_migration_table_exists(&self) {}
+
+        fn remove_migration_table(&self) {}
+
+        fn get_current_number(&self) -> i32 {
+            0
+        }
+
+        fn set_current_number(&self, _: i32, _: i32) -> Result<()> {
+            Ok(())
+        }
+
+        fn migrate(&self, migration: String, _: i32, _: i32) -> Result<()> {
+            self.execute(&migration)
+        }
+
+        fn execute(&self, sql: &str) -> Result<()> {
+            if sql.starts_with("ALTER TABLE") {
+                self.has_column.set(true);
+            }
+            self.executed.borrow_mut().push(sql.to_owned());
+            Ok(())
+        }
+
+        fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+            if !self.has_column.get() {
+                bail!("Unknown column dirty");
+            }
+            Ok(if sql.contains("1 = 0") { vec![] } else { vec![vec![Some("3".to_owned())]] })
+        }
+    }
+
+    #[test]
+    fn test_column_is_added_with_the_given_statement() {
+        let driver = OldTableDriver::default();
+        let add_column = "ALTER TABLE migrations ADD (dirty INTEGER);";
+        update_dirty_with(&driver, "migrations", add_column, Some(3)).unwrap();
+        assert_eq!(select_dirty_with(&driver, "migrations", add_column).unwrap(), Some(3));
+        assert_eq!(*driver.executed.borrow(), vec![
+            "ALTER TABLE migrations ADD (dirty INTEGER);",
+            "UPDATE migrations SET dirty = 3 WHERE id = 1;",
+        ]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use url::Url;
+
+use super::{query_value, Driver};
+use super::google::{value_to_text, GoogleApi};
+use super::portable::{adapt, is_tracking_sql};
+use annotations::check_condition;
+use history::{current_user, utc_now};
+use lock::LOCK_TABLE;
+use schema::rows_to_schema;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+const API: &'static str = "https://bigquery.googleapis.com/bigquery/v2";
+const RESERVED: &'static [&'static str] = &["current"];
+// Primary keys can only be declared NOT ENFORCED, which is of no use to dbmigrate
+const REPLACEMENTS: &'static [(&'static str, &'static str)] = &[
+    ("INTEGER", "INT64"),
+    ("VARCHAR", "STRING"),
+    ("TEXT", "STRING"),
+    ("PRIMARY KEY", ""),
+];
+// How long a request waits for the query job before polling again
+const JOB_WAIT: Duration = Duration::from_secs(10);
+
+
+pub struct BigQuery {
+    api: GoogleApi,
+    project: String,
+    dataset: String,
+    location: Option<String>,
+}
+
+// Rows of a query job, and the rows changed by DML statements
+struct JobResult {
+    rows: Vec<Vec<Option<String>>>,
+    affected: Option<i64>,
+}
+
+impl BigQuery {
+    pub fn new(url: &str) -> Result<BigQuery> {
+        let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+        let segments = parsed_url.path_segments().map_or(vec![], |s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>());
+        let (project, dataset) = match (parsed_url.host_str(), segments.as_slice()) {
+            (Some(project), [dataset]) => (project.to_owned(), dataset.to_string()),
+            _ => bail!("Invalid URL: {}, expected bigquery://project/dataset", redact_urls(url)),
+        };
+        let location = parsed_url.query_pairs().find(|&(ref key, _)| key == "location").map(|(_, value)| value.into_owned());
+
+        let bigquery = BigQuery { api: GoogleApi::new()?, project: project, dataset: dataset, location: location };
+        bigquery.ensure_migration_table_exists();
+        Ok(bigquery)
+    }
+
+    // Runs the SQL, which can be a script of several statements, as a query job in the dataset
+    // and waits for it to complete
+    fn job(&self, sql: &str) -> Result<JobResult> {
+        let sql = if is_tracking_sql(sql) { adapt(sql, '`', RESERVED, REPLACEMENTS) } else { sql.to_owned() };
+        let mut body = json!({
+            "query": sql,
+            "useLegacySql": false,
+            "defaultDataset": { "projectId": self.project, "datasetId": self.dataset },
+            "timeoutMs": JOB_WAIT.as_secs() * 1000,
+        });
+        if let Some(ref location) = self.location {
+            body["location"] = json!(location);
+        }
+        let mut response = self.api.call("POST", &format!("{}/projects/{}/queries", API, self.project), Some(body))
+            .chain_err(|| format!("Query failed: {}", sql))?;
+
+        let mut result = JobResult { rows: vec![], affected: None };
+        loop {
+            let complete = response["jobComplete"].as_bool().unwrap_or(false);
+            if complete {
+                result.rows.extend(rows(&response));
+                if let Some(affected) = response["numDmlAffectedRows"].as_str().and_then(|n| n.parse().ok()) {
+                    result.affected = Some(affected);
+                }
+            }
+            // Incomplete jobs are polled, complete ones paged through
+            let page_token = response["pageToken"].as_str().map(|t| t.to_owned());
+            if complete && page_token.is_none() {
+                return Ok(result);
+            }
+            let job = match response.pointer("/jobReference/jobId").and_then(Value::as_str) {
+                Some(job) => job.to_owned(),
+                None => bail!("BigQuery didn't return the job of: {}", sql),
+            };
+            let mut url = Url::parse(&format!("{}/projects/{}/queries/{}", API, self.project, job)).unwrap();
+            url.query_pairs_mut().append_pair("timeoutMs", &(JOB_WAIT.as_secs() * 1000).to_string());
+            if let Some(location) = response.pointer("/jobReference/location").and_then(Value::as_str) {
+                url.query_pairs_mut().append_pair("location", location);
+            }
+            if let Some(token) = page_token {
+                url.query_pairs_mut().append_pair("pageToken", &token);
+            }
+            response = self.api.call("GET", url.as_str(), None).chain_err(|| format!("Query failed: {}", sql))?;
+        }
+    }
+}
+
+impl Driver for BigQuery {
+    fn ensure_migration_table_exists(&self) {
+        self.job("CREATE TABLE IF NOT EXISTS __dbmigrate_table (id INTEGER, current INTEGER);").unwrap();
+        if self.query("SELECT current FROM __dbmigrate_table WHERE id = 1;").unwrap().is_empty() {
+            self.job("INSERT INTO __dbmigrate_table (id, current) VALUES (1, 0);").unwrap();
+        }
+    }
+
+    fn remove_migration_table(&self) {
+        self.job("DROP TABLE __dbmigrate_table;").unwrap();
+    }
+
+    fn get_current_number(&self) -> i32 {
+        query_value(self, "SELECT current FROM __dbmigrate_table WHERE id = 1;").unwrap().parse().unwrap()
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let result = self.job(&format!(
+            "UPDATE __dbmigrate_table SET current = {} WHERE id = 1 AND current = {};", number, expected
+        ))?;
+        if result.affected == Some(0) {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
+    }
+
+    // Schema changes can't be rolled back, a failing migration leaves the statements before the failing one applied
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.job(&migration).chain_err(|| "Migration failed")?;
+        self.set_current_number(expected, number)
+    }
+
+    // Nothing can be rolled back, the migration number just isn't updated when an assertion fails
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        self.job(&migration).chain_err(|| "Migration failed")?;
+        for assertion in assertions {
+            if !check_condition(self, assertion)? {
+                bail!("Assertion failed: {}", assertion);
+            }
+        }
+        self.set_current_number(expected, number)
+    }
+
+    // Every request is a new HTTP call, there is no connection to keep alive
+    fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.job(sql).map(|_| ())
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        self.job(sql).map(|result| result.rows)
+    }
+
+    fn schema(&self) -> Result<String> {
+        let mut rows = self.query("
+            SELECT 'column', table_name, column_name, data_type, is_nullable, column_default
+            FROM INFORMATION_SCHEMA.COLUMNS;
+        ")?;
+        rows.extend(self.query("
+            SELECT 'view', table_name, view_definition
+            FROM INFORMATION_SCHEMA.VIEWS;
+        ")?);
+        Ok(rows_to_schema(rows))
+    }
+
+    // Primary keys aren't enforced, so the lock row is only inserted if there is none
+    fn try_lock(&self) -> Result<bool> {
+        self.job(&format!("
+            CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER NOT NULL,
+                locked_at VARCHAR(19) NOT NULL,
+                locked_by VARCHAR(255)
+            );
+        ", LOCK_TABLE)).chain_err(|| "Failed to create the lock table")?;
+
+        let locked_by = current_user().map_or("NULL".to_owned(), |user| quote(&user));
+        let result = self.job(&format!(
+            "INSERT INTO {table} (id, locked_at, locked_by) SELECT 1, {}, {} FROM UNNEST([1])
+            WHERE NOT EXISTS (SELECT 1 FROM {table} WHERE id = 1);",
+            quote(&utc_now()), locked_by, table = LOCK_TABLE
+        ))?;
+        Ok(result.affected == Some(1))
+    }
+}
+
+fn rows(response: &Value) -> Vec<Vec<Option<String>>> {
+    response["rows"].as_array().map_or(vec![], |rows| {
+        rows.iter().map(|row| {
+            row["f"].as_array().map_or(vec![], |fields| fields.iter().map(|field| value_to_text(&field["v"])).collect())
+        }).collect()
+    })
+}
+
+// A GoogleSQL string literal
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rows;
+
+    #[test]
+    fn test_rows() {
+        let response = json!({
+            "jobComplete": true,
+            "rows": [{ "f": [{ "v": "1" }, { "v": null }] }, { "f": [{ "v": "2" }, { "v": "users" }] }],
+        });
+        assert_eq!(rows(&response), vec![
+            vec![Some("1".to_owned()), None],
+            vec![Some("2".to_owned()), Some("users".to_owned())],
+        ]);
+    }
+}
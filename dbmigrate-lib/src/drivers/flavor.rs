@@ -0,0 +1,115 @@
+///! The flavors of the servers speaking the MySQL protocol, given by the URL
+use url::Url;
+
+use errors::{redact_urls, Result, ResultExt};
+
+const TIDB_PORT: u16 = 4000;
+
+/// The servers speaking the MySQL protocol which need to be handled differently
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flavor {
+    Mysql,
+    /// MariaDB, given by `mariadb://` URLs or detected from the server version
+    Mariadb,
+    /// TiDB, whose named locks aren't reliable and whose versions before 6.2 can't make
+    /// several changes in one `ALTER TABLE`
+    Tidb { multi_schema_change: bool },
+    /// Vitess, PlanetScale included, which doesn't support foreign keys and can run the DDL statements
+    /// as online DDL migrations
+    Vitess { online_ddl: bool },
+    /// A Galera cluster, Percona XtraDB Cluster included, whose node has to be synced with the cluster
+    /// before migrating and whose DDL statements can be run in rolling upgrade mode
+    Galera,
+}
+
+impl Flavor {
+    // Whether GET_LOCK can be relied on for the migration lock, Galera doesn't replicate it to the other nodes
+    pub fn has_named_locks(&self) -> bool {
+        match *self {
+            Flavor::Mysql | Flavor::Mariadb => true,
+            Flavor::Tidb { .. } | Flavor::Vitess { .. } | Flavor::Galera => false,
+        }
+    }
+}
+
+// The flavor of the URL, TiDB for `tidb://` ones and MariaDB for `mariadb://` ones, otherwise given by
+// `flavor` and `ddl_strategy`. The URL is returned as a `mysql://` one without these parameters.
+// Whether TiDB supports multi-schema changes is only known once connected
+pub fn parse_flavor(url: &str) -> Result<(String, Flavor)> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let mut flavor = None;
+    if parsed_url.scheme() == "mariadb" {
+        flavor = Some("mariadb".to_owned());
+        parsed_url = Url::parse(&format!("mysql{}", &url["mariadb".len()..]))
+            .chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    }
+    if parsed_url.scheme() == "tidb" {
+        flavor = Some("tidb".to_owned());
+        parsed_url = Url::parse(&format!("mysql{}", &url["tidb".len()..]))
+            .chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+        if parsed_url.port().is_none() {
+            parsed_url.set_port(Some(TIDB_PORT)).map_err(|_| format!("Invalid URL: {}", redact_urls(url)))?;
+        }
+    }
+
+    let mut ddl_strategy = None;
+    let mut others = vec![];
+    for (key, value) in parsed_url.query_pairs().into_owned() {
+        match key.as_str() {
+            "flavor" => flavor = Some(value),
+            "ddl_strategy" => ddl_strategy = Some(value),
+            _ => others.push((key, value)),
+        }
+    }
+    if others.is_empty() {
+        parsed_url.set_query(None);
+    } else {
+        parsed_url.query_pairs_mut().clear().extend_pairs(others);
+    }
+
+    let flavor = match (flavor.as_ref().map(String::as_str), ddl_strategy.as_ref().map(String::as_str)) {
+        (Some("vitess"), None) | (Some("vitess"), Some("direct")) => Flavor::Vitess { online_ddl: false },
+        (Some("vitess"), Some("vitess")) | (Some("vitess"), Some("online")) => Flavor::Vitess { online_ddl: true },
+        (Some("vitess"), Some(strategy)) => bail!("Invalid ddl_strategy {}, expected direct or vitess", strategy),
+        (_, Some(_)) => bail!("The ddl_strategy parameter is only supported with flavor=vitess"),
+        (None, None) | (Some("mysql"), None) => Flavor::Mysql,
+        (Some("mariadb"), None) => Flavor::Mariadb,
+        (Some("tidb"), None) => Flavor::Tidb { multi_schema_change: false },
+        (Some("galera"), None) => Flavor::Galera,
+        (Some(flavor), None) => bail!("Invalid flavor {}, expected mysql, mariadb, tidb, vitess or galera", flavor),
+    };
+    Ok((parsed_url.into_string(), flavor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_flavor, Flavor};
+
+    #[test]
+    fn test_parse_flavor() {
+        assert_eq!(
+            parse_flavor("tidb://root@db/app").unwrap(),
+            ("mysql://root@db:4000/app".to_owned(), Flavor::Tidb { multi_schema_change: false })
+        );
+        assert_eq!(
+            parse_flavor("mysql://root@db/app?flavor=tidb&charset=utf8mb4").unwrap(),
+            ("mysql://root@db/app?charset=utf8mb4".to_owned(), Flavor::Tidb { multi_schema_change: false })
+        );
+        assert_eq!(
+            parse_flavor("mysql://root@db:3307/app").unwrap(),
+            ("mysql://root@db:3307/app".to_owned(), Flavor::Mysql)
+        );
+        assert_eq!(
+            parse_flavor("mysql://root@db/app?flavor=vitess&ddl_strategy=vitess").unwrap(),
+            ("mysql://root@db/app".to_owned(), Flavor::Vitess { online_ddl: true })
+        );
+        assert_eq!(parse_flavor("mariadb://root@db/app").unwrap(), ("mysql://root@db/app".to_owned(), Flavor::Mariadb));
+        assert_eq!(
+            parse_flavor("mysql://root@db/app?flavor=galera").unwrap(),
+            ("mysql://root@db/app".to_owned(), Flavor::Galera)
+        );
+        assert!(parse_flavor("mysql://root@db/app?flavor=percona").is_err());
+        assert!(parse_flavor("mysql://root@db/app?ddl_strategy=vitess").is_err());
+        assert!(parse_flavor("mysql://root@db/app?flavor=vitess&ddl_strategy=gh-ost").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+///! Calls to the REST APIs of Google Cloud, authenticated like the gcloud CLI
+use std::env;
+use std::process::Command;
+use std::time::Duration;
+
+use serde_json::Value;
+use ureq;
+
+use super::options::DEFAULT_CONNECT_TIMEOUT;
+use errors::{Result, ResultExt};
+
+pub struct GoogleApi {
+    token: String,
+}
+
+impl GoogleApi {
+    /// Uses the access token in `GOOGLE_OAUTH_ACCESS_TOKEN`, or the one of the account gcloud is logged in with
+    pub fn new() -> Result<GoogleApi> {
+        let token = match env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+            Ok(token) => token,
+            Err(_) => {
+                let output = Command::new("gcloud").arg("auth").arg("print-access-token").output()
+                    .chain_err(|| "Failed to run gcloud, set GOOGLE_OAUTH_ACCESS_TOKEN or install the Google Cloud SDK")?;
+                if !output.status.success() {
+                    bail!("gcloud auth print-access-token failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+                }
+                String::from_utf8_lossy(&output.stdout).trim().to_owned()
+            },
+        };
+        Ok(GoogleApi { token: token })
+    }
+
+    /// Sends the request, returning the JSON response or failing with the message of the API
+    pub fn call(&self, method: &str, url: &str, body: Option<Value>) -> Result<Value> {
+        let mut request = ureq::request(method, url);
+        request.set("Authorization", &format!("Bearer {}", self.token))
+            .timeout_connect(DEFAULT_CONNECT_TIMEOUT.as_secs() * 1000)
+            .timeout(Duration::from_secs(60));
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+        if let Some(ref e) = *response.synthetic_error() {
+            bail!("{} {} failed: {}", method, url, e);
+        }
+        let status = response.status();
+        let json = response.into_json();
+        if status >= 300 {
+            let error = json.unwrap_or(Value::Null);
+            let message = error.pointer("/error/message").and_then(Value::as_str).unwrap_or("no message");
+            bail!("{} {} failed with {}: {}", method, url, status, message);
+        }
+        json.chain_err(|| format!("Invalid response to {} {}", method, url))
+    }
+}
+
+/// A JSON value of a query result as text, NULL being None
+pub fn value_to_text(value: &Value) -> Option<String> {
+    match *value {
+        Value::Null => None,
+        Value::String(ref s) => Some(s.clone()),
+        ref other => Some(other.to_string()),
+    }
+}
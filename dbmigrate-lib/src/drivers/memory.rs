@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::Driver;
+use errors::{Error, ErrorKind, Result};
+use files::Direction;
+use history::HistoryEntry;
+
+#[derive(Debug, Default)]
+struct State {
+    current: i32,
+    executed: Vec<String>,
+    query_results: HashMap<String, Vec<Vec<Option<String>>>>,
+    history: Vec<HistoryEntry>,
+    server_version: Option<String>,
+    locked: bool,
+    dirty: Option<i32>,
+    read_only: Option<String>,
+    not_ready: Option<String>,
+    transactional_ddl: bool,
+    // Migrations still to fail, with which error and whether before running anything
+    failures: usize,
+    failure: String,
+    refused: bool,
+}
+
+/// A fake driver keeping everything in memory, to test code embedding dbmigrate without a
+/// real database. It records the SQL it is given instead of running it.
+/// Clones share the same state: keep one to inspect it after giving the other to a `Migrator`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDriver {
+    state: Rc<RefCell<State>>,
+}
+
+impl MemoryDriver {
+    /// Creates a driver with no migration applied
+    pub fn new() -> MemoryDriver {
+        MemoryDriver::default()
+    }
+
+    /// All the SQL executed so far, migrations and queries, in order
+    pub fn executed(&self) -> Vec<String> {
+        self.state.borrow().executed.clone()
+    }
+
+    /// Sets the rows `query` will return for the given SQL, no rows being returned otherwise
+    pub fn set_query_result(&self, sql: &str, rows: Vec<Vec<Option<String>>>) {
+        self.state.borrow_mut().query_results.insert(sql.to_owned(), rows);
+    }
+
+    /// Sets the version `server_version` returns, it fails until then
+    pub fn set_server_version(&self, version: &str) {
+        self.state.borrow_mut().server_version = Some(version.to_owned());
+    }
+
+    /// Makes the database read-only for the given reason, like a replica, or writable again with `None`
+    pub fn set_read_only(&self, reason: Option<&str>) {
+        self.state.borrow_mut().read_only = reason.map(|r| r.to_owned());
+    }
+
+    /// Makes `check_ready` fail for the given reason, or succeed again with `None`
+    pub fn set_not_ready(&self, reason: Option<&str>) {
+        self.state.borrow_mut().not_ready = reason.map(|r| r.to_owned());
+    }
+
+    /// Makes the driver report transactional DDL, its failed migrations then counting as rolled back
+    pub fn set_transactional_ddl(&self, transactional: bool) {
+        self.state.borrow_mut().transactional_ddl = transactional;
+    }
+
+    /// Makes the next `times` migrations fail with the given error, without being recorded
+    pub fn fail_migrations(&self, times: usize, error: &str) {
+        let mut state = self.state.borrow_mut();
+        state.failures = times;
+        state.failure = error.to_owned();
+        state.refused = false;
+    }
+
+    /// Same as `fail_migrations`, the migrations being refused before running anything like drivers
+    /// do with statements the server doesn't support
+    pub fn refuse_migrations(&self, times: usize, error: &str) {
+        self.fail_migrations(times, error);
+        self.state.borrow_mut().refused = true;
+    }
+}
+
+impl Driver for MemoryDriver {
+    fn ensure_migration_table_exists(&self) {}
+
+    fn remove_migration_table(&self) {
+        self.state.borrow_mut().current = 0;
+    }
+
+    fn get_current_number(&self) -> i32 {
+        self.state.borrow().current
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if state.current != expected {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        state.current = number;
+        Ok(())
+    }
+
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.failures > 0 {
+                state.failures -= 1;
+                let error = Error::from(state.failure.clone());
+                if state.refused {
+                    return Err(Error::with_chain(error, ErrorKind::NotStarted));
+                }
+                return Err(error);
+            }
+            state.executed.push(migration);
+        }
+        self.set_current_number(expected, number)
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.state.borrow_mut().executed.push(sql.to_owned());
+        Ok(())
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let mut state = self.state.borrow_mut();
+        state.executed.push(sql.to_owned());
+        Ok(state.query_results.get(sql).cloned().unwrap_or_default())
+    }
+
+    // There is no schema, the SQL executed so far stands in for it
+    fn schema(&self) -> Result<String> {
+        Ok(self.state.borrow().executed.join("\n"))
+    }
+
+    fn server_version(&self) -> Result<String> {
+        match self.state.borrow().server_version {
+            Some(ref version) => Ok(version.clone()),
+            None => bail!("No server version was set"),
+        }
+    }
+
+    fn try_lock(&self) -> Result<bool> {
+        let mut state = self.state.borrow_mut();
+        if state.locked {
+            return Ok(false);
+        }
+        state.locked = true;
+        Ok(true)
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.state.borrow_mut().locked = false;
+        Ok(())
+    }
+
+    fn has_transactional_ddl(&self) -> bool {
+        self.state.borrow().transactional_ddl
+    }
+
+    fn ensure_history_table(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // The history is kept apart so it doesn't show up in `executed`
+    fn record_history(&self, entry: &HistoryEntry) -> Result<()> {
+        self.state.borrow_mut().history.push(entry.clone());
+        Ok(())
+    }
+
+    fn history(&self) -> Result<Vec<HistoryEntry>> {
+        Ok(self.state.borrow().history.clone())
+    }
+
+    fn remove_history(&self, number: i32) -> Result<()> {
+        self.state.borrow_mut().history.retain(|entry| entry.number != number);
+        Ok(())
+    }
+
+    fn schema_snapshot(&self, number: i32) -> Result<Option<String>> {
+        let state = self.state.borrow();
+        let snapshot = state.history.iter().rev()
+            .filter(|entry| entry.number == number && entry.direction == Direction::Up)
+            .filter_map(|entry| entry.schema_snapshot.clone())
+            .next();
+        Ok(snapshot)
+    }
+
+    fn update_checksum(&self, number: i32, checksum: &str) -> Result<()> {
+        for entry in self.state.borrow_mut().history.iter_mut() {
+            if entry.number == number && entry.direction == Direction::Up {
+                entry.checksum = Some(checksum.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_only(&self) -> Result<Option<String>> {
+        Ok(self.state.borrow().read_only.clone())
+    }
+
+    fn check_ready(&self) -> Result<()> {
+        match self.state.borrow().not_ready {
+            Some(ref reason) => bail!("The database isn't ready: {}", reason),
+            None => Ok(()),
+        }
+    }
+
+    fn dirty(&self) -> Result<Option<i32>> {
+        Ok(self.state.borrow().dirty)
+    }
+
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        self.state.borrow_mut().dirty = number;
+        Ok(())
+    }
+}
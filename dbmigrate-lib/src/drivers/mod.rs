@@ -1,12 +1,57 @@
 ///! Driver interface and implementations
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
 use url::{Url};
 
-use errors::{Result, ResultExt};
+use annotations::{check_condition, Annotations};
+use csv_load::insert_csv_rows;
+use dirty;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+use files::MigrationKind;
+use history::{self, HistoryEntry};
+use lock;
+use timeout::Canceller;
+
+#[cfg(any(feature = "postgres_support", feature = "mysql_support", feature = "odbc_support"))]
+use self::options::ConnectionOptions;
+
+pub use self::flavor::{parse_flavor, Flavor};
+pub use self::options::{with_connect_timeout, with_postgres_option, with_search_path, DEFAULT_CONNECT_TIMEOUT};
 
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
+#[cfg(feature = "bigquery_support")]
+mod bigquery;
+#[cfg(feature = "db2_support")]
+mod db2;
+// Also used by `lint`, to tell MariaDB apart without the MySQL driver
+#[cfg_attr(not(feature = "mysql_support"), allow(dead_code))]
+mod flavor;
+#[cfg(any(feature = "spanner_support", feature = "bigquery_support"))]
+mod google;
+#[cfg(feature = "mongodb_support")]
+mod mongodb;
 #[cfg(feature = "mysql_support")]
 mod mysql;
+#[cfg(feature = "neo4j_support")]
+mod neo4j;
+#[cfg(feature = "odbc_support")]
+mod odbc;
+#[cfg(feature = "oracle_support")]
+mod oracle;
+// Only the functions changing URLs are used without the drivers connecting with URL parameters
+#[cfg_attr(not(any(feature = "postgres_support", feature = "mysql_support", feature = "odbc_support",
+    feature = "oracle_support", feature = "mongodb_support", feature = "neo4j_support")), allow(dead_code))]
+mod options;
+#[cfg(any(test, feature = "oracle_support", feature = "odbc_support", feature = "spanner_support",
+    feature = "bigquery_support"))]
+mod portable;
 #[cfg(feature = "postgres_support")]
 mod postgres;
+#[cfg(feature = "spanner_support")]
+mod spanner;
 #[cfg(feature = "sqlite_support")]
 mod sqlite;
 
@@ -21,26 +66,365 @@ pub trait Driver {
     fn remove_migration_table(&self);
     /// Get the current migration number from the database
     fn get_current_number(&self) -> i32;
-    /// Set the current migration number in the database
-    fn set_current_number(&self, number: i32);
+    /// Set the current migration number in the database, only if it is still `expected`.
+    /// Fails with `ErrorKind::ConcurrentModification` otherwise
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()>;
     /// Perform the `migration` content on the database and set
-    /// the migration number to be the `number` given
-    fn migrate(&self, migration: String, number: i32) -> Result<()>;
+    /// the migration number from `expected` to the `number` given
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()>;
+    /// Same as `migrate` for migrations too large to be held in memory, given as statements which
+    /// are run one at a time as they are read, along with the annotations of their file as the
+    /// statements don't have its comments. Drivers doing more than running the SQL in `migrate`
+    /// do it here too. Runs them in a transaction on databases with transactional DDL, a failed
+    /// migration leaves the statements before the failing one applied on the others
+    fn migrate_statements(
+        &self, statements: &mut Iterator<Item = Result<String>>, _annotations: &Annotations, expected: i32,
+        number: i32
+    ) -> Result<()> {
+        migrate_statements_in_transaction(self, statements, expected, number)
+    }
+    /// Same as `migrate` but in a transaction, which is rolled back if one of the assertions
+    /// (queries returning a boolean) isn't true after the migration ran.
+    /// Note that MySQL commits implicitly after most DDL statements.
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        migrate_in_transaction(self, migration, expected, number, assertions)
+    }
+    /// Whether schema changes can be rolled back, which isn't the case for MySQL
+    fn has_transactional_ddl(&self) -> bool {
+        false
+    }
+    /// The kind of migration files `migrate` runs, SQL for all the databases but MongoDB and Neo4j
+    fn migration_kind(&self) -> MigrationKind {
+        MigrationKind::Sql
+    }
+    /// A function cancelling the migration this driver is running, called from another thread
+    /// when it runs for longer than its timeout. None if the database doesn't support it
+    fn canceller(&self) -> Result<Option<Canceller>> {
+        Ok(None)
+    }
+    /// Version of the database server, as it reports it (`8.0.33-0ubuntu0.22.04.1` for example)
+    fn server_version(&self) -> Result<String> {
+        bail!("The server version isn't available for this database")
+    }
+    /// Why the database only accepts reads, like a replica, None when it accepts writes. Drivers
+    /// check it when connecting, before creating their table. None by default
+    fn read_only(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Fails when the database can't be migrated right now, like a Galera node out of sync.
+    /// Checked before each migration, whatever its kind. Ready by default
+    fn check_ready(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Runs a trivial query so that the connection isn't closed for being idle while the migrator
+    /// waits, `SELECT 1` by default
+    fn ping(&self) -> Result<()> {
+        self.query("SELECT 1;").map(|_| ())
+    }
+    /// Run the given SQL without changing the migration number
+    fn execute(&self, sql: &str) -> Result<()>;
+    /// Run a query and return all its rows, each value converted to a string
+    /// (`None` being NULL). Used to read the state of other tools or the database itself
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>>;
+    /// A textual description of the schema (tables, columns, indexes...), one object per line
+    /// in a stable order so dumps can be compared. The dbmigrate tables are left out
+    fn schema(&self) -> Result<String> {
+        bail!("Schema dumps aren't supported by this database")
+    }
+    /// Load a CSV file, whose first row names the columns, into the given table.
+    /// Uses batched INSERTs unless the database has something better
+    fn load_csv(&self, table: &str, path: &Path) -> Result<()> {
+        insert_csv_rows(self, table, path)
+    }
+    /// Takes the lock making concurrent runs wait for each other, returning false right away
+    /// if another process holds it. Uses a lock table unless the database has something better
+    fn try_lock(&self) -> Result<bool> {
+        lock::try_lock_table(self)
+    }
+    /// Releases the lock taken by `try_lock`
+    fn unlock(&self) -> Result<()> {
+        lock::unlock_table(self)
+    }
+    /// Creates the history table or brings an older one up to date, once when connecting
+    fn ensure_history_table(&self) -> Result<()> {
+        history::ensure_table(self, history::HISTORY_TABLE)
+    }
+    /// Appends a migration that was just applied or reverted to the history table
+    fn record_history(&self, entry: &HistoryEntry) -> Result<()> {
+        history::insert_entry(self, history::HISTORY_TABLE, entry)
+    }
+    /// All the migrations applied and reverted so far, oldest first
+    fn history(&self) -> Result<Vec<HistoryEntry>> {
+        history::select_entries(self, history::HISTORY_TABLE)
+    }
+    /// Forgets every history entry of a migration
+    fn remove_history(&self, number: i32) -> Result<()> {
+        history::delete_entries(self, history::HISTORY_TABLE, number)
+    }
+    /// The dump of the schema recorded the last time the migration was applied, if schema
+    /// snapshots were enabled then
+    fn schema_snapshot(&self, number: i32) -> Result<Option<String>> {
+        history::select_schema_snapshot(self, history::HISTORY_TABLE, number)
+    }
+    /// Replaces the checksum recorded when the migration was applied, accepting a change of its file
+    fn update_checksum(&self, number: i32, checksum: &str) -> Result<()> {
+        history::update_checksum(self, history::HISTORY_TABLE, number, checksum)
+    }
+    /// The migration that failed partway and left the database dirty, if one did
+    fn dirty(&self) -> Result<Option<i32>> {
+        dirty::select_dirty(self, dirty::MIGRATION_TABLE)
+    }
+    /// Marks the database as left dirty by the given migration, or as clean with `None`
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        dirty::update_dirty(self, dirty::MIGRATION_TABLE, number)
+    }
+}
+
+impl Driver {
+    /// Connects to the database of the URL with the driver of its scheme, like `postgres://` or
+    /// `mysql://`. Same as `get_driver`
+    pub fn from_url(url: &str) -> Result<Box<Driver>> {
+        get_driver(url)
+    }
+}
+
+// Every URL scheme with the feature its driver needs
+const SCHEMES: [(&'static str, &'static str); 14] = [
+    ("postgres", "postgres_support"),
+    ("mysql", "mysql_support"),
+    ("mariadb", "mysql_support"),
+    ("tidb", "mysql_support"),
+    ("sqlite", "sqlite_support"),
+    ("oracle", "oracle_support"),
+    ("db2", "db2_support"),
+    ("odbc", "odbc_support"),
+    ("spanner", "spanner_support"),
+    ("bigquery", "bigquery_support"),
+    ("mongodb", "mongodb_support"),
+    ("mongodb+srv", "mongodb_support"),
+    ("neo4j", "neo4j_support"),
+    ("bolt", "neo4j_support"),
+];
+
+// Schemes other tools use, or easily mistaken for the ones above
+const MISTAKES: [(&'static str, &'static str); 8] = [
+    ("postgresql", "postgres"),
+    ("pg", "postgres"),
+    ("psql", "postgres"),
+    ("sqlite3", "sqlite"),
+    ("mongo", "mongodb"),
+    ("mssql", "odbc"),
+    ("sqlserver", "odbc"),
+    ("cockroachdb", "postgres"),
+];
+
+fn is_enabled(feature: &str) -> bool {
+    match feature {
+        "postgres_support" => cfg!(feature = "postgres_support"),
+        "mysql_support" => cfg!(feature = "mysql_support"),
+        "sqlite_support" => cfg!(feature = "sqlite_support"),
+        "oracle_support" => cfg!(feature = "oracle_support"),
+        "db2_support" => cfg!(feature = "db2_support"),
+        "odbc_support" => cfg!(feature = "odbc_support"),
+        "spanner_support" => cfg!(feature = "spanner_support"),
+        "bigquery_support" => cfg!(feature = "bigquery_support"),
+        "mongodb_support" => cfg!(feature = "mongodb_support"),
+        "neo4j_support" => cfg!(feature = "neo4j_support"),
+        _ => false,
+    }
+}
+
+/// The URL schemes this build has a driver for
+pub fn supported_schemes() -> Vec<&'static str> {
+    SCHEMES.iter().filter(|&&(_, feature)| is_enabled(feature)).map(|&(scheme, _)| scheme).collect()
+}
+
+// Why there is no driver for the scheme: a typo, or a driver left out of this build
+fn unsupported_scheme(scheme: &str) -> String {
+    let supported = supported_schemes().join(", ");
+    if let Some(&(_, feature)) = SCHEMES.iter().find(|&&(s, _)| s == scheme) {
+        return format!("dbmigrate was built without the {} feature, which {}:// URLs need", feature, scheme);
+    }
+    match MISTAKES.iter().find(|&&(mistake, _)| mistake == scheme) {
+        Some(&(_, meant)) if meant == "odbc" => {
+            let hint = "SQL Server is reached with odbc:// URLs";
+            format!("Unsupported URL scheme {}://, {} (supported: {})", scheme, hint, supported)
+        },
+        Some(&(_, meant)) => {
+            format!("Unsupported URL scheme {}://, did you mean {}://? (supported: {})", scheme, meant, supported)
+        },
+        None => format!("Unsupported URL scheme {}://, expected one of {}", scheme, supported),
+    }
 }
 
 /// Returns a driver instance depending on url
 pub fn get_driver(url: &str) -> Result<Box<Driver>> {
+    let driver = connect(url)?;
+    // Replicas get the table of their primary, which they can't write to anyway
+    if driver.read_only()?.is_none() {
+        driver.ensure_history_table()?;
+    }
+    Ok(driver)
+}
+
+// Connects with the driver of the scheme of the URL
+fn connect(url: &str) -> Result<Box<Driver>> {
     let parsed_url = Url::parse(url)
-        .chain_err(|| format!("Invalid URL: {}", url))?;
+        .chain_err(|| ErrorKind::ParseError(format!("Invalid URL: {}", redact_urls(url))))?;
 
     match parsed_url.scheme() {
         #[cfg(feature = "postgres_support")]
         "postgres" => postgres::Postgres::new(url).map(|d| Box::new(d) as Box<Driver>),
         #[cfg(feature = "mysql_support")]
-        "mysql" => mysql::Mysql::new(url).map(|d| Box::new(d) as Box<Driver>),
+        "mysql" | "mariadb" | "tidb" => mysql::Mysql::new(url).map(|d| Box::new(d) as Box<Driver>),
         #[cfg(feature = "sqlite_support")]
         "sqlite" => sqlite::Sqlite::new(url).map(|d| Box::new(d) as Box<Driver>),
-        _ => bail!("Invalid URL: {}", url)
+        #[cfg(feature = "oracle_support")]
+        "oracle" => oracle::Oracle::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "db2_support")]
+        "db2" => db2::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "odbc_support")]
+        "odbc" => odbc::Odbc::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "spanner_support")]
+        "spanner" => spanner::Spanner::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "bigquery_support")]
+        "bigquery" => bigquery::BigQuery::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "mongodb_support")]
+        "mongodb" | "mongodb+srv" => mongodb::Mongodb::new(url).map(|d| Box::new(d) as Box<Driver>),
+        #[cfg(feature = "neo4j_support")]
+        "neo4j" | "bolt" => neo4j::Neo4j::new(url).map(|d| Box::new(d) as Box<Driver>),
+        scheme => bail!(ErrorKind::ParseError(unsupported_scheme(scheme))),
+    }
+}
+
+
+/// Fails with `ErrorKind::ReadOnly` if the database only accepts reads, instead of the database
+/// refusing the first write of the migrations with an error of its own
+pub fn check_writable<D: Driver + ?Sized>(driver: &D) -> Result<()> {
+    match driver.read_only()? {
+        Some(reason) => bail!(ErrorKind::ReadOnly(reason)),
+        None => Ok(()),
+    }
+}
+
+/// The single value returned by a query, such as a version
+pub fn query_value<D: Driver + ?Sized>(driver: &D, sql: &str) -> Result<String> {
+    let rows = driver.query(sql)?;
+    match rows.into_iter().next().and_then(|row| row.into_iter().next()).and_then(|v| v) {
+        Some(value) => Ok(value),
+        None => bail!("No value returned by {}", sql),
+    }
+}
+
+// What `migrate_with_assertions` does by default, for drivers overriding it to do more
+fn migrate_in_transaction<D: Driver + ?Sized>(
+    driver: &D, migration: String, expected: i32, number: i32, assertions: &[String]
+) -> Result<()> {
+    driver.execute("BEGIN;")?;
+    let res = driver.execute(&migration).chain_err(|| "Migration failed").and_then(|_| {
+        for assertion in assertions {
+            if !check_condition(driver, assertion)? {
+                bail!("Assertion failed: {}", assertion);
+            }
+        }
+        Ok(())
+    }).and_then(|_| driver.set_current_number(expected, number));
+
+    match res {
+        Ok(_) => driver.execute("COMMIT;"),
+        Err(e) => {
+            driver.execute("ROLLBACK;").ok();
+            Err(e)
+        }
+    }
+}
+
+// What `migrate_statements` does by default, for drivers overriding it to do more
+fn migrate_statements_in_transaction<D: Driver + ?Sized>(
+    driver: &D, statements: &mut Iterator<Item = Result<String>>, expected: i32, number: i32
+) -> Result<()> {
+    let run = |statements: &mut Iterator<Item = Result<String>>| {
+        for statement in statements {
+            driver.execute(&statement?).chain_err(|| "Migration failed")?;
+        }
+        driver.set_current_number(expected, number)
+    };
+    if !driver.has_transactional_ddl() {
+        return run(statements);
+    }
+
+    driver.execute("BEGIN;")?;
+    match run(statements) {
+        Ok(_) => driver.execute("COMMIT;"),
+        Err(e) => {
+            driver.execute("ROLLBACK;").ok();
+            Err(e)
+        }
+    }
+}
+
+/// Path of the file of a SQLite URL, its parameters removed
+pub fn sqlite_path(url: &str) -> PathBuf {
+    let without_params = url.split('?').next().unwrap_or(url);
+    // the replace is probably wrong
+    PathBuf::from(without_params.replace("sqlite:/", ""))
+}
+
+/// Creates a database named `name` on the server at `url`, returning the URL to connect to it.
+/// Sqlite databases are created in the temporary directory.
+pub fn create_database(url: &str, name: &str) -> Result<String> {
+    let mut parsed_url = Url::parse(url)
+        .chain_err(|| ErrorKind::ParseError(format!("Invalid URL: {}", redact_urls(url))))?;
+
+    match parsed_url.scheme() {
+        #[cfg(feature = "postgres_support")]
+        "postgres" => postgres::execute_on_server(url, &format!("CREATE DATABASE {};", name))?,
+        #[cfg(feature = "mysql_support")]
+        "mysql" | "mariadb" | "tidb" => mysql::execute_on_server(url, &format!("CREATE DATABASE {};", name))?,
+        #[cfg(feature = "sqlite_support")]
+        "sqlite" => {
+            // The file is created when connecting
+            let path = env::temp_dir().join(format!("{}.db", name));
+            return Ok(format!("sqlite://{}", path.display()));
+        },
+        _ => bail!(ErrorKind::ParseError(format!("Invalid URL: {}", redact_urls(url))))
+    }
+
+    parsed_url.set_path(&format!("/{}", name));
+    Ok(parsed_url.into_string())
+}
+
+/// Drops a database created by `create_database`
+pub fn drop_database(url: &str, name: &str) -> Result<()> {
+    let parsed_url = Url::parse(url)
+        .chain_err(|| ErrorKind::ParseError(format!("Invalid URL: {}", redact_urls(url))))?;
+
+    match parsed_url.scheme() {
+        #[cfg(feature = "postgres_support")]
+        "postgres" => postgres::execute_on_server(url, &format!("DROP DATABASE IF EXISTS {};", name)),
+        #[cfg(feature = "mysql_support")]
+        "mysql" | "mariadb" | "tidb" => mysql::execute_on_server(url, &format!("DROP DATABASE IF EXISTS {};", name)),
+        #[cfg(feature = "sqlite_support")]
+        "sqlite" => {
+            let path = env::temp_dir().join(format!("{}.db", name));
+            fs::remove_file(&path).chain_err(|| format!("Failed to remove {:?}", path))
+        },
+        _ => bail!(ErrorKind::ParseError(format!("Invalid URL: {}", redact_urls(url))))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{supported_schemes, Driver};
+
+    #[test]
+    fn test_unsupported_schemes_are_explained() {
+        let message = |url: &str| Driver::from_url(url).err().unwrap().to_string();
+        assert!(message("postgresql://db/app").contains("postgresql://, did you mean postgres://?"));
+        assert!(message("sqlserver://db/app").contains("odbc://"));
+        assert!(message("redis://db").ends_with(&format!("expected one of {}", supported_schemes().join(", "))));
+        if !supported_schemes().contains(&"neo4j") {
+            assert!(message("neo4j://db").contains("built without the neo4j_support feature"));
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
+
+use mongodb_client::bson::{doc, Bson, Document};
+use mongodb_client::options::{FindOneOptions, FindOptions};
+use mongodb_client::sync::{Client, Collection, Database};
+use serde_json::{self, Value};
+use url::Url;
+
+use super::Driver;
+use super::options::{connecting, ConnectionOptions};
+use annotations::check_condition;
+use files::{Direction, MigrationKind};
+use history::{current_user, utc_now, HistoryEntry, HISTORY_TABLE};
+use schema::rows_to_schema;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+// Holds the `version` document with the current number and the `lock` one while migrating
+const COLLECTION: &'static str = "__dbmigrate";
+
+
+pub struct Mongodb {
+    db: Database,
+}
+
+impl Mongodb {
+    pub fn new(url: &str) -> Result<Mongodb> {
+        // The client only connects on the first command, which waits for a server up to the
+        // server selection timeout
+        let options = ConnectionOptions::parse(url)?;
+        let timeout = options.connect_timeout();
+        let client = Client::with_uri_str(&with_timeouts(&options.url, timeout)?)
+            .chain_err(|| ErrorKind::ConnectionError(redact_urls(url)))?;
+        let db = match client.default_database() {
+            Some(db) => db,
+            None => bail!("Invalid URL: {}, it needs a database", redact_urls(url)),
+        };
+        let mongodb = Mongodb { db: db };
+        connecting(url, timeout, || mongodb.ping().chain_err(|| ErrorKind::ConnectionError(redact_urls(url))))?;
+        // Secondaries refuse writes, the version document being replicated from the primary
+        if mongodb.read_only()?.is_none() {
+            mongodb.ensure_migration_table_exists();
+        }
+        Ok(mongodb)
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.db.collection(COLLECTION)
+    }
+
+    fn history_collection(&self) -> Collection<Document> {
+        self.db.collection(HISTORY_TABLE)
+    }
+
+    fn run_command(&self, command: Document) -> Result<Document> {
+        let result = self.db.run_command(command.clone(), None)
+            .chain_err(|| format!("Command failed: {}", to_json(&command)))?;
+        // Write commands like `update` succeed while reporting the errors of the documents
+        if let Ok(errors) = result.get_array("writeErrors") {
+            bail!("Command failed: {}, {}", to_json(&command), Bson::Array(errors.clone()).into_relaxed_extjson());
+        }
+        Ok(result)
+    }
+
+    fn run(&self, migration: &str) -> Result<()> {
+        for command in parse_commands(migration)? {
+            self.run_command(command)?;
+        }
+        Ok(())
+    }
+}
+
+impl Driver for Mongodb {
+    fn ensure_migration_table_exists(&self) {
+        let version = self.collection().find_one(doc! { "_id": "version" }, None).unwrap();
+        if version.is_none() {
+            // Another process may have created it in the meantime
+            if let Err(e) = self.collection().insert_one(doc! { "_id": "version", "current": 0 }, None) {
+                if !e.to_string().contains("E11000") {
+                    panic!("Failed to create the version document: {}", e);
+                }
+            }
+        }
+    }
+
+    fn remove_migration_table(&self) {
+        self.collection().drop(None).unwrap();
+    }
+
+    fn get_current_number(&self) -> i32 {
+        let version = self.collection().find_one(doc! { "_id": "version" }, None).unwrap();
+        version.and_then(|v| v.get_i32("current").ok()).unwrap_or(0)
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let result = self.collection().update_one(
+            doc! { "_id": "version", "current": expected },
+            doc! { "$set": { "current": number } },
+            None,
+        )?;
+        if result.matched_count == 0 {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
+    }
+
+    // Commands aren't run in a transaction, most of them (creating collections and indexes) not being
+    // allowed in one: a failing migration leaves the commands before the failing one applied
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.run(&migration).chain_err(|| "Migration failed")?;
+        self.set_current_number(expected, number)
+    }
+
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        self.run(&migration).chain_err(|| "Migration failed")?;
+        for assertion in assertions {
+            if !check_condition(self, assertion)? {
+                bail!("Assertion failed: {}", assertion);
+            }
+        }
+        self.set_current_number(expected, number)
+    }
+
+    fn migration_kind(&self) -> MigrationKind {
+        MigrationKind::Json
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.run_command(doc! { "ping": 1 }).map(|_| ())
+    }
+
+    fn server_version(&self) -> Result<String> {
+        let info = self.run_command(doc! { "buildInfo": 1 })?;
+        Ok(info.get_str("version").chain_err(|| "buildInfo didn't return the version")?.to_owned())
+    }
+
+    // `isMaster` rather than `hello`, which servers before 4.4.2 don't know. Standalone servers are
+    // reported as primaries
+    fn read_only(&self) -> Result<Option<String>> {
+        let status = self.run_command(doc! { "isMaster": 1 })?;
+        match status.get_bool("ismaster") {
+            Ok(false) => Ok(Some("the node isn't the primary of its replica set".to_owned())),
+            _ => Ok(None),
+        }
+    }
+
+    fn execute(&self, commands: &str) -> Result<()> {
+        self.run(commands)
+    }
+
+    // Runs a single command. The rows are the documents of its cursor, `find` or `aggregate` ones,
+    // or the result of the command itself
+    fn query(&self, command: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let mut commands = parse_commands(command)?;
+        if commands.len() != 1 {
+            bail!("Queries must be a single command: {}", command);
+        }
+        let result = self.run_command(commands.remove(0))?;
+        let documents = if result.contains_key("cursor") { first_batch(result) } else { vec![result] };
+        Ok(documents.iter().map(|document| document.values().map(bson_to_text).collect()).collect())
+    }
+
+    // Collections with their options (validators), and their indexes
+    fn schema(&self) -> Result<String> {
+        let mut rows = vec![];
+        for collection in first_batch(self.run_command(doc! { "listCollections": 1 })?) {
+            let name = collection.get_str("name").unwrap_or_default().to_owned();
+            let options = collection.get_document("options").map(to_json).unwrap_or_default();
+            rows.push(vec![Some("collection".to_owned()), Some(name.clone()), Some(options)]);
+            if collection.get_str("type").unwrap_or("collection") != "collection" {
+                continue;
+            }
+            for index in first_batch(self.run_command(doc! { "listIndexes": name.as_str() })?) {
+                rows.push(vec![
+                    Some("index".to_owned()),
+                    Some(name.clone()),
+                    index.get_str("name").ok().map(|n| n.to_owned()),
+                    index.get_document("key").ok().map(to_json),
+                    Some(index.get_bool("unique").unwrap_or(false).to_string()),
+                ]);
+            }
+        }
+        Ok(rows_to_schema(rows))
+    }
+
+    fn load_csv(&self, _table: &str, _path: &Path) -> Result<()> {
+        bail!("CSV files can't be loaded into MongoDB, use a JSON migration with an insert command")
+    }
+
+    // The lock document can only be inserted once since its `_id` is unique
+    fn try_lock(&self) -> Result<bool> {
+        let lock = doc! { "_id": "lock", "locked_at": utc_now(), "locked_by": current_user() };
+        match self.collection().insert_one(lock, None) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.to_string().contains("E11000") => Ok(false),
+            Err(e) => Err(e).chain_err(|| "Failed to take the lock"),
+        }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.collection().delete_one(doc! { "_id": "lock" }, None).chain_err(|| "Failed to release the lock")?;
+        Ok(())
+    }
+
+    // Documents and nodes take new fields as they come
+    fn ensure_history_table(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn record_history(&self, entry: &HistoryEntry) -> Result<()> {
+        self.history_collection().insert_one(doc! {
+            "number": entry.number,
+            "name": entry.name.as_str(),
+            "direction": entry.direction.to_string(),
+            "applied_at": entry.applied_at.as_str(),
+            "applied_by": entry.applied_by.clone(),
+            "checksum": entry.checksum.clone(),
+            "schema_snapshot": entry.schema_snapshot.clone(),
+            "skipped_in": entry.skipped_in.clone(),
+            "duration_ms": entry.duration_ms().map(|ms| ms as i64),
+            "hostname": entry.hostname.clone(),
+            "git_commit": entry.git_commit.clone(),
+            "out_of_order": entry.out_of_order,
+        }, None).chain_err(|| format!("Failed to record migration {} in the history", entry.number))?;
+        Ok(())
+    }
+
+    // Ordered by `_id`, which is an ObjectId starting with the time it was created
+    fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut entries = vec![];
+        for document in self.history_collection().find(None, options)? {
+            let document = document?;
+            let optional = |key: &str| document.get_str(key).ok().map(|v| v.to_owned());
+            entries.push(HistoryEntry {
+                number: document.get_i32("number").chain_err(|| "Invalid history number")?,
+                name: optional("name").unwrap_or_default(),
+                direction: if optional("direction").map_or(false, |d| d == "down") { Direction::Down } else { Direction::Up },
+                applied_at: optional("applied_at").unwrap_or_default(),
+                applied_by: optional("applied_by"),
+                hostname: optional("hostname"),
+                git_commit: optional("git_commit"),
+                checksum: optional("checksum"),
+                schema_snapshot: None,
+                skipped_in: optional("skipped_in"),
+                duration: document.get_i64("duration_ms").ok().map(|ms| Duration::from_millis(ms as u64)),
+                out_of_order: document.get_bool("out_of_order").unwrap_or(false),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn update_checksum(&self, number: i32, checksum: &str) -> Result<()> {
+        self.history_collection().update_many(
+            doc! { "number": number, "direction": "up" },
+            doc! { "$set": { "checksum": checksum } },
+            None,
+        ).chain_err(|| format!("Failed to update the checksum of migration {}", number))?;
+        Ok(())
+    }
+
+    fn dirty(&self) -> Result<Option<i32>> {
+        let version = self.collection().find_one(doc! { "_id": "version" }, None)?;
+        Ok(version.and_then(|v| v.get_i32("dirty").ok()))
+    }
+
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        self.collection().update_one(doc! { "_id": "version" }, doc! { "$set": { "dirty": number } }, None)
+            .chain_err(|| "Failed to update the dirty marker")?;
+        Ok(())
+    }
+
+    fn remove_history(&self, number: i32) -> Result<()> {
+        self.history_collection().delete_many(doc! { "number": number }, None)?;
+        Ok(())
+    }
+
+    fn schema_snapshot(&self, number: i32) -> Result<Option<String>> {
+        let options = FindOneOptions::builder().sort(doc! { "_id": -1 }).build();
+        let entry = self.history_collection().find_one(
+            doc! { "number": number, "direction": "up", "schema_snapshot": { "$type": "string" } }, options
+        )?;
+        Ok(entry.and_then(|e| e.get_str("schema_snapshot").ok().map(|s| s.to_owned())))
+    }
+}
+
+/// Parses a JSON migration: a database command, like `{"createIndexes": "users", "indexes": [...]}`,
+/// or an array of commands run in order. Values can use MongoDB extended JSON, `{"$date": ...}` for example
+pub fn parse_commands(content: &str) -> Result<Vec<Document>> {
+    let value = serde_json::from_str::<Value>(content).chain_err(|| "Invalid JSON")?;
+    let commands = match value {
+        Value::Array(commands) => commands,
+        command => vec![command],
+    };
+    commands.into_iter().map(|command| match Bson::try_from(command) {
+        Ok(Bson::Document(document)) => Ok(document),
+        Ok(other) => bail!("Commands must be JSON objects, not {}", other),
+        Err(e) => Err(e).chain_err(|| "Invalid extended JSON"),
+    }).collect()
+}
+
+// The documents returned by a command with a cursor, all of them for the commands dbmigrate runs
+fn first_batch(result: Document) -> Vec<Document> {
+    result.get_document("cursor").and_then(|c| c.get_array("firstBatch"))
+        .map(|batch| batch.iter().filter_map(Bson::as_document).cloned().collect())
+        .unwrap_or_default()
+}
+
+fn to_json(document: &Document) -> String {
+    Bson::Document(document.clone()).into_relaxed_extjson().to_string()
+}
+
+fn bson_to_text(value: &Bson) -> Option<String> {
+    match *value {
+        Bson::Null => None,
+        Bson::String(ref s) => Some(s.clone()),
+        ref other => Some(other.clone().into_relaxed_extjson().to_string()),
+    }
+}
+
+// Sets the timeouts of the client to the connection timeout, unless the URL has its own
+fn with_timeouts(url: &str, timeout: Duration) -> Result<String> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let millis = (timeout.as_secs() * 1000).to_string();
+    for option in &["connectTimeoutMS", "serverSelectionTimeoutMS"] {
+        // MongoDB options are case-insensitive
+        if !parsed_url.query_pairs().any(|(key, _)| key.eq_ignore_ascii_case(option)) {
+            parsed_url.query_pairs_mut().append_pair(option, &millis);
+        }
+    }
+    Ok(parsed_url.into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mongodb_client::bson::doc;
+
+    use super::{parse_commands, with_timeouts};
+
+    #[test]
+    fn test_with_timeouts() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            with_timeouts("mongodb://db/app", timeout).unwrap(),
+            "mongodb://db/app?connectTimeoutMS=5000&serverSelectionTimeoutMS=5000"
+        );
+        assert_eq!(
+            with_timeouts("mongodb://db/app?connecttimeoutms=100&replicaSet=rs0", timeout).unwrap(),
+            "mongodb://db/app?connecttimeoutms=100&replicaSet=rs0&serverSelectionTimeoutMS=5000"
+        );
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let commands = parse_commands(r#"[
+            {"createCollection": "users", "validator": {"$jsonSchema": {"required": ["email"]}}},
+            {"createIndexes": "users", "indexes": [{"key": {"email": 1}, "name": "email_1", "unique": true}]}
+        ]"#).unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].get_str("createCollection").unwrap(), "users");
+        assert_eq!(parse_commands(r#"{"drop": "users"}"#).unwrap(), vec![doc! { "drop": "users" }]);
+        assert!(parse_commands("[1, 2]").is_err());
+        assert!(parse_commands("{\"drop\": ").is_err());
+    }
+}
@@ -1,42 +1,220 @@
-use mysql_client::{from_row, Pool};
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use super::Driver;
-use errors::{Result, ResultExt};
+use mysql_client::{from_row, Conn, Opts, OptsBuilder, Value};
+use regex::Regex;
 
+use super::{query_value, ConnectionOptions, Driver};
+use super::flavor::{parse_flavor, Flavor};
+use super::options::connecting;
+use annotations::{is_true, Annotations};
+use csv_load::read_columns;
+use lock;
+use schema::rows_to_schema;
+use statements::split_statements;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+use timeout::Canceller;
 
+
+// Named locks are per server, hence the database name in it
+const LOCK_NAME: &'static str = "CONCAT('__dbmigrate_', DATABASE())";
+const VITESS_POLL_INTERVAL: u64 = 2;
+
+// Everything, named locks included, runs on a single connection: a run is short-lived and
+// the current number has to be updated on the connection which ran the migration
 #[derive(Debug)]
 pub struct Mysql {
-    pool: Pool
+    conn: RefCell<Conn>,
+    // To open another connection cancelling the migration running on this one
+    opts: Opts,
+    conn_id: Option<String>,
+    // Whether a migration is running on the connection, the only thing the canceller may kill
+    migrating: Arc<Mutex<bool>>,
+    flavor: Flavor,
+    application_name: String,
 }
 
 impl Mysql {
     pub fn new(url: &str) -> Result<Mysql> {
-        let pool = Pool::new(url)?;
-        let mysql = Mysql { pool: pool };
-        mysql.ensure_migration_table_exists();
+        let (url, flavor) = parse_flavor(url)?;
+        let options = ConnectionOptions::parse(&url)?;
+        let opts = mk_opts(&options)?;
+        let mut conn = connecting(&url, options.connect_timeout(), || {
+            Conn::new(opts.clone()).chain_err(|| ErrorKind::ConnectionError(redact_urls(&url)))
+        })?;
+        let conn_id = match conn.query("SELECT CONNECTION_ID();")?.next() {
+            Some(row) => row?.unwrap().into_iter().next().and_then(value_to_string),
+            None => None,
+        };
+        let mut mysql = Mysql {
+            conn: RefCell::new(conn),
+            opts: opts,
+            conn_id: conn_id,
+            migrating: Arc::new(Mutex::new(false)),
+            flavor: flavor,
+            application_name: options.application_name().to_owned(),
+        };
+        match flavor {
+            Flavor::Tidb { .. } => {
+                let multi_schema_change = supports_multi_schema_change(&mysql.server_version()?);
+                mysql.flavor = Flavor::Tidb { multi_schema_change: multi_schema_change };
+            },
+            Flavor::Mysql if mysql.server_version()?.contains("MariaDB") => mysql.flavor = Flavor::Mariadb,
+            _ => (),
+        }
+        // The table of a replica is replicated from the primary, and migrating refuses read-only databases
+        if mysql.read_only()?.is_none() {
+            mysql.ensure_migration_table_exists();
+        }
 
         Ok(mysql)
     }
+
+    // Fails before running anything if the migration can't be run on this server
+    fn check_annotations(&self, annotations: &Annotations) -> Result<()> {
+        if annotations.osu_method.is_some() && self.flavor != Flavor::Galera {
+            bail!("The osu-method annotation is only supported with flavor=galera");
+        }
+        Ok(())
+    }
+
+    // Fails before running them if the statements have some the server can't run
+    fn check_statements(&self, migration: &str) -> Result<()> {
+        match self.flavor {
+            Flavor::Tidb { multi_schema_change: false } => {
+                if let Some(statement) = multi_schema_changes(migration).into_iter().next() {
+                    bail!("TiDB before 6.2 can only make one change per ALTER TABLE, split it: {}", statement);
+                }
+            },
+            Flavor::Vitess { .. } => {
+                if let Some(statement) = foreign_keys(migration).into_iter().next() {
+                    bail!("Vitess doesn't support foreign keys: {}", statement);
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // DDL statements run on a node which isn't part of the primary component or still catching up
+    // would be lost or stall the cluster
+    fn check_wsrep_status(&self) -> Result<()> {
+        let rows = self.query("SHOW GLOBAL STATUS WHERE Variable_name IN \
+            ('wsrep_ready', 'wsrep_cluster_status', 'wsrep_local_state_comment');")?;
+        let status = |name: &str| rows.iter()
+            .find(|row| row.get(0).and_then(|v| v.as_ref()).map_or(false, |v| v.eq_ignore_ascii_case(name)))
+            .and_then(|row| row.get(1).cloned().and_then(|v| v))
+            .unwrap_or_else(|| "unknown".to_owned());
+        let expected = [
+            ("wsrep_ready", "ON"), ("wsrep_cluster_status", "Primary"), ("wsrep_local_state_comment", "Synced"),
+        ];
+        for &(name, value) in expected.iter() {
+            let actual = status(name);
+            if !actual.eq_ignore_ascii_case(value) {
+                bail!("The Galera node isn't ready to migrate, {} is {} instead of {}", name, actual, value);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the migration with the Vitess online DDL strategy, set on the connection for the migration only
+    fn run_online(&self, conn: &mut Conn, migration: &str) -> Result<()> {
+        conn.query("SET @@ddl_strategy = 'vitess';")?;
+        let res = self.run_statements_online(conn, migration);
+        // The connection is used for the rest of the run
+        conn.query("SET @@ddl_strategy = 'direct';")?;
+        res
+    }
+
+    // Vitess schedules the DDL statements and returns the UUID of their migration. Each one is
+    // waited for before running the next statement, which may depend on it
+    fn run_statements_online(&self, conn: &mut Conn, migration: &str) -> Result<()> {
+        for statement in split_statements(migration, ';') {
+            let uuid = match conn.query(self.tagged(statement.clone()))
+                .chain_err(|| format!("Statement failed: {}", statement))?.next() {
+                Some(row) => row?.unwrap().into_iter().next().and_then(value_to_string),
+                None => None,
+            };
+            if let (Some(uuid), true) = (uuid, is_online_ddl(&statement)) {
+                wait_for_vitess_migration(conn, &uuid).chain_err(|| format!("Statement failed: {}", statement))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs SQL of the migration, as online DDL on Vitess when enabled
+    fn run_migration(&self, conn: &mut Conn, migration: String) -> Result<()> {
+        if self.flavor == (Flavor::Vitess { online_ddl: true }) {
+            self.run_online(conn, &migration)
+        } else {
+            conn.query(self.tagged(migration)).map(|_| ()).map_err(|e| e.into())
+        }
+    }
+
+    // Runs the migration in a transaction, with the current number locked first and updated last
+    fn migrate_in_transaction<F>(&self, annotations: &Annotations, expected: i32, number: i32, f: F) -> Result<()>
+        where F: FnOnce(&mut Conn) -> Result<()>
+    {
+        self.check_annotations(annotations).chain_err(|| ErrorKind::NotStarted)?;
+        // Set on the connection for the migration only, as the current number has to be replicated
+        let rsu = annotations.osu_method.as_ref().map_or(false, |method| method == "RSU");
+        let previous = if rsu {
+            Some(query_value(self, "SELECT @@SESSION.wsrep_OSU_method;").chain_err(|| ErrorKind::NotStarted)?)
+        } else {
+            None
+        };
+        let mut conn = self.conn.borrow_mut();
+        let res = if previous.is_some() {
+            conn.query("SET SESSION wsrep_OSU_method = 'RSU';").map(|_| ())
+                .chain_err(|| "Failed to set wsrep_OSU_method")
+                .chain_err(|| ErrorKind::NotStarted)
+        } else {
+            Ok(())
+        }.and_then(|_| {
+            *self.migrating.lock().unwrap() = true;
+            let res = run_in_transaction(&mut conn, expected, number, f);
+            // Waits for a cancellation being sent, so that it can't kill what the connection runs next
+            *self.migrating.lock().unwrap() = false;
+            res
+        });
+        // Restored whether the migration succeeded or not, the connection being used for the rest of the run
+        if let Some(previous) = previous {
+            conn.query(format!("SET SESSION wsrep_OSU_method = '{}';", previous.replace('\'', "")))
+                .chain_err(|| "Failed to restore wsrep_OSU_method")?;
+        }
+        res
+    }
+
+    // The mysql crate can't set connection attributes like program_name, but the processlist
+    // shows the comment starting the statement running the migration
+    fn tagged(&self, migration: String) -> String {
+        format!("/* {} */ {}", self.application_name.replace("*/", ""), migration)
+    }
 }
 
 
 impl Driver for Mysql {
+    // Percona XtraDB Cluster refuses to replicate writes to tables without a primary key
     fn ensure_migration_table_exists(&self) {
-        let mut conn = self.pool.get_conn().unwrap();
-        conn.query("
-            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER);
+        let id = if self.flavor == Flavor::Galera { "id INTEGER NOT NULL PRIMARY KEY" } else { "id INTEGER" };
+        self.conn.borrow_mut().query(format!("
+            CREATE TABLE IF NOT EXISTS __dbmigrate_table({}, current INTEGER);
             INSERT INTO __dbmigrate_table (id, current)
             SELECT 1, 0 FROM DUAL
             WHERE NOT EXISTS(SELECT * FROM __dbmigrate_table WHERE id = 1);
-        ").unwrap();
+        ", id)).unwrap();
     }
 
     fn remove_migration_table(&self) {
-        self.pool.prep_exec("DROP TABLE __dbmigrate_table;", ()).unwrap();
+        self.conn.borrow_mut().prep_exec("DROP TABLE __dbmigrate_table;", ()).unwrap();
     }
 
     fn get_current_number(&self) -> i32 {
-        let mut result = self.pool.prep_exec("
+        let mut conn = self.conn.borrow_mut();
+        let mut result = conn.prep_exec("
             SELECT current FROM __dbmigrate_table WHERE id = 1;
         ", ()).unwrap();
         // That is quite ugly
@@ -44,18 +222,332 @@ impl Driver for Mysql {
         from_row::<i32>(row.unwrap())
     }
 
-    fn set_current_number(&self, number: i32) {
-        self.pool.prep_exec(
-            "UPDATE __dbmigrate_table SET current = ? WHERE id = 1;",
-            (&number, )
-        ).unwrap();
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        update_current_number(&mut self.conn.borrow_mut(), expected, number)
+    }
+
+    // The current number is updated in the same transaction, which only makes a difference for
+    // migrations without DDL statements as they commit implicitly
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.migrate_with_assertions(migration, expected, number, &[])
     }
 
-    fn migrate(&self, migration: String, number: i32) -> Result<()> {
-        let mut conn = self.pool.get_conn()?;
-        conn.query(migration).chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        let annotations = Annotations::parse(&migration).chain_err(|| ErrorKind::NotStarted)?;
+        self.check_statements(&migration).chain_err(|| ErrorKind::NotStarted)?;
+        self.migrate_in_transaction(&annotations, expected, number, |conn| {
+            self.run_migration(conn, migration).chain_err(|| "Migration failed")?;
+            for assertion in assertions {
+                let value = match conn.query(assertion.as_str())?.next() {
+                    Some(row) => row?.unwrap().into_iter().next().and_then(value_to_string),
+                    None => None,
+                };
+                if !is_true(value) {
+                    bail!("Assertion failed: {}", assertion);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // The statements are checked as they are read, those before an unsupported one having run
+    fn migrate_statements(
+        &self, statements: &mut Iterator<Item = Result<String>>, annotations: &Annotations, expected: i32,
+        number: i32
+    ) -> Result<()> {
+        self.migrate_in_transaction(annotations, expected, number, |conn| {
+            let mut ran = false;
+            for statement in statements {
+                let statement = statement?;
+                match self.check_statements(&statement) {
+                    Err(e) if !ran => return Err(e).chain_err(|| ErrorKind::NotStarted),
+                    res => res?,
+                }
+                self.run_migration(conn, statement).chain_err(|| "Migration failed")?;
+                ran = true;
+            }
+            Ok(())
+        })
+    }
 
+    // TiDB only implements named locks in recent versions, and not across all its servers
+    // in older ones, and Vitess only on a single shard, so they use the lock table
+    fn try_lock(&self) -> Result<bool> {
+        if !self.flavor.has_named_locks() {
+            return lock::try_lock_table(self);
+        }
+        // NULL when taking the lock failed, which `query_value` reports
+        Ok(is_true(Some(query_value(self, &format!("SELECT GET_LOCK({}, 0);", LOCK_NAME))?)))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        if !self.flavor.has_named_locks() {
+            return lock::unlock_table(self);
+        }
+        self.execute(&format!("SELECT RELEASE_LOCK({});", LOCK_NAME))
+    }
+
+    fn canceller(&self) -> Result<Option<Canceller>> {
+        let opts = self.opts.clone();
+        let conn_id = self.conn_id.clone();
+        let migrating = self.migrating.clone();
+        Ok(Some(Box::new(move || {
+            // `KILL QUERY` kills whatever the connection is running, so the migration is kept from
+            // finishing until it was sent, and it isn't sent once the migration finished
+            let migrating = migrating.lock().unwrap();
+            if let (true, Some(id)) = (*migrating, conn_id.as_ref()) {
+                Conn::new(opts.clone())?.query(format!("KILL QUERY {};", id))?;
+            }
+            Ok(())
+        })))
+    }
+
+    fn server_version(&self) -> Result<String> {
+        query_value(self, "SELECT VERSION();")
+    }
+
+    // Users with the SUPER privilege can write to a read-only replica, making it diverge from its source.
+    // TiDB and Vitess don't have replicas to point dbmigrate at by mistake
+    fn read_only(&self) -> Result<Option<String>> {
+        match self.flavor {
+            Flavor::Tidb { .. } | Flavor::Vitess { .. } => Ok(None),
+            _ if is_true(Some(query_value(self, "SELECT @@global.read_only;")?)) => {
+                Ok(Some("read_only is set, as on replicas".to_owned()))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn check_ready(&self) -> Result<()> {
+        match self.flavor {
+            Flavor::Galera => self.check_wsrep_status(),
+            _ => Ok(()),
+        }
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.conn.borrow_mut().query(sql).chain_err(|| "Query failed")?;
         Ok(())
     }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let mut conn = self.conn.borrow_mut();
+        let mut results = vec![];
+        for row in conn.query(sql).chain_err(|| format!("Query failed: {}", sql))? {
+            let values = row?.unwrap().into_iter().map(value_to_string).collect();
+            results.push(values);
+        }
+        Ok(results)
+    }
+
+    fn schema(&self) -> Result<String> {
+        let mut rows = self.query("
+            SELECT 'column', TABLE_NAME, COLUMN_NAME, COLUMN_TYPE, IS_NULLABLE, COLUMN_DEFAULT
+            FROM information_schema.COLUMNS
+            WHERE TABLE_SCHEMA = DATABASE();
+        ")?;
+        rows.extend(self.query("
+            SELECT 'index', TABLE_NAME, INDEX_NAME, SEQ_IN_INDEX, COLUMN_NAME, NON_UNIQUE
+            FROM information_schema.STATISTICS
+            WHERE TABLE_SCHEMA = DATABASE();
+        ")?);
+        rows.extend(self.query("
+            SELECT 'view', TABLE_NAME, VIEW_DEFINITION
+            FROM information_schema.VIEWS
+            WHERE TABLE_SCHEMA = DATABASE();
+        ")?);
+        Ok(rows_to_schema(rows))
+    }
+
+    fn load_csv(&self, table: &str, path: &Path) -> Result<()> {
+        let columns = read_columns(path)?;
+        let filename = path.to_string_lossy().replace("\\", "\\\\").replace("'", "\\'");
+        self.execute(&format!(
+            "LOAD DATA LOCAL INFILE '{}' INTO TABLE {}
+            FIELDS TERMINATED BY ',' OPTIONALLY ENCLOSED BY '\"'
+            LINES TERMINATED BY '\\n'
+            IGNORE 1 LINES ({});",
+            filename, table, columns.join(", ")
+        )).chain_err(|| format!("Failed to load {:?} into {}", path, table))
+    }
+}
+
+/// Runs statements on the server without creating the migration table,
+/// used to create and drop databases
+pub fn execute_on_server(url: &str, sql: &str) -> Result<()> {
+    let mut conn = Conn::new(mk_opts(&ConnectionOptions::parse(&parse_flavor(url)?.0)?)?)?;
+    conn.query(sql).chain_err(|| format!("Query failed: {}", sql))?;
+    Ok(())
+}
+
+// Polls the status of a Vitess online DDL migration until it completes
+fn wait_for_vitess_migration(conn: &mut Conn, uuid: &str) -> Result<()> {
+    loop {
+        let (status, message) = {
+            let mut result = conn.query(format!("SHOW VITESS_MIGRATIONS LIKE '{}';", uuid))?;
+            let status_index = result.column_index("migration_status");
+            let message_index = result.column_index("message");
+            let values = match result.next() {
+                Some(row) => row?.unwrap(),
+                None => bail!("Vitess doesn't know the online DDL migration {}", uuid),
+            };
+            let get = |index: Option<usize>| index.and_then(|i| values.get(i).cloned()).and_then(value_to_string);
+            (get(status_index), get(message_index))
+        };
+        match status.as_ref().map(String::as_str) {
+            Some("complete") => return Ok(()),
+            Some("failed") | Some("cancelled") => {
+                bail!("Online DDL migration {} {}: {}", uuid, status.unwrap(), message.unwrap_or_default())
+            },
+            _ => thread::sleep(Duration::from_secs(VITESS_POLL_INTERVAL)),
+        }
+    }
+}
+
+// The statements Vitess runs as online DDL migrations
+fn is_online_ddl(statement: &str) -> bool {
+    let ddl = Regex::new(r"(?i)^(ALTER|CREATE|DROP)\s+(OR\s+REPLACE\s+)?(TABLE|VIEW)\b").unwrap();
+    ddl.is_match(statement)
+}
+
+/// The statements of the migration creating foreign keys
+fn foreign_keys(migration: &str) -> Vec<String> {
+    let foreign_key = Regex::new(r"(?i)\bFOREIGN\s+KEY\b|\bREFERENCES\s").unwrap();
+    split_statements(migration, ';').into_iter().filter(|statement| foreign_key.is_match(statement)).collect()
+}
+
+// TiDB reports versions like `8.0.11-TiDB-v7.5.0`, multi-schema changes came with 6.2
+fn supports_multi_schema_change(version: &str) -> bool {
+    let tidb_version = Regex::new(r"TiDB-v(\d+)\.(\d+)").unwrap();
+    match tidb_version.captures(version) {
+        Some(captures) => {
+            let version = (captures[1].parse::<u32>().unwrap_or(0), captures[2].parse::<u32>().unwrap_or(0));
+            version >= (6, 2)
+        },
+        None => false,
+    }
+}
+
+/// The `ALTER TABLE` statements of the migration making several changes, like adding a column and an index
+fn multi_schema_changes(migration: &str) -> Vec<String> {
+    let alter = Regex::new(r"(?is)^ALTER\s+TABLE\s+\S+\s+(.+)$").unwrap();
+    split_statements(migration, ';').into_iter().filter(|statement| {
+        alter.captures(statement).map_or(false, |captures| has_top_level_comma(&captures[1]))
+    }).collect()
+}
+
+// Commas in parentheses, like the ones of `DECIMAL(10, 2)` or of an index, and in quotes don't count
+fn has_top_level_comma(changes: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in changes.chars() {
+        match (c, quote) {
+            (_, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => (),
+            ('\'', None) | ('"', None) | ('`', None) => quote = Some(c),
+            ('(', None) => depth += 1,
+            (')', None) => depth -= 1,
+            (',', None) if depth == 0 => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+// The mysql crate rejects the parameters it doesn't know, the common ones are set here
+fn mk_opts(options: &ConnectionOptions) -> Result<Opts> {
+    options.reject("MySQL", &["sslmode"])?;
+    let opts = Opts::from_url(&options.url).map_err(|e| format!("Invalid MySQL URL: {}", e))?;
+    let mut builder = OptsBuilder::from_opts(opts);
+    builder.tcp_connect_timeout(Some(options.connect_timeout()));
+    if let Some(ref charset) = options.charset {
+        builder.init(vec![format!("SET NAMES {}", charset)]);
+    }
+    Ok(builder.into())
+}
+
+fn value_to_string(value: Value) -> Option<String> {
+    match value {
+        Value::NULL => None,
+        Value::Bytes(bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        other => Some(other.as_sql(true).trim_matches('\'').to_owned()),
+    }
+}
+
+// Checks the current number is still the expected one before the migration runs, locking its row until
+// the transaction ends. The first DDL statement commits implicitly and releases it, the update after the
+// migration still checks the number again
+fn lock_current_number(conn: &mut Conn, expected: i32) -> Result<()> {
+    let locked = conn.prep_exec(
+        "SELECT current FROM __dbmigrate_table WHERE id = 1 AND current = ? FOR UPDATE;",
+        (&expected, )
+    )?.count();
+    if locked == 0 {
+        bail!(ErrorKind::ConcurrentModification(expected));
+    }
+    Ok(())
+}
+
+// Runs the migration in a transaction, committed if it succeeded and rolled back otherwise
+fn run_in_transaction<F>(conn: &mut Conn, expected: i32, number: i32, f: F) -> Result<()>
+    where F: FnOnce(&mut Conn) -> Result<()>
+{
+    conn.query("START TRANSACTION;").chain_err(|| ErrorKind::NotStarted)?;
+    // Another runner migrated the database meanwhile, which mustn't be marked dirty
+    let res = lock_current_number(conn, expected).chain_err(|| ErrorKind::NotStarted)
+        .and_then(|_| f(&mut *conn))
+        .and_then(|_| update_current_number(conn, expected, number));
+    match res {
+        Ok(_) => {
+            conn.query("COMMIT;")?;
+            Ok(())
+        },
+        Err(e) => {
+            conn.query("ROLLBACK;").ok();
+            Err(e)
+        }
+    }
+}
+
+fn update_current_number(conn: &mut Conn, expected: i32, number: i32) -> Result<()> {
+    // MySQL only counts rows that actually changed as affected
+    let updated = if expected == number {
+        conn.prep_exec("SELECT current FROM __dbmigrate_table WHERE id = 1 AND current = ?;", (&expected, ))?.count()
+    } else {
+        conn.prep_exec(
+            "UPDATE __dbmigrate_table SET current = ? WHERE id = 1 AND current = ?;",
+            (&number, &expected)
+        )?.affected_rows() as usize
+    };
+    if updated == 0 {
+        bail!(ErrorKind::ConcurrentModification(expected));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{foreign_keys, is_online_ddl, multi_schema_changes, supports_multi_schema_change, Flavor};
+
+    #[test]
+    fn test_vitess_statements() {
+        let migration = "CREATE TABLE posts (id INT PRIMARY KEY, user_id INT, FOREIGN KEY (user_id) REFERENCES users (id));\n\
+            ALTER TABLE users ADD COLUMN referencesCount INT;\n\
+            UPDATE users SET referencesCount = 0;";
+        assert_eq!(foreign_keys(migration).len(), 1);
+        let statements = super::split_statements(migration, ';');
+        assert_eq!(statements.iter().map(|s| is_online_ddl(s)).collect::<Vec<_>>(), vec![true, true, false]);
+    }
+
+    #[test]
+    fn test_multi_schema_changes() {
+        assert!(supports_multi_schema_change("8.0.11-TiDB-v7.5.0"));
+        assert!(!supports_multi_schema_change("5.7.25-TiDB-v5.4.3"));
+        let migration = "ALTER TABLE users ADD COLUMN price DECIMAL(10, 2);\n\
+            ALTER TABLE users ADD INDEX idx_name_email (name, email);\n\
+            ALTER TABLE users ADD COLUMN nickname VARCHAR(255) DEFAULT 'a,b', DROP COLUMN login;";
+        assert_eq!(multi_schema_changes(migration), vec![
+            "ALTER TABLE users ADD COLUMN nickname VARCHAR(255) DEFAULT 'a,b', DROP COLUMN login",
+        ]);
+    }
 }
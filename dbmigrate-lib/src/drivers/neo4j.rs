@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use neo4rs::{query, BoltNull, BoltType, ConfigBuilder, Graph, Query, Row};
+use serde_json::Value;
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::timeout;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use super::Driver;
+use super::options::{connecting, ConnectionOptions};
+use annotations::check_condition;
+use files::{Direction, MigrationKind};
+use history::{current_user, utc_now, HistoryEntry};
+use schema::rows_to_schema;
+use statements::Statements;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+
+pub struct Neo4j {
+    // neo4rs is asynchronous, each call blocks on this runtime
+    runtime: Runtime,
+    graph: Graph,
+}
+
+impl Neo4j {
+    pub fn new(url: &str) -> Result<Neo4j> {
+        let options = ConnectionOptions::parse(url)?;
+        let connect_timeout = options.connect_timeout();
+        let parsed_url = Url::parse(&options.url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+        let decode = |s: &str| percent_decode(s.as_bytes()).decode_utf8_lossy().into_owned();
+        let host = match parsed_url.host_str() {
+            Some(host) => host.to_owned(),
+            None => bail!("Invalid URL: {}, it needs a host", redact_urls(url)),
+        };
+        let mut config = ConfigBuilder::default()
+            .uri(format!("{}:{}", host, parsed_url.port().unwrap_or(7687)))
+            .user(decode(parsed_url.username()))
+            .password(decode(parsed_url.password().unwrap_or("")));
+        let database = parsed_url.path().trim_left_matches('/');
+        if !database.is_empty() {
+            config = config.db(database);
+        }
+
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let config = config.build()?;
+        let graph = connecting(url, connect_timeout, || {
+            match runtime.block_on(timeout(connect_timeout, Graph::connect(config))) {
+                Ok(graph) => graph.chain_err(|| ErrorKind::ConnectionError(redact_urls(url))),
+                Err(_) => bail!(ErrorKind::ConnectionError(redact_urls(url))),
+            }
+        })?;
+        let neo4j = Neo4j { runtime: runtime, graph: graph };
+        neo4j.ensure_migration_table_exists();
+        Ok(neo4j)
+    }
+
+    fn run(&self, query: Query) -> Result<()> {
+        Ok(self.runtime.block_on(self.graph.run(query))?)
+    }
+
+    fn rows(&self, query: Query) -> Result<Vec<Row>> {
+        let mut stream = self.runtime.block_on(self.graph.execute(query))?;
+        let mut rows = vec![];
+        while let Some(row) = self.runtime.block_on(stream.next())? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    // Each statement runs and is committed on its own: schema changes and writes can't share a transaction
+    fn run_statements(&self, cypher: &str) -> Result<()> {
+        for statement in split_statements(cypher) {
+            self.run(query(&statement)).chain_err(|| format!("Statement failed: {}", statement))?;
+        }
+        Ok(())
+    }
+}
+
+impl Driver for Neo4j {
+    fn ensure_migration_table_exists(&self) {
+        self.run(query(
+            "CREATE CONSTRAINT dbmigrate_lock IF NOT EXISTS FOR (l:`__dbmigrate_lock`) REQUIRE l.id IS UNIQUE"
+        )).unwrap();
+        self.run(query("MERGE (v:`__dbmigrate` {id: 1}) ON CREATE SET v.current = 0")).unwrap();
+    }
+
+    fn remove_migration_table(&self) {
+        self.run(query("MATCH (v:`__dbmigrate`) DELETE v")).unwrap();
+    }
+
+    fn get_current_number(&self) -> i32 {
+        let rows = self.rows(query("MATCH (v:`__dbmigrate` {id: 1}) RETURN v.current AS current")).unwrap();
+        rows.first().and_then(|row| row.get::<i64>("current").ok()).unwrap_or(0) as i32
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let rows = self.rows(
+            query("MATCH (v:`__dbmigrate` {id: 1}) WHERE v.current = $expected SET v.current = $number RETURN v.id AS id")
+                .param("expected", expected as i64)
+                .param("number", number as i64)
+        )?;
+        if rows.is_empty() {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
+    }
+
+    // A failing migration leaves the statements before the failing one applied
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.run_statements(&migration).chain_err(|| "Migration failed")?;
+        self.set_current_number(expected, number)
+    }
+
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        self.run_statements(&migration).chain_err(|| "Migration failed")?;
+        for assertion in assertions {
+            if !check_condition(self, assertion)? {
+                bail!("Assertion failed: {}", assertion);
+            }
+        }
+        self.set_current_number(expected, number)
+    }
+
+    fn migration_kind(&self) -> MigrationKind {
+        MigrationKind::Cypher
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.query("RETURN 1").map(|_| ())
+    }
+
+    fn server_version(&self) -> Result<String> {
+        let rows = self.rows(query("CALL dbms.components() YIELD versions RETURN versions[0] AS version"))?;
+        match rows.first().and_then(|row| row.get::<String>("version").ok()) {
+            Some(version) => Ok(version),
+            None => bail!("Neo4j didn't return its version"),
+        }
+    }
+
+    fn execute(&self, cypher: &str) -> Result<()> {
+        self.run_statements(cypher)
+    }
+
+    // The values of each row are ordered by column name
+    fn query(&self, cypher: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let cypher = cypher.trim().trim_right_matches(';');
+        let rows = self.rows(query(cypher)).chain_err(|| format!("Query failed: {}", cypher))?;
+        rows.iter().map(|row| {
+            let columns = row.to::<BTreeMap<String, Value>>().chain_err(|| format!("Unsupported values returned by {}", cypher))?;
+            Ok(columns.values().map(json_to_text).collect())
+        }).collect()
+    }
+
+    // Constraints and indexes, the only schema of a graph
+    fn schema(&self) -> Result<String> {
+        let mut rows = vec![];
+        for (kind, show) in &[("constraint", "SHOW CONSTRAINTS"), ("index", "SHOW INDEXES")] {
+            let cypher = format!("{} YIELD name, type, labelsOrTypes, properties RETURN *", show);
+            for row in self.rows(query(&cypher))? {
+                let list = |key: &str| row.get::<Option<Vec<String>>>(key).ok().and_then(|l| l).map(|l| l.join(","));
+                rows.push(vec![
+                    Some(kind.to_string()),
+                    row.get::<String>("name").ok(),
+                    row.get::<String>("type").ok(),
+                    list("labelsOrTypes"),
+                    list("properties"),
+                ]);
+            }
+        }
+        Ok(rows_to_schema(rows))
+    }
+
+    fn load_csv(&self, _table: &str, _path: &Path) -> Result<()> {
+        bail!("CSV files can't be loaded into Neo4j, use a Cypher migration with LOAD CSV")
+    }
+
+    // The lock node can only be created once, thanks to the constraint on its id
+    fn try_lock(&self) -> Result<bool> {
+        let lock = query("CREATE (l:`__dbmigrate_lock` {id: 1, locked_at: $locked_at, locked_by: $locked_by})")
+            .param("locked_at", utc_now())
+            .param("locked_by", optional(&current_user()));
+        match self.run(lock) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.to_string().contains("ConstraintValidationFailed") => Ok(false),
+            Err(e) => Err(e).chain_err(|| "Failed to take the lock"),
+        }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.run(query("MATCH (l:`__dbmigrate_lock` {id: 1}) DELETE l")).chain_err(|| "Failed to release the lock")
+    }
+
+    // Documents and nodes take new fields as they come
+    fn ensure_history_table(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn record_history(&self, entry: &HistoryEntry) -> Result<()> {
+        self.run(query("
+            OPTIONAL MATCH (h:`__dbmigrate_history`)
+            WITH coalesce(max(h.seq), 0) + 1 AS seq
+            CREATE (:`__dbmigrate_history` {
+                seq: seq, number: $number, name: $name, direction: $direction, applied_at: $applied_at,
+                applied_by: $applied_by, checksum: $checksum, schema_snapshot: $schema_snapshot, skipped_in: $skipped_in,
+                duration_ms: $duration_ms, hostname: $hostname, git_commit: $git_commit, out_of_order: $out_of_order
+            })
+        ")
+            .param("number", entry.number as i64)
+            .param("name", entry.name.clone())
+            .param("direction", entry.direction.to_string())
+            .param("applied_at", entry.applied_at.clone())
+            .param("applied_by", optional(&entry.applied_by))
+            .param("checksum", optional(&entry.checksum))
+            .param("schema_snapshot", optional(&entry.schema_snapshot))
+            .param("skipped_in", optional(&entry.skipped_in))
+            .param("duration_ms", entry.duration_ms().map_or(BoltType::Null(BoltNull), |ms| BoltType::from(ms as i64)))
+            .param("hostname", optional(&entry.hostname))
+            .param("git_commit", optional(&entry.git_commit))
+            .param("out_of_order", entry.out_of_order)
+        ).chain_err(|| format!("Failed to record migration {} in the history", entry.number))
+    }
+
+    fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let rows = self.rows(query("
+            MATCH (h:`__dbmigrate_history`)
+            RETURN h.number AS number, h.name AS name, h.direction AS direction, h.applied_at AS applied_at,
+                h.applied_by AS applied_by, h.checksum AS checksum, h.skipped_in AS skipped_in,
+                h.duration_ms AS duration_ms, h.hostname AS hostname, h.git_commit AS git_commit,
+                coalesce(h.out_of_order, false) AS out_of_order
+            ORDER BY h.seq
+        "))?;
+        rows.iter().map(|row| {
+            let optional = |key: &str| row.get::<Option<String>>(key).ok().and_then(|v| v);
+            Ok(HistoryEntry {
+                number: row.get::<i64>("number").chain_err(|| "Invalid history number")? as i32,
+                name: optional("name").unwrap_or_default(),
+                direction: if optional("direction").map_or(false, |d| d == "down") { Direction::Down } else { Direction::Up },
+                applied_at: optional("applied_at").unwrap_or_default(),
+                applied_by: optional("applied_by"),
+                hostname: optional("hostname"),
+                git_commit: optional("git_commit"),
+                checksum: optional("checksum"),
+                schema_snapshot: None,
+                skipped_in: optional("skipped_in"),
+                duration: row.get::<Option<i64>>("duration_ms").ok().and_then(|v| v).map(|ms| Duration::from_millis(ms as u64)),
+                out_of_order: row.get::<bool>("out_of_order").unwrap_or(false),
+            })
+        }).collect()
+    }
+
+    fn update_checksum(&self, number: i32, checksum: &str) -> Result<()> {
+        self.run(query("
+            MATCH (h:`__dbmigrate_history`) WHERE h.number = $number AND h.direction = 'up' SET h.checksum = $checksum
+        ")
+            .param("number", number as i64)
+            .param("checksum", checksum.to_owned()))
+    }
+
+    fn dirty(&self) -> Result<Option<i32>> {
+        let rows = self.rows(query("MATCH (v:`__dbmigrate` {id: 1}) RETURN v.dirty AS dirty"))?;
+        Ok(rows.first().and_then(|row| row.get::<Option<i64>>("dirty").ok()).and_then(|v| v).map(|n| n as i32))
+    }
+
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        self.run(query("MATCH (v:`__dbmigrate` {id: 1}) SET v.dirty = $dirty")
+            .param("dirty", number.map_or(BoltType::Null(BoltNull), |n| BoltType::from(n as i64))))
+    }
+
+    fn remove_history(&self, number: i32) -> Result<()> {
+        self.run(query("MATCH (h:`__dbmigrate_history`) WHERE h.number = $number DELETE h").param("number", number as i64))
+    }
+
+    fn schema_snapshot(&self, number: i32) -> Result<Option<String>> {
+        let rows = self.rows(query("
+            MATCH (h:`__dbmigrate_history`)
+            WHERE h.number = $number AND h.direction = 'up' AND h.schema_snapshot IS NOT NULL
+            RETURN h.schema_snapshot AS schema_snapshot ORDER BY h.seq DESC LIMIT 1
+        ").param("number", number as i64))?;
+        Ok(rows.first().and_then(|row| row.get::<String>("schema_snapshot").ok()))
+    }
+}
+
+fn optional(value: &Option<String>) -> BoltType {
+    match *value {
+        Some(ref value) => BoltType::from(value.clone()),
+        None => BoltType::Null(BoltNull),
+    }
+}
+
+fn json_to_text(value: &Value) -> Option<String> {
+    match *value {
+        Value::Null => None,
+        Value::String(ref s) => Some(s.clone()),
+        ref other => Some(other.to_string()),
+    }
+}
+
+/// Splits a Cypher script on the `;` outside of strings, leaving out its `//` comment lines. Lines
+/// starting with `--` are relationships, not comments
+pub fn split_statements(cypher: &str) -> Vec<String> {
+    Statements::with_comment(cypher.lines().map(|line| Ok(line.to_owned())), ';', "//")
+        .filter_map(|statement| statement.ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_statements;
+
+    #[test]
+    fn test_split_statements() {
+        let script = "// People\n\
+            CREATE CONSTRAINT person_email IF NOT EXISTS FOR (p:Person) REQUIRE p.email IS UNIQUE;\n\
+            MATCH (p:Person) WHERE p.name = 'a;b'\n\
+            SET p.active = true;\n\
+            MATCH (p:Person)\n\
+            --(c:Company) SET p.employed = true;\n";
+        assert_eq!(split_statements(script), vec![
+            "CREATE CONSTRAINT person_email IF NOT EXISTS FOR (p:Person) REQUIRE p.email IS UNIQUE",
+            "MATCH (p:Person) WHERE p.name = 'a;b'\nSET p.active = true",
+            "MATCH (p:Person)\n--(c:Company) SET p.employed = true",
+        ]);
+    }
+}
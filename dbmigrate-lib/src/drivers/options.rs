@@ -0,0 +1,238 @@
+///! Connection settings common to the drivers, given as URL parameters
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use url::Url;
+
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+/// How dbmigrate identifies its sessions on the server, unless the URL sets `application_name`
+pub const DEFAULT_APPLICATION_NAME: &'static str = concat!("dbmigrate v", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a connection unless the URL sets `connect_timeout`, an unreachable host
+/// taking minutes to fail otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The schemes of the databases reached over the network, whose drivers honour `connect_timeout`
+const NETWORK_SCHEMES: [&'static str; 11] =
+    ["postgres", "mysql", "mariadb", "tidb", "oracle", "db2", "odbc", "mongodb", "mongodb+srv", "neo4j", "bolt"];
+
+/// The parameters every driver understands, like `postgres://db/app?connect_timeout=10&application_name=deploy`.
+/// The other ones are left in the URL for the driver to handle, or reject
+#[derive(Debug, Default, PartialEq)]
+pub struct ConnectionOptions {
+    /// How long to wait for the connection, in seconds in the URL
+    pub connect_timeout: Option<Duration>,
+    /// Name the session shows up with on the server, `DEFAULT_APPLICATION_NAME` if not set
+    pub application_name: Option<String>,
+    /// Character set of the connection
+    pub charset: Option<String>,
+    /// Whether to use TLS: disable, allow, prefer, require, verify-ca or verify-full
+    pub sslmode: Option<String>,
+    /// The URL without the parameters above
+    pub url: String,
+}
+
+impl ConnectionOptions {
+    /// Takes the common parameters out of the URL
+    pub fn parse(url: &str) -> Result<ConnectionOptions> {
+        let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+        let mut options = ConnectionOptions::default();
+        let mut others = vec![];
+        for (key, value) in parsed_url.query_pairs().into_owned() {
+            match key.as_str() {
+                "connect_timeout" => {
+                    let secs = value.parse::<u64>().chain_err(|| format!("Invalid connect_timeout: {}", value))?;
+                    options.connect_timeout = Some(Duration::from_secs(secs));
+                },
+                "application_name" => options.application_name = Some(value),
+                "charset" => options.charset = Some(value),
+                "sslmode" => {
+                    if !["disable", "allow", "prefer", "require", "verify-ca", "verify-full"].contains(&value.as_str()) {
+                        bail!("Invalid sslmode {}, expected disable, allow, prefer, require, verify-ca or verify-full", value);
+                    }
+                    options.sslmode = Some(value);
+                },
+                _ => others.push((key, value)),
+            }
+        }
+
+        if others.is_empty() {
+            parsed_url.set_query(None);
+        } else {
+            parsed_url.query_pairs_mut().clear().extend_pairs(others);
+            // Spaces are encoded as `+`, which the postgres crate doesn't decode, see `schema_url`
+            let query = parsed_url.query().map(|q| q.replace('+', "%20"));
+            parsed_url.set_query(query.as_ref().map(String::as_str));
+        }
+        options.url = parsed_url.into_string();
+        Ok(options)
+    }
+
+    /// The connection timeout of the URL, or the default one
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    /// The application name of the URL, or the default one
+    pub fn application_name(&self) -> &str {
+        self.application_name.as_ref().map_or(DEFAULT_APPLICATION_NAME, |name| name.as_str())
+    }
+
+    /// Fails if one of the given parameters was set, for drivers which can't honour them
+    pub fn reject(&self, database: &str, parameters: &[&str]) -> Result<()> {
+        for parameter in parameters {
+            let set = match *parameter {
+                "connect_timeout" => self.connect_timeout.is_some(),
+                "application_name" => self.application_name.is_some(),
+                "charset" => self.charset.is_some(),
+                "sslmode" => self.sslmode.is_some(),
+                _ => false,
+            };
+            if set {
+                bail!("The {} parameter isn't supported for {}", parameter, database);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the `connect_timeout` parameter of the URL, unless it already has one or its database isn't
+/// reached over the network
+pub fn with_connect_timeout(url: &str, timeout: Duration) -> Result<String> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let scheme = parsed_url.scheme().to_owned();
+    let has_timeout = parsed_url.query_pairs().any(|(key, _)| key == "connect_timeout");
+    if !NETWORK_SCHEMES.contains(&scheme.as_str()) || has_timeout {
+        return Ok(url.to_owned());
+    }
+    parsed_url.query_pairs_mut().append_pair("connect_timeout", &timeout.as_secs().to_string());
+    Ok(parsed_url.into_string())
+}
+
+/// Sets the schemas unqualified names refer to on every connection made with the URL: the `search_path`
+/// of Postgres, a list like `app, public`, or the database of MySQL, MariaDB and TiDB. The migration table
+/// is created there too, so each schema is migrated on its own
+pub fn with_search_path(url: &str, search_path: &str) -> Result<String> {
+    let schemas = search_path.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    let name = Regex::new(r"^[A-Za-z0-9_$]+$").unwrap();
+    if schemas.is_empty() || schemas.iter().any(|schema| !name.is_match(schema)) {
+        bail!(ErrorKind::ParseError(format!(
+            "Invalid search path {:?}, expected schema names separated by commas", search_path
+        )));
+    }
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    match parsed_url.scheme() {
+        "postgres" => with_postgres_option(url, &format!("-c search_path={}", schemas.join(","))),
+        "mysql" | "mariadb" | "tidb" if schemas.len() == 1 => {
+            parsed_url.set_path(schemas[0]);
+            Ok(parsed_url.into_string())
+        },
+        "mysql" | "mariadb" | "tidb" => bail!("MySQL has a single default database, not a search path"),
+        scheme => bail!("The search path can only be set on Postgres and MySQL, not {}", scheme),
+    }
+}
+
+/// Adds the option, like `-c search_path=app`, to the `options` parameter of the Postgres URL
+pub fn with_postgres_option(url: &str, option: &str) -> Result<String> {
+    let mut parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let mut params = parsed_url.query_pairs().into_owned().collect::<Vec<_>>();
+    match params.iter().position(|&(ref key, _)| key == "options") {
+        Some(i) => params[i].1 = format!("{} {}", params[i].1, option),
+        None => params.push(("options".to_owned(), option.to_owned())),
+    }
+    parsed_url.query_pairs_mut().clear().extend_pairs(params);
+    // Spaces are encoded as `+`, which the postgres crate doesn't decode. Literal `+` being
+    // encoded as `%2B`, all of them are spaces
+    let query = parsed_url.query().map(|q| q.replace('+', "%20"));
+    parsed_url.set_query(query.as_ref().map(String::as_str));
+    Ok(parsed_url.into_string())
+}
+
+/// Runs `connect`, reporting a failure which took the whole timeout as the host of the URL being unreachable
+pub fn connecting<T, F: FnOnce() -> Result<T>>(url: &str, timeout: Duration, connect: F) -> Result<T> {
+    let start = Instant::now();
+    let res = connect();
+    if res.is_err() && start.elapsed() >= timeout {
+        let parsed_url = Url::parse(url).ok();
+        let host = match parsed_url.as_ref().and_then(|u| u.host_str().map(|host| (host, u.port()))) {
+            Some((host, Some(port))) => format!("{}:{}", host, port),
+            Some((host, None)) => host.to_owned(),
+            None => redact_urls(url),
+        };
+        return res.chain_err(|| ErrorKind::Unreachable(host, timeout.as_secs()));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{connecting, with_connect_timeout, with_search_path, ConnectionOptions};
+    use errors::ErrorKind;
+
+    #[test]
+    fn test_parse_connection_options() {
+        let options = ConnectionOptions::parse(
+            "postgres://app:pw@db/app?connect_timeout=10&application_name=deploy&options=-c%20search_path%3Da&sslmode=require"
+        ).unwrap();
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(options.application_name, Some("deploy".to_owned()));
+        assert_eq!(options.application_name(), "deploy");
+        assert_eq!(options.charset, None);
+        assert_eq!(options.sslmode, Some("require".to_owned()));
+        assert_eq!(options.url, "postgres://app:pw@db/app?options=-c%20search_path%3Da");
+        assert!(options.reject("MySQL", &["charset"]).is_ok());
+        assert!(options.reject("MySQL", &["charset", "sslmode"]).is_err());
+
+        let options = ConnectionOptions::parse("mysql://db/app?charset=utf8mb4").unwrap();
+        assert_eq!(options.url, "mysql://db/app");
+        assert!(options.application_name().starts_with("dbmigrate v"));
+        assert!(ConnectionOptions::parse("mysql://db/app?connect_timeout=soon").is_err());
+        assert!(ConnectionOptions::parse("postgres://db/app?sslmode=maybe").is_err());
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(with_connect_timeout("postgres://db/app", timeout).unwrap(), "postgres://db/app?connect_timeout=5");
+        assert_eq!(
+            with_connect_timeout("mysql://db/app?charset=utf8mb4", timeout).unwrap(),
+            "mysql://db/app?charset=utf8mb4&connect_timeout=5"
+        );
+        assert_eq!(
+            with_connect_timeout("postgres://db/app?connect_timeout=30", timeout).unwrap(),
+            "postgres://db/app?connect_timeout=30"
+        );
+        assert_eq!(with_connect_timeout("sqlite:///app.db", timeout).unwrap(), "sqlite:///app.db");
+        assert_eq!(ConnectionOptions::parse("postgres://db/app").unwrap().connect_timeout(), Duration::from_secs(10));
+
+        let res: ::errors::Result<()> = connecting("postgres://app:pw@db:5433/app", Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(20));
+            bail!("timed out")
+        });
+        match res.unwrap_err().0 {
+            ErrorKind::Unreachable(ref host, 0) if host == "db:5433" => (),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+        let res: ::errors::Result<()> = connecting("postgres://db/app", timeout, || bail!("authentication failed"));
+        assert_eq!(res.unwrap_err().to_string(), "authentication failed");
+    }
+
+    #[test]
+    fn test_search_path() {
+        assert_eq!(
+            with_search_path("postgres://db/app?sslmode=require", "staging, public").unwrap(),
+            "postgres://db/app?sslmode=require&options=-c%20search_path%3Dstaging%2Cpublic"
+        );
+        assert_eq!(
+            with_search_path("mysql://root@db/app?flavor=tidb", "staging").unwrap(),
+            "mysql://root@db/staging?flavor=tidb"
+        );
+        assert!(with_search_path("mysql://root@db/app", "staging, public").is_err());
+        assert!(with_search_path("postgres://db/app", "staging; DROP SCHEMA public").is_err());
+        assert!(with_search_path("sqlite:///app.db", "main").is_err());
+    }
+}
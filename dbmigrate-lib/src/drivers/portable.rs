@@ -0,0 +1,54 @@
+///! Adapting the SQL dbmigrate runs on its own tables, written for Postgres, MySQL and SQLite,
+///! to databases stricter about identifiers and types
+use regex::Regex;
+
+/// Whether the SQL is about the dbmigrate tables rather than a migration, which is never adapted
+pub fn is_tracking_sql(sql: &str) -> bool {
+    sql.contains("__dbmigrate_")
+}
+
+/// Quotes the dbmigrate tables, whose names can't start with an underscore unquoted, and the given
+/// reserved words used as column names, with `"` or the quote of the database. Then replaces the given
+/// keywords, like `TEXT` by `CLOB`. String literals are left untouched
+pub fn adapt(sql: &str, quote: char, reserved: &[&str], replacements: &[(&str, &str)]) -> String {
+    let tables = Regex::new(r"\b__dbmigrate_\w+").unwrap();
+    let words = reserved.iter().map(|word| Regex::new(&format!(r"(?i)\b{}\b", word)).unwrap()).collect::<Vec<_>>();
+    let keywords = replacements.iter()
+        .map(|&(from, to)| (Regex::new(&format!(r"(?i)\b{}\b", from.replace(' ', r"\s+"))).unwrap(), to))
+        .collect::<Vec<_>>();
+
+    // Splitting on quotes, odd parts are the literals. Escaped quotes give empty parts, which keeps it right
+    sql.split('\'').enumerate().map(|(i, part)| {
+        if i % 2 == 1 {
+            return part.to_owned();
+        }
+        let mut part = tables.replace_all(part, format!("{}$0{}", quote, quote).as_str()).into_owned();
+        for (word, regex) in reserved.iter().zip(&words) {
+            part = regex.replace_all(&part, format!("{}{}{}", quote, word, quote).as_str()).into_owned();
+        }
+        for &(ref regex, to) in &keywords {
+            part = regex.replace_all(&part, to).into_owned();
+        }
+        part
+    }).collect::<Vec<_>>().join("'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adapt, is_tracking_sql};
+
+    #[test]
+    fn test_adapt() {
+        let sql = "CREATE TABLE IF NOT EXISTS __dbmigrate_history (number INTEGER, schema_snapshot TEXT);";
+        assert!(is_tracking_sql(sql));
+        assert!(!is_tracking_sql("CREATE TABLE users (number INTEGER);"));
+        assert_eq!(
+            adapt(sql, '"', &["number"], &[("CREATE TABLE IF NOT EXISTS", "CREATE TABLE"), ("TEXT", "CLOB")]),
+            "CREATE TABLE \"__dbmigrate_history\" (\"number\" INTEGER, schema_snapshot CLOB);"
+        );
+        assert_eq!(
+            adapt("INSERT INTO __dbmigrate_lock VALUES ('number __dbmigrate_x', 'it''s text')", '`', &["number"], &[("TEXT", "CLOB")]),
+            "INSERT INTO `__dbmigrate_lock` VALUES ('number __dbmigrate_x', 'it''s text')"
+        );
+    }
+}
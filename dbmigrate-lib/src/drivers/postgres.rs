@@ -1,35 +1,79 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
 use postgres_client::{Connection, TlsMode};
+use postgres_client::params::{ConnectParams, IntoConnectParams};
+use postgres_client::rows::Row;
 use postgres_native_tls::NativeTls;
-use url::Url;
 
-use super::Driver;
-use errors::{Result, ResultExt};
+use super::{migrate_in_transaction, migrate_statements_in_transaction, query_value, ConnectionOptions,
+            Driver};
+use super::options::connecting;
+use annotations::{is_true, Annotations};
+use csv_load::read_columns;
+use dialect::{strip_dialect, Dialect};
+use schema::rows_to_schema;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+use timeout::Canceller;
 
-const SSLMODE: &'static str = "sslmode";
+// Advisory locks are per database, any key unlikely to be used by applications does
+const LOCK_KEY: i64 = 0x6462_6d69_6772_6174;
 
 
 #[derive(Debug)]
 pub struct Postgres {
-    conn: Connection
+    conn: Connection,
+    // Needed to cancel migrations from another connection, without the dialect parameter
+    url: String,
+    dialect: Dialect,
+    // Whether a migration is running on the connection, the only thing the canceller may cancel
+    migrating: Arc<Mutex<bool>>,
 }
 
 impl Postgres {
     pub fn new(url: &str) -> Result<Postgres> {
-        let conn = mk_connection(url)?;
-        let pg = Postgres { conn: conn };
-        pg.ensure_migration_table_exists();
+        let (url, dialect) = strip_dialect(url)?;
+        let conn = mk_connection(&url)?;
+        let pg = Postgres { conn: conn, url: url, dialect: dialect, migrating: Arc::new(Mutex::new(false)) };
+        // A standby refuses even CREATE TABLE IF NOT EXISTS, the table is replicated from the primary and
+        // migrating refuses read-only databases
+        if pg.read_only()?.is_none() {
+            pg.ensure_migration_table_exists();
+        }
         Ok(pg)
     }
+
+    // Runs the statements of the migration the dialect needs in sessions of their own, returning the rest
+    fn run_extension_statements(&self, migration: String) -> Result<String> {
+        let (statements, rest) = self.dialect.extension_statements(&migration);
+        for statement in statements {
+            mk_connection(&self.url)?.batch_execute(&statement)
+                .chain_err(|| format!("Statement failed: {}", statement))?;
+        }
+        Ok(rest)
+    }
+
+    // Runs the transaction of a migration, the only time the canceller cancels what the connection runs
+    fn while_migrating<F: FnOnce() -> Result<()>>(&self, f: F) -> Result<()> {
+        *self.migrating.lock().unwrap() = true;
+        let res = f();
+        // Waits for a cancellation being sent, so that it can't cancel what the connection runs next
+        *self.migrating.lock().unwrap() = false;
+        res
+    }
 }
 
 impl Driver for Postgres {
     fn ensure_migration_table_exists(&self) {
-        self.conn.batch_execute("
-            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER);
+        // Greenplum would distribute it by its first column anyway, but complains about it
+        let distribution = if self.dialect == Dialect::Greenplum { " DISTRIBUTED BY (id)" } else { "" };
+        self.conn.batch_execute(&format!("
+            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER){};
             INSERT INTO __dbmigrate_table (id, current)
             SELECT 1, 0
             WHERE NOT EXISTS(SELECT * FROM __dbmigrate_table WHERE id = 1);
-        ").unwrap();
+        ", distribution)).unwrap();
     }
 
     fn remove_migration_table(&self) {
@@ -45,53 +89,195 @@ impl Driver for Postgres {
         results.get(0).get("current")
     }
 
-    fn set_current_number(&self, number: i32) {
-        let stmt = self.conn.prepare(
-            "UPDATE __dbmigrate_table SET current = $1 WHERE id = 1;"
-        ).unwrap();
-        stmt.execute(&[&number]).unwrap();
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE __dbmigrate_table SET current = $1 WHERE id = 1 AND current = $2;",
+            &[&number, &expected]
+        )?;
+        if updated == 0 {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
+    }
+
+    // The current number is compared and set in the transaction of the migration, which is rolled
+    // back if another run changed it in the meantime
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.migrate_with_assertions(migration, expected, number, &[])
+    }
+
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        let migration = self.run_extension_statements(migration).chain_err(|| "Migration failed")?;
+        self.while_migrating(|| migrate_in_transaction(self, migration, expected, number, assertions))
+    }
+
+    fn migrate_statements(
+        &self, statements: &mut Iterator<Item = Result<String>>, _annotations: &Annotations, expected: i32,
+        number: i32
+    ) -> Result<()> {
+        // The split statements lost the `;` the dialect looks for
+        let mut statements = statements.map(|statement| {
+            statement.and_then(|statement| {
+                self.run_extension_statements(format!("{};", statement)).chain_err(|| "Migration failed")
+            })
+        });
+        self.while_migrating(|| migrate_statements_in_transaction(self, &mut statements, expected, number))
+    }
+
+    fn try_lock(&self) -> Result<bool> {
+        Ok(is_true(Some(query_value(self, &format!("SELECT pg_try_advisory_lock({});", LOCK_KEY))?)))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.execute(&format!("SELECT pg_advisory_unlock({});", LOCK_KEY))
+    }
+
+    fn canceller(&self) -> Result<Option<Canceller>> {
+        let pid = query_value(self, "SELECT pg_backend_pid();")?;
+        let url = self.url.clone();
+        let migrating = self.migrating.clone();
+        Ok(Some(Box::new(move || {
+            // `pg_cancel_backend` cancels whatever the connection is running, so the migration is kept from
+            // finishing until it was sent, and it isn't sent once the migration finished
+            let migrating = migrating.lock().unwrap();
+            if *migrating {
+                let conn = mk_connection(&url)?;
+                conn.batch_execute(&format!("SELECT pg_cancel_backend({});", pid))?;
+            }
+            Ok(())
+        })))
+    }
+
+    fn server_version(&self) -> Result<String> {
+        query_value(self, "SHOW server_version;")
+    }
+
+    fn read_only(&self) -> Result<Option<String>> {
+        if is_true(Some(query_value(self, "SELECT pg_is_in_recovery();")?)) {
+            return Ok(Some("the server is a standby in recovery".to_owned()));
+        }
+        if query_value(self, "SHOW transaction_read_only;")? == "on" {
+            return Ok(Some("transactions are read-only, see default_transaction_read_only".to_owned()));
+        }
+        Ok(None)
     }
 
-    fn migrate(&self, migration: String, number: i32) -> Result<()> {
-        self.conn.batch_execute(&migration).chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+    fn has_transactional_ddl(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.conn.batch_execute(sql).chain_err(|| "Query failed")
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let rows = self.conn.query(sql, &[]).chain_err(|| format!("Query failed: {}", sql))?;
+        let mut results = vec![];
+        for row in &rows {
+            let mut values = vec![];
+            for i in 0..row.len() {
+                values.push(value_to_string(&row, i)?);
+            }
+            results.push(values);
+        }
+        Ok(results)
+    }
 
+    fn schema(&self) -> Result<String> {
+        // information_schema uses domains which can't be read as strings, hence the casts
+        let mut rows = self.query("
+            SELECT 'column'::text, table_schema::text || '.' || table_name::text, column_name::text,
+                data_type::text, is_nullable::text, column_default::text
+            FROM information_schema.columns
+            WHERE table_schema NOT IN ('pg_catalog', 'information_schema');
+        ")?;
+        rows.extend(self.query("
+            SELECT 'index'::text, schemaname::text || '.' || tablename::text, indexname::text, indexdef
+            FROM pg_indexes
+            WHERE schemaname NOT IN ('pg_catalog', 'information_schema');
+        ")?);
+        rows.extend(self.query("
+            SELECT 'view'::text, schemaname::text || '.' || viewname::text, definition
+            FROM pg_views
+            WHERE schemaname NOT IN ('pg_catalog', 'information_schema');
+        ")?);
+        Ok(rows_to_schema(rows))
+    }
+
+    fn load_csv(&self, table: &str, path: &Path) -> Result<()> {
+        let columns = read_columns(path)?;
+        let mut file = File::open(path).chain_err(|| format!("Failed to open {:?}", path))?;
+        let stmt = self.conn.prepare(&format!(
+            "COPY {} ({}) FROM STDIN WITH (FORMAT csv, HEADER true)", table, columns.join(", ")
+        ))?;
+        stmt.copy_in(&[], &mut file).chain_err(|| format!("Failed to load {:?} into {}", path, table))?;
         Ok(())
     }
 }
 
+// Rows are typed so we try the usual suspects until one matches
+fn value_to_string(row: &Row, i: usize) -> Result<Option<String>> {
+    if let Some(Ok(v)) = row.get_opt::<_, Option<String>>(i) {
+        return Ok(v);
+    }
+    if let Some(Ok(v)) = row.get_opt::<_, Option<bool>>(i) {
+        return Ok(v.map(|v| v.to_string()));
+    }
+    if let Some(Ok(v)) = row.get_opt::<_, Option<i64>>(i) {
+        return Ok(v.map(|v| v.to_string()));
+    }
+    if let Some(Ok(v)) = row.get_opt::<_, Option<i32>>(i) {
+        return Ok(v.map(|v| v.to_string()));
+    }
+    if let Some(Ok(v)) = row.get_opt::<_, Option<i16>>(i) {
+        return Ok(v.map(|v| v.to_string()));
+    }
+    bail!("Unsupported type for column {}", row.columns()[i].name())
+}
+
+/// Runs statements on the server without creating the migration table,
+/// used to create and drop databases
+pub fn execute_on_server(url: &str, sql: &str) -> Result<()> {
+    let conn = mk_connection(&strip_dialect(url)?.0)?;
+    conn.batch_execute(sql).chain_err(|| format!("Query failed: {}", sql))
+}
+
 // rust-postgres doesn't automatically support SSL from the url
 // (https://github.com/sfackler/rust-postgres/issues/166)
 // So we need to parse the url manually to check if we have some sslmode in it
 // and create a connection with the correct one
 fn mk_connection(url: &str) -> Result<Connection> {
     let negotiator = NativeTls::new().unwrap();
-    let url = Url::parse(url).unwrap();
-    let sslmode = url.query_pairs()
-        .find(|&(ref k, _)| k == SSLMODE)
-        .map_or(
-            TlsMode::None,
-            |(_, v)| match v.as_ref() {
-                "allow" | "prefer" => TlsMode::Prefer(&negotiator),
-                "require" => TlsMode::Require(&negotiator),
-                // No support for certificate verification yet.
-                "verify-ca" | "verify-full" => unimplemented!(),
-                _ => TlsMode::None
-            }
-        );
+    let options = ConnectionOptions::parse(url)?;
+    let sslmode = match options.sslmode.as_ref().map(String::as_str) {
+        Some("allow") | Some("prefer") => TlsMode::Prefer(&negotiator),
+        Some("require") => TlsMode::Require(&negotiator),
+        Some("verify-ca") | Some("verify-full") => bail!("sslmode {} isn't supported yet", options.sslmode.unwrap()),
+        _ => TlsMode::None,
+    };
 
-    Connection::connect(without_sslmode(&url).as_ref(), sslmode).map_err(From::from)
-}
-
-fn without_sslmode(url: &Url) -> String {
-    let pairs = url.query_pairs()
-        .filter(|&(ref k, _)| k != SSLMODE);
-
-    let mut cloned_url = url.clone();
-    cloned_url.query_pairs_mut().clear();
-    for (name, value) in pairs {
-        cloned_url.query_pairs_mut().append_pair(name.as_ref(), value.as_ref());
+    // The other parameters of the URL are sent to the server as run-time parameters
+    let params = options.url.as_str().into_connect_params()
+        .map_err(|e| format!("Invalid URL {}: {}", redact_urls(url), e))?;
+    let mut builder = ConnectParams::builder();
+    builder.port(params.port()).connect_timeout(Some(options.connect_timeout()));
+    if let Some(user) = params.user() {
+        builder.user(user.name(), user.password());
+    }
+    if let Some(database) = params.database() {
+        builder.database(database);
+    }
+    for &(ref name, ref value) in params.options() {
+        builder.option(name, value);
+    }
+    // Shows up in pg_stat_activity
+    builder.option("application_name", options.application_name());
+    if let Some(ref charset) = options.charset {
+        builder.option("client_encoding", charset);
     }
 
-    cloned_url.as_str().to_owned()
+    connecting(url, options.connect_timeout(), || {
+        Connection::connect(builder.build(params.host().clone()), sslmode)
+            .chain_err(|| ErrorKind::ConnectionError(redact_urls(url)))
+    })
 }
@@ -0,0 +1,301 @@
+use std::thread;
+use std::time::Duration;
+
+use regex::Regex;
+use serde_json::Value;
+use url::Url;
+
+use super::{query_value, Driver};
+use super::google::{value_to_text, GoogleApi};
+use super::portable::{self, is_tracking_sql};
+use annotations::check_condition;
+use dirty;
+use schema::rows_to_schema;
+use statements::split_statements;
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+const API: &'static str = "https://spanner.googleapis.com/v1";
+// Spanner names start with a letter, and `current` is reserved
+const RESERVED: &'static [&'static str] = &["current"];
+// The SQL shared by the drivers for the dirty marker, the history and the lock lands on the tables of Spanner
+const REPLACEMENTS: &'static [(&'static str, &'static str)] = &[
+    ("__dbmigrate_table", "dbmigrate_table"),
+    ("__dbmigrate_history", "dbmigrate_history"),
+    ("__dbmigrate_lock", "dbmigrate_lock"),
+    ("INTEGER", "INT64"),
+    ("VARCHAR", "STRING"),
+    ("TEXT", "STRING(MAX)"),
+];
+// Names of Spanner can't start with an underscore, so the migration table has its own
+const MIGRATION_TABLE: &'static str = "dbmigrate_table";
+const ADD_DIRTY_COLUMN: &'static str = "ALTER TABLE dbmigrate_table ADD COLUMN dirty INT64;";
+const DDL_POLL_INTERVAL: u64 = 2;
+
+
+pub struct Spanner {
+    api: GoogleApi,
+    // `projects/<project>/instances/<instance>/databases/<database>`
+    database: String,
+    session: String,
+}
+
+impl Spanner {
+    pub fn new(url: &str) -> Result<Spanner> {
+        let database = database_path(url)?;
+        let api = GoogleApi::new()?;
+        let session = api.call("POST", &format!("{}/{}/sessions", API, database), Some(json!({})))
+            .chain_err(|| ErrorKind::ConnectionError(redact_urls(url)))?;
+        let session = match session["name"].as_str() {
+            Some(name) => name.to_owned(),
+            None => bail!("Spanner didn't return a session for {}", database),
+        };
+        let spanner = Spanner { api: api, database: database, session: session };
+        spanner.ensure_migration_table_exists();
+        Ok(spanner)
+    }
+
+    // Schema changes go through the admin API as long-running operations, which are polled until
+    // they are done so the next statements and the migration number only come after them
+    fn update_ddl(&self, statements: &[String]) -> Result<()> {
+        let mut operation = self.api.call(
+            "PATCH", &format!("{}/{}/ddl", API, self.database), Some(json!({ "statements": statements }))
+        )?;
+        while !operation["done"].as_bool().unwrap_or(false) {
+            thread::sleep(Duration::from_secs(DDL_POLL_INTERVAL));
+            let name = match operation["name"].as_str() {
+                Some(name) => name.to_owned(),
+                None => bail!("Spanner didn't return the operation of the schema change"),
+            };
+            operation = self.api.call("GET", &format!("{}/{}", API, name), None)?;
+        }
+        if let Some(message) = operation.pointer("/error/message").and_then(Value::as_str) {
+            bail!("Schema change failed: {}", message);
+        }
+        Ok(())
+    }
+
+    // Data changes run in a read-write transaction of the session, returning the rows changed by each statement
+    fn execute_dml(&self, statements: &[String]) -> Result<Vec<i64>> {
+        let body = json!({
+            "transaction": { "begin": { "readWrite": {} } },
+            "statements": statements.iter().map(|sql| json!({ "sql": sql })).collect::<Vec<_>>(),
+            "seqno": "1",
+        });
+        let response = self.api.call("POST", &format!("{}/{}:executeBatchDml", API, self.session), Some(body))?;
+        let transaction = response.pointer("/resultSets/0/metadata/transaction/id").and_then(Value::as_str);
+        if let Some(message) = response.pointer("/status/message").and_then(Value::as_str) {
+            if let Some(id) = transaction {
+                self.api.call("POST", &format!("{}/{}:rollback", API, self.session), Some(json!({ "transactionId": id }))).ok();
+            }
+            bail!("Statement failed: {}", message);
+        }
+        let counts = response["resultSets"].as_array().map_or(vec![], |sets| {
+            sets.iter()
+                .map(|set| set.pointer("/stats/rowCountExact").and_then(value_to_text).and_then(|n| n.parse().ok()).unwrap_or(0))
+                .collect()
+        });
+        if let Some(id) = transaction {
+            self.api.call("POST", &format!("{}/{}:commit", API, self.session), Some(json!({ "transactionId": id })))?;
+        }
+        Ok(counts)
+    }
+
+    // Consecutive schema changes are submitted together, as are consecutive data changes
+    fn run(&self, sql: &str) -> Result<()> {
+        let tracking = is_tracking_sql(sql);
+        let sql = adapt(sql);
+        let mut statements = split_statements(&sql, ';').into_iter().peekable();
+        while let Some(statement) = statements.next() {
+            let ddl = is_ddl(&statement);
+            let mut batch = vec![statement];
+            while statements.peek().map_or(false, |next| is_ddl(next) == ddl) {
+                batch.push(statements.next().unwrap());
+            }
+            if ddl && tracking {
+                self.update_ddl(&batch.iter().map(|s| move_primary_key(s)).collect::<Vec<_>>())?;
+            } else if ddl {
+                self.update_ddl(&batch)?;
+            } else {
+                self.execute_dml(&batch)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Driver for Spanner {
+    fn ensure_migration_table_exists(&self) {
+        self.update_ddl(&[
+            "CREATE TABLE IF NOT EXISTS dbmigrate_table (id INT64 NOT NULL, `current` INT64) PRIMARY KEY (id)".to_owned()
+        ]).unwrap();
+        if self.query("SELECT `current` FROM dbmigrate_table WHERE id = 1").unwrap().is_empty() {
+            self.execute_dml(&["INSERT INTO dbmigrate_table (id, `current`) VALUES (1, 0)".to_owned()]).unwrap();
+        }
+    }
+
+    fn remove_migration_table(&self) {
+        self.update_ddl(&["DROP TABLE dbmigrate_table".to_owned()]).unwrap();
+    }
+
+    fn get_current_number(&self) -> i32 {
+        query_value(self, "SELECT `current` FROM dbmigrate_table WHERE id = 1").unwrap().parse().unwrap()
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let counts = self.execute_dml(&[format!(
+            "UPDATE dbmigrate_table SET `current` = {} WHERE id = 1 AND `current` = {}", number, expected
+        )])?;
+        if counts.first() == Some(&0) {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
+    }
+
+    // Schema changes can't be part of a transaction: a failing migration leaves the statements before
+    // the failing one applied
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.run(&migration).chain_err(|| "Migration failed")?;
+        self.set_current_number(expected, number)
+    }
+
+    // Nothing can be rolled back, the migration number just isn't updated when an assertion fails
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        self.run(&migration).chain_err(|| "Migration failed")?;
+        for assertion in assertions {
+            if !check_condition(self, assertion)? {
+                bail!("Assertion failed: {}", assertion);
+            }
+        }
+        self.set_current_number(expected, number)
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.run(sql).chain_err(|| "Query failed")
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let sql = adapt(sql);
+        let sql = sql.trim().trim_right_matches(';');
+        let response = self.api.call("POST", &format!("{}/{}:executeSql", API, self.session), Some(json!({ "sql": sql })))
+            .chain_err(|| format!("Query failed: {}", sql))?;
+        let rows = response["rows"].as_array().map_or(vec![], |rows| {
+            rows.iter()
+                .map(|row| row.as_array().map_or(vec![], |values| values.iter().map(value_to_text).collect()))
+                .collect()
+        });
+        Ok(rows)
+    }
+
+    fn schema(&self) -> Result<String> {
+        let mut rows = self.query("
+            SELECT 'column', table_name, column_name, spanner_type, is_nullable, column_default
+            FROM information_schema.columns
+            WHERE table_schema = '' AND NOT STARTS_WITH(table_name, 'dbmigrate_')
+        ")?;
+        rows.extend(self.query("
+            SELECT 'index', table_name, index_name, index_type, CAST(is_unique AS STRING)
+            FROM information_schema.indexes
+            WHERE table_schema = '' AND NOT STARTS_WITH(table_name, 'dbmigrate_')
+        ")?);
+        rows.extend(self.query("
+            SELECT 'view', table_name, view_definition
+            FROM information_schema.views
+            WHERE table_schema = ''
+        ")?);
+        Ok(rows_to_schema(rows))
+    }
+
+    fn dirty(&self) -> Result<Option<i32>> {
+        dirty::select_dirty_with(self, MIGRATION_TABLE, ADD_DIRTY_COLUMN)
+    }
+
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        dirty::update_dirty_with(self, MIGRATION_TABLE, ADD_DIRTY_COLUMN, number)
+    }
+}
+
+// `spanner://project/instance/database` becomes `projects/project/instances/instance/databases/database`
+fn database_path(url: &str) -> Result<String> {
+    let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    let segments = parsed_url.path_segments().map_or(vec![], |s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>());
+    match (parsed_url.host_str(), segments.as_slice()) {
+        (Some(project), [instance, database]) => {
+            Ok(format!("projects/{}/instances/{}/databases/{}", project, instance, database))
+        },
+        _ => bail!("Invalid URL: {}, expected spanner://project/instance/database", redact_urls(url)),
+    }
+}
+
+// The tables and types of the dbmigrate SQL in GoogleSQL
+fn adapt(sql: &str) -> String {
+    if is_tracking_sql(sql) {
+        portable::adapt(sql, '`', RESERVED, REPLACEMENTS)
+    } else {
+        sql.to_owned()
+    }
+}
+
+fn is_ddl(statement: &str) -> bool {
+    let keyword = statement.split_whitespace().next().unwrap_or("").to_uppercase();
+    ["CREATE", "ALTER", "DROP", "RENAME", "GRANT", "REVOKE", "ANALYZE"].contains(&keyword.as_str())
+}
+
+// Spanner declares the primary key after the columns: `CREATE TABLE t (id INT64 NOT NULL) PRIMARY KEY (id)`
+fn move_primary_key(statement: &str) -> String {
+    let inline = Regex::new(r"(?i)`?(\w+)`?(\s+\w+(\(\w+\))?\s+NOT\s+NULL)\s+PRIMARY\s+KEY").unwrap();
+    match inline.captures(statement) {
+        Some(captures) => format!("{} PRIMARY KEY ({})", inline.replace(statement, "$1$2").trim_right(), &captures[1]),
+        None => statement.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adapt, database_path, is_ddl, move_primary_key, ADD_DIRTY_COLUMN};
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(
+            database_path("spanner://acme-prod/main/app").unwrap(),
+            "projects/acme-prod/instances/main/databases/app"
+        );
+        assert!(database_path("spanner://acme-prod/app").is_err());
+    }
+
+    #[test]
+    fn test_adapt_tracking_table() {
+        let sql = adapt("CREATE TABLE IF NOT EXISTS __dbmigrate_lock (\n\
+            id INTEGER NOT NULL PRIMARY KEY,\n\
+            locked_at VARCHAR(19) NOT NULL,\n\
+            note TEXT\n\
+            )");
+        assert_eq!(move_primary_key(&sql), "CREATE TABLE IF NOT EXISTS `dbmigrate_lock` (\n\
+            id INT64 NOT NULL,\n\
+            locked_at STRING(19) NOT NULL,\n\
+            note STRING(MAX)\n\
+            ) PRIMARY KEY (id)");
+        assert_eq!(adapt("CREATE TABLE users (id INTEGER)"), "CREATE TABLE users (id INTEGER)");
+    }
+
+    #[test]
+    fn test_add_dirty_column() {
+        assert!(is_ddl(ADD_DIRTY_COLUMN));
+        assert_eq!(adapt(ADD_DIRTY_COLUMN), ADD_DIRTY_COLUMN);
+    }
+
+    #[test]
+    fn test_adapt_dirty_and_history() {
+        assert_eq!(
+            adapt("ALTER TABLE __dbmigrate_table ADD COLUMN dirty INTEGER;"),
+            "ALTER TABLE `dbmigrate_table` ADD COLUMN dirty INT64;"
+        );
+        assert_eq!(
+            adapt("UPDATE __dbmigrate_table SET dirty = NULL WHERE id = 1;"),
+            "UPDATE `dbmigrate_table` SET dirty = NULL WHERE id = 1;"
+        );
+        assert_eq!(
+            adapt("SELECT number, name FROM __dbmigrate_history WHERE name = 'current' ORDER BY id;"),
+            "SELECT number, name FROM `dbmigrate_history` WHERE name = 'current' ORDER BY id;"
+        );
+    }
+}
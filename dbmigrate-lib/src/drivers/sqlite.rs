@@ -1,7 +1,93 @@
-use sqlite_client::Connection;
+use std::path::Path;
+use std::sync::Mutex;
 
-use super::Driver;
-use errors::{Result, ResultExt};
+use sqlite_client::{Connection, OpenFlags};
+use sqlite_client::types::Value;
+use url::form_urlencoded;
+
+use super::{query_value, sqlite_path, Driver};
+use annotations::check_condition;
+use csv_load::insert_csv_rows;
+use schema::rows_to_schema;
+use errors::{ErrorKind, Result, ResultExt};
+
+/// URL of the in-memory database
+pub const MEMORY_URL: &'static str = "sqlite::memory:";
+// A named in-memory database with a shared cache is the same for all connections of the process
+const MEMORY_URI: &'static str = "file:dbmigrate_memory?mode=memory&cache=shared";
+
+lazy_static! {
+    // An in-memory database disappears with its last connection, this one keeps it
+    // around for the lifetime of the process
+    static ref MEMORY_KEEPER: Mutex<Option<Connection>> = Mutex::new(None);
+}
+
+fn open_in_memory() -> Result<Connection> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI;
+    let mut keeper = MEMORY_KEEPER.lock().unwrap();
+    if keeper.is_none() {
+        *keeper = Some(Connection::open_with_flags(MEMORY_URI, flags)?);
+    }
+    Ok(Connection::open_with_flags(MEMORY_URI, flags)?)
+}
+
+
+const JOURNAL_MODES: [&'static str; 6] = ["delete", "truncate", "persist", "memory", "wal", "off"];
+// Long enough to wait for the transactions of an application using the same file
+const DEFAULT_BUSY_TIMEOUT: u64 = 5000;
+
+// Connection settings given as URL parameters, like `sqlite:///app.db?journal_mode=wal&foreign_keys=on`
+#[derive(Debug, PartialEq)]
+struct Options {
+    journal_mode: Option<String>,
+    foreign_keys: Option<bool>,
+    // In milliseconds
+    busy_timeout: u64,
+}
+
+impl Options {
+    fn parse(url: &str) -> Result<Options> {
+        let mut options = Options { journal_mode: None, foreign_keys: None, busy_timeout: DEFAULT_BUSY_TIMEOUT };
+        let params = match url.find('?') {
+            Some(i) => &url[i + 1..],
+            None => return Ok(options),
+        };
+
+        for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+            let value = value.to_lowercase();
+            match key.as_ref() {
+                "journal_mode" => {
+                    if !JOURNAL_MODES.contains(&value.as_str()) {
+                        bail!("Invalid journal_mode {}, expected one of {}", value, JOURNAL_MODES.join(", "));
+                    }
+                    options.journal_mode = Some(value);
+                },
+                "foreign_keys" => options.foreign_keys = match value.as_str() {
+                    "on" | "true" | "1" => Some(true),
+                    "off" | "false" | "0" => Some(false),
+                    _ => bail!("Invalid foreign_keys {}, expected on or off", value),
+                },
+                "busy_timeout" => {
+                    options.busy_timeout = value.parse().chain_err(|| format!("Invalid busy_timeout: {}", value))?;
+                },
+                _ => bail!("Unknown SQLite URL parameter {}, expected journal_mode, foreign_keys or busy_timeout", key),
+            }
+        }
+        Ok(options)
+    }
+
+    fn apply(&self, conn: &Connection) -> Result<()> {
+        // Those return the new value, which `execute` refuses
+        let _: i64 = conn.query_row(&format!("PRAGMA busy_timeout = {};", self.busy_timeout), &[], |row| row.get(0))?;
+        if let Some(ref mode) = self.journal_mode {
+            let _: String = conn.query_row(&format!("PRAGMA journal_mode = {};", mode), &[], |row| row.get(0))?;
+        }
+        if let Some(enabled) = self.foreign_keys {
+            conn.execute_batch(&format!("PRAGMA foreign_keys = {};", if enabled { "ON" } else { "OFF" }))?;
+        }
+        Ok(())
+    }
+}
 
 
 #[derive(Debug)]
@@ -11,12 +97,30 @@ pub struct Sqlite {
 
 impl Sqlite {
     pub fn new(url: &str) -> Result<Sqlite> {
-        // the replace is probably wrong
-        let conn = Connection::open(url.replace("sqlite:/", ""))?;
+        let options = Options::parse(url)?;
+        let conn = if url.split('?').next() == Some(MEMORY_URL) {
+            open_in_memory()?
+        } else {
+            Connection::open(sqlite_path(url)).chain_err(|| ErrorKind::ConnectionError(url.to_owned()))?
+        };
+        options.apply(&conn).chain_err(|| "Failed to configure the SQLite connection")?;
         let sqlite = Sqlite { conn: conn };
         sqlite.ensure_migration_table_exists();
         Ok(sqlite)
     }
+
+    // BEGIN IMMEDIATE takes the write lock right away, waiting up to busy_timeout for other
+    // connections, rather than failing with "database is locked" halfway through
+    fn in_transaction<F: FnOnce() -> Result<()>>(&self, f: F) -> Result<()> {
+        self.execute("BEGIN IMMEDIATE;")?;
+        match f() {
+            Ok(_) => self.execute("COMMIT;"),
+            Err(e) => {
+                self.execute("ROLLBACK;").ok();
+                Err(e)
+            }
+        }
+    }
 }
 
 impl Driver for Sqlite {
@@ -39,17 +143,118 @@ impl Driver for Sqlite {
         ", &[], |row| { row.get(0)}).unwrap()
     }
 
-    fn set_current_number(&self, number: i32) {
-        let mut stmt = self.conn.prepare(
-            "UPDATE __dbmigrate_table SET current = ? WHERE id = 1;"
-        ).unwrap();
-        stmt.execute(&[&number]).unwrap();
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE __dbmigrate_table SET current = ? WHERE id = 1 AND current = ?;",
+            &[&number, &expected]
+        )?;
+        if updated == 0 {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(())
     }
 
-    fn migrate(&self, migration: String, number: i32) -> Result<()> {
-        self.conn.execute_batch(&migration).chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        self.in_transaction(|| {
+            self.conn.execute_batch(&migration).chain_err(|| "Migration failed")?;
+            self.set_current_number(expected, number)
+        })
+    }
 
-        Ok(())
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        self.in_transaction(|| {
+            self.conn.execute_batch(&migration).chain_err(|| "Migration failed")?;
+            for assertion in assertions {
+                if !check_condition(self, assertion)? {
+                    bail!("Assertion failed: {}", assertion);
+                }
+            }
+            self.set_current_number(expected, number)
+        })
+    }
+
+    fn server_version(&self) -> Result<String> {
+        query_value(self, "SELECT sqlite_version();")
+    }
+
+    fn has_transactional_ddl(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.conn.execute_batch(sql).chain_err(|| "Query failed")
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        let mut stmt = self.conn.prepare(sql).chain_err(|| format!("Query failed: {}", sql))?;
+        let count = stmt.column_count();
+        let rows = stmt.query_map(&[], |row| {
+            (0..count).map(|i| match row.get_checked::<_, Value>(i) {
+                Ok(Value::Integer(v)) => Some(v.to_string()),
+                Ok(Value::Real(v)) => Some(v.to_string()),
+                Ok(Value::Text(v)) => Some(v),
+                Ok(Value::Blob(v)) => Some(String::from_utf8_lossy(&v).into_owned()),
+                Ok(Value::Null) | Err(_) => None,
+            }).collect::<Vec<_>>()
+        })?;
+
+        let mut results = vec![];
+        for row in rows {
+            results.push(row?);
+        }
+        Ok(results)
+    }
+
+    fn schema(&self) -> Result<String> {
+        let rows = self.query("
+            SELECT type, name, sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%';
+        ")?;
+        Ok(rows_to_schema(rows))
+    }
+
+    fn load_csv(&self, table: &str, path: &Path) -> Result<()> {
+        // Inserting row by row outside of a transaction is painfully slow in SQLite
+        self.in_transaction(|| insert_csv_rows(self, table, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::{Options, Sqlite, DEFAULT_BUSY_TIMEOUT, MEMORY_URL};
+    use drivers::Driver;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(Options::parse("sqlite:///app.db").unwrap(), Options {
+            journal_mode: None, foreign_keys: None, busy_timeout: DEFAULT_BUSY_TIMEOUT,
+        });
+        assert_eq!(Options::parse("sqlite:///app.db?journal_mode=WAL&foreign_keys=on&busy_timeout=100").unwrap(), Options {
+            journal_mode: Some("wal".to_owned()), foreign_keys: Some(true), busy_timeout: 100,
+        });
+        assert!(Options::parse("sqlite:///app.db?journal_mode=fast").is_err());
+        assert!(Options::parse("sqlite:///app.db?cache=shared").is_err());
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let dir = TempDir::new("sqlite").unwrap();
+        let url = format!("sqlite://{}?journal_mode=wal", dir.path().join("app.db").display());
+        let sqlite = Sqlite::new(&url).unwrap();
+
+        assert!(sqlite.migrate("CREATE TABLE a (id INTEGER); INSERT INTO missing VALUES (1);".to_owned(), 0, 1).is_err());
+        assert_eq!(sqlite.get_current_number(), 0);
+        assert!(sqlite.query("SELECT * FROM a;").is_err());
+    }
+
+    #[test]
+    fn test_memory_database_is_shared_by_the_process() {
+        let first = Sqlite::new(MEMORY_URL).unwrap();
+        first.set_current_number(0, 3).unwrap();
+        drop(first);
+
+        let second = Sqlite::new(MEMORY_URL).unwrap();
+        assert_eq!(second.get_current_number(), 3);
     }
 }
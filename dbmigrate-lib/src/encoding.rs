@@ -0,0 +1,104 @@
+///! Decoding migration files written by editors that don't default to plain UTF-8
+use std::str::FromStr;
+
+use errors::{Error, Result};
+
+const UTF8_BOM: &'static [u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &'static [u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &'static [u8] = &[0xFE, 0xFF];
+
+/// How to read migration files that aren't valid UTF-8.
+/// Byte order marks are always handled: UTF-8 ones are stripped and UTF-16 files are decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Refuse them
+    Utf8,
+    /// Read them as ISO-8859-1 (Latin-1), files that are valid UTF-8 being still read as UTF-8
+    Latin1,
+}
+
+impl Encoding {
+    /// All the names accepted by `from_str`
+    pub fn variants() -> [&'static str; 2] {
+        ["utf8", "latin1"]
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Utf8
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Encoding> {
+        match s.to_lowercase().replace("-", "").as_str() {
+            "utf8" => Ok(Encoding::Utf8),
+            "latin1" | "iso88591" => Ok(Encoding::Latin1),
+            _ => bail!("Unknown encoding {}, expected one of {}", s, Encoding::variants().join(", ")),
+        }
+    }
+}
+
+/// Turns the raw content of a file into a string according to its BOM or the encoding
+pub fn decode(bytes: Vec<u8>, encoding: Encoding) -> Result<String> {
+    if bytes.starts_with(UTF8_BOM) {
+        return decode(bytes[UTF8_BOM.len()..].to_vec(), encoding);
+    }
+    if bytes.starts_with(UTF16_LE_BOM) {
+        return decode_utf16(&bytes[UTF16_LE_BOM.len()..], |pair| u16::from(pair[0]) | u16::from(pair[1]) << 8);
+    }
+    if bytes.starts_with(UTF16_BE_BOM) {
+        return decode_utf16(&bytes[UTF16_BE_BOM.len()..], |pair| u16::from(pair[0]) << 8 | u16::from(pair[1]));
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(content),
+        Err(e) => match encoding {
+            // Every byte is a valid Latin-1 character, with the same code point
+            Encoding::Latin1 => Ok(e.into_bytes().into_iter().map(char::from).collect()),
+            Encoding::Utf8 => bail!(
+                "Invalid UTF-8 at byte {}, convert the file to UTF-8 or use the latin1 encoding",
+                e.utf8_error().valid_up_to()
+            ),
+        },
+    }
+}
+
+fn decode_utf16<F: Fn(&[u8]) -> u16>(bytes: &[u8], to_unit: F) -> Result<String> {
+    if bytes.len() % 2 != 0 {
+        bail!("Invalid UTF-16: odd number of bytes");
+    }
+    let units = bytes.chunks(2).map(to_unit).collect::<Vec<_>>();
+    match String::from_utf16(&units) {
+        Ok(content) => Ok(content),
+        Err(_) => bail!("Invalid UTF-16"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Encoding};
+
+    #[test]
+    fn test_utf8_bom_is_stripped() {
+        let bytes = b"\xEF\xBB\xBFSELECT 1;".to_vec();
+        assert_eq!(decode(bytes, Encoding::Utf8).unwrap(), "SELECT 1;");
+    }
+
+    #[test]
+    fn test_utf16_with_bom_is_decoded() {
+        assert_eq!(decode(b"\xFF\xFEO\0K\0".to_vec(), Encoding::Utf8).unwrap(), "OK");
+        assert_eq!(decode(b"\xFE\xFF\0O\0K".to_vec(), Encoding::Utf8).unwrap(), "OK");
+    }
+
+    #[test]
+    fn test_latin1_is_only_read_when_asked() {
+        let bytes = b"SELECT 'caf\xE9';".to_vec();
+        assert!(decode(bytes.clone(), Encoding::Utf8).is_err());
+        assert_eq!(decode(bytes, Encoding::Latin1).unwrap(), "SELECT 'café';");
+        assert_eq!(decode("SELECT 'café';".as_bytes().to_vec(), Encoding::Latin1).unwrap(), "SELECT 'café';");
+    }
+}
@@ -1,15 +1,28 @@
+use std::borrow::Cow;
 use std::fs;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::iter::{repeat};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+#[cfg(not(feature = "git"))]
+use std::process::Command;
 use std::collections::{BTreeMap};
 
+use flate2::read::GzDecoder;
 use regex::Regex;
-use errors::{Result, ResultExt};
+use annotations::{Annotations, PREFIX};
+use checksum::{checksum, checksum_lines};
+use encoding::{decode, Encoding};
+use history::{current_user, utc_now};
+use errors::{ErrorKind, Result, ResultExt};
+use statements::Statements;
+
+/// The subdirectory of the migrations directory `archive_migrations` moves old migrations to.
+/// The loader doesn't read it, only checking that the numbers continue after it
+pub const ARCHIVE_DIRECTORY: &'static str = "archive";
 
 /// A migration direction, can be Up or Down
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Direction {
     /// Self-explanatory
     Up,
@@ -26,11 +39,33 @@ impl ToString for Direction {
     }
 }
 
+/// What a migration file contains
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MigrationKind {
+    /// SQL statements, possibly gzipped
+    Sql,
+    /// An executable, run with the database URL in its environment
+    Script,
+    /// A manifest loading a CSV file into a table
+    Csv,
+    /// Database commands in JSON, for MongoDB
+    Json,
+    /// Cypher statements, for Neo4j
+    Cypher,
+}
+
 /// A single direction migration file
 #[derive(Debug)]
 pub struct MigrationFile {
-    /// Content of the file
+    /// Content of the file, always None for scripts and for files read by `read_migration_files_lazily`.
+    /// `read_content` reads it in that case
     pub content: Option<String>,
+    /// How the file is decoded when `read_content` reads it
+    pub encoding: Encoding,
+    /// Kind of migration
+    pub kind: MigrationKind,
+    /// Path to the file
+    pub path: PathBuf,
     /// Direction
     pub direction: Direction,
     /// Number
@@ -38,7 +73,9 @@ pub struct MigrationFile {
     /// Filename
     pub filename: String,
     /// Actual migration name (filename with number removed)
-    pub name: String
+    pub name: String,
+    /// Annotations found in the content of SQL files
+    pub annotations: Annotations,
 }
 
 /// A migration has 2 files: one up and one down
@@ -46,38 +83,172 @@ pub struct MigrationFile {
 pub struct Migration {
     /// The Up file
     pub up: Option<MigrationFile>,
-    /// The Down file
+    /// The Down file, None for irreversible migrations
     pub down: Option<MigrationFile>
 }
 
+impl Migration {
+    /// The file to run in the given direction. Fails for the down file of migrations annotated
+    /// `-- dbmigrate:irreversible` or without one
+    pub fn file(&self, direction: Direction) -> Result<&MigrationFile> {
+        let up = match self.up {
+            Some(ref up) => up,
+            None => bail!("Migration is missing its up file"),
+        };
+        if direction == Direction::Up {
+            return Ok(up);
+        }
+        if up.annotations.irreversible {
+            bail!("Migration {} ({}) is irreversible", up.number, up.name);
+        }
+        match self.down {
+            Some(ref down) => Ok(down),
+            None => bail!(ErrorKind::FileError(up.path.clone(), format!("Migration {} ({}) has no down file", up.number, up.name))),
+        }
+    }
+}
+
 /// Simple way to hold migrations indexed by their number
 pub type Migrations = BTreeMap<i32, Migration>;
 
 impl MigrationFile {
     /// Used when getting the info, therefore setting content to None at that point
-    fn new(filename: &str, name: &str, number: i32, direction: Direction) -> MigrationFile {
+    fn new(filename: &str, name: &str, number: i32, direction: Direction, kind: MigrationKind) -> MigrationFile {
         MigrationFile {
             content: None,
+            encoding: Encoding::default(),
+            kind: kind,
+            path: PathBuf::from(filename),
             filename: filename.to_owned(),
             number: number,
             name: name.to_owned(),
-            direction: direction
+            direction: direction,
+            annotations: Annotations::default(),
+        }
+    }
+
+    /// The content of the file, read from it if it wasn't loaded with the file. Empty for scripts
+    pub fn read_content<'a>(&'a self) -> Result<Cow<'a, str>> {
+        match self.content {
+            Some(ref content) => Ok(Cow::Borrowed(content)),
+            None if self.kind == MigrationKind::Script => Ok(Cow::Borrowed("")),
+            None => read_file(&self.path, self.encoding).map(Cow::Owned),
         }
     }
+
+    /// The lines of the content with their line endings, read one at a time if it wasn't loaded with the file
+    pub fn lines<'a>(&'a self) -> Result<Box<Iterator<Item = Result<String>> + 'a>> {
+        match self.content {
+            Some(ref content) => Ok(Box::new(content.split_inclusive('\n').map(|line| Ok(line.to_owned())))),
+            None if self.kind == MigrationKind::Script => Ok(Box::new(None.into_iter())),
+            None => read_lines(&self.path, self.encoding),
+        }
+    }
+
+    /// The statements of the content, split on `;` like `split_statements` as they are read, for
+    /// migrations too large to be held in memory
+    pub fn statements<'a>(&'a self) -> Result<Statements<Box<Iterator<Item = Result<String>> + 'a>>> {
+        Ok(Statements::new(self.lines()?, ';'))
+    }
+
+    /// Checksum of the content, see `checksum`. `None` for scripts, whose content isn't read,
+    /// and files which can't be read anymore
+    pub fn checksum(&self, strict: bool) -> Option<String> {
+        match self.content {
+            _ if self.kind == MigrationKind::Script => None,
+            Some(ref content) => Some(checksum(content, strict)),
+            None => self.lines().and_then(|lines| checksum_lines(lines, strict)).ok(),
+        }
+    }
+
+    /// First comment of the content, see `description`
+    pub fn description(&self) -> Option<String> {
+        self.read_content().ok().and_then(|content| description(&content))
+    }
+}
+
+/// The first `--` comment of a SQL migration, its consecutive lines joined, ignoring annotations
+pub fn description(content: &str) -> Option<String> {
+    let lines = content.lines()
+        .map(|line| line.trim())
+        .skip_while(|line| !is_description_line(line))
+        .take_while(|line| is_description_line(line))
+        .map(|line| line.trim_left_matches('-').trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    if lines.is_empty() { None } else { Some(lines.join(" ")) }
 }
 
-/// Creates 2 migration file: one up and one down
+fn is_description_line(line: &str) -> bool {
+    line.starts_with("--") && !line.starts_with("-- dbmigrate:") && !line.starts_with(HEADER_PREFIX)
+}
+
+/// Prefix of the header lines `create_migration` writes at the top of the files: the migration,
+/// its author, when it was created and by which version of dbmigrate. Checksums ignore them.
+/// It is an SQL comment for every database, MySQL only taking `--` followed by a space as one
+pub const HEADER_PREFIX: &'static str = "-- dbmigrate-header: ";
+
+fn header(filename: &str, author: Option<&str>) -> String {
+    let mut fields = vec![format!("Migration: {}", filename)];
+    if let Some(author) = author {
+        fields.push(format!("Author: {}", author));
+    }
+    fields.push(format!("Date: {} UTC", utc_now()));
+    fields.push(format!("dbmigrate: {}", env!("CARGO_PKG_VERSION")));
+    fields.iter().map(|field| format!("{}{}\n", HEADER_PREFIX, field)).collect()
+}
+
+// `Name <email>` from the git config, whichever of them is set
+fn git_author() -> Option<String> {
+    let (name, email) = git_config_user();
+    match (name.filter(|name| !name.is_empty()), email.filter(|email| !email.is_empty())) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (name, email) => name.or(email),
+    }
+}
+
+#[cfg(feature = "git")]
+fn git_config_user() -> (Option<String>, Option<String>) {
+    match ::git2::Config::open_default() {
+        Ok(config) => (config.get_string("user.name").ok(), config.get_string("user.email").ok()),
+        Err(_) => (None, None),
+    }
+}
+
+// Without git2, from the git CLI if it is installed
+#[cfg(not(feature = "git"))]
+fn git_config_user() -> (Option<String>, Option<String>) {
+    let config = |key: &str| Command::new("git").args(&["config", key]).output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned());
+    (config("user.name"), config("user.email"))
+}
+
+/// Creates 2 migration file: one up and one down, starting with a header (see `HEADER_PREFIX`)
 pub fn create_migration(path: &Path, slug: &str, number: i32) -> Result<()> {
+    create_migration_with_content(path, slug, number, "", "")
+}
+
+/// Same as `create_migration`, writing the given SQL in the files
+pub fn create_migration_with_content(path: &Path, slug: &str, number: i32, up: &str, down: &str) -> Result<()> {
     let fixed_slug = slug.replace(" ", "_");
     let filename_up = get_filename(&fixed_slug, number, Direction::Up);
     parse_filename(&filename_up)?;
     let filename_down = get_filename(&fixed_slug, number, Direction::Down);
     parse_filename(&filename_down)?;
 
+    let author = git_author().or_else(current_user);
+    let author = author.as_ref().map(String::as_str);
     println!("Creating {}", filename_up);
-    File::create(path.join(filename_up.clone())).chain_err(|| format!("Failed to create {}", filename_up))?;
+    File::create(path.join(filename_up.clone()))
+        .and_then(|mut f| f.write_all(header(&filename_up, author).as_bytes()).and_then(|_| f.write_all(up.as_bytes())))
+        .chain_err(|| file_error(&path.join(&filename_up), "Failed to create"))?;
     println!("Creating {}", filename_down);
-    File::create(path.join(filename_down.clone())).chain_err(|| format!("Failed to create {}", filename_down))?;
+    File::create(path.join(filename_down.clone()))
+        .and_then(|mut f| {
+            f.write_all(header(&filename_down, author).as_bytes()).and_then(|_| f.write_all(down.as_bytes()))
+        })
+        .chain_err(|| file_error(&path.join(&filename_down), "Failed to create"))?;
 
     Ok(())
 }
@@ -92,21 +263,138 @@ fn get_filename(slug: &str, number: i32, direction: Direction) -> String {
 /// Read the path given and read all the migration files, pairing them by migration
 /// number and checking for errors along the way
 pub fn read_migration_files(path: &Path) -> Result<Migrations> {
+    read_migration_files_with_encoding(path, Encoding::Utf8)
+}
+
+/// Same as `read_migration_files`, reading files that aren't valid UTF-8 with the given encoding
+pub fn read_migration_files_with_encoding(path: &Path, encoding: Encoding) -> Result<Migrations> {
+    let migrations = load_migration_files_with_encoding(path, encoding)?;
+    check_sequence(baseline(path)?, migrations.keys())?;
+    Ok(migrations)
+}
+
+/// Same as `read_migration_files` but without checking that the numbers are contiguous,
+/// for when other migrations (eg code ones) fill the gaps
+pub fn load_migration_files(path: &Path) -> Result<Migrations> {
+    load_migration_files_with_encoding(path, Encoding::Utf8)
+}
+
+/// Same as `load_migration_files`, reading files that aren't valid UTF-8 with the given encoding
+pub fn load_migration_files_with_encoding(path: &Path, encoding: Encoding) -> Result<Migrations> {
+    load_files(path, &ReadOptions { encoding: encoding, ..ReadOptions::default() }, false)
+}
+
+/// Same as `read_migration_files_with_encoding` without keeping the content of the files in memory,
+/// so that folders with huge migrations, like seeds, can be listed. Files are still read once, line by
+/// line, for their annotations. `MigrationFile::read_content` reads the content when it is needed
+pub fn read_migration_files_lazily(path: &Path, encoding: Encoding) -> Result<Migrations> {
+    let migrations = load_files(path, &ReadOptions { encoding: encoding, ..ReadOptions::default() }, true)?;
+    check_sequence(baseline(path)?, migrations.keys())?;
+    Ok(migrations)
+}
+
+/// What `read_migration_files_with_options` accepts beyond `read_migration_files_lazily`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadOptions {
+    /// How files that aren't valid UTF-8 are decoded
+    pub encoding: Encoding,
+    /// Accept migrations without a down file, for projects which don't write them.
+    /// `Migration::file` fails when asked for their down file
+    pub optional_down: bool,
+    /// Accept gaps in the numbers, like when old migrations were squashed into a baseline and deleted
+    pub allow_gaps: bool,
+    /// Also load the migrations of the subdirectories, except the archive, so that the modules of an
+    /// application can keep their migrations next to their code. They share one sequence
+    pub recursive: bool,
+}
+
+/// Same as `read_migration_files_lazily`, with the checks relaxed as set in the options
+pub fn read_migration_files_with_options(path: &Path, options: &ReadOptions) -> Result<Migrations> {
+    read_migration_directories(&[path], options)
+}
+
+/// Same as `read_migration_files_with_options`, merging the migrations of several directories into
+/// one sequence. Each number can only be used in one of them
+pub fn read_migration_directories<P: AsRef<Path>>(paths: &[P], options: &ReadOptions) -> Result<Migrations> {
+    let mut migrations = BTreeMap::new();
+    let mut first = 1;
+    for path in paths {
+        merge(&mut migrations, load_files(path.as_ref(), options, true)?)?;
+        first = first.max(baseline(path.as_ref())?);
+    }
+    if !options.allow_gaps {
+        check_sequence(first, migrations.keys())?;
+    }
+    Ok(migrations)
+}
+
+// Adds the migrations of another directory, refusing numbers both have
+fn merge(migrations: &mut Migrations, other: Migrations) -> Result<()> {
+    for (number, migration) in other {
+        if let Some(existing) = migrations.get(&number) {
+            // Safe unwraps, loaded migrations always have an up file
+            bail!(ErrorKind::ParseError(format!(
+                "Migration {} is defined by both {:?} and {:?}",
+                number, existing.up.as_ref().unwrap().path, migration.up.as_ref().unwrap().path
+            )));
+        }
+        migrations.insert(number, migration);
+    }
+    Ok(())
+}
+
+fn load_files(path: &Path, options: &ReadOptions, lazily: bool) -> Result<Migrations> {
+    let encoding = options.encoding;
     let mut btreemap: Migrations = BTreeMap::new();
+    let mut subdirectories = vec![];
 
-    for entry in fs::read_dir(path).chain_err(|| format!("Failed to open {:?}", path))? {
-        let entry = entry.unwrap();
-        // Will panic on invalid unicode in filename, unlikely (heh)
-        let info = match parse_filename(entry.file_name().to_str().unwrap()) {
+    for entry in fs::read_dir(path).chain_err(|| file_error(path, "Failed to open"))? {
+        let entry = entry.chain_err(|| file_error(path, "Failed to read"))?;
+        let file_name = entry.file_name();
+        if options.recursive && entry.path().is_dir() {
+            if file_name != ARCHIVE_DIRECTORY {
+                subdirectories.push(entry.path());
+            }
+            continue;
+        }
+        let filename = match file_name.to_str() {
+            Some(filename) => filename,
+            None => {
+                // Unrelated files are ignored whatever their name, but not broken migrations
+                let lossy = file_name.to_string_lossy();
+                if looks_like_migration(&lossy) {
+                    bail!(ErrorKind::ParseError(format!("Migration filename {:?} isn't valid UTF-8", lossy)));
+                }
+                continue;
+            }
+        };
+        let info = match parse_filename(filename) {
             Ok(info) => info,
             Err(_) => continue,
         };
-        let mut file = File::open(entry.path())
-            .chain_err(|| format!("Failed to open {:?}", entry.path()))?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)?;
-
-        let migration_file = MigrationFile { content: Some(content), ..info };
+        let migration_file = if info.kind == MigrationKind::Script {
+            if !is_executable(&entry.path())? {
+                bail!(ErrorKind::FileError(entry.path(), format!("Script migration {:?} is not executable", entry.path())));
+            }
+            MigrationFile { path: entry.path(), encoding: encoding, ..info }
+        } else if lazily {
+            let annotations = if info.kind == MigrationKind::Sql {
+                read_annotations(&entry.path(), encoding)
+                    .chain_err(|| ErrorKind::ParseError(format!("Invalid annotation in {:?}", entry.path())))?
+            } else {
+                Annotations::default()
+            };
+            MigrationFile { path: entry.path(), encoding: encoding, annotations: annotations, ..info }
+        } else {
+            let content = read_file(&entry.path(), encoding)?;
+            let annotations = if info.kind == MigrationKind::Sql {
+                Annotations::parse(&content)
+                    .chain_err(|| ErrorKind::ParseError(format!("Invalid annotation in {:?}", entry.path())))?
+            } else {
+                Annotations::default()
+            };
+            MigrationFile { content: Some(content), path: entry.path(), encoding: encoding, annotations: annotations, ..info }
+        };
         let migration_number = migration_file.number;
         let mut migration = match btreemap.remove(&migration_number) {
             None => Migration { up: None, down: None },
@@ -120,29 +408,169 @@ pub fn read_migration_files(path: &Path) -> Result<Migrations> {
         btreemap.insert(migration_number, migration);
     }
 
-    // Let's check the all the files we need now
-    let mut index = 1;
+    // Let's check the all the files we need now, irreversible migrations not needing a down file
     for (number, migration) in &btreemap {
+        let irreversible = migration.up.as_ref().map_or(false, |up| up.annotations.irreversible);
+        if migration.up.is_none() || (migration.down.is_none() && !irreversible && !options.optional_down) {
+            bail!(ErrorKind::FileError(path.to_owned(), format!("Migration {} is missing its up or down file", number)));
+        }
+    }
+    // Only merged once complete, a number used in a subdirectory and here is refused instead of paired
+    for subdirectory in subdirectories {
+        merge(&mut btreemap, load_files(&subdirectory, options, lazily)?)?;
+    }
+    Ok(btreemap)
+}
+
+// Large seed migrations can be shipped gzipped
+fn open_file(path: &Path) -> Result<Box<Read>> {
+    let file = File::open(path).chain_err(|| file_error(path, "Failed to open"))?;
+    if path.extension().map_or(false, |e| e == "gz") {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+fn read_file(path: &Path, encoding: Encoding) -> Result<String> {
+    let mut bytes = Vec::new();
+    open_file(path)?.read_to_end(&mut bytes).chain_err(|| file_error(path, "Failed to read"))?;
+    decode(bytes, encoding).chain_err(|| file_error(path, "Failed to decode"))
+}
+
+// The lines of the file with their line endings, read one at a time. UTF-16 files are read at once as
+// they can't be split on bytes
+fn read_lines(path: &Path, encoding: Encoding) -> Result<Box<Iterator<Item = Result<String>>>> {
+    let mut reader = BufReader::new(open_file(path)?);
+    let utf16 = {
+        let start = reader.fill_buf().chain_err(|| file_error(path, "Failed to read"))?;
+        start.starts_with(&[0xFF, 0xFE]) || start.starts_with(&[0xFE, 0xFF])
+    };
+    if utf16 {
+        let content = read_file(path, encoding)?;
+        let lines = content.split_inclusive('\n').map(|line| Ok(line.to_owned())).collect::<Vec<_>>();
+        return Ok(Box::new(lines.into_iter()));
+    }
+
+    let path = path.to_owned();
+    let mut line = Vec::new();
+    let mut failed = false;
+    Ok(Box::new(::std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        line.clear();
+        let res = match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(_) => decode(line.clone(), encoding).chain_err(|| file_error(&path, "Failed to decode")),
+            Err(e) => Err(e).chain_err(|| file_error(&path, "Failed to read")),
+        };
+        failed = res.is_err();
+        Some(res)
+    })))
+}
+
+// Only keeps the annotation lines in memory
+fn read_annotations(path: &Path, encoding: Encoding) -> Result<Annotations> {
+    let mut annotations = String::new();
+    for line in read_lines(path, encoding)? {
+        let line = line?;
+        if line.trim().starts_with(PREFIX) {
+            annotations.push_str(line.trim_right());
+            annotations.push('\n');
+        }
+    }
+    Annotations::parse(&annotations)
+}
+
+fn file_error(path: &Path, action: &str) -> ErrorKind {
+    ErrorKind::FileError(path.to_owned(), format!("{} {:?}", action, path))
+}
+
+// Whether the filename starts like a migration one, a 4 digits number and a dot
+fn looks_like_migration(filename: &str) -> bool {
+    let bytes = filename.as_bytes();
+    bytes.len() > 5 && bytes[..4].iter().all(|b| b.is_ascii_digit()) && bytes[4] == b'.'
+}
+
+/// The first migration of the directory, the ones before it having been moved to its archive
+/// subdirectory by `archive_migrations`. 1 when nothing was archived
+pub fn baseline(path: &Path) -> Result<i32> {
+    let archive = path.join(ARCHIVE_DIRECTORY);
+    if !archive.is_dir() {
+        return Ok(1);
+    }
+    let mut last = 0;
+    for entry in fs::read_dir(&archive).chain_err(|| file_error(&archive, "Failed to open"))? {
+        let entry = entry.chain_err(|| file_error(&archive, "Failed to read"))?;
+        if let Some(info) = entry.file_name().to_str().and_then(|filename| parse_filename(filename).ok()) {
+            last = last.max(info.number);
+        }
+    }
+    Ok(last + 1)
+}
+
+/// Moves the files of the migrations before `baseline` to the archive subdirectory, returning how many
+/// migrations were archived. The baseline usually replaces them, like a squash of the archived ones
+pub fn archive_migrations(path: &Path, migrations: &Migrations, baseline: i32) -> Result<usize> {
+    if !migrations.contains_key(&baseline) {
+        bail!(ErrorKind::ParseError(format!("Migration {} doesn't exist, it can't be the baseline", baseline)));
+    }
+    let archive = path.join(ARCHIVE_DIRECTORY);
+    fs::create_dir_all(&archive).chain_err(|| file_error(&archive, "Failed to create"))?;
+
+    let mut count = 0;
+    for migration in migrations.range(..baseline).map(|(_, m)| m) {
+        for file in migration.up.iter().chain(migration.down.iter()) {
+            let target = archive.join(&file.filename);
+            fs::rename(&file.path, &target).chain_err(|| file_error(&file.path, "Failed to archive"))?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Checks that migration numbers start at `first`, 1 unless old migrations were archived, and don't have any gap
+pub fn check_sequence<'a, I: Iterator<Item = &'a i32>>(first: i32, numbers: I) -> Result<()> {
+    let mut index = first;
+    for number in numbers {
         if index != *number {
             bail!("Files for migration {} are missing", index);
         }
-        if migration.up.is_none() || migration.down.is_none() {
-            bail!("Migration {} is missing its up or down file", index);
-        }
         index += 1;
     }
-    Ok(btreemap)
+    Ok(())
 }
 
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = fs::metadata(path).chain_err(|| file_error(path, "Failed to open"))?;
+    Ok(metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> Result<bool> {
+    Ok(true)
+}
+
+// Extensions of the scripts run as migrations, besides files without extension
+const SCRIPT_EXTENSIONS: [&'static str; 5] = ["sh", "bash", "py", "rb", "pl"];
+
 /// Gets a filename and check whether it's a valid format.
-/// If it is, grabs all the info from it
+/// If it is, grabs all the info from it.
+/// Files can optionally be gzipped, in which case they end with `.sql.gz`.
+/// `.load` files are manifests describing a CSV file to load, `.json` ones MongoDB commands and
+/// `.cypher` ones Neo4j statements.
+/// Files without extension or with the extension of a scripting language (eg `.sh`) are script migrations,
+/// other extensions like `.bak` being rejected so that stray copies are left alone.
 fn parse_filename(filename: &str) -> Result<MigrationFile> {
     let re = Regex::new(
-        r"^(?P<number>[0-9]{4})\.(?P<name>[_0-9a-zA-Z]*)\.(?P<direction>up|down)\.sql$"
+        r"^(?P<number>[0-9]{4})\.(?P<name>[_0-9a-zA-Z]*)\.(?P<direction>up|down)(\.(?P<extension>sql(\.gz)?|[0-9a-zA-Z]+))?$"
     ).unwrap();
 
     let caps = match re.captures(filename) {
-        None => bail!("File {} has an invalid filename", filename),
+        None => bail!(ErrorKind::ParseError(format!("File {} has an invalid filename", filename))),
         Some(c) => c
     };
 
@@ -155,16 +583,37 @@ fn parse_filename(filename: &str) -> Result<MigrationFile> {
         Direction::Down
     };
 
-    Ok(MigrationFile::new(filename, name, number, direction))
+    let kind = match caps.name("extension").map(|e| e.as_str()) {
+        Some("sql") | Some("sql.gz") => MigrationKind::Sql,
+        Some("load") => MigrationKind::Csv,
+        Some("json") => MigrationKind::Json,
+        Some("cypher") => MigrationKind::Cypher,
+        Some(extension) if !SCRIPT_EXTENSIONS.contains(&extension) => {
+            bail!(ErrorKind::ParseError(format!("File {} has an unknown extension", filename)))
+        },
+        _ => MigrationKind::Script,
+    };
+
+    Ok(MigrationFile::new(filename, name, number, direction, kind))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_filename, read_migration_files, Direction, get_filename};
+    use super::{
+        parse_filename, read_migration_files, read_migration_files_with_encoding, read_migration_files_lazily,
+        read_migration_files_with_options, read_migration_directories, ReadOptions, archive_migrations, baseline,
+        description, Direction, MigrationKind,
+        get_filename, create_migration, HEADER_PREFIX,
+    };
+    use checksum::checksum;
+    use encoding::Encoding;
     use tempdir::TempDir;
     use std::path::{PathBuf};
     use std::io::prelude::*;
+    use std::fs;
     use std::fs::File;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
 
     fn create_file(path: &PathBuf, filename: &str) {
         let mut new_path = path.clone();
@@ -179,6 +628,36 @@ mod tests {
         assert_eq!(result.number, 1);
         assert_eq!(result.name, "tests");
         assert_eq!(result.direction, Direction::Up);
+        assert_eq!(result.kind, MigrationKind::Sql);
+    }
+
+    #[test]
+    fn test_parse_gzipped_filename() {
+        let result = parse_filename("0001.tests.up.sql.gz").unwrap();
+        assert_eq!(result.number, 1);
+        assert_eq!(result.name, "tests");
+        assert_eq!(result.direction, Direction::Up);
+    }
+
+    #[test]
+    fn test_parse_json_filename() {
+        let result = parse_filename("0001.users_indexes.up.json").unwrap();
+        assert_eq!(result.name, "users_indexes");
+        assert_eq!(result.kind, MigrationKind::Json);
+        assert_eq!(parse_filename("0002.people.down.cypher").unwrap().kind, MigrationKind::Cypher);
+    }
+
+    #[test]
+    fn test_parse_script_filename() {
+        let result = parse_filename("0001.tests.up.sh").unwrap();
+        assert_eq!(result.number, 1);
+        assert_eq!(result.kind, MigrationKind::Script);
+        let result = parse_filename("0001.tests.down").unwrap();
+        assert_eq!(result.direction, Direction::Down);
+        assert_eq!(result.kind, MigrationKind::Script);
+        assert_eq!(parse_filename("0002.seed.up.py").unwrap().kind, MigrationKind::Script);
+        assert!(parse_filename("0001.tests.up.bak").is_err());
+        assert!(parse_filename("0001.tests.up.txt").is_err());
     }
 
     #[test]
@@ -206,6 +685,92 @@ mod tests {
         assert_eq!(migrations.is_ok(), true);
     }
 
+    #[test]
+    fn test_read_gzipped_migration() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        let gz_file = File::create(pathbuf.join("0001.tests.up.sql.gz")).unwrap();
+        let mut encoder = GzEncoder::new(gz_file, Compression::default());
+        encoder.write_all(b"INSERT INTO seeds VALUES (1);").unwrap();
+        encoder.finish().unwrap();
+        create_file(&pathbuf, "0001.tests.down.sql");
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+
+        let up = migrations.get(&1).unwrap().up.as_ref().unwrap();
+        assert_eq!(up.content, Some("INSERT INTO seeds VALUES (1);".to_owned()));
+    }
+
+    #[test]
+    fn test_read_migrations_lazily() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        let gz_file = File::create(pathbuf.join("0001.seeds.up.sql.gz")).unwrap();
+        let mut encoder = GzEncoder::new(gz_file, Compression::default());
+        encoder.write_all(b"-- Seeds\n-- dbmigrate:tags seeds\nINSERT INTO seeds VALUES ('caf\xE9');").unwrap();
+        encoder.finish().unwrap();
+        create_file(&pathbuf, "0001.seeds.down.sql");
+
+        assert!(read_migration_files_lazily(pathbuf.as_path(), Encoding::Utf8).is_err());
+        let eager = read_migration_files_with_encoding(pathbuf.as_path(), Encoding::Latin1).unwrap();
+        let lazy = read_migration_files_lazily(pathbuf.as_path(), Encoding::Latin1).unwrap();
+        let (eager, lazy) = (eager[&1].up.as_ref().unwrap(), lazy[&1].up.as_ref().unwrap());
+        assert_eq!(lazy.content, None);
+        assert_eq!(lazy.annotations, eager.annotations);
+        assert_eq!(lazy.read_content().unwrap(), "-- Seeds\n-- dbmigrate:tags seeds\nINSERT INTO seeds VALUES ('café');");
+        assert_eq!(lazy.checksum(false), eager.checksum(false));
+        assert_eq!(lazy.description(), Some("Seeds".to_owned()));
+    }
+
+    #[test]
+    fn test_read_latin1_migration() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        File::create(pathbuf.join("0001.tests.up.sql")).unwrap().write_all(b"SELECT 'caf\xE9';").unwrap();
+        create_file(&pathbuf, "0001.tests.down.sql");
+
+        let error = read_migration_files(pathbuf.as_path()).unwrap_err();
+        assert!(format!("{}", error).contains("0001.tests.up.sql"));
+        let migrations = read_migration_files_with_encoding(pathbuf.as_path(), Encoding::Latin1).unwrap();
+        let up = migrations.get(&1).unwrap().up.as_ref().unwrap();
+        assert_eq!(up.content, Some("SELECT 'café';".to_owned()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_script_migration() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sh");
+        create_file(&pathbuf, "0001.tests.down.sql");
+        // Left alone, like any file that isn't a migration
+        create_file(&pathbuf, "0001.tests.down.sql.orig");
+        create_file(&pathbuf, "0001.tests.up.bak");
+        // Scripts need to be executable
+        assert_eq!(read_migration_files(pathbuf.as_path()).is_err(), true);
+
+        fs::set_permissions(pathbuf.join("0001.tests.up.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        let up = migrations.get(&1).unwrap().up.as_ref().unwrap();
+        assert_eq!(up.kind, MigrationKind::Script);
+        assert_eq!(up.content, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_filenames() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql");
+        create_file(&pathbuf, "0001.tests.down.sql");
+        File::create(pathbuf.join(OsStr::from_bytes(b"notes\xFF.txt"))).unwrap();
+        assert_eq!(read_migration_files(pathbuf.as_path()).unwrap().len(), 1);
+
+        File::create(pathbuf.join(OsStr::from_bytes(b"0002.caf\xE9.up.sql"))).unwrap();
+        let error = read_migration_files(pathbuf.as_path()).unwrap_err();
+        assert!(format!("{}", error).contains("0002.caf"));
+    }
+
     #[test]
     fn test_parse_missing_migrations_directory() {
         let pathbuf = TempDir::new("migrations").unwrap().into_path();
@@ -217,6 +782,33 @@ mod tests {
         assert_eq!(migrations.is_err(), true);
     }
 
+    #[test]
+    fn test_irreversible_migrations() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        File::create(pathbuf.join("0001.purge.up.sql")).unwrap()
+            .write_all(b"-- dbmigrate:irreversible\nDELETE FROM sessions;").unwrap();
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+
+        let migration = migrations.get(&1).unwrap();
+        assert!(migration.down.is_none());
+        assert_eq!(migration.file(Direction::Up).unwrap().name, "purge");
+        assert_eq!(migration.file(Direction::Down).unwrap_err().to_string(), "Migration 1 (purge) is irreversible");
+    }
+
+    #[test]
+    fn test_optional_down_files() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql");
+        assert!(read_migration_files(pathbuf.as_path()).is_err());
+
+        let options = ReadOptions { optional_down: true, ..ReadOptions::default() };
+        let migrations = read_migration_files_with_options(pathbuf.as_path(), &options).unwrap();
+        let error = migrations.get(&1).unwrap().file(Direction::Down).unwrap_err();
+        assert_eq!(error.to_string(), "Migration 1 (tests) has no down file");
+        create_file(&pathbuf, "0002.tests.down.sql");
+        assert!(read_migration_files_with_options(pathbuf.as_path(), &options).is_err());
+    }
+
     #[test]
     fn test_parse_skipping_migrations_directory() {
         let pathbuf = TempDir::new("migrations").unwrap().into_path();
@@ -227,5 +819,81 @@ mod tests {
         let migrations = read_migration_files(pathbuf.as_path());
 
         assert_eq!(migrations.is_err(), true);
+        let options = ReadOptions { allow_gaps: true, ..ReadOptions::default() };
+        let migrations = read_migration_files_with_options(pathbuf.as_path(), &options).unwrap();
+        assert_eq!(migrations.keys().cloned().collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_read_migration_directories() {
+        let accounts = TempDir::new("accounts").unwrap().into_path();
+        let billing = TempDir::new("billing").unwrap().into_path();
+        create_file(&accounts, "0001.users.up.sql");
+        create_file(&accounts, "0001.users.down.sql");
+        create_file(&billing, "0002.invoices.up.sql");
+        create_file(&billing, "0002.invoices.down.sql");
+        let options = ReadOptions::default();
+        let migrations = read_migration_directories(&[&accounts, &billing], &options).unwrap();
+        assert_eq!(migrations.keys().cloned().collect::<Vec<_>>(), vec![1, 2]);
+
+        create_file(&accounts, "0002.sessions.up.sql");
+        create_file(&accounts, "0002.sessions.down.sql");
+        assert!(read_migration_directories(&[&accounts, &billing], &options).is_err());
+    }
+
+    #[test]
+    fn test_read_migrations_recursively() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.users.up.sql");
+        create_file(&pathbuf, "0001.users.down.sql");
+        fs::create_dir(pathbuf.join("billing")).unwrap();
+        create_file(&pathbuf.join("billing"), "0002.invoices.up.sql");
+        create_file(&pathbuf.join("billing"), "0002.invoices.down.sql");
+        fs::create_dir(pathbuf.join("archive")).unwrap();
+        assert!(read_migration_files(pathbuf.as_path()).is_ok());
+
+        let options = ReadOptions { recursive: true, ..ReadOptions::default() };
+        let migrations = read_migration_files_with_options(pathbuf.as_path(), &options).unwrap();
+        assert_eq!(migrations.keys().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        create_file(&pathbuf.join("billing"), "0001.users.up.sql");
+        create_file(&pathbuf.join("billing"), "0001.users.down.sql");
+        assert!(read_migration_files_with_options(pathbuf.as_path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_archive_migrations() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        for filename in &["0001.first", "0002.second", "0003.baseline"] {
+            create_file(&pathbuf, &format!("{}.up.sql", filename));
+            create_file(&pathbuf, &format!("{}.down.sql", filename));
+        }
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        assert!(archive_migrations(pathbuf.as_path(), &migrations, 4).is_err());
+
+        assert_eq!(archive_migrations(pathbuf.as_path(), &migrations, 3).unwrap(), 2);
+        assert!(pathbuf.join("archive/0002.second.down.sql").exists());
+        assert_eq!(baseline(pathbuf.as_path()).unwrap(), 3);
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        assert_eq!(migrations.keys().cloned().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn test_created_files_have_a_header() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_migration(&pathbuf, "add users", 1).unwrap();
+        let migrations = read_migration_files(&pathbuf).unwrap();
+        let up = migrations.get(&1).unwrap().up.as_ref().unwrap();
+        let content = up.read_content().unwrap();
+        assert!(content.starts_with(&format!("{}Migration: 0001.add_users.up.sql\n", HEADER_PREFIX)));
+        assert!(content.contains(&format!("{}dbmigrate: ", HEADER_PREFIX)));
+        assert_eq!(up.description(), None);
+        assert_eq!(up.checksum(false), Some(checksum("", false)));
+    }
+
+    #[test]
+    fn test_description() {
+        let content = "-- dbmigrate:timeout 10m\n-- Adds the users table,\n-- replacing accounts\n\nCREATE TABLE users();\n-- Ignored\n";
+        assert_eq!(description(content), Some("Adds the users table, replacing accounts".to_owned()));
+        assert_eq!(description("CREATE TABLE users();"), None);
     }
 }
@@ -0,0 +1,536 @@
+///! Append-only history of the migrations applied and reverted on a database
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use annotations::is_true;
+use csv_load::quote;
+use drivers::Driver;
+use errors::{Result, ResultExt};
+use files::{Direction, Migrations};
+
+/// Name of the table storing the history, the current number staying in `__dbmigrate_table`
+pub const HISTORY_TABLE: &'static str = "__dbmigrate_history";
+
+/// A migration applied or reverted at some point
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Number of the migration
+    pub number: i32,
+    /// Name of the migration
+    pub name: String,
+    /// Whether the migration was applied or reverted
+    pub direction: Direction,
+    /// UTC time, formatted as `YYYY-MM-DD HH:MM:SS`
+    pub applied_at: String,
+    /// OS user who ran dbmigrate, if known
+    pub applied_by: Option<String>,
+    /// Host on which dbmigrate ran, if known
+    pub hostname: Option<String>,
+    /// Commit of the git repository holding the migrations, when they are in one
+    pub git_commit: Option<String>,
+    /// Checksum of the file, `None` for scripts and code migrations
+    pub checksum: Option<String>,
+    /// Dump of the schema once the migration ran, stored compressed when schema snapshots are enabled.
+    /// It isn't read back with the rest of the history, see `schema_snapshot`
+    pub schema_snapshot: Option<String>,
+    /// Environment in which the migration was skipped instead of being run, as it is restricted
+    /// to other environments
+    pub skipped_in: Option<String>,
+    /// How long it took to run, unknown for the entries recorded before durations were
+    pub duration: Option<Duration>,
+    /// Whether it was run out of sequence by `Migrator::apply_out_of_order`, `up` and `down` then
+    /// skipping it once they reach it
+    pub out_of_order: bool,
+}
+
+impl HistoryEntry {
+    /// An entry for a migration being run now by the current user on this host, without checksum
+    /// nor commit
+    pub fn new(number: i32, name: &str, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            number: number,
+            name: name.to_owned(),
+            direction: direction,
+            applied_at: utc_now(),
+            applied_by: current_user(),
+            hostname: current_hostname(),
+            git_commit: None,
+            checksum: None,
+            schema_snapshot: None,
+            skipped_in: None,
+            duration: None,
+            out_of_order: false,
+        }
+    }
+
+    /// The duration in milliseconds, as stored
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration.map(|d| d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000)
+    }
+}
+
+// Columns added after the first version of the table, which older tables lack
+const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+    ("schema_snapshot", "TEXT"),
+    ("skipped_in", "VARCHAR(255)"),
+    ("duration_ms", "INTEGER"),
+    ("hostname", "VARCHAR(255)"),
+    ("git_commit", "VARCHAR(40)"),
+    ("out_of_order", "INTEGER"),
+];
+
+/// Creates the history table, or adds the columns an older one lacks. Run once when connecting
+/// rather than by every function below
+pub fn ensure_table<D: Driver + ?Sized>(driver: &D, table: &str) -> Result<()> {
+    driver.execute(&format!("
+        CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER NOT NULL PRIMARY KEY,
+            number INTEGER NOT NULL,
+            name VARCHAR(255) NOT NULL,
+            direction VARCHAR(4) NOT NULL,
+            applied_at VARCHAR(19) NOT NULL,
+            applied_by VARCHAR(255),
+            checksum VARCHAR(8),
+            schema_snapshot TEXT,
+            skipped_in VARCHAR(255),
+            duration_ms INTEGER,
+            hostname VARCHAR(255),
+            git_commit VARCHAR(40),
+            out_of_order INTEGER
+        );
+    ", table)).chain_err(|| "Failed to create the history table")?;
+
+    for &(column, column_type) in ADDED_COLUMNS {
+        if driver.query(&format!("SELECT {} FROM {} WHERE 1 = 0;", column, table)).is_err() {
+            driver.execute(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, column_type))
+                .chain_err(|| format!("Failed to add the {} column to the history table", column))?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends an entry to the history table
+pub fn insert_entry<D: Driver + ?Sized>(driver: &D, table: &str, entry: &HistoryEntry) -> Result<()> {
+    let snapshot = match entry.schema_snapshot {
+        Some(ref dump) => Some(compress(dump)?),
+        None => None,
+    };
+    // Computing the id in SQL avoids relying on each database's auto increment syntax
+    driver.execute(&format!(
+        "INSERT INTO {table} (id, number, name, direction, applied_at, applied_by, checksum, schema_snapshot, skipped_in,
+            duration_ms, hostname, git_commit, out_of_order)
+        SELECT COALESCE(MAX(id), 0) + 1, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {} FROM {table};",
+        entry.number, quote(&entry.name), quote(&entry.direction.to_string()), quote(&entry.applied_at),
+        quote_optional(&entry.applied_by), quote_optional(&entry.checksum), quote_optional(&snapshot),
+        quote_optional(&entry.skipped_in), entry.duration_ms().map_or("NULL".to_owned(), |ms| ms.to_string()),
+        quote_optional(&entry.hostname), quote_optional(&entry.git_commit), if entry.out_of_order { 1 } else { 0 },
+        table = table
+    )).chain_err(|| format!("Failed to record migration {} in the history", entry.number))
+}
+
+fn quote_optional(value: &Option<String>) -> String {
+    value.as_ref().map_or("NULL".to_owned(), |v| quote(v))
+}
+
+/// Reads the whole history table, oldest entry first
+pub fn select_entries<D: Driver + ?Sized>(driver: &D, table: &str) -> Result<Vec<HistoryEntry>> {
+    let rows = driver.query(&format!(
+        "SELECT number, name, direction, applied_at, applied_by, checksum, skipped_in, duration_ms, hostname, git_commit,
+            out_of_order
+        FROM {} ORDER BY id;",
+        table
+    ))?;
+
+    rows.into_iter().map(|row| {
+        let mut values = row.into_iter();
+        let mut next = || values.next().and_then(|v| v);
+        let number = next().unwrap_or_default();
+        Ok(HistoryEntry {
+            number: number.parse().chain_err(|| format!("Invalid history number: {}", number))?,
+            name: next().unwrap_or_default(),
+            direction: if next().map_or(false, |d| d == "down") { Direction::Down } else { Direction::Up },
+            applied_at: next().unwrap_or_default(),
+            applied_by: next(),
+            checksum: next(),
+            schema_snapshot: None,
+            skipped_in: next(),
+            duration: next().and_then(|ms| ms.parse().ok()).map(Duration::from_millis),
+            hostname: next(),
+            git_commit: next(),
+            out_of_order: is_true(next()),
+        })
+    }).collect()
+}
+
+/// The schema snapshot taken the last time the migration was applied, if there is one
+pub fn select_schema_snapshot<D: Driver + ?Sized>(driver: &D, table: &str, number: i32) -> Result<Option<String>> {
+    let rows = driver.query(&format!(
+        "SELECT schema_snapshot FROM {} WHERE number = {} AND direction = 'up' AND schema_snapshot IS NOT NULL
+        ORDER BY id DESC;",
+        table, number
+    ))?;
+    match rows.into_iter().next().and_then(|row| row.into_iter().next()).and_then(|v| v) {
+        Some(snapshot) => decompress(&snapshot).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Removes every history entry of the given migration
+pub fn delete_entries<D: Driver + ?Sized>(driver: &D, table: &str, number: i32) -> Result<()> {
+    driver.execute(&format!("DELETE FROM {} WHERE number = {};", table, number))
+        .chain_err(|| format!("Failed to remove migration {} from the history", number))
+}
+
+/// Replaces the checksum recorded when the given migration was applied
+pub fn update_checksum<D: Driver + ?Sized>(driver: &D, table: &str, number: i32, checksum: &str) -> Result<()> {
+    driver.execute(&format!(
+        "UPDATE {} SET checksum = {} WHERE number = {} AND direction = 'up';", table, quote(checksum), number
+    )).chain_err(|| format!("Failed to update the checksum of migration {}", number))
+}
+
+/// The migrations currently applied according to the history: the ones whose last entry is an up
+pub fn applied(history: &[HistoryEntry]) -> BTreeMap<i32, &HistoryEntry> {
+    let mut applied = BTreeMap::new();
+    for entry in history {
+        if entry.direction == Direction::Up {
+            applied.insert(entry.number, entry);
+        } else {
+            applied.remove(&entry.number);
+        }
+    }
+    applied
+}
+
+/// The last up entry of each migration currently applied according to the history
+pub fn applied_migrations(driver: &Driver) -> Result<BTreeMap<i32, HistoryEntry>> {
+    let history = driver.history()?;
+    let applied = applied(&history).into_iter().map(|(number, entry)| (number, entry.clone())).collect();
+    Ok(applied)
+}
+
+/// Whether the expand migration of a contract migration is applied and settled: applied for at least
+/// `min_age`, and followed by at least `min_deploys` deploys, counted as the distinct git commits of the
+/// migrations applied after it
+pub fn is_settled(history: &[HistoryEntry], expand: i32, min_age: Option<Duration>, min_deploys: usize) -> bool {
+    let entry = match applied(history).get(&expand) {
+        Some(entry) => *entry,
+        None => return false,
+    };
+    // Timestamps compare in the same order as strings
+    if min_age.map_or(false, |age| entry.applied_at > utc_ago(age)) {
+        return false;
+    }
+    let later = history.iter().rev().take_while(|e| !ptr::eq(*e, entry));
+    let deploys = later.filter(|e| e.direction == Direction::Up)
+        .filter_map(|e| e.git_commit.as_ref())
+        .filter(|commit| Some(*commit) != entry.git_commit.as_ref())
+        .collect::<BTreeSet<_>>();
+    deploys.len() >= min_deploys
+}
+
+/// Applied migrations whose files no longer exist, or were renamed, in the given migrations. The ones
+/// before the baseline, archived or squashed into it, aren't expected to have files
+pub fn find_orphans(driver: &Driver, migrations: &Migrations, baseline: i32) -> Result<Vec<HistoryEntry>> {
+    let history = driver.history()?;
+    let orphans = applied(&history).into_iter()
+        .filter(|&(number, _)| number >= baseline)
+        .filter(|&(number, entry)| {
+            match migrations.get(&number).and_then(|m| m.up.as_ref()) {
+                Some(file) => file.name != entry.name,
+                None => true,
+            }
+        })
+        .map(|(_, entry)| entry.clone())
+        .collect();
+    Ok(orphans)
+}
+
+/// Applied migrations whose file changed since, comparing checksums computed the same way
+/// (`strict` or not) as when they were recorded
+pub fn find_modified(driver: &Driver, migrations: &Migrations, strict: bool) -> Result<Vec<HistoryEntry>> {
+    let history = driver.history()?;
+    let modified = applied(&history).into_iter()
+        .filter(|&(number, entry)| {
+            match (migrations.get(&number).and_then(|m| m.up.as_ref()), entry.checksum.as_ref()) {
+                (Some(file), Some(checksum)) => file.name == entry.name && file.checksum(strict).as_ref() != Some(checksum),
+                _ => false,
+            }
+        })
+        .map(|(_, entry)| entry.clone())
+        .collect();
+    Ok(modified)
+}
+
+/// Records the checksums of the current files of the given modified migrations, accepting their changes
+pub fn accept_modified(
+    driver: &Driver, migrations: &Migrations, modified: &[HistoryEntry], strict: bool
+) -> Result<()> {
+    for entry in modified {
+        let checksum = migrations.get(&entry.number).and_then(|m| m.up.as_ref()).and_then(|f| f.checksum(strict));
+        if let Some(checksum) = checksum {
+            driver.update_checksum(entry.number, &checksum)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the history of the given orphans, so the history matches the migration files again
+pub fn prune_orphans(driver: &Driver, orphans: &[HistoryEntry]) -> Result<()> {
+    for orphan in orphans {
+        driver.remove_history(orphan.number)?;
+    }
+    Ok(())
+}
+
+// Schema dumps are gzipped and hex encoded to fit in a text column on every database
+fn compress(dump: &str) -> Result<String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(dump.as_bytes())?;
+    let bytes = encoder.finish()?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn decompress(snapshot: &str) -> Result<String> {
+    if snapshot.len() % 2 != 0 {
+        bail!("Invalid schema snapshot");
+    }
+    let bytes = (0..snapshot.len()).step_by(2)
+        .map(|i| u8::from_str_radix(snapshot.get(i..i + 2).unwrap_or(""), 16))
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .chain_err(|| "Invalid schema snapshot")?;
+    let mut dump = String::new();
+    GzDecoder::new(&bytes[..]).read_to_string(&mut dump).chain_err(|| "Invalid schema snapshot")?;
+    Ok(dump)
+}
+
+/// The OS user, as far as the environment tells
+pub fn current_user() -> Option<String> {
+    env::var("USER").or_else(|_| env::var("USERNAME")).ok()
+}
+
+/// The name of this host, from the environment or the `hostname` command
+pub fn current_hostname() -> Option<String> {
+    env::var("HOSTNAME").or_else(|_| env::var("COMPUTERNAME")).ok().or_else(|| {
+        Command::new("hostname").output().ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            .filter(|hostname| !hostname.is_empty())
+    })
+}
+
+/// The commit checked out in the git repository holding the given folder, if it is in one
+pub fn git_commit(path: &Path) -> Option<String> {
+    Command::new("git").arg("-C").arg(path).args(&["rev-parse", "HEAD"]).output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .filter(|commit| !commit.is_empty())
+}
+
+/// The current UTC time, as stored in the history
+pub fn utc_now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format_timestamp(secs)
+}
+
+/// The UTC time the given duration ago, formatted like `utc_now`
+pub fn utc_ago(age: Duration) -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format_timestamp(secs.saturating_sub(age.as_secs()))
+}
+
+// Formats seconds since the epoch without pulling a date crate, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    use super::{
+        accept_modified, applied, compress, decompress, ensure_table, find_modified, find_orphans, format_timestamp,
+        insert_entry, is_settled, prune_orphans, select_entries, utc_now, HistoryEntry, HISTORY_TABLE,
+    };
+    use checksum::checksum;
+    use drivers::Driver;
+    use drivers::memory::MemoryDriver;
+    use files::{baseline, load_migration_files, Direction, ARCHIVE_DIRECTORY};
+
+    fn entry(number: i32, name: &str, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            number: number,
+            name: name.to_owned(),
+            direction: direction,
+            applied_at: String::new(),
+            applied_by: None,
+            hostname: None,
+            git_commit: None,
+            checksum: None,
+            schema_snapshot: None,
+            skipped_in: None,
+            duration: None,
+            out_of_order: false,
+        }
+    }
+
+    #[test]
+    fn test_schema_snapshot_roundtrip() {
+        let dump = "column public.users id integer NO NULL\ncolumn public.users email text YES NULL";
+        let snapshot = compress(dump).unwrap();
+        assert!(snapshot.chars().all(|c| c.is_digit(16)));
+        assert_eq!(decompress(&snapshot).unwrap(), dump);
+        assert!(decompress("zz").is_err());
+    }
+
+    #[test]
+    fn test_table_is_only_changed_when_ensured() {
+        let driver = MemoryDriver::new();
+        let changes = |driver: &MemoryDriver| {
+            driver.executed().into_iter().filter(|sql| !sql.starts_with("SELECT")).count()
+        };
+        ensure_table(&driver, HISTORY_TABLE).unwrap();
+        let created = changes(&driver);
+        assert!(created > 0);
+        insert_entry(&driver, HISTORY_TABLE, &entry(1, "users", Direction::Up)).unwrap();
+        select_entries(&driver, HISTORY_TABLE).unwrap();
+        assert_eq!(changes(&driver), created + 1);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951782400 + 3661), "2000-02-29 01:01:01");
+        assert_eq!(format_timestamp(1791676800), "2026-10-11 00:00:00");
+    }
+
+    #[test]
+    fn test_reverted_migrations_are_not_applied() {
+        let driver = MemoryDriver::new();
+        driver.record_history(&entry(1, "first", Direction::Up)).unwrap();
+        driver.record_history(&entry(2, "second", Direction::Up)).unwrap();
+        driver.record_history(&entry(2, "second", Direction::Down)).unwrap();
+
+        let history = driver.history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(applied(&history).keys().cloned().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_is_settled() {
+        let commit = |number: i32, commit: &str| {
+            let mut entry = entry(number, "migration", Direction::Up);
+            entry.applied_at = "2024-01-01 00:00:00".to_owned();
+            entry.git_commit = Some(commit.to_owned());
+            entry
+        };
+        let history = vec![commit(1, "a"), commit(2, "a"), commit(3, "b"), commit(4, "c")];
+        assert!(is_settled(&history, 2, Some(Duration::from_secs(86400)), 2));
+        assert!(!is_settled(&history, 2, None, 3));
+        assert!(!is_settled(&history, 5, None, 0));
+
+        let mut recent = commit(5, "d");
+        recent.applied_at = utc_now();
+        assert!(!is_settled(&[recent], 5, Some(Duration::from_secs(86400)), 0));
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let driver = MemoryDriver::new();
+        driver.record_history(&entry(1, "first", Direction::Up)).unwrap();
+        driver.record_history(&entry(2, "second", Direction::Up)).unwrap();
+        driver.record_history(&entry(3, "third", Direction::Up)).unwrap();
+
+        let dir = TempDir::new("migrations").unwrap();
+        for filename in &["0001.first.up.sql", "0001.first.down.sql", "0002.renamed.up.sql", "0002.renamed.down.sql"] {
+            File::create(dir.path().join(filename)).unwrap();
+        }
+        let migrations = load_migration_files(dir.path()).unwrap();
+
+        let orphans = find_orphans(&driver, &migrations, 1).unwrap();
+        assert_eq!(orphans.iter().map(|o| o.number).collect::<Vec<_>>(), vec![2, 3]);
+        let orphans = find_orphans(&driver, &migrations, 3).unwrap();
+        assert_eq!(orphans.iter().map(|o| o.number).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn test_prune_orphans_keeps_archived_migrations() {
+        let driver = MemoryDriver::new();
+        for &(number, name) in &[(1, "first"), (2, "baseline"), (3, "deleted")] {
+            driver.record_history(&entry(number, name, Direction::Up)).unwrap();
+        }
+
+        let dir = TempDir::new("migrations").unwrap();
+        let archive = dir.path().join(ARCHIVE_DIRECTORY);
+        fs::create_dir(&archive).unwrap();
+        for filename in &["0001.first.up.sql", "0001.first.down.sql"] {
+            File::create(archive.join(filename)).unwrap();
+        }
+        for filename in &["0002.baseline.up.sql", "0002.baseline.down.sql"] {
+            File::create(dir.path().join(filename)).unwrap();
+        }
+        let migrations = load_migration_files(dir.path()).unwrap();
+
+        let orphans = find_orphans(&driver, &migrations, baseline(dir.path()).unwrap()).unwrap();
+        prune_orphans(&driver, &orphans).unwrap();
+        assert_eq!(driver.history().unwrap().iter().map(|e| e.number).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_find_modified() {
+        let driver = MemoryDriver::new();
+        let mut first = entry(1, "first", Direction::Up);
+        first.checksum = Some(checksum("CREATE TABLE a (id INT);\n", false));
+        driver.record_history(&first).unwrap();
+        let mut second = entry(2, "second", Direction::Up);
+        second.checksum = Some(checksum("CREATE TABLE b (id INT);\n", false));
+        driver.record_history(&second).unwrap();
+
+        let dir = TempDir::new("migrations").unwrap();
+        for &(filename, content) in &[
+            ("0001.first.up.sql", "CREATE TABLE a (id INT);\r\n"),
+            ("0001.first.down.sql", ""),
+            ("0002.second.up.sql", "CREATE TABLE b (id BIGINT);\n"),
+            ("0002.second.down.sql", ""),
+        ] {
+            File::create(dir.path().join(filename)).unwrap().write_all(content.as_bytes()).unwrap();
+        }
+        let migrations = load_migration_files(dir.path()).unwrap();
+
+        let modified = find_modified(&driver, &migrations, false).unwrap();
+        assert_eq!(modified.iter().map(|m| m.number).collect::<Vec<_>>(), vec![2]);
+        let modified = find_modified(&driver, &migrations, true).unwrap();
+        assert_eq!(modified.iter().map(|m| m.number).collect::<Vec<_>>(), vec![1, 2]);
+
+        accept_modified(&driver, &migrations, &modified, false).unwrap();
+        assert!(find_modified(&driver, &migrations, false).unwrap().is_empty());
+    }
+}
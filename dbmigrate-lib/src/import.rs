@@ -0,0 +1,228 @@
+///! Importing the state of other migration tools
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use drivers::Driver;
+use errors::{Error, Result, ResultExt};
+use files::Migrations;
+
+/// Another migration tool whose tracking table can be imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportSource {
+    /// Flyway's `flyway_schema_history` table
+    Flyway,
+    /// Liquibase's `DATABASECHANGELOG` table
+    Liquibase,
+    /// Diesel's `__diesel_schema_migrations` table
+    Diesel,
+    /// sqlx's `_sqlx_migrations` table
+    Sqlx,
+}
+
+impl ImportSource {
+    /// All the names accepted by `from_str`
+    pub fn variants() -> [&'static str; 4] {
+        ["flyway", "liquibase", "diesel", "sqlx"]
+    }
+
+    // The query returns the applied migrations, in application order
+    fn query(&self) -> &'static str {
+        match *self {
+            ImportSource::Flyway => "
+                SELECT version, description FROM flyway_schema_history
+                WHERE success AND version IS NOT NULL AND type NOT LIKE 'UNDO%'
+                ORDER BY installed_rank;
+            ",
+            ImportSource::Liquibase => "
+                SELECT ID FROM DATABASECHANGELOG
+                WHERE EXECTYPE IN ('EXECUTED', 'RERAN', 'MARK_RAN')
+                ORDER BY ORDEREXECUTED;
+            ",
+            ImportSource::Diesel => "
+                SELECT version FROM __diesel_schema_migrations ORDER BY version;
+            ",
+            ImportSource::Sqlx => "
+                SELECT version, description FROM _sqlx_migrations
+                WHERE success
+                ORDER BY version;
+            ",
+        }
+    }
+
+    // Turns the rows returned by `query` into (version, description) records
+    fn records(&self, rows: Vec<Vec<Option<String>>>) -> Vec<(Option<String>, Option<String>)> {
+        match *self {
+            // Diesel only stores the timestamp of the migrations so the only thing we can
+            // go by is their order: the oldest one is the first dbmigrate migration
+            ImportSource::Diesel => (1..rows.len() as i32 + 1)
+                .map(|n| (Some(n.to_string()), None))
+                .collect(),
+            // Changesets are identified by their ID, which is either a number or a name.
+            // FILENAME is the changelog holding them, shared by every changeset in it
+            ImportSource::Liquibase => rows.into_iter()
+                .map(|mut row| {
+                    let id = row.pop().and_then(|v| v);
+                    (id.clone(), id)
+                })
+                .collect(),
+            _ => rows.into_iter()
+                .map(|mut row| {
+                    let description = row.pop().and_then(|v| v);
+                    let version = row.pop().and_then(|v| v);
+                    (version, description)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for ImportSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ImportSource> {
+        match s {
+            "flyway" => Ok(ImportSource::Flyway),
+            "liquibase" => Ok(ImportSource::Liquibase),
+            "diesel" => Ok(ImportSource::Diesel),
+            "sqlx" => Ok(ImportSource::Sqlx),
+            _ => bail!("Unknown import source {}, expected one of {}", s, ImportSource::variants().join(", ")),
+        }
+    }
+}
+
+impl ToString for ImportSource {
+    fn to_string(&self) -> String {
+        match *self {
+            ImportSource::Flyway => "flyway".to_owned(),
+            ImportSource::Liquibase => "liquibase".to_owned(),
+            ImportSource::Diesel => "diesel".to_owned(),
+            ImportSource::Sqlx => "sqlx".to_owned(),
+        }
+    }
+}
+
+/// Reads the tracking table of another tool, maps the migrations it applied to the local
+/// files and sets the current migration number accordingly.
+/// Returns the number the database is now at.
+pub fn import_state(driver: &Driver, migrations: &Migrations, source: ImportSource) -> Result<i32> {
+    let rows = driver.query(source.query())
+        .chain_err(|| format!("Failed to read the {} tracking table", source.to_string()))?;
+    let records = source.records(rows);
+
+    let number = map_records(&records, migrations)?;
+    driver.set_current_number(driver.get_current_number(), number)?;
+    Ok(number)
+}
+
+// Matches a migration name against a description, which most tools derive from
+// the filename by replacing underscores with spaces
+fn same_name(name: &str, description: &str) -> bool {
+    let normalize = |s: &str| s.trim().to_lowercase().replace(" ", "_");
+    normalize(name) == normalize(description)
+}
+
+/// Finds which local migration each record corresponds to, first by version number and
+/// then by name, and checks that they form the linear history dbmigrate expects.
+/// Returns the highest migration number applied.
+fn map_records(records: &[(Option<String>, Option<String>)], migrations: &Migrations) -> Result<i32> {
+    let mut applied = BTreeSet::new();
+
+    for &(ref version, ref description) in records {
+        let by_version = version.as_ref()
+            .and_then(|v| v.trim().parse::<i32>().ok())
+            .filter(|n| migrations.contains_key(n));
+        let by_name = || description.as_ref().and_then(|d| {
+            migrations.iter()
+                .find(|&(_, m)| m.up.as_ref().map_or(false, |f| same_name(&f.name, d)))
+                .map(|(n, _)| *n)
+        });
+
+        match by_version.or_else(by_name) {
+            Some(n) => { applied.insert(n); },
+            None => bail!(
+                "Applied migration {} ({}) doesn't match any local migration file",
+                version.as_ref().map_or("?", |v| v.as_str()),
+                description.as_ref().map_or("?", |d| d.as_str())
+            ),
+        }
+    }
+
+    let mut expected = 1;
+    for n in &applied {
+        if *n != expected {
+            bail!("Migration {} was applied but migration {} wasn't, dbmigrate can only track a linear history", n, expected);
+        }
+        expected += 1;
+    }
+
+    Ok(applied.into_iter().max().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{map_records, ImportSource};
+    use std::path::PathBuf;
+    use annotations::Annotations;
+    use encoding::Encoding;
+    use files::{Migration, MigrationFile, MigrationKind, Migrations, Direction};
+
+    fn migrations(names: &[&str]) -> Migrations {
+        names.iter().enumerate().map(|(i, name)| {
+            let number = i as i32 + 1;
+            let filename = format!("{:04}.{}.up.sql", number, name);
+            let file = MigrationFile {
+                content: None,
+                encoding: Encoding::default(),
+                kind: MigrationKind::Sql,
+                path: PathBuf::from(&filename),
+                direction: Direction::Up,
+                number: number,
+                filename: filename,
+                name: name.to_string(),
+                annotations: Annotations::default(),
+            };
+            (number, Migration { up: Some(file), down: None })
+        }).collect()
+    }
+
+    fn record(version: &str, description: &str) -> (Option<String>, Option<String>) {
+        (Some(version.to_owned()), Some(description.to_owned()))
+    }
+
+    #[test]
+    fn test_map_records_by_version_and_name() {
+        let migrations = migrations(&["initial", "add_users"]);
+        let records = vec![record("1", "initial"), record("users-1", "add users")];
+        assert_eq!(map_records(&records, &migrations).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_map_records_unknown_migration() {
+        let migrations = migrations(&["initial"]);
+        let records = vec![record("1", "initial"), record("7", "something else")];
+        assert!(map_records(&records, &migrations).is_err());
+    }
+
+    #[test]
+    fn test_map_records_non_linear_history() {
+        let migrations = migrations(&["initial", "add_users", "add_companies"]);
+        let records = vec![record("1", "initial"), record("3", "add companies")];
+        assert!(map_records(&records, &migrations).is_err());
+    }
+
+    #[test]
+    fn test_diesel_records_are_mapped_by_order() {
+        let rows = vec![vec![Some("20180101000000".to_owned())], vec![Some("20180305120000".to_owned())]];
+        let records = ImportSource::Diesel.records(rows);
+        assert_eq!(records, vec![(Some("1".to_owned()), None), (Some("2".to_owned()), None)]);
+    }
+
+    #[test]
+    fn test_liquibase_records_are_mapped_by_id() {
+        let migrations = migrations(&["initial", "add_users"]);
+        let rows = vec![vec![Some("1".to_owned())], vec![Some("add_users".to_owned())]];
+        let records = ImportSource::Liquibase.records(rows);
+        assert_eq!(records, vec![record("1", "1"), record("add_users", "add_users")]);
+        assert_eq!(map_records(&records, &migrations).unwrap(), 2);
+    }
+}
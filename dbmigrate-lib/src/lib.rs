@@ -5,7 +5,10 @@
 #[cfg(test)]
 extern crate tempdir;
 
+extern crate csv;
+extern crate flate2;
 extern crate regex;
+extern crate sha2;
 extern crate url;
 #[cfg(feature = "postgres_support")]
 extern crate postgres as postgres_client;
@@ -15,20 +18,108 @@ extern crate postgres_native_tls;
 extern crate mysql as mysql_client;
 #[cfg(feature = "sqlite_support")]
 extern crate rusqlite as sqlite_client;
+#[cfg(feature = "oracle_support")]
+extern crate oracle as oracle_client;
+#[cfg(feature = "odbc_support")]
+extern crate odbc_api;
+#[cfg(feature = "mongodb_support")]
+extern crate mongodb as mongodb_client;
+#[cfg(feature = "neo4j_support")]
+extern crate neo4rs;
+#[cfg(feature = "neo4j_support")]
+extern crate tokio;
+#[cfg(any(feature = "spanner_support", feature = "bigquery_support", feature = "mongodb_support",
+    feature = "neo4j_support"))]
+#[macro_use]
+extern crate serde_json;
+#[cfg(any(feature = "spanner_support", feature = "bigquery_support"))]
+extern crate ureq;
+#[cfg(feature = "git")]
+extern crate git2;
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate tracing;
 
+mod annotations;
+mod backup;
+mod bundle;
+mod checksum;
+mod csv_load;
+mod dialect;
+mod dirty;
+mod encoding;
 mod files;
 mod drivers;
+mod history;
+mod import;
+mod lock;
+mod migrator;
+mod observer;
+mod online;
+mod retry;
+mod schema;
+mod script;
+mod statements;
+mod temp_db;
+mod templates;
+mod tenants;
+mod timeout;
+mod tracks;
+mod version;
 /// All possible errors
 pub mod errors;
 
-pub use drivers::{get_driver, Driver};
+pub use drivers::{
+    check_writable, get_driver, supported_schemes, with_connect_timeout, with_search_path, Driver,
+    DEFAULT_CONNECT_TIMEOUT,
+};
+#[cfg(feature = "testing")]
+pub use drivers::memory::MemoryDriver;
+pub use annotations::Annotations;
+pub use backup::backup;
+pub use bundle::{sign as sign_bundle, signature_path, verify_signature, Bundle, BUNDLE_FILENAME};
+pub use csv_load::LoadManifest;
+pub use dialect::{lint, Dialect, LintTarget, LintWarning};
+pub use encoding::Encoding;
+pub use checksum::checksum;
+pub use history::{
+    accept_modified, applied_migrations, current_hostname, current_user, find_modified, find_orphans, git_commit,
+    is_settled, prune_orphans, utc_now, HistoryEntry, HISTORY_TABLE,
+};
+pub use import::{import_state, ImportSource};
+pub use migrator::{CodeMigration, Migrator, DEFAULT_STREAMING_THRESHOLD};
+pub use observer::{MigrationEvent, Observer};
+pub use online::{OnlineSchemaChange, OnlineTool};
+pub use retry::{RetryPolicy, DEFAULT_RETRY_ON};
+pub use script::run_script;
+pub use statements::Statements;
+pub use schema::{check_drift, diff as schema_diff, generate_down};
+pub use temp_db::TemporaryDatabase;
+pub use templates::{ColumnDefinition, IndexDefinition, TableDefinition, Template};
+pub use tenants::{schema_url, tenant_schemas};
+pub use tracks::Track;
+pub use timeout::{parse_duration, CancellationToken, Canceller};
+pub use version::VersionRequirement;
 pub use files::{
+    archive_migrations,
+    baseline,
     create_migration,
+    create_migration_with_content,
     read_migration_files,
+    read_migration_files_with_encoding,
+    read_migration_files_lazily,
+    read_migration_files_with_options,
+    read_migration_directories,
+    load_migration_files,
+    load_migration_files_with_encoding,
     MigrationFile,
+    MigrationKind,
+    ReadOptions,
     Migration,
     Migrations,
     Direction,
+    ARCHIVE_DIRECTORY,
 };
@@ -0,0 +1,92 @@
+///! Lock making concurrent runs on the same database wait for each other
+use csv_load::quote;
+use drivers::Driver;
+use errors::{Result, ResultExt};
+use history::{current_user, utc_now};
+
+/// Table holding the lock on databases without a better mechanism, its single row being the lock
+pub const LOCK_TABLE: &'static str = "__dbmigrate_lock";
+
+/// Takes the lock by inserting its row, which fails while another process holds it. Other failures,
+/// leaving no row behind, are errors rather than a lock to wait for
+pub fn try_lock_table<D: Driver + ?Sized>(driver: &D) -> Result<bool> {
+    driver.execute(&format!("
+        CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER NOT NULL PRIMARY KEY,
+            locked_at VARCHAR(19) NOT NULL,
+            locked_by VARCHAR(255)
+        );
+    ", LOCK_TABLE)).chain_err(|| "Failed to create the lock table")?;
+
+    let locked_by = current_user().map_or("NULL".to_owned(), |user| quote(&user));
+    let inserted = driver.execute(&format!(
+        "INSERT INTO {} (id, locked_at, locked_by) VALUES (1, {}, {});", LOCK_TABLE, quote(&utc_now()), locked_by
+    ));
+    match inserted {
+        Ok(()) => Ok(true),
+        Err(error) => {
+            if driver.query(&format!("SELECT id FROM {} WHERE id = 1;", LOCK_TABLE))?.is_empty() {
+                return Err(error).chain_err(|| "Failed to take the lock");
+            }
+            Ok(false)
+        },
+    }
+}
+
+/// Releases the lock taken by `try_lock_table`
+pub fn unlock_table<D: Driver + ?Sized>(driver: &D) -> Result<()> {
+    driver.execute(&format!("DELETE FROM {} WHERE id = 1;", LOCK_TABLE))
+        .chain_err(|| "Failed to release the lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::try_lock_table;
+    use drivers::Driver;
+    use errors::Result;
+
+    // Refuses every insert, with the lock row there or not
+    struct RefusingDriver {
+        locked: Cell<bool>,
+    }
+
+    impl Driver for RefusingDriver {
+        fn ensure_migration_table_exists(&self) {}
+
+        fn remove_migration_table(&self) {}
+
+        fn get_current_number(&self) -> i32 {
+            0
+        }
+
+        fn set_current_number(&self, _: i32, _: i32) -> Result<()> {
+            Ok(())
+        }
+
+        fn migrate(&self, migration: String, _: i32, _: i32) -> Result<()> {
+            self.execute(&migration)
+        }
+
+        fn execute(&self, sql: &str) -> Result<()> {
+            if sql.starts_with("INSERT") {
+                bail!("Insert refused");
+            }
+            Ok(())
+        }
+
+        fn query(&self, _: &str) -> Result<Vec<Vec<Option<String>>>> {
+            Ok(if self.locked.get() { vec![vec![Some("1".to_owned())]] } else { vec![] })
+        }
+    }
+
+    #[test]
+    fn test_failures_are_not_taken_for_the_lock() {
+        let driver = RefusingDriver { locked: Cell::new(true) };
+        assert!(!try_lock_table(&driver).unwrap());
+        driver.locked.set(false);
+        let error = try_lock_table(&driver).unwrap_err();
+        assert!(error.iter().any(|e| e.to_string() == "Insert refused"), "{}", error);
+    }
+}
@@ -0,0 +1,1758 @@
+///! Running migrations from files and code
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::iter;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use annotations::check_condition;
+use csv_load::LoadManifest;
+use drivers::{check_writable, get_driver, Driver};
+use errors::{redact_urls, Error, ErrorKind, Result, ResultExt};
+use files::{
+    baseline, check_sequence, load_migration_files, Direction, Migration, MigrationFile, MigrationKind, Migrations,
+};
+use history::{applied_migrations, find_modified, git_commit, HistoryEntry};
+use observer::{MigrationEvent, Observer};
+use online::OnlineSchemaChange;
+use retry::RetryPolicy;
+use timeout::{with_in_flight, CancellationToken, Watchdog};
+use tracks::Track;
+use script::run_script;
+use statements::{has_dollar_quotes, split_statements};
+
+/// A migration written in Rust, for changes that are impractical to express in SQL.
+/// The driver can be used to run queries.
+pub trait CodeMigration {
+    /// Applies the migration
+    fn up(&self, driver: &Driver) -> Result<()>;
+    /// Reverts the migration
+    fn down(&self, driver: &Driver) -> Result<()>;
+}
+
+// Allows registering a pair of closures as a migration
+struct FnMigration<U, D> {
+    up: U,
+    down: D,
+}
+
+impl<U, D> CodeMigration for FnMigration<U, D>
+    where U: Fn(&Driver) -> Result<()>, D: Fn(&Driver) -> Result<()> {
+    fn up(&self, driver: &Driver) -> Result<()> {
+        (self.up)(driver)
+    }
+
+    fn down(&self, driver: &Driver) -> Result<()> {
+        (self.down)(driver)
+    }
+}
+
+// A single numbered migration, whatever its kind
+enum Step {
+    Files(Migration),
+    Code { name: String, migration: Box<CodeMigration> },
+}
+
+impl Step {
+    fn name(&self) -> &str {
+        match *self {
+            Step::Files(ref migration) => migration.up.as_ref().map_or("", |f| &f.name),
+            Step::Code { ref name, .. } => name,
+        }
+    }
+
+    // The expand migration whose contract phase this is, see `Annotations::contract`
+    fn contract(&self) -> Option<i32> {
+        match *self {
+            Step::Files(ref migration) => migration.up.as_ref().and_then(|f| f.annotations.contract),
+            Step::Code { .. } => None,
+        }
+    }
+}
+
+/// Runs SQL file migrations and code migrations, interleaved by number
+pub struct Migrator {
+    driver: Box<Driver>,
+    // Needed by script migrations
+    url: Option<String>,
+    steps: BTreeMap<i32, Step>,
+    strict_checksums: bool,
+    schema_snapshots: bool,
+    lock_timeout: Duration,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    sleep_between: Option<Duration>,
+    online: OnlineSchemaChange,
+    observers: Vec<Box<Observer>>,
+    cancellation: Option<CancellationToken>,
+    environment: Option<String>,
+    streaming_threshold: Option<u64>,
+    statement_progress: bool,
+    keep_alive: Option<Duration>,
+    allow_gaps: bool,
+    baseline: i32,
+    git_commit: Option<String>,
+    // The migrations last run by `apply_out_of_order` and in which direction, read from the history
+    // once per run while the lock is held
+    out_of_order: RefCell<Option<BTreeMap<i32, Direction>>>,
+}
+
+// A SQL file run with its preconditions and assertions, owned to be sent to the threads of `apply_parallel`
+struct CheckedRun {
+    number: i32,
+    name: String,
+    filename: String,
+    content: String,
+    preconditions: Vec<String>,
+    assertions: Vec<String>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl CheckedRun {
+    fn new(file: &MigrationFile) -> Result<CheckedRun> {
+        Ok(CheckedRun {
+            number: file.number,
+            name: file.name.clone(),
+            filename: file.filename.clone(),
+            content: file.read_content()?.into_owned(),
+            preconditions: file.annotations.preconditions.clone(),
+            assertions: file.annotations.assertions.clone(),
+            timeout: file.annotations.timeout,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    // Runs the content like `execute` as a migration would be: in a transaction where DDL is transactional,
+    // retried and cancelled after its timeout
+    fn migrate(&self, driver: &Driver) -> Result<()> {
+        let watchdog = match self.timeout {
+            Some(timeout) => match driver.canceller()? {
+                Some(cancel) => Some(Watchdog::start(timeout, cancel)),
+                None => bail!("Migrations can't be cancelled on this database, a timeout can't be set"),
+            },
+            None => None,
+        };
+        let res = self.retry.run(|| in_transaction(driver, || self.execute(driver)));
+        if watchdog.map_or(false, |w| w.stop()) {
+            res.chain_err(|| format!("Cancelled after running for more than {:?}", self.timeout.unwrap()))
+        } else {
+            res
+        }
+    }
+
+    // Runs the content, leaving the migration number alone
+    fn execute(&self, driver: &Driver) -> Result<()> {
+        for precondition in &self.preconditions {
+            if !check_condition(driver, precondition)? {
+                bail!(ErrorKind::StateConflict(format!("Precondition of {} not met: {}", self.filename, precondition)));
+            }
+        }
+        driver.execute(&self.content)
+            .chain_err(|| ErrorKind::MigrationFailed(self.number, self.name.clone()))?;
+        for assertion in &self.assertions {
+            if !check_condition(driver, assertion)? {
+                bail!("Assertion of {} failed: {}", self.filename, assertion);
+            }
+        }
+        Ok(())
+    }
+}
+
+// How often a migrator waiting for the lock checks whether it was released
+const LOCK_POLL_INTERVAL: u64 = 1;
+
+/// A size from which to stream SQL migrations, for `Migrator::set_streaming_threshold`
+pub const DEFAULT_STREAMING_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+impl Migrator {
+    /// Creates a migrator without any migration.
+    /// Script migrations can't be run as the migrator doesn't know the database URL.
+    pub fn new(driver: Box<Driver>) -> Migrator {
+        Migrator {
+            driver: driver,
+            url: None,
+            steps: BTreeMap::new(),
+            strict_checksums: false,
+            schema_snapshots: false,
+            lock_timeout: Duration::from_secs(600),
+            retry: RetryPolicy::default(),
+            timeout: None,
+            sleep_between: None,
+            online: OnlineSchemaChange::default(),
+            observers: vec![],
+            cancellation: None,
+            environment: None,
+            streaming_threshold: None,
+            statement_progress: false,
+            keep_alive: None,
+            allow_gaps: false,
+            baseline: 1,
+            git_commit: None,
+            out_of_order: RefCell::new(None),
+        }
+    }
+
+    /// Connects to the database at the given URL and creates a migrator without any migration
+    pub fn from_url(url: &str) -> Result<Migrator> {
+        let mut migrator = Migrator::new(get_driver(url)?);
+        migrator.url = Some(url.to_owned());
+        Ok(migrator)
+    }
+
+    /// Moves the migrator to the given track of the database, see `Track`
+    pub fn on_track(self, name: &str) -> Result<Migrator> {
+        let track = Track::new(self.driver, name)?;
+        Ok(Migrator { driver: Box::new(track), ..self })
+    }
+
+    /// The driver migrations are run with
+    pub fn driver(&self) -> &Driver {
+        &*self.driver
+    }
+
+    /// Whether checksums are computed on the exact file content instead of ignoring
+    /// line endings and trailing whitespace, the default
+    pub fn strict_checksums(&self) -> bool {
+        self.strict_checksums
+    }
+
+    /// Sets whether checksums are computed on the exact file content. Changing it makes
+    /// the checksums recorded before mismatch
+    pub fn set_strict_checksums(&mut self, strict: bool) {
+        self.strict_checksums = strict;
+    }
+
+    /// Sets whether a dump of the schema is stored in the history after each migration,
+    /// to be read back with `Driver::schema_snapshot`. Needs a driver able to dump its schema
+    pub fn set_schema_snapshots(&mut self, enabled: bool) {
+        self.schema_snapshots = enabled;
+    }
+
+    /// Sets how long `up` and `down` wait for another process holding the migration lock
+    /// before giving up, 10 minutes by default
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Sets how SQL migrations failing with a transient error, like a deadlock, are retried.
+    /// They aren't by default. On MySQL, a migration whose first statements were committed
+    /// before a later one failed runs them again
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Sets how long SQL migrations may run before being cancelled, which fails the run.
+    /// A `-- dbmigrate:timeout` annotation overrides it for its file. Only Postgres
+    /// and MySQL migrations can be cancelled
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets whether migration numbers can have gaps, like when old migrations were squashed into a baseline
+    /// and deleted. An up migration then follows the current one whatever its number, and a down migration
+    /// goes back to the migration applied before it according to the history, or to the number below it
+    pub fn set_allow_gaps(&mut self, allow: bool) {
+        self.allow_gaps = allow;
+    }
+
+    /// Sets the first migration, the ones before it having been archived. A new database starts with it,
+    /// and reverting it goes back to the migration applied before it like with `set_allow_gaps`.
+    /// `add_migration_files` sets it from the archive of the directory
+    pub fn set_baseline(&mut self, number: i32) {
+        self.baseline = number;
+    }
+
+    /// The first migration, the ones before it having been archived
+    pub fn baseline(&self) -> i32 {
+        self.baseline
+    }
+
+    /// Sets the commit of the migrations recorded in the history with the migrations that run.
+    /// `add_migration_files` sets it from the git repository of the directory, if it is in one
+    pub fn set_git_commit(&mut self, commit: Option<String>) {
+        self.git_commit = commit;
+    }
+
+    // The migration number before and after running migration `number` in the given direction
+    fn transition(&self, number: i32, direction: Direction) -> Result<(i32, i32)> {
+        if !self.allow_gaps && number != self.baseline {
+            return Ok(transition(number, direction));
+        }
+        let current = self.driver.get_current_number();
+        match direction {
+            // An already applied migration keeps the usual transition, to fail as a concurrent modification
+            Direction::Up if current < number => Ok((current, number)),
+            Direction::Up => Ok(transition(number, direction)),
+            Direction::Down => {
+                let applied = applied_migrations(&*self.driver)?;
+                let previous = applied.range(..number).map(|(n, _)| *n)
+                    .chain(self.steps.range(..number).map(|(n, _)| *n))
+                    .max();
+                // A new database started with the baseline
+                let fallback = if number == self.baseline { 0 } else { number - 1 };
+                Ok((number, previous.unwrap_or(fallback)))
+            },
+        }
+    }
+
+    /// Sets a pause between two migrations, letting replicas catch up during long chains of migrations
+    pub fn set_sleep_between(&mut self, pause: Option<Duration>) {
+        self.sleep_between = pause;
+    }
+
+    /// Sleeps for the pause set with `set_sleep_between`, for code applying migrations one by one
+    pub fn pause_between_migrations(&self) {
+        if let Some(pause) = self.sleep_between {
+            info!(pause_secs = pause.as_secs(), "pausing before the next migration");
+            let end = Instant::now() + pause;
+            while Instant::now() < end {
+                let left = end - Instant::now();
+                match self.keep_alive {
+                    Some(interval) if interval < left => {
+                        thread::sleep(interval);
+                        self.ping();
+                    },
+                    _ => thread::sleep(left),
+                }
+            }
+        }
+    }
+
+    /// Sets how often the database is pinged while the migrator waits without using its connection:
+    /// during pauses between migrations, script and online migrations, and parallel ones. This keeps
+    /// the connection, and the lock it may hold, from being closed for being idle during long runs.
+    /// Never by default
+    pub fn set_keep_alive(&mut self, interval: Option<Duration>) {
+        self.keep_alive = interval;
+    }
+
+    // A failed ping is only logged, the next use of the connection failing if it is lost
+    fn ping(&self) {
+        if let Err(e) = self.driver.ping() {
+            warn!(error = %redact_urls(&e.to_string()), "keep-alive ping failed");
+        }
+    }
+
+    // Waits for the next message, pinging the database meanwhile. None once the senders are gone
+    fn recv_keeping_alive<T>(&self, receiver: &mpsc::Receiver<T>) -> Option<T> {
+        let interval = match self.keep_alive {
+            Some(interval) => interval,
+            None => return receiver.recv().ok(),
+        };
+        loop {
+            match receiver.recv_timeout(interval) {
+                Ok(message) => return Some(message),
+                Err(RecvTimeoutError::Timeout) => self.ping(),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    // Runs something not using the driver, like a script, on another thread so this one can ping the database
+    fn keeping_alive<T: Send, F: FnOnce() -> Result<T> + Send>(&self, f: F) -> Result<T> {
+        if self.keep_alive.is_none() {
+            return f();
+        }
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            scope.spawn(move || sender.send(f()).ok());
+            match self.recv_keeping_alive(&receiver) {
+                Some(res) => res,
+                None => bail!("The migration panicked"),
+            }
+        })
+    }
+
+    /// Sets the tool running MySQL migrations annotated `-- dbmigrate:online`, pt-online-schema-change
+    /// by default. Those migrations need a migrator created with `Migrator::from_url`
+    pub fn set_online_schema_change(&mut self, online: OnlineSchemaChange) {
+        self.online = online;
+    }
+
+    /// Sets the environment, like `production`, migrations restricted to other environments with
+    /// `-- dbmigrate:environments` being skipped. They are all skipped when no environment is set.
+    /// Skipped migrations count as applied and are recorded as skipped in the history
+    pub fn set_environment(&mut self, environment: Option<String>) {
+        self.environment = environment;
+    }
+
+    /// Sets the size of the files, in bytes on disk, from which SQL migrations without assertions are
+    /// streamed: their statements, split on `;`, are read and run one at a time with
+    /// `Driver::migrate_statements` instead of all at once, in a transaction on databases with
+    /// transactional DDL. They shouldn't hold blocks with `;` in them, like function bodies, which is
+    /// why they are never streamed by default (`None`)
+    pub fn set_streaming_threshold(&mut self, threshold: Option<u64>) {
+        self.streaming_threshold = threshold;
+    }
+
+    /// Runs the SQL migrations of several statements without assertions one statement at a time, split on `;`
+    /// like streamed migrations, so that observers are notified of each with `MigrationEvent::Statement` and
+    /// failures say which statement failed. They are run in a transaction on databases with transactional DDL,
+    /// with `Driver::execute`, skipping what drivers do on top of running the SQL like Vitess online DDL.
+    /// Streamed migrations always report their statements
+    pub fn set_statement_progress(&mut self, enabled: bool) {
+        self.statement_progress = enabled;
+    }
+
+    /// Adds an observer notified when each migration starts, finishes or fails
+    pub fn add_observer(&mut self, observer: Box<Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Sets the token with which another thread can stop `up` and `down`, between migrations
+    /// and, on Postgres and MySQL, during them
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancellation.as_ref().map_or(false, |t| t.is_cancelled()) {
+            bail!(ErrorKind::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Runs `f` holding the migration lock, so that processes migrating the same database
+    /// at the same time, like the replicas of an application at boot, run one after the other.
+    /// The others wait until the lock is released and then find the migrations already applied.
+    pub fn with_lock<T, E, F>(&self, f: F) -> ::std::result::Result<T, E>
+        where F: FnOnce() -> ::std::result::Result<T, E>, E: From<Error> {
+        let start = Instant::now();
+        let mut waiting = false;
+        while !self.driver.try_lock().chain_err(|| "Failed to take the migration lock")? {
+            if start.elapsed() >= self.lock_timeout {
+                return Err(Error::from(ErrorKind::LockTimeout).into());
+            }
+            self.check_cancelled()?;
+            if !waiting {
+                info!("Another process is migrating the database, waiting for it to finish");
+                waiting = true;
+            }
+            thread::sleep(Duration::from_secs(LOCK_POLL_INTERVAL));
+        }
+
+        *self.out_of_order.borrow_mut() = None;
+        let res = f();
+        *self.out_of_order.borrow_mut() = None;
+        let unlocked = self.driver.unlock().chain_err(|| "Failed to release the migration lock");
+        let value = res?;
+        unlocked?;
+        Ok(value)
+    }
+
+    /// Adds all the migration files of the given folder
+    pub fn add_migration_files(&mut self, path: &Path) -> Result<()> {
+        self.baseline = self.baseline.max(baseline(path)?);
+        if self.git_commit.is_none() {
+            self.git_commit = git_commit(path);
+        }
+        for (number, migration) in load_migration_files(path)? {
+            self.add_step(number, Step::Files(migration))?;
+        }
+        Ok(())
+    }
+
+    /// Adds a migration implemented in Rust
+    pub fn add_code_migration(&mut self, number: i32, name: &str, migration: Box<CodeMigration>) -> Result<()> {
+        self.add_step(number, Step::Code { name: name.to_owned(), migration: migration })
+    }
+
+    /// Adds a migration implemented as two closures, one for up and one for down
+    pub fn add_fn_migration<U, D>(&mut self, number: i32, name: &str, up: U, down: D) -> Result<()>
+        where U: Fn(&Driver) -> Result<()> + 'static, D: Fn(&Driver) -> Result<()> + 'static {
+        self.add_code_migration(number, name, Box::new(FnMigration { up: up, down: down }))
+    }
+
+    fn add_step(&mut self, number: i32, step: Step) -> Result<()> {
+        if let Some(existing) = self.steps.get(&number) {
+            bail!(ErrorKind::ParseError(format!(
+                "Migration {} is defined twice: {} and {}", number, existing.name(), step.name()
+            )));
+        }
+        self.steps.insert(number, step);
+        Ok(())
+    }
+
+    /// Applies all the migrations that haven't been applied yet, waiting for other
+    /// processes migrating the same database to finish. It stops before the first contract
+    /// migration, left to be applied with `apply_file` once its expand migration settled
+    pub fn up(&self) -> Result<()> {
+        if !self.allow_gaps {
+            check_sequence(self.baseline, self.steps.keys())?;
+        }
+        let span = info_span!("migrate", direction = "up");
+        let _enter = span.enter();
+        self.with_lock(|| {
+            let current = self.driver.get_current_number();
+            for (i, (number, step)) in self.steps.range(current + 1..).enumerate() {
+                if let Some(expand) = step.contract() {
+                    info!(number = *number, expand = expand, "stopping before the contract phase of a migration");
+                    break;
+                }
+                if i > 0 {
+                    self.pause_between_migrations();
+                }
+                self.check_cancelled()?;
+                self.apply(*number, step, Direction::Up)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reverts all the applied migrations, waiting for other processes migrating
+    /// the same database to finish
+    pub fn down(&self) -> Result<()> {
+        if !self.allow_gaps {
+            check_sequence(self.baseline, self.steps.keys())?;
+        }
+        let span = info_span!("migrate", direction = "down");
+        let _enter = span.enter();
+        self.with_lock(|| {
+            let current = self.driver.get_current_number();
+            // Nothing is reverted if one of the migrations can't be
+            for step in self.steps.range(..current + 1).map(|(_, step)| step) {
+                if let Step::Files(ref migration) = *step {
+                    migration.file(Direction::Down)?;
+                }
+            }
+            for (i, (number, step)) in self.steps.range(..current + 1).rev().enumerate() {
+                if i > 0 {
+                    self.pause_between_migrations();
+                }
+                self.check_cancelled()?;
+                self.apply(*number, step, Direction::Down)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs a single migration file, SQL or script, sets the migration number accordingly
+    /// and records it in the history
+    pub fn apply_file(&self, file: &MigrationFile) -> Result<()> {
+        let span = info_span!(
+            "migration",
+            number = file.number, name = file.name.as_str(), direction = file.direction.to_string().as_str()
+        );
+        let _enter = span.enter();
+        self.observe(file.number, &file.name, file.direction, || self.apply_file_in_span(file, false))
+    }
+
+    /// Runs a single migration file out of sequence, leaving the migration number as is, to backport
+    /// an up migration to a database which isn't at the one before it yet, or revert one below the
+    /// current number. It is recorded in the history, which `up` and `down` then rely on to skip it
+    /// once they reach it
+    pub fn apply_out_of_order(&self, file: &MigrationFile) -> Result<()> {
+        let span = info_span!(
+            "migration",
+            number = file.number, name = file.name.as_str(), direction = file.direction.to_string().as_str()
+        );
+        let _enter = span.enter();
+        self.observe(file.number, &file.name, file.direction, || self.apply_file_in_span(file, true))
+    }
+
+    // Runs a migration, notifying the observers
+    fn observe<F: FnOnce() -> Result<()>>(&self, number: i32, name: &str, direction: Direction, f: F) -> Result<()> {
+        self.notify(&MigrationEvent::Started { number: number, name: name, direction: direction });
+        let start = Instant::now();
+        let res = f();
+        match res {
+            Ok(_) => self.notify(&MigrationEvent::Finished {
+                number: number, name: name, direction: direction, duration: start.elapsed(),
+            }),
+            Err(ref e) => {
+                error!(error = %redact_urls(&e.to_string()), "migration failed");
+                self.notify(&MigrationEvent::Failed { number: number, name: name, direction: direction, error: e });
+            },
+        }
+        res
+    }
+
+    fn notify(&self, event: &MigrationEvent) {
+        for observer in &self.observers {
+            observer.on_event(event);
+        }
+    }
+
+    fn apply_file_in_span(&self, file: &MigrationFile, out_of_order: bool) -> Result<()> {
+        self.check_migratable()?;
+        let (expected, new_number) = if out_of_order {
+            let current = self.driver.get_current_number();
+            (current, current)
+        } else {
+            self.transition(file.number, file.direction)?
+        };
+        if !out_of_order && self.done_out_of_order(file.number, file.direction)? {
+            info!("skipping migration already run out of order");
+            return self.driver.set_current_number(expected, new_number);
+        }
+        if let Some(environment) = self.skipped_in(file)? {
+            info!(environment = environment.as_str(), "skipping migration restricted to other environments");
+            self.driver.set_current_number(expected, new_number)?;
+            let mut entry = self.history_entry(file.number, &file.name, file.direction)?;
+            entry.skipped_in = Some(environment);
+            return self.driver.record_history(&entry);
+        }
+        if !file.annotations.requires_server.is_empty() {
+            let version = self.driver.server_version()?;
+            for requirement in &file.annotations.requires_server {
+                if !requirement.matches(&version)? {
+                    bail!(ErrorKind::StateConflict(format!(
+                        "{} requires a server {} but it is {}", file.filename, requirement, version
+                    )));
+                }
+            }
+        }
+        for precondition in &file.annotations.preconditions {
+            if !check_condition(&*self.driver, precondition)? {
+                bail!(ErrorKind::StateConflict(format!("Precondition of {} not met: {}", file.filename, precondition)));
+            }
+        }
+
+        let start = Instant::now();
+        let res = match file.kind {
+            MigrationKind::Sql | MigrationKind::Json | MigrationKind::Cypher if file.kind != self.driver.migration_kind() => {
+                bail!("{} can't be run on this database, which runs {:?} migrations", file.filename, self.driver.migration_kind())
+            },
+            MigrationKind::Sql if file.annotations.online => {
+                let url = match self.url {
+                    Some(ref url) => url,
+                    None => bail!("Online migrations need a migrator created with `Migrator::from_url`"),
+                };
+                if !file.annotations.assertions.is_empty() {
+                    bail!("{} is online and can't have assertions", file.filename);
+                }
+                let content = file.read_content()?;
+                let online = &self.online;
+                self.keeping_alive(|| online.run(url, &content))
+                    .and_then(|_| self.driver.set_current_number(expected, new_number))
+            },
+            MigrationKind::Sql | MigrationKind::Json | MigrationKind::Cypher => {
+                let content = if self.is_streamed(file)? { None } else { Some(file.read_content()?) };
+                let statements = match content {
+                    // Function bodies would be split
+                    Some(ref content)
+                        if self.statement_progress && file.annotations.assertions.is_empty()
+                            && !has_dollar_quotes(content) => {
+                        Some(split_statements(content, ';')).filter(|statements| statements.len() > 1)
+                    },
+                    _ => None,
+                };
+                let timeout = file.annotations.timeout.or(self.timeout);
+                let watchdog = match timeout {
+                    Some(timeout) => match self.driver.canceller()? {
+                        Some(cancel) => Some(Watchdog::start(timeout, cancel)),
+                        None => bail!("Migrations can't be cancelled on this database, a timeout can't be set"),
+                    },
+                    None => None,
+                };
+                // Retrying is only safe once the failed attempt was rolled back
+                let retry = if self.is_atomic(file)? { self.retry.clone() } else { RetryPolicy::default() };
+                let run = || retry.run(|| match content {
+                    // Counting the statements would read the file twice
+                    None => self.migrate_statements(file, &mut file.statements()?, None, expected, new_number),
+                    Some(_) if statements.is_some() => {
+                        let statements = statements.as_ref().unwrap();
+                        let mut iter = statements.iter().map(|statement| Ok(statement.clone()));
+                        self.migrate_statements(file, &mut iter, Some(statements.len()), expected, new_number)
+                    },
+                    Some(ref content) if file.annotations.assertions.is_empty() => {
+                        self.driver.migrate(content.to_string(), expected, new_number)
+                    },
+                    Some(ref content) => {
+                        self.driver.migrate_with_assertions(content.to_string(), expected, new_number, &file.annotations.assertions)
+                    },
+                });
+                let res = match self.cancellation {
+                    Some(ref token) => match self.driver.canceller()? {
+                        Some(cancel) => with_in_flight(token, cancel, run),
+                        None => run(),
+                    },
+                    None => run(),
+                };
+                if watchdog.map_or(false, |w| w.stop()) {
+                    res.chain_err(|| format!("Cancelled after running for more than {:?}", timeout.unwrap()))
+                } else if self.cancellation.as_ref().map_or(false, |t| t.is_cancelled()) {
+                    res.chain_err(|| ErrorKind::Cancelled)
+                } else {
+                    res
+                }
+            },
+            MigrationKind::Script => {
+                let url = match self.url {
+                    Some(ref url) => url,
+                    None => bail!("Script migrations need a migrator created with `Migrator::from_url`"),
+                };
+                self.keeping_alive(|| run_script(file, url))
+                    .and_then(|_| self.driver.set_current_number(expected, new_number))
+            },
+            MigrationKind::Csv => {
+                let manifest = LoadManifest::parse(&file.read_content()?)
+                    .chain_err(|| format!("Invalid manifest {}", file.filename))?;
+                // The CSV file path is relative to the manifest
+                let csv_path = match file.path.parent() {
+                    Some(dir) => dir.join(&manifest.file),
+                    None => manifest.file.clone(),
+                };
+                self.driver.load_csv(&manifest.table, &csv_path)
+                    .and_then(|_| self.driver.set_current_number(expected, new_number))
+            },
+        };
+        match res {
+            Err(ref e) if started(e) && !self.is_atomic(file)? => self.mark_dirty(file.number),
+            _ => (),
+        }
+
+        res.and_then(|_| {
+            let duration = start.elapsed();
+            let mut entry = self.history_entry(file.number, &file.name, file.direction)?;
+            entry.checksum = file.checksum(self.strict_checksums);
+            entry.duration = Some(duration);
+            entry.out_of_order = out_of_order;
+            self.driver.record_history(&entry)?;
+            if let Some(ref mut done) = *self.out_of_order.borrow_mut() {
+                if out_of_order {
+                    done.insert(file.number, file.direction);
+                } else {
+                    done.remove(&file.number);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Whether a failure of the migration is rolled back entirely, leaving the database clean
+    fn is_atomic(&self, file: &MigrationFile) -> Result<bool> {
+        Ok(file.kind == MigrationKind::Sql && !file.annotations.online && self.driver.has_transactional_ddl())
+    }
+
+    // Refuses to migrate a read-only database, like a replica, one that isn't ready, or one a failed
+    // migration left in an unknown state
+    fn check_migratable(&self) -> Result<()> {
+        check_writable(&*self.driver)?;
+        self.driver.check_ready()?;
+        match self.driver.dirty()? {
+            Some(number) => bail!(ErrorKind::Dirty(number)),
+            None => Ok(()),
+        }
+    }
+
+    // Marks the database dirty after a migration failed partway, the error of the migration being
+    // the one reported if this fails too
+    fn mark_dirty(&self, number: i32) {
+        if let Err(e) = self.driver.set_dirty(Some(number)) {
+            error!(error = %redact_urls(&e.to_string()), "failed to mark the database dirty");
+        }
+    }
+
+    /// Sets the current migration number and clears the dirty marker, once a migration that failed
+    /// partway was completed or undone by hand so that the database matches `number`
+    pub fn force(&self, number: i32) -> Result<()> {
+        let current = self.driver.get_current_number();
+        self.driver.set_current_number(current, number)?;
+        self.driver.set_dirty(None)
+    }
+
+    /// Fails with `ErrorKind::StateConflict` if an applied migration was modified since, its file
+    /// not giving the checksum recorded in the history anymore
+    pub fn check_unmodified(&self, migrations: &Migrations) -> Result<()> {
+        if let Some(entry) = find_modified(&*self.driver, migrations, self.strict_checksums)?.first() {
+            bail!(ErrorKind::StateConflict(format!(
+                "Migration {} ({}) was modified after being applied, revert it or restore its file",
+                entry.number, entry.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs SQL that isn't a migration, like a hotfix, without changing the current number. It is
+    /// refused like a migration when the database is read-only, not ready or dirty, and is run in a
+    /// transaction rolled back on failure when the database has transactional DDL
+    pub fn apply_sql(&self, sql: &str) -> Result<()> {
+        self.check_migratable()?;
+        if !self.driver.has_transactional_ddl() {
+            return self.driver.execute(sql);
+        }
+
+        self.driver.execute("BEGIN;")?;
+        match self.driver.execute(sql) {
+            Ok(_) => self.driver.execute("COMMIT;"),
+            Err(e) => {
+                self.driver.execute("ROLLBACK;").ok();
+                Err(e)
+            }
+        }
+    }
+
+    // Runs the statements one at a time, notifying the observers before each one. A failure names
+    // the statement, unless it is setting the number once they all ran
+    fn migrate_statements(
+        &self, file: &MigrationFile, statements: &mut Iterator<Item = Result<String>>, total: Option<usize>,
+        expected: i32, number: i32
+    ) -> Result<()> {
+        let index = Cell::new(0);
+        let current = RefCell::new(String::new());
+        let exhausted = Cell::new(false);
+        let mut reported = iter::from_fn(|| match statements.next() {
+            Some(statement) => {
+                index.set(index.get() + 1);
+                if let Ok(ref statement) = statement {
+                    *current.borrow_mut() = statement.lines().next().unwrap_or("").to_owned();
+                }
+                info!(statement = index.get(), total = ?total, "running statement");
+                self.notify(&MigrationEvent::Statement {
+                    number: file.number, name: &file.name, direction: file.direction, index: index.get(), total: total,
+                });
+                Some(statement)
+            },
+            None => {
+                exhausted.set(true);
+                None
+            },
+        });
+        let res = self.driver.migrate_statements(&mut reported, &file.annotations, expected, number);
+        if exhausted.get() {
+            return res;
+        }
+        let position = match total {
+            Some(total) => format!("{}/{}", index.get(), total),
+            None => index.get().to_string(),
+        };
+        res.chain_err(|| format!("Statement {} failed: {}", position, current.borrow()))
+    }
+
+    // Whether the file is a SQL migration large enough to be streamed
+    fn is_streamed(&self, file: &MigrationFile) -> Result<bool> {
+        let threshold = match self.streaming_threshold {
+            Some(threshold) if file.kind == MigrationKind::Sql && file.annotations.assertions.is_empty() => threshold,
+            _ => return Ok(false),
+        };
+        let size = match file.content {
+            Some(ref content) => content.len() as u64,
+            None => fs::metadata(&file.path).chain_err(|| format!("Failed to read {:?}", file.path))?.len(),
+        };
+        Ok(size >= threshold)
+    }
+
+    // Whether the migration was already applied, or reverted, by `apply_out_of_order`: it was last
+    // run by it, the other way around than the current number would
+    fn done_out_of_order(&self, number: i32, direction: Direction) -> Result<bool> {
+        if self.out_of_order.borrow().is_none() {
+            let history = self.driver.history()?;
+            let mut done = BTreeMap::new();
+            for entry in history {
+                if entry.out_of_order {
+                    done.insert(entry.number, entry.direction);
+                } else {
+                    done.remove(&entry.number);
+                }
+            }
+            *self.out_of_order.borrow_mut() = Some(done);
+        }
+        if self.out_of_order.borrow().as_ref().and_then(|done| done.get(&number)) != Some(&direction) {
+            return Ok(false);
+        }
+        let current = self.driver.get_current_number();
+        Ok(match direction {
+            Direction::Up => number > current,
+            Direction::Down => number <= current,
+        })
+    }
+
+    // The environment the file is skipped in, if it is restricted to others. Down migrations are
+    // also skipped when their up migration was
+    fn skipped_in(&self, file: &MigrationFile) -> Result<Option<String>> {
+        let environment = self.environment.clone().unwrap_or_else(|| "none".to_owned());
+        let environments = &file.annotations.environments;
+        if !environments.is_empty() && !environments.contains(&environment) {
+            return Ok(Some(environment));
+        }
+        if file.direction == Direction::Down {
+            let applied = applied_migrations(&*self.driver)?;
+            if let Some(skipped_in) = applied.get(&file.number).and_then(|e| e.skipped_in.clone()) {
+                return Ok(Some(skipped_in));
+            }
+        }
+        Ok(None)
+    }
+
+    // The history entry of a migration that just ran, with a schema snapshot if enabled
+    fn history_entry(&self, number: i32, name: &str, direction: Direction) -> Result<HistoryEntry> {
+        let mut entry = HistoryEntry::new(number, name, direction);
+        entry.git_commit = self.git_commit.clone();
+        if self.schema_snapshots {
+            entry.schema_snapshot = Some(self.driver.schema().chain_err(|| "Failed to take a schema snapshot")?);
+        }
+        Ok(entry)
+    }
+
+    /// Runs the given SQL files, checking their preconditions and assertions, in a single
+    /// transaction which is always rolled back. This checks they at least execute without
+    /// changing the database, which requires a database supporting transactional DDL
+    pub fn test_files(&self, files: &[&MigrationFile]) -> Result<()> {
+        if !self.driver.has_transactional_ddl() {
+            bail!("This database can't roll back schema changes, migrations can't be tested in a transaction");
+        }
+        if let Some(file) = files.iter().find(|f| f.kind != MigrationKind::Sql) {
+            bail!("{} isn't a SQL migration and can't be tested in a transaction", file.filename);
+        }
+
+        self.driver.execute("BEGIN;")?;
+        let res = files.iter().map(|file| self.execute_checked(file)).collect::<Result<Vec<_>>>();
+        self.driver.execute("ROLLBACK;")?;
+
+        res.map(|_| ())
+    }
+
+    // Runs the content of a SQL file with its preconditions and assertions, leaving
+    // the migration number alone
+    fn execute_checked(&self, file: &MigrationFile) -> Result<()> {
+        CheckedRun::new(file)?.execute(&*self.driver)
+    }
+
+    /// Runs consecutive up SQL migrations at the same time, on up to `connections` connections of their
+    /// own, for independent migrations like index creations. The migration number is then set to the last
+    /// of the first migrations which all succeeded, and those are recorded in the history. The migrations
+    /// after a failed one are run again next time even if they succeeded, so they should be idempotent
+    /// (`CREATE INDEX IF NOT EXISTS`). Each one is run like `apply_file` runs it: in a transaction where DDL
+    /// is transactional, retried and cancelled after its timeout. Needs a migrator created with
+    /// `Migrator::from_url`
+    pub fn apply_parallel(&self, files: &[&MigrationFile], connections: usize) -> Result<()> {
+        let url = match self.url {
+            Some(ref url) => url.clone(),
+            None => bail!("Parallel migrations need a migrator created with `Migrator::from_url`"),
+        };
+        self.apply_parallel_with(files, connections, &|| get_driver(&url))
+    }
+
+    // `apply_parallel` on connections made by `connect`
+    fn apply_parallel_with(
+        &self, files: &[&MigrationFile], connections: usize, connect: &(Fn() -> Result<Box<Driver>> + Sync)
+    ) -> Result<()> {
+        self.check_migratable()?;
+        let first = match files.first() {
+            Some(file) => file.number,
+            None => return Ok(()),
+        };
+        let version = if files.iter().any(|f| !f.annotations.requires_server.is_empty()) {
+            Some(self.driver.server_version()?)
+        } else {
+            None
+        };
+        let mut runs = VecDeque::new();
+        let mut skipped = BTreeMap::new();
+        let mut done = vec![];
+        for (i, file) in files.iter().enumerate() {
+            if file.direction != Direction::Up || file.number != first + i as i32 {
+                bail!("Only consecutive up migrations can run in parallel, not {}", file.filename);
+            }
+            if file.kind != MigrationKind::Sql || file.kind != self.driver.migration_kind() || file.annotations.online {
+                bail!("{} can't run in parallel, only plain SQL migrations can", file.filename);
+            }
+            for requirement in &file.annotations.requires_server {
+                let version = version.as_ref().unwrap();
+                if !requirement.matches(version)? {
+                    bail!(ErrorKind::StateConflict(format!(
+                        "{} requires a server {} but it is {}", file.filename, requirement, version
+                    )));
+                }
+            }
+            if self.done_out_of_order(file.number, Direction::Up)? {
+                done.push(file.number);
+                continue;
+            }
+            match self.skipped_in(file)? {
+                Some(environment) => {
+                    skipped.insert(file.number, environment);
+                },
+                None => {
+                    let mut run = CheckedRun::new(file)?;
+                    run.timeout = run.timeout.or(self.timeout);
+                    if self.driver.has_transactional_ddl() {
+                        run.retry = self.retry.clone();
+                    }
+                    runs.push_back(run);
+                },
+            }
+        }
+
+        let workers = connections.max(1).min(runs.len());
+        let queue = Arc::new(Mutex::new(runs));
+        let (sender, receiver) = mpsc::channel();
+        for file in files.iter().filter(|f| !skipped.contains_key(&f.number) && !done.contains(&f.number)) {
+            self.notify(&MigrationEvent::Started { number: file.number, name: &file.name, direction: file.direction });
+        }
+        let mut results = BTreeMap::new();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let queue = queue.clone();
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let driver = connect();
+                    loop {
+                        let run = match queue.lock().unwrap().pop_front() {
+                            Some(run) => run,
+                            None => break,
+                        };
+                        let start = Instant::now();
+                        let res = match driver {
+                            Ok(ref driver) => run.migrate(&**driver),
+                            Err(ref e) => {
+                                let e = Error::from(format!("Failed to connect: {}", e));
+                                Err(Error::with_chain(e, ErrorKind::NotStarted))
+                            },
+                        };
+                        sender.send((run.number, res.map(|_| start.elapsed()))).ok();
+                    }
+                });
+            }
+            drop(sender);
+
+            while let Some((number, res)) = self.recv_keeping_alive(&receiver) {
+                let file = files[(number - first) as usize];
+                match res {
+                    Ok(duration) => self.notify(&MigrationEvent::Finished {
+                        number: number, name: &file.name, direction: file.direction, duration: duration,
+                    }),
+                    Err(ref e) => self.notify(&MigrationEvent::Failed {
+                        number: number, name: &file.name, direction: file.direction, error: e,
+                    }),
+                }
+                results.insert(number, res);
+            }
+        });
+
+        for file in files {
+            let (expected, new_number) = self.transition(file.number, Direction::Up)?;
+            if done.contains(&file.number) {
+                self.driver.set_current_number(expected, new_number)?;
+                continue;
+            }
+            let mut entry = self.history_entry(file.number, &file.name, file.direction)?;
+            match (skipped.remove(&file.number), results.remove(&file.number)) {
+                (Some(environment), _) => entry.skipped_in = Some(environment),
+                (None, Some(Ok(duration))) => {
+                    entry.checksum = file.checksum(self.strict_checksums);
+                    entry.duration = Some(duration);
+                },
+                (None, Some(Err(e))) => {
+                    if started(&e) && !self.driver.has_transactional_ddl() {
+                        self.mark_dirty(file.number);
+                    }
+                    return Err(e);
+                },
+                (None, None) => bail!("Migration {} ({}) wasn't run", file.number, file.name),
+            }
+            self.driver.set_current_number(expected, new_number)?;
+            self.driver.record_history(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, number: i32, step: &Step, direction: Direction) -> Result<()> {
+        let res = match *step {
+            Step::Files(ref migration) => {
+                migration.file(direction).and_then(|file| self.apply_file(file))
+            },
+            Step::Code { ref name, ref migration } => {
+                let span = info_span!(
+                    "migration",
+                    number = number, name = name.as_str(), direction = direction.to_string().as_str()
+                );
+                let _enter = span.enter();
+                self.observe(number, name, direction, || {
+                    self.check_migratable()?;
+                    let start = Instant::now();
+                    let (expected, new_number) = self.transition(number, direction)?;
+                    let res = if direction == Direction::Up {
+                        migration.up(&*self.driver)
+                    } else {
+                        migration.down(&*self.driver)
+                    };
+                    // Code migrations don't run in a transaction
+                    if res.is_err() {
+                        self.mark_dirty(number);
+                    }
+                    res.and_then(|_| self.driver.set_current_number(expected, new_number))
+                        .and_then(|_| {
+                            let duration = start.elapsed();
+                            let mut entry = self.history_entry(number, name, direction)?;
+                            entry.duration = Some(duration);
+                            self.driver.record_history(&entry)
+                        })
+                })
+            },
+        };
+
+        res.chain_err(|| ErrorKind::MigrationFailed(number, step.name().to_owned()))
+    }
+}
+
+// Whether the failed migration may have run some of its statements, unlike one the driver refused
+fn started(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::NotStarted => false,
+        _ => true,
+    }
+}
+
+// Runs `f` in a transaction on databases with transactional DDL, where `Driver::migrate` would
+fn in_transaction<F: FnOnce() -> Result<()>>(driver: &Driver, f: F) -> Result<()> {
+    if !driver.has_transactional_ddl() {
+        return f();
+    }
+    driver.execute("BEGIN;")?;
+    match f() {
+        Ok(_) => driver.execute("COMMIT;"),
+        Err(e) => {
+            driver.execute("ROLLBACK;").ok();
+            Err(e)
+        },
+    }
+}
+
+// The migration number before and after running migration `number` in the given direction
+fn transition(number: i32, direction: Direction) -> (i32, i32) {
+    if direction == Direction::Up { (number - 1, number) } else { (number, number - 1) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    use super::Migrator;
+    use checksum::checksum;
+    use drivers::Driver;
+    use observer::MigrationEvent;
+    use retry::RetryPolicy;
+    use timeout::CancellationToken;
+    use drivers::memory::MemoryDriver;
+    use errors::{Error, ErrorKind, Result};
+    use files::{load_migration_files, Direction};
+    use history::HistoryEntry;
+
+    // Stands in for the connections of parallel migrations, which `MemoryDriver` can't be shared with
+    #[derive(Clone, Default)]
+    struct SharedDriver {
+        executed: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Driver for SharedDriver {
+        fn ensure_migration_table_exists(&self) {}
+
+        fn remove_migration_table(&self) {}
+
+        fn get_current_number(&self) -> i32 {
+            0
+        }
+
+        fn set_current_number(&self, _: i32, _: i32) -> Result<()> {
+            Ok(())
+        }
+
+        fn migrate(&self, migration: String, _: i32, _: i32) -> Result<()> {
+            self.execute(&migration)
+        }
+
+        fn execute(&self, sql: &str) -> Result<()> {
+            self.executed.lock().unwrap().push(sql.to_owned());
+            Ok(())
+        }
+
+        fn query(&self, _: &str) -> Result<Vec<Vec<Option<String>>>> {
+            Ok(vec![])
+        }
+    }
+
+    fn create_file(path: &Path, filename: &str, content: &str) {
+        let mut f = File::create(path.join(filename)).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn migrator_with_files(driver: &MemoryDriver) -> Migrator {
+        let dir = TempDir::new("migrations").unwrap().into_path();
+        create_file(&dir, "0001.first.up.sql", "UP 1");
+        create_file(&dir, "0001.first.down.sql", "DOWN 1");
+        create_file(&dir, "0003.third.up.sql", "UP 3");
+        create_file(&dir, "0003.third.down.sql", "DOWN 3");
+
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(&dir).unwrap();
+        migrator
+    }
+
+    #[test]
+    fn test_server_requirements_are_checked() {
+        let driver = MemoryDriver::new();
+        driver.set_server_version("5.7.40-log");
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.json.up.sql", "-- dbmigrate:requires-server >=8.0\nUP 1");
+        create_file(dir.path(), "0001.json.down.sql", "DOWN 1");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+
+        assert!(migrator.up().is_err());
+        assert_eq!(driver.get_current_number(), 0);
+        driver.set_server_version("8.0.33");
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 1);
+    }
+
+    #[test]
+    fn test_schema_snapshots() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_schema_snapshots(true);
+
+        migrator.up().unwrap();
+        assert_eq!(driver.schema_snapshot(1).unwrap(), Some("UP 1".to_owned()));
+        assert_eq!(driver.schema_snapshot(2).unwrap(), Some("UP 1\nUP 2".to_owned()));
+        assert_eq!(driver.schema_snapshot(4).unwrap(), None);
+    }
+
+    #[test]
+    fn test_git_commit_is_recorded() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_git_commit(Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_owned()));
+
+        migrator.up().unwrap();
+        let history = driver.history().unwrap();
+        assert!(history.iter().all(|e| e.git_commit == Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_owned())));
+    }
+
+    #[test]
+    fn test_code_migrations_are_interleaved() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 2", "UP 3"]);
+        assert_eq!(driver.get_current_number(), 3);
+
+        migrator.down().unwrap();
+        assert_eq!(driver.executed()[3..].to_vec(), vec!["DOWN 3", "DOWN 2", "DOWN 1"]);
+        assert_eq!(driver.get_current_number(), 0);
+    }
+
+    #[test]
+    fn test_migrations_of_another_kind_are_refused() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.users.up.json", "{\"createCollection\": \"users\"}");
+        create_file(dir.path(), "0001.users.down.json", "{\"drop\": \"users\"}");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+
+        assert!(migrator.up().is_err());
+        assert!(driver.executed().is_empty());
+    }
+
+    #[test]
+    fn test_gaps_are_refused() {
+        let driver = MemoryDriver::new();
+        let migrator = migrator_with_files(&driver);
+        assert!(migrator.up().is_err());
+        assert!(driver.executed().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_modification_is_detected() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        // Another runner applies migration 2 while this one is running it
+        migrator.add_fn_migration(2, "second", |d| d.set_current_number(1, 2), |_| Ok(())).unwrap();
+
+        assert!(migrator.up().is_err());
+        assert_eq!(driver.get_current_number(), 2);
+        assert_eq!(driver.executed(), vec!["UP 1"]);
+    }
+
+    #[test]
+    fn test_lock_is_waited_for() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_lock_timeout(Duration::from_secs(0));
+
+        // Another replica is migrating
+        assert!(driver.try_lock().unwrap());
+        assert!(migrator.up().is_err());
+        assert!(driver.executed().is_empty());
+
+        driver.unlock().unwrap();
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 3);
+        // Released after the run
+        assert!(driver.try_lock().unwrap());
+    }
+
+    #[test]
+    fn test_deadlocks_are_retried() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_retry_policy(RetryPolicy { attempts: 2, backoff: Duration::from_millis(1), ..RetryPolicy::default() });
+        driver.set_transactional_ddl(true);
+
+        driver.fail_migrations(2, "Deadlock found when trying to get lock; try restarting transaction");
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 2", "UP 3"]);
+
+        migrator.down().unwrap();
+        driver.fail_migrations(1, "syntax error");
+        assert!(migrator.up().is_err());
+        assert_eq!(driver.get_current_number(), 0);
+        assert_eq!(driver.dirty().unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrations_applied_partway_are_not_retried() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_retry_policy(RetryPolicy { attempts: 2, backoff: Duration::from_millis(1), ..RetryPolicy::default() });
+
+        // Without transactional DDL, the statements before the deadlock stay applied
+        driver.fail_migrations(1, "Deadlock found when trying to get lock; try restarting transaction");
+        assert!(migrator.up().is_err());
+        assert!(driver.executed().is_empty());
+        assert_eq!(driver.dirty().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_failed_migrations_leave_the_database_dirty() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+
+        driver.fail_migrations(1, "syntax error");
+        assert!(migrator.up().is_err());
+        assert_eq!(driver.dirty().unwrap(), Some(1));
+        let err = migrator.up().unwrap_err();
+        assert!(err.iter().any(|e| e.to_string().contains("left the database dirty")));
+        assert!(driver.executed().is_empty());
+
+        migrator.force(1).unwrap();
+        assert_eq!(driver.dirty().unwrap(), None);
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 2", "UP 3"]);
+    }
+
+    #[test]
+    fn test_refused_migrations_leave_the_database_clean() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+
+        driver.refuse_migrations(1, "Vitess doesn't support foreign keys");
+        let err = migrator.up().unwrap_err();
+        assert!(err.iter().any(|e| e.to_string().contains("refused before running anything")));
+        assert_eq!(driver.dirty().unwrap(), None);
+        assert_eq!(driver.get_current_number(), 0);
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 2", "UP 3"]);
+    }
+
+    #[test]
+    fn test_migrations_run_out_of_order_are_skipped() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        for number in 1..4 {
+            create_file(dir.path(), &format!("000{}.index.up.sql", number), &format!("UP {}", number));
+            create_file(dir.path(), &format!("000{}.index.down.sql", number), &format!("DOWN {}", number));
+        }
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        let files = load_migration_files(dir.path()).unwrap();
+
+        migrator.apply_out_of_order(files[&3].up.as_ref().unwrap()).unwrap();
+        assert_eq!(driver.get_current_number(), 0);
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 3);
+        assert_eq!(driver.executed(), vec!["UP 3", "UP 1", "UP 2"]);
+
+        migrator.apply_out_of_order(files[&2].down.as_ref().unwrap()).unwrap();
+        migrator.down().unwrap();
+        assert_eq!(driver.get_current_number(), 0);
+        assert_eq!(driver.executed(), vec!["UP 3", "UP 1", "UP 2", "DOWN 2", "DOWN 3", "DOWN 1"]);
+        assert!(driver.history().unwrap().iter().filter(|e| e.out_of_order).map(|e| e.number).eq(vec![3, 2]));
+    }
+
+    #[test]
+    fn test_migrations_forced_back_are_run_again() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+
+        migrator.up().unwrap();
+        migrator.force(1).unwrap();
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 2", "UP 3", "UP 2", "UP 3"]);
+    }
+
+    #[test]
+    fn test_observers_are_notified() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |_| bail!("oops"), |_| Ok(())).unwrap();
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorded = events.clone();
+        migrator.add_observer(Box::new(move |event: &MigrationEvent| {
+            let description = match *event {
+                MigrationEvent::Started { number, .. } => format!("started {}", number),
+                MigrationEvent::Finished { number, name, .. } => format!("finished {} {}", number, name),
+                MigrationEvent::Statement { number, index, .. } => format!("statement {} of {}", index, number),
+                MigrationEvent::Failed { number, .. } => format!("failed {}", number),
+            };
+            recorded.borrow_mut().push(description);
+        }));
+
+        let err = migrator.up().unwrap_err();
+        assert!(match *err.kind() { ErrorKind::MigrationFailed(2, ref name) => name == "second", _ => false });
+        assert_eq!(*events.borrow(), vec!["started 1", "finished 1 first", "started 2", "failed 2"]);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        migrator.add_fn_migration(2, "second", move |_| Ok(cancel.cancel()), |_| Ok(())).unwrap();
+        migrator.set_cancellation_token(token);
+
+        let err = migrator.up().unwrap_err();
+        assert!(match *err.kind() { ErrorKind::Cancelled => true, _ => false });
+        assert_eq!(driver.executed(), vec!["UP 1"]);
+        assert_eq!(driver.get_current_number(), 2);
+    }
+
+    #[test]
+    fn test_migrations_of_other_environments_are_skipped() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.users.up.sql", "UP 1");
+        create_file(dir.path(), "0001.users.down.sql", "DOWN 1");
+        create_file(dir.path(), "0002.sample_users.up.sql", "-- dbmigrate:environments dev\nUP 2");
+        create_file(dir.path(), "0002.sample_users.down.sql", "DOWN 2");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        migrator.set_environment(Some("production".to_owned()));
+
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 2);
+        let history = driver.history().unwrap();
+        assert_eq!(history[1].skipped_in, Some("production".to_owned()));
+        assert!(history[0].duration.is_some());
+        assert_eq!(history[1].duration, None);
+
+        migrator.down().unwrap();
+        assert_eq!(driver.get_current_number(), 0);
+        assert_eq!(driver.executed(), vec!["UP 1", "DOWN 1"]);
+    }
+
+    #[test]
+    fn test_irreversible_migrations_are_not_reverted() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.users.up.sql", "UP 1");
+        create_file(dir.path(), "0001.users.down.sql", "DOWN 1");
+        create_file(dir.path(), "0002.purge.up.sql", "-- dbmigrate:irreversible\nUP 2");
+        create_file(dir.path(), "0003.posts.up.sql", "UP 3");
+        create_file(dir.path(), "0003.posts.down.sql", "DOWN 3");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+
+        migrator.up().unwrap();
+        assert!(migrator.down().is_err());
+        assert_eq!(driver.get_current_number(), 3);
+        assert!(!driver.executed().contains(&"DOWN 3".to_owned()));
+    }
+
+    #[test]
+    fn test_gaps_can_be_allowed() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.set_allow_gaps(true);
+
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 3);
+        migrator.down().unwrap();
+        assert_eq!(driver.get_current_number(), 0);
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 3", "DOWN 3", "DOWN 1"]);
+    }
+
+    #[test]
+    fn test_read_only_databases_are_refused() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.set_allow_gaps(true);
+        driver.set_read_only(Some("the server is a replica"));
+
+        let err = migrator.up().unwrap_err();
+        match *err.kind() {
+            ErrorKind::MigrationFailed(1, _) => (),
+            ref kind => panic!("Unexpected error: {:?}", kind),
+        }
+        assert!(err.iter().any(|e| e.to_string().contains("the server is a replica")));
+        assert!(driver.executed().is_empty());
+        assert_eq!(driver.dirty().unwrap(), None);
+
+        driver.set_read_only(None);
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1", "UP 3"]);
+    }
+
+    #[test]
+    fn test_state_conflicts() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap().into_path();
+        create_file(&dir, "0001.first.up.sql", "-- dbmigrate:precondition SELECT COUNT(*) = 0 FROM users\nUP 1");
+        create_file(&dir, "0001.first.down.sql", "DOWN 1");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(&dir).unwrap();
+
+        let err = migrator.up().unwrap_err();
+        match err.1.next_error.as_ref().and_then(|e| e.downcast_ref::<Error>()).map(Error::kind) {
+            Some(&ErrorKind::StateConflict(ref message)) => assert!(message.contains("Precondition"), "{}", message),
+            kind => panic!("Unexpected cause: {:?}", kind),
+        }
+
+        let mut entry = HistoryEntry::new(1, "first", Direction::Up);
+        entry.checksum = Some("00000000".to_owned());
+        driver.record_history(&entry).unwrap();
+        match migrator.check_unmodified(&load_migration_files(&dir).unwrap()).unwrap_err().0 {
+            ErrorKind::StateConflict(ref message) => assert!(message.contains("was modified"), "{}", message),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+
+        match migrator.add_fn_migration(1, "again", |_| Ok(()), |_| Ok(())).unwrap_err().0 {
+            ErrorKind::ParseError(_) => (),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_apply_sql() {
+        let driver = MemoryDriver::new();
+        let migrator = Migrator::new(Box::new(driver.clone()));
+        driver.set_dirty(Some(2)).unwrap();
+
+        match *migrator.apply_sql("UPDATE users SET active = 0;").unwrap_err().kind() {
+            ErrorKind::Dirty(2) => (),
+            ref kind => panic!("Unexpected error: {:?}", kind),
+        }
+        assert!(driver.executed().is_empty());
+
+        driver.set_dirty(None).unwrap();
+        migrator.apply_sql("UPDATE users SET active = 0;").unwrap();
+        assert_eq!(driver.executed(), vec!["UPDATE users SET active = 0;"]);
+        assert_eq!(driver.get_current_number(), 0);
+    }
+
+    #[test]
+    fn test_databases_not_ready_are_refused() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.countries.up.load", "table = countries\nfile = countries.csv\n");
+        create_file(dir.path(), "0001.countries.down.sql", "DELETE FROM countries;");
+        create_file(dir.path(), "countries.csv", "code,name\nfr,France\n");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        driver.set_not_ready(Some("wsrep_ready is OFF"));
+
+        let err = migrator.up().unwrap_err();
+        assert!(err.iter().any(|e| e.to_string().contains("wsrep_ready is OFF")));
+        assert!(driver.executed().is_empty());
+        assert_eq!(driver.get_current_number(), 0);
+
+        driver.set_not_ready(None);
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 1);
+    }
+
+    #[test]
+    fn test_up_stops_before_contract_migrations() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        for &(number, name) in &[(1, "add_column"), (2, "drop_column"), (3, "index")] {
+            create_file(dir.path(), &format!("000{}.{}.up.sql", number, name), &format!("UP {}", number));
+            create_file(dir.path(), &format!("000{}.{}.down.sql", number, name), &format!("DOWN {}", number));
+        }
+        create_file(dir.path(), "0002.drop_column.up.sql", "-- dbmigrate:contract 1\nUP 2");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec!["UP 1"]);
+        assert_eq!(driver.get_current_number(), 1);
+    }
+
+    #[test]
+    fn test_new_databases_start_at_the_baseline() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        fs::create_dir(dir.path().join("archive")).unwrap();
+        create_file(&dir.path().join("archive"), "0001.first.up.sql", "UP 1");
+        create_file(dir.path(), "0002.baseline.up.sql", "UP 2");
+        create_file(dir.path(), "0002.baseline.down.sql", "DOWN 2");
+        create_file(dir.path(), "0003.third.up.sql", "UP 3");
+        create_file(dir.path(), "0003.third.down.sql", "DOWN 3");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+
+        migrator.up().unwrap();
+        assert_eq!(driver.get_current_number(), 3);
+        migrator.down().unwrap();
+        assert_eq!(driver.get_current_number(), 0);
+        assert_eq!(driver.executed(), vec!["UP 2", "UP 3", "DOWN 3", "DOWN 2"]);
+    }
+
+    #[test]
+    fn test_duplicate_numbers_are_refused() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        assert!(migrator.add_fn_migration(1, "again", |_| Ok(()), |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_parallel_migrations_need_an_url() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        for number in 1..3 {
+            create_file(dir.path(), &format!("000{}.index.up.sql", number), "-- dbmigrate:parallel\nUP");
+            create_file(dir.path(), &format!("000{}.index.down.sql", number), "DOWN");
+        }
+        let files = load_migration_files(dir.path()).unwrap();
+        let up = files.values().map(|m| m.up.as_ref().unwrap()).collect::<Vec<_>>();
+
+        let migrator = Migrator::new(Box::new(driver.clone()));
+        assert!(migrator.apply_parallel(&up, 2).is_err());
+        assert_eq!(driver.get_current_number(), 0);
+        assert!(driver.executed().is_empty());
+    }
+
+    #[test]
+    fn test_parallel_migrations() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        for number in 1..4 {
+            let content = format!("-- dbmigrate:parallel\nUP {}", number);
+            create_file(dir.path(), &format!("000{}.index.up.sql", number), &content);
+            create_file(dir.path(), &format!("000{}.index.down.sql", number), "DOWN");
+        }
+        let files = load_migration_files(dir.path()).unwrap();
+        let up = files.values().map(|m| m.up.as_ref().unwrap()).collect::<Vec<_>>();
+        let connections = SharedDriver::default();
+        let connection = connections.clone();
+
+        let migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.apply_parallel_with(&up, 2, &move || Ok(Box::new(connection.clone()) as Box<Driver>)).unwrap();
+        let mut executed = connections.executed.lock().unwrap().clone();
+        executed.sort();
+        assert_eq!(executed, (1..4).map(|n| format!("-- dbmigrate:parallel\nUP {}", n)).collect::<Vec<_>>());
+        assert_eq!(driver.get_current_number(), 3);
+        let history = driver.history().unwrap();
+        assert_eq!(history.iter().map(|e| e.number).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(history.iter().all(|e| e.duration.is_some() && e.checksum.is_some()));
+    }
+
+    #[test]
+    fn test_large_migrations_are_streamed() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        create_file(dir.path(), "0001.seeds.up.sql", "-- Seeds\nINSERT INTO a VALUES ('x;y');\nINSERT INTO a VALUES (2);\n");
+        create_file(dir.path(), "0001.seeds.down.sql", "DELETE FROM a;");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        migrator.set_streaming_threshold(Some(30));
+
+        migrator.up().unwrap();
+        migrator.down().unwrap();
+        assert_eq!(driver.executed(), vec!["INSERT INTO a VALUES ('x;y')", "INSERT INTO a VALUES (2)", "DELETE FROM a;"]);
+        assert_eq!(driver.get_current_number(), 0);
+        let history = driver.history().unwrap();
+        assert_eq!(history[0].checksum, Some(checksum("-- Seeds\nINSERT INTO a VALUES ('x;y');\nINSERT INTO a VALUES (2);", false)));
+    }
+
+    #[test]
+    fn test_statement_progress() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        let up = "CREATE TABLE users (id INT);\nCREATE INDEX users_id ON users (id);\n";
+        create_file(dir.path(), "0001.users.up.sql", up);
+        create_file(dir.path(), "0001.users.down.sql", "DROP TABLE users;");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        migrator.set_statement_progress(true);
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorded = events.clone();
+        migrator.add_observer(Box::new(move |event: &MigrationEvent| {
+            if let MigrationEvent::Statement { index, total, .. } = *event {
+                recorded.borrow_mut().push((index, total));
+            }
+        }));
+
+        migrator.up().unwrap();
+        migrator.down().unwrap();
+        assert_eq!(
+            driver.executed(),
+            vec!["CREATE TABLE users (id INT)", "CREATE INDEX users_id ON users (id)", "DROP TABLE users;"]
+        );
+        assert_eq!(*events.borrow(), vec![(1, Some(2)), (2, Some(2))]);
+        assert_eq!(driver.get_current_number(), 0);
+    }
+
+    #[test]
+    fn test_statement_progress_runs_dollar_quotes_at_once() {
+        let driver = MemoryDriver::new();
+        let dir = TempDir::new("migrations").unwrap();
+        let up = "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;\nSELECT f();";
+        create_file(dir.path(), "0001.function.up.sql", up);
+        create_file(dir.path(), "0001.function.down.sql", "DROP FUNCTION f;");
+        let mut migrator = Migrator::new(Box::new(driver.clone()));
+        migrator.add_migration_files(dir.path()).unwrap();
+        migrator.set_statement_progress(true);
+        let statements = Rc::new(Cell::new(0));
+        let counted = statements.clone();
+        migrator.add_observer(Box::new(move |event: &MigrationEvent| {
+            if let MigrationEvent::Statement { .. } = *event {
+                counted.set(counted.get() + 1);
+            }
+        }));
+
+        migrator.up().unwrap();
+        assert_eq!(driver.executed(), vec![up]);
+        assert_eq!(statements.get(), 0);
+    }
+
+    #[test]
+    fn test_connection_is_kept_alive_during_pauses() {
+        let driver = MemoryDriver::new();
+        let mut migrator = migrator_with_files(&driver);
+        migrator.add_fn_migration(2, "second", |d| d.execute("UP 2"), |d| d.execute("DOWN 2")).unwrap();
+        migrator.set_sleep_between(Some(Duration::from_millis(50)));
+
+        migrator.up().unwrap();
+        assert!(!driver.executed().contains(&"SELECT 1;".to_owned()));
+        migrator.set_keep_alive(Some(Duration::from_millis(10)));
+        migrator.down().unwrap();
+        assert!(driver.executed().iter().filter(|sql| *sql == "SELECT 1;").count() >= 2);
+    }
+}
@@ -0,0 +1,69 @@
+///! Notifying embedding applications of the progress of migrations
+use std::time::Duration;
+
+use errors::Error;
+use files::Direction;
+
+/// Something that happened to a migration run by a `Migrator`
+#[derive(Debug)]
+pub enum MigrationEvent<'a> {
+    /// The migration is about to run
+    Started {
+        /// Number of the migration
+        number: i32,
+        /// Name of the migration
+        name: &'a str,
+        /// Whether it is applied or reverted
+        direction: Direction,
+    },
+    /// The migration ran successfully
+    Finished {
+        /// Number of the migration
+        number: i32,
+        /// Name of the migration
+        name: &'a str,
+        /// Whether it was applied or reverted
+        direction: Direction,
+        /// How long it took
+        duration: Duration,
+    },
+    /// A statement of a migration run one statement at a time is about to run, see
+    /// `Migrator::set_statement_progress`
+    Statement {
+        /// Number of the migration
+        number: i32,
+        /// Name of the migration
+        name: &'a str,
+        /// Whether it is applied or reverted
+        direction: Direction,
+        /// Position of the statement in the migration, from 1
+        index: usize,
+        /// How many statements the migration has, unknown for streamed migrations which aren't read
+        /// twice to count them
+        total: Option<usize>,
+    },
+    /// The migration failed, the run stops there
+    Failed {
+        /// Number of the migration
+        number: i32,
+        /// Name of the migration
+        name: &'a str,
+        /// Whether it was being applied or reverted
+        direction: Direction,
+        /// Why it failed
+        error: &'a Error,
+    },
+}
+
+/// Receives the events of the migrations a `Migrator` runs, to show progress in the
+/// application's own UI or logs. Closures taking a `&MigrationEvent` are observers.
+pub trait Observer {
+    /// Called synchronously, the migrator waits for it to return
+    fn on_event(&self, event: &MigrationEvent);
+}
+
+impl<F: Fn(&MigrationEvent)> Observer for F {
+    fn on_event(&self, event: &MigrationEvent) {
+        self(event)
+    }
+}
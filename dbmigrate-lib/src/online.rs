@@ -0,0 +1,142 @@
+///! Running MySQL `ALTER TABLE` migrations through pt-online-schema-change or gh-ost,
+///! which copy the table in the background instead of locking it
+use std::process::Command;
+
+use regex::Regex;
+use url::Url;
+
+use backup::decode;
+use errors::{redact_urls, Result, ResultExt};
+use statements::split_statements;
+
+/// Tool running the `ALTER TABLE` statements of migrations annotated `-- dbmigrate:online`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnlineTool {
+    /// Percona's pt-online-schema-change
+    PtOsc,
+    /// GitHub's gh-ost
+    GhOst,
+}
+
+/// How online schema changes are run
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnlineSchemaChange {
+    /// Which tool to use, pt-online-schema-change by default
+    pub tool: OnlineTool,
+    /// Path of the tool, looked up in the PATH by default
+    pub path: Option<String>,
+    /// Extra flags given to the tool, like `--max-load=Threads_running=25`
+    pub flags: Vec<String>,
+}
+
+impl Default for OnlineSchemaChange {
+    fn default() -> OnlineSchemaChange {
+        OnlineSchemaChange { tool: OnlineTool::PtOsc, path: None, flags: vec![] }
+    }
+}
+
+impl OnlineSchemaChange {
+    /// Runs every `ALTER TABLE` statement of the migration with the tool. The migration
+    /// can't contain anything else as the tool only knows how to alter a table
+    pub fn run(&self, url: &str, migration: &str) -> Result<()> {
+        let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+        if !["mysql", "mariadb"].contains(&parsed_url.scheme()) {
+            bail!("Online schema changes are only supported on MySQL and MariaDB");
+        }
+        for (table, alter) in parse_alters(migration)? {
+            let program = self.program();
+            let status = self.command(&parsed_url, &table, &alter).status()
+                .chain_err(|| format!("Failed to run {}, is it installed?", program))?;
+            if !status.success() {
+                bail!("{} failed to alter {}: {}", program, table, status);
+            }
+        }
+        Ok(())
+    }
+
+    fn program(&self) -> &str {
+        match self.path {
+            Some(ref path) => path,
+            None if self.tool == OnlineTool::PtOsc => "pt-online-schema-change",
+            None => "gh-ost",
+        }
+    }
+
+    fn command(&self, url: &Url, table: &str, alter: &str) -> Command {
+        let database = url.path().trim_left_matches('/');
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port().unwrap_or(3306).to_string();
+        let user = decode(url.username());
+
+        let mut cmd = Command::new(self.program());
+        match self.tool {
+            OnlineTool::PtOsc => {
+                cmd.arg("--alter").arg(alter).arg("--execute");
+                cmd.args(&self.flags);
+                cmd.arg(format!("h={},P={},u={},D={},t={}", host, port, user, database, table));
+                // Keeps the password out of the process list
+                if let Some(password) = url.password() {
+                    cmd.env("MYSQL_PWD", decode(password));
+                }
+            },
+            OnlineTool::GhOst => {
+                cmd.arg("--host").arg(host).arg("--port").arg(port).arg("--user").arg(user)
+                    .arg("--database").arg(database).arg("--table").arg(table)
+                    .arg("--alter").arg(alter).arg("--execute");
+                // gh-ost only reads it from the command line or a config file
+                if let Some(password) = url.password() {
+                    cmd.arg("--password").arg(decode(password));
+                }
+                cmd.args(&self.flags);
+            },
+        }
+        cmd
+    }
+}
+
+// The table and the changes of each `ALTER TABLE table changes;` statement
+fn parse_alters(migration: &str) -> Result<Vec<(String, String)>> {
+    let alter_re = Regex::new(r"(?is)^ALTER\s+TABLE\s+(\S+)\s+(.+)$").unwrap();
+    let mut alters = vec![];
+    for statement in split_statements(migration, ';') {
+        let captures = match alter_re.captures(&statement) {
+            Some(c) => c,
+            None => bail!("Online migrations can only contain ALTER TABLE statements, not: {}", statement),
+        };
+        // pt-osc and gh-ost take the database separately
+        let table = captures[1].rsplit('.').next().unwrap_or("").trim_matches('`').to_owned();
+        alters.push((table, captures[2].trim().to_owned()));
+    }
+    if alters.is_empty() {
+        bail!("Online migration without any ALTER TABLE statement");
+    }
+    Ok(alters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_alters, OnlineSchemaChange};
+
+    #[test]
+    fn test_parse_alters() {
+        let migration = "-- dbmigrate:online\n\
+            ALTER TABLE `app`.`users` ADD COLUMN nickname VARCHAR(255) DEFAULT 'a;b';\n\
+            ALTER TABLE events\n  ADD INDEX idx_created_at (created_at);\n";
+        assert_eq!(parse_alters(migration).unwrap(), vec![
+            ("users".to_owned(), "ADD COLUMN nickname VARCHAR(255) DEFAULT 'a;b'".to_owned()),
+            ("events".to_owned(), "ADD INDEX idx_created_at (created_at)".to_owned()),
+        ]);
+        assert!(parse_alters("ALTER TABLE users DROP COLUMN login; UPDATE users SET active = 1;").is_err());
+        assert!(parse_alters("-- dbmigrate:online\n").is_err());
+    }
+
+    #[test]
+    fn test_only_mysql_and_mariadb() {
+        let online = OnlineSchemaChange::default();
+        let error = online.run("postgres://db/app", "").unwrap_err().to_string();
+        assert!(error.contains("only supported on MySQL and MariaDB"), "{}", error);
+        // Gets past the scheme to the statements, which are checked before running the tool
+        let error = online.run("mariadb://db/app", "").unwrap_err().to_string();
+        assert!(error.contains("without any ALTER TABLE"), "{}", error);
+    }
+}
@@ -0,0 +1,107 @@
+///! Retrying migrations that failed for transient reasons, like deadlocks
+use std::thread;
+use std::time::Duration;
+
+use errors::{redact_urls, Error, Result};
+
+/// Error messages retried by default: Postgres deadlocks and serialization failures,
+/// MySQL deadlocks and lock wait timeouts, and SQLite busy databases
+pub const DEFAULT_RETRY_ON: &'static [&'static str] = &[
+    "deadlock",
+    "could not serialize access",
+    "lock wait timeout exceeded",
+    "database is locked",
+];
+
+/// How migrations failing with a transient error are retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a failed migration is retried, none by default
+    pub attempts: u32,
+    /// Pause before the first retry, doubled before each of the next ones
+    pub backoff: Duration,
+    /// Errors are retried when one of their causes contains one of these, ignoring case
+    pub retry_on: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 0,
+            backoff: Duration::from_secs(1),
+            retry_on: DEFAULT_RETRY_ON.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the error is one of the transient ones worth retrying
+    pub fn is_transient(&self, error: &Error) -> bool {
+        error.iter().any(|cause| {
+            let message = cause.to_string().to_lowercase();
+            self.retry_on.iter().any(|pattern| message.contains(&pattern.to_lowercase()))
+        })
+    }
+
+    /// Runs `f` until it succeeds, fails with an error that isn't transient, or all attempts are used
+    pub fn run<T, F: FnMut() -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(ref e) if attempt < self.attempts && self.is_transient(e) => {
+                    attempt += 1;
+                    warn!(error = %redact_urls(&e.to_string()), attempt = attempt, "transient failure, retrying");
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use errors::Error;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy { attempts: attempts, backoff: Duration::from_millis(1), ..RetryPolicy::default() }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_transient(&Error::from("Deadlock found when trying to get lock; try restarting transaction")));
+        assert!(policy.is_transient(&Error::from("ERROR: could not serialize access due to concurrent update")));
+        assert!(!policy.is_transient(&Error::from("ERROR: relation \"users\" already exists")));
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let calls = Cell::new(0);
+        let res = policy(3).run(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 { Err(Error::from("deadlock detected")) } else { Ok(calls.get()) }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        calls.set(0);
+        assert!(policy(1).run(|| -> Result<(), Error> {
+            calls.set(calls.get() + 1);
+            Err(Error::from("deadlock detected"))
+        }).is_err());
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        assert!(policy(3).run(|| -> Result<(), Error> {
+            calls.set(calls.get() + 1);
+            Err(Error::from("syntax error"))
+        }).is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
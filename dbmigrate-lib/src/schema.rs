@@ -0,0 +1,188 @@
+///! Comparing schema dumps
+use std::collections::BTreeSet;
+
+use errors::{ErrorKind, Result};
+
+/// Turns the rows describing schema objects into a dump, one object per line in a stable order.
+/// The dbmigrate tables are left out
+pub fn rows_to_schema(rows: Vec<Vec<Option<String>>>) -> String {
+    let mut lines = rows.into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|v| v.unwrap_or_else(|| "NULL".to_owned()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|line| !line.contains("__dbmigrate"))
+        .collect::<Vec<_>>();
+    lines.sort();
+    lines.join("\n")
+}
+
+/// Fails with `ErrorKind::StateConflict`, listing the differences, if the schema of the database isn't
+/// the one its applied migrations give
+pub fn check_drift(expected: &str, actual: &str) -> Result<()> {
+    let drift = diff(expected, actual);
+    if !drift.is_empty() {
+        bail!(ErrorKind::StateConflict(format!(
+            "The database schema differs from the one its applied migrations give:\n  {}", drift.join("\n  ")
+        )));
+    }
+    Ok(())
+}
+
+/// Lists the differences between two schema dumps: lines only in `before`
+/// are prefixed by `-` and lines only in `after` by `+`
+pub fn diff(before: &str, after: &str) -> Vec<String> {
+    let before_lines = before.lines().collect::<BTreeSet<_>>();
+    let after_lines = after.lines().collect::<BTreeSet<_>>();
+
+    before_lines.difference(&after_lines).map(|l| format!("- {}", l))
+        .chain(after_lines.difference(&before_lines).map(|l| format!("+ {}", l)))
+        .collect()
+}
+
+// Kind, object and name of a dump line: `column <table> <column> ...`, `index <table> <index> ...` and
+// `view <view> ...` for Postgres and MySQL, `<kind> <name> <sql>` for SQLite where the name is left empty
+fn object_key(line: &str) -> (&str, &str, &str) {
+    let mut parts = line.splitn(4, ' ');
+    let kind = parts.next().unwrap_or("");
+    let object = parts.next().unwrap_or("");
+    let name = parts.next().unwrap_or("");
+    match kind {
+        "column" | "index" if name != "CREATE" => (kind, object, name),
+        _ => (kind, object, ""),
+    }
+}
+
+/// Best-effort SQL reverting the changes between the schema dumps taken `before` and `after` an up
+/// migration: the tables, columns, indexes and views it created are dropped and everything else,
+/// such as modified or dropped objects, is left as TODO comments. It always needs a review
+pub fn generate_down(before: &str, after: &str) -> String {
+    let before_lines = before.lines().collect::<BTreeSet<_>>();
+    let after_lines = after.lines().collect::<BTreeSet<_>>();
+    let before_keys = before_lines.iter().map(|l| object_key(l)).collect::<BTreeSet<_>>();
+    let before_tables = before_keys.iter()
+        .filter(|&&(kind, _, _)| kind == "column" || kind == "table")
+        .map(|&(_, object, _)| object)
+        .collect::<BTreeSet<_>>();
+
+    let mut views = BTreeSet::new();
+    let mut indexes = BTreeSet::new();
+    let mut columns = BTreeSet::new();
+    let mut tables = BTreeSet::new();
+    let mut todos = vec![];
+    for line in after_lines.difference(&before_lines) {
+        let key = object_key(line);
+        if before_keys.contains(&key) {
+            todos.push(format!("-- TODO: revert the change of {}", line));
+            continue;
+        }
+        match key {
+            ("column", table, column) if before_tables.contains(table) => {
+                columns.insert(format!("ALTER TABLE {} DROP COLUMN {};", table, column));
+            },
+            ("column", table, _) | ("table", table, _) => {
+                tables.insert(format!("DROP TABLE {};", table));
+            },
+            ("index", index, "") => {
+                indexes.insert(format!("DROP INDEX {};", index));
+            },
+            // Dropped along with their table
+            ("index", table, _) if !before_tables.contains(table) => (),
+            ("index", table, index) => {
+                // Postgres tables are qualified by their schema, which the index shares
+                indexes.insert(match table.find('.') {
+                    Some(i) => format!("DROP INDEX {}.{};", &table[..i], index),
+                    None => format!("DROP INDEX {} ON {};", index, table),
+                });
+            },
+            ("view", view, _) => {
+                views.insert(format!("DROP VIEW {};", view));
+            },
+            ("trigger", trigger, _) => {
+                views.insert(format!("DROP TRIGGER {};", trigger));
+            },
+            _ => todos.push(format!("-- TODO: drop {}", line)),
+        }
+    }
+    for line in before_lines.difference(&after_lines) {
+        if !after_lines.iter().any(|l| object_key(l) == object_key(line)) {
+            todos.push(format!("-- TODO: restore {}", line));
+        }
+    }
+
+    let mut down = vec!["-- Generated from the schema changes of the up migration, review it before use".to_owned()];
+    down.extend(views);
+    down.extend(indexes);
+    down.extend(columns);
+    down.extend(tables);
+    down.extend(todos);
+    down.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_drift, diff, generate_down, rows_to_schema};
+    use errors::ErrorKind;
+
+    #[test]
+    fn test_rows_to_schema() {
+        let rows = vec![
+            vec![Some("table".to_owned()), Some("users".to_owned()), None],
+            vec![Some("table".to_owned()), Some("__dbmigrate_table".to_owned()), None],
+            vec![Some("index".to_owned()), Some("users_email".to_owned()), Some("users".to_owned())],
+        ];
+        assert_eq!(rows_to_schema(rows), "index users_email users\ntable users NULL");
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = "table users\ncolumn users.id integer";
+        let after = "table users\ncolumn users.id bigint";
+        assert_eq!(diff(before, after), vec!["- column users.id integer", "+ column users.id bigint"]);
+        assert!(diff(before, before).is_empty());
+    }
+
+    #[test]
+    fn test_check_drift() {
+        let expected = "table users\ncolumn users.id integer";
+        check_drift(expected, expected).unwrap();
+        match check_drift(expected, "table users").unwrap_err().0 {
+            ErrorKind::StateConflict(ref message) => assert!(message.ends_with("\n  - column users.id integer")),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_generate_down() {
+        let before = "column public.users id integer NO NULL\n\
+            column public.users login text NO NULL";
+        let after = "column public.users id bigint NO NULL\n\
+            column public.users email text YES NULL\n\
+            column public.posts id integer NO NULL\n\
+            index public.posts posts_pkey CREATE UNIQUE INDEX posts_pkey ON public.posts USING btree (id)\n\
+            index public.users users_email CREATE INDEX users_email ON public.users USING btree (email)";
+        assert_eq!(generate_down(before, after), "\
+            -- Generated from the schema changes of the up migration, review it before use\n\
+            DROP INDEX public.users_email;\n\
+            ALTER TABLE public.users DROP COLUMN email;\n\
+            DROP TABLE public.posts;\n\
+            -- TODO: revert the change of column public.users id bigint NO NULL\n\
+            -- TODO: restore column public.users login text NO NULL\n\
+        ");
+    }
+
+    #[test]
+    fn test_generate_down_sqlite() {
+        let before = "table users CREATE TABLE users (id INTEGER)";
+        let after = "table users CREATE TABLE users (id INTEGER)\n\
+            table posts CREATE TABLE posts (id INTEGER)\n\
+            index posts_id CREATE INDEX posts_id ON posts (id)";
+        assert_eq!(
+            generate_down(before, after),
+            "-- Generated from the schema changes of the up migration, review it before use\n\
+            DROP INDEX posts_id;\nDROP TABLE posts;\n"
+        );
+    }
+}
@@ -0,0 +1,22 @@
+///! Running script migrations
+use std::process::Command;
+
+use errors::{Result, ResultExt};
+use files::MigrationFile;
+
+/// Runs an executable migration file, giving it the database URL, migration number and
+/// direction through the `DBMIGRATE_URL`, `DBMIGRATE_NUMBER` and `DBMIGRATE_DIRECTION`
+/// environment variables. Its output goes to the terminal.
+pub fn run_script(file: &MigrationFile, url: &str) -> Result<()> {
+    let status = Command::new(&file.path)
+        .env("DBMIGRATE_URL", url)
+        .env("DBMIGRATE_NUMBER", file.number.to_string())
+        .env("DBMIGRATE_DIRECTION", file.direction.to_string())
+        .status()
+        .chain_err(|| format!("Failed to run {}", file.filename))?;
+
+    if !status.success() {
+        bail!("{} failed: {}", file.filename, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+///! Splitting scripts into statements, for databases and tools running one statement at a time
+use std::collections::VecDeque;
+
+use regex::Regex;
+
+use errors::Result;
+
+/// Statements of the script without its comment lines, split on the terminator, usually `;`,
+/// when it isn't quoted
+pub fn split_statements(sql: &str, terminator: char) -> Vec<String> {
+    Statements::new(sql.lines().map(|line| Ok(line.to_owned())), terminator)
+        .filter_map(|statement| statement.ok())
+        .collect()
+}
+
+/// The statements of a script given line by line, split like `split_statements` as the lines are read
+/// so that the whole script is never in memory. The lines may still end with their line ending
+pub struct Statements<I> {
+    lines: I,
+    terminator: char,
+    comment: &'static str,
+    quote: Option<char>,
+    current: String,
+    started: bool,
+    ready: VecDeque<String>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<String>>> Statements<I> {
+    /// Splits the lines on the terminator, leaving out the SQL comment lines starting with `--`
+    pub fn new(lines: I, terminator: char) -> Statements<I> {
+        Statements::with_comment(lines, terminator, "--")
+    }
+
+    /// Splits the lines on the terminator, leaving out the comment lines starting with the given
+    /// prefix, like `//` in Cypher where `--` starts relationships
+    pub fn with_comment(lines: I, terminator: char, comment: &'static str) -> Statements<I> {
+        Statements {
+            lines: lines,
+            terminator: terminator,
+            comment: comment,
+            quote: None,
+            current: String::new(),
+            started: false,
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn push_line(&mut self, line: &str) {
+        let line = strip_line_ending(line);
+        if line.trim().starts_with(self.comment) {
+            return;
+        }
+        if self.started {
+            self.current.push('\n');
+        }
+        self.started = true;
+        for c in line.chars() {
+            match (c, self.quote) {
+                (_, None) if c == self.terminator => {
+                    let statement = self.current.split_off(0);
+                    self.push_statement(&statement);
+                },
+                ('\'', None) | ('"', None) | ('`', None) => {
+                    self.quote = Some(c);
+                    self.current.push(c);
+                },
+                (_, Some(q)) if c == q => {
+                    self.quote = None;
+                    self.current.push(c);
+                },
+                _ => self.current.push(c),
+            }
+        }
+    }
+
+    fn push_statement(&mut self, statement: &str) {
+        let statement = statement.trim();
+        if !statement.is_empty() {
+            self.ready.push_back(statement.to_owned());
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<String>>> Iterator for Statements<I> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        loop {
+            if let Some(statement) = self.ready.pop_front() {
+                return Some(Ok(statement));
+            }
+            if self.done {
+                return None;
+            }
+            match self.lines.next() {
+                Some(Ok(line)) => self.push_line(&line),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+                None => {
+                    self.done = true;
+                    let last = self.current.split_off(0);
+                    self.push_statement(&last);
+                },
+            }
+        }
+    }
+}
+
+/// The byte offsets at which the statements of the script start, past the whitespace and comments
+/// before them. Quoted strings, Postgres dollar quotes and comments are skipped over, so what they
+/// contain never starts a statement
+pub fn statement_starts(sql: &str) -> Vec<usize> {
+    let dollar_tag = Regex::new(r"^\$([A-Za-z_][A-Za-z0-9_]*)?\$").unwrap();
+    let mut starts = vec![];
+    let mut at_start = true;
+    let mut i = 0;
+    while let Some(c) = sql[i..].chars().next() {
+        let rest = &sql[i..];
+        // Where the comment or quote starting here ends, the rest of the script if it doesn't
+        let skip_to = |from: usize, end: &str| rest[from..].find(end).map_or(sql.len(), |n| i + from + n + end.len());
+        if rest.starts_with("--") {
+            i = skip_to(2, "\n");
+            continue;
+        }
+        if rest.starts_with("/*") {
+            i = skip_to(2, "*/");
+            continue;
+        }
+        if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        }
+        if at_start {
+            starts.push(i);
+            at_start = false;
+        }
+        i = match c {
+            ';' => {
+                at_start = true;
+                i + 1
+            },
+            '\'' | '"' | '`' => skip_to(1, &c.to_string()),
+            '$' => match dollar_tag.find(rest) {
+                Some(tag) => skip_to(tag.end(), tag.as_str()),
+                None => i + 1,
+            },
+            _ => i + c.len_utf8(),
+        };
+    }
+    starts
+}
+
+/// Whether the script has Postgres dollar quotes, like function bodies, which the splitting on the
+/// terminator doesn't know about
+pub fn has_dollar_quotes(sql: &str) -> bool {
+    Regex::new(r"\$([A-Za-z_][A-Za-z0-9_]*)?\$").unwrap().is_match(sql)
+}
+
+/// The line without its `\n` or `\r\n` ending
+pub fn strip_line_ending(line: &str) -> &str {
+    let line = if line.ends_with('\n') { &line[..line.len() - 1] } else { line };
+    if line.ends_with('\r') { &line[..line.len() - 1] } else { line }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_dollar_quotes, split_statements, statement_starts, Statements};
+
+    #[test]
+    fn test_split_statements() {
+        let script = "-- Users\nCREATE TABLE users (name VARCHAR(10) DEFAULT 'a;b');\nINSERT INTO users VALUES ('c');";
+        assert_eq!(split_statements(script, ';'), vec![
+            "CREATE TABLE users (name VARCHAR(10) DEFAULT 'a;b')",
+            "INSERT INTO users VALUES ('c')",
+        ]);
+        assert_eq!(split_statements("BEGIN ATOMIC\n  UPDATE a SET b = 1;\nEND\n@\n", '@'), vec![
+            "BEGIN ATOMIC\n  UPDATE a SET b = 1;\nEND",
+        ]);
+    }
+
+    #[test]
+    fn test_statements_are_split_as_lines_are_read() {
+        let lines = vec!["-- Seeds\r\n", "INSERT INTO users VALUES ('a;\n", "b'); INSERT INTO users\n", "VALUES ('c');"];
+        let statements = Statements::new(lines.iter().map(|line| Ok(line.to_string())), ';')
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(statements, vec!["INSERT INTO users VALUES ('a;\nb')", "INSERT INTO users\nVALUES ('c')"]);
+
+        let failing = vec![Ok("INSERT INTO a VALUES (1); INSERT".to_owned()), Err("Failed to read".into())];
+        let mut statements = Statements::new(failing.into_iter(), ';');
+        assert_eq!(statements.next().unwrap().unwrap(), "INSERT INTO a VALUES (1)");
+        assert!(statements.next().unwrap().is_err());
+        assert!(statements.next().is_none());
+    }
+
+    #[test]
+    fn test_other_comments() {
+        let lines = vec!["// People", "MATCH (p:Person)", "--(c:Company) SET p.employed = true;"];
+        let statements = Statements::with_comment(lines.iter().map(|line| Ok(line.to_string())), ';', "//")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(statements, vec!["MATCH (p:Person)\n--(c:Company) SET p.employed = true"]);
+    }
+
+    #[test]
+    fn test_statement_starts() {
+        let script = "-- CREATE EXTENSION in a comment;\nSELECT 'a;b';\n\
+            /* ; */ CREATE FUNCTION f() AS $body$ SELECT 1; $body$;";
+        let starts = statement_starts(script);
+        assert_eq!(starts.iter().map(|&i| &script[i..i + 6]).collect::<Vec<_>>(), vec!["SELECT", "CREATE"]);
+        assert_eq!(statement_starts("SELECT 1;  ").len(), 1);
+    }
+
+    #[test]
+    fn test_has_dollar_quotes() {
+        assert!(has_dollar_quotes("CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;"));
+        assert!(has_dollar_quotes("DO $body$ BEGIN NULL; END $body$;"));
+        assert!(!has_dollar_quotes("SELECT $1, '$'; UPDATE a SET price = '$5';"));
+    }
+}
@@ -0,0 +1,34 @@
+///! Temporary databases for tests
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use drivers::{create_database, drop_database};
+use errors::Result;
+
+/// A uniquely named database created on a server, to run migrations into during tests.
+/// It needs to be dropped explicitly with `drop_database`, after closing connections to it.
+#[derive(Debug)]
+pub struct TemporaryDatabase {
+    /// URL to connect to the temporary database
+    pub url: String,
+    /// Name of the database
+    pub name: String,
+    // URL of the server it was created from
+    server_url: String,
+}
+
+impl TemporaryDatabase {
+    /// Creates a new database on the server the URL points to
+    pub fn create(server_url: &str) -> Result<TemporaryDatabase> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let name = format!("dbmigrate_test_{}_{}", timestamp, process::id());
+        let url = create_database(server_url, &name)?;
+
+        Ok(TemporaryDatabase { url: url, name: name, server_url: server_url.to_owned() })
+    }
+
+    /// Drops the database
+    pub fn drop_database(self) -> Result<()> {
+        drop_database(&self.server_url, &self.name)
+    }
+}
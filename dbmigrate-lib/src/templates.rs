@@ -0,0 +1,251 @@
+///! Skeletons of the common migrations, for `create --template` and `create --interactive`
+use std::str::FromStr;
+
+use errors::{Error, Result};
+
+/// A common kind of migration whose up and down SQL can be written ahead, with placeholders
+/// like `table_name` left to replace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Template {
+    /// Creating a table, dropped by the down migration
+    CreateTable,
+    /// Adding a column to a table
+    AddColumn,
+    /// Creating an index
+    AddIndex,
+}
+
+impl Template {
+    /// All the names accepted by `from_str`
+    pub fn variants() -> [&'static str; 3] {
+        ["create_table", "add_column", "add_index"]
+    }
+
+    /// SQL of the up migration, starting with a description
+    pub fn up(&self) -> &'static str {
+        match *self {
+            Template::CreateTable => "\
+-- Creates table_name
+CREATE TABLE table_name (
+    id INTEGER NOT NULL PRIMARY KEY,
+    column_name column_type NOT NULL
+);
+",
+            Template::AddColumn => "\
+-- Adds column_name to table_name
+ALTER TABLE table_name ADD COLUMN column_name column_type;
+",
+            Template::AddIndex => "\
+-- Indexes table_name by column_name
+CREATE INDEX index_name ON table_name (column_name);
+",
+        }
+    }
+
+    /// SQL of the up migration for the database of the URL scheme: MariaDB is asked to add columns
+    /// instantly, failing rather than copying the table
+    pub fn up_for(&self, scheme: &str) -> String {
+        match (*self, scheme) {
+            (Template::AddColumn, "mariadb") => self.up().replace(" column_type;", " column_type, ALGORITHM=INSTANT;"),
+            _ => self.up().to_owned(),
+        }
+    }
+
+    /// SQL of the down migration, undoing the up one
+    pub fn down(&self) -> &'static str {
+        match *self {
+            Template::CreateTable => "DROP TABLE table_name;\n",
+            Template::AddColumn => "ALTER TABLE table_name DROP COLUMN column_name;\n",
+            // MySQL wants the table too: DROP INDEX index_name ON table_name
+            Template::AddIndex => "DROP INDEX index_name;\n",
+        }
+    }
+}
+
+/// A table to create, as described to `create --interactive`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableDefinition {
+    /// Name of the table
+    pub name: String,
+    /// Whether the table starts with an auto incremented `id` primary key
+    pub id: bool,
+    /// The other columns
+    pub columns: Vec<ColumnDefinition>,
+    /// Indexes created with the table
+    pub indexes: Vec<IndexDefinition>,
+}
+
+/// A column of a `TableDefinition`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnDefinition {
+    /// Name of the column
+    pub name: String,
+    /// Type, as written in SQL (`VARCHAR(255)` for example)
+    pub sql_type: String,
+    /// Whether it can be NULL
+    pub nullable: bool,
+}
+
+/// An index of a `TableDefinition`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexDefinition {
+    /// The indexed columns, in order
+    pub columns: Vec<String>,
+    /// Whether the index is unique
+    pub unique: bool,
+}
+
+impl TableDefinition {
+    /// The up and down SQL of the table for the database of the URL scheme (`postgres`, `mysql`...),
+    /// which only changes how the `id` column is auto incremented
+    pub fn sql(&self, scheme: &str) -> (String, String) {
+        let mut columns = vec![];
+        if self.id {
+            columns.push(format!("id {}", id_column(scheme)));
+        }
+        for column in &self.columns {
+            let null = if column.nullable { "" } else { " NOT NULL" };
+            columns.push(format!("{} {}{}", column.name, column.sql_type, null));
+        }
+
+        let mut up = format!(
+            "-- Creates {}\nCREATE TABLE {} (\n    {}\n);\n", self.name, self.name, columns.join(",\n    ")
+        );
+        for index in &self.indexes {
+            up.push_str(&format!(
+                "CREATE {}INDEX {}_{}_{} ON {} ({});\n",
+                if index.unique { "UNIQUE " } else { "" }, self.name, index.columns.join("_"),
+                if index.unique { "key" } else { "idx" }, self.name, index.columns.join(", ")
+            ));
+        }
+        // Dropping the table drops its indexes
+        (up, format!("DROP TABLE {};\n", self.name))
+    }
+}
+
+// An auto incremented primary key in each database's syntax
+fn id_column(scheme: &str) -> &'static str {
+    match scheme {
+        "postgres" => "BIGSERIAL PRIMARY KEY",
+        "mysql" | "mariadb" | "tidb" => "BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY",
+        "sqlite" => "INTEGER PRIMARY KEY AUTOINCREMENT",
+        "oracle" | "db2" => "INTEGER GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY",
+        _ => "INTEGER NOT NULL PRIMARY KEY",
+    }
+}
+
+impl FromStr for ColumnDefinition {
+    type Err = Error;
+
+    /// Parses `name TYPE`, followed by `null` if the column can be NULL
+    fn from_str(s: &str) -> Result<ColumnDefinition> {
+        let mut words = s.split_whitespace().collect::<Vec<_>>();
+        let nullable = words.len() > 2 && words[words.len() - 1].eq_ignore_ascii_case("null");
+        if nullable {
+            words.pop();
+        }
+        if words.len() < 2 {
+            bail!("A column needs a name and a type");
+        }
+        Ok(ColumnDefinition {
+            name: words[0].to_owned(),
+            sql_type: words[1..].join(" "),
+            nullable: nullable,
+        })
+    }
+}
+
+impl FromStr for IndexDefinition {
+    type Err = Error;
+
+    /// Parses comma separated columns, followed by `unique` for a unique index
+    fn from_str(s: &str) -> Result<IndexDefinition> {
+        let s = s.trim();
+        let (columns, unique) = match s.rfind(char::is_whitespace) {
+            Some(i) if s[i..].trim().eq_ignore_ascii_case("unique") => (&s[..i], true),
+            _ => (s, false),
+        };
+        let columns = columns.split(',').map(|c| c.trim().to_owned()).filter(|c| !c.is_empty()).collect::<Vec<_>>();
+        if columns.is_empty() {
+            bail!("An index needs at least one column");
+        }
+        Ok(IndexDefinition { columns: columns, unique: unique })
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Template> {
+        match s {
+            "create_table" => Ok(Template::CreateTable),
+            "add_column" => Ok(Template::AddColumn),
+            "add_index" => Ok(Template::AddIndex),
+            _ => bail!("Unknown template {}, expected one of {}", s, Template::variants().join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColumnDefinition, IndexDefinition, TableDefinition, Template};
+    use files::description;
+
+    #[test]
+    fn test_templates_are_described() {
+        for name in Template::variants().iter() {
+            let template = name.parse::<Template>().unwrap();
+            assert!(description(template.up()).is_some());
+            assert!(!template.down().is_empty());
+        }
+        assert!("drop_table".parse::<Template>().is_err());
+        assert!(Template::AddColumn.up_for("mariadb").contains("column_type, ALGORITHM=INSTANT;"));
+        assert_eq!(Template::AddColumn.up_for("mysql"), Template::AddColumn.up());
+    }
+
+    #[test]
+    fn test_table_definition_sql() {
+        let table = TableDefinition {
+            name: "users".to_owned(),
+            id: true,
+            columns: vec![
+                ColumnDefinition { name: "email".to_owned(), sql_type: "VARCHAR(255)".to_owned(), nullable: false },
+                ColumnDefinition { name: "bio".to_owned(), sql_type: "TEXT".to_owned(), nullable: true },
+            ],
+            indexes: vec![IndexDefinition { columns: vec!["email".to_owned()], unique: true }],
+        };
+        let (up, down) = table.sql("mysql");
+        assert_eq!(up, "-- Creates users\nCREATE TABLE users (\n    \
+            id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,\n    email VARCHAR(255) NOT NULL,\n    bio TEXT\n);\n\
+            CREATE UNIQUE INDEX users_email_key ON users (email);\n");
+        assert_eq!(down, "DROP TABLE users;\n");
+        assert!(table.sql("postgres").0.contains("id BIGSERIAL PRIMARY KEY"));
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        assert_eq!(
+            "bio  TEXT NULL".parse::<ColumnDefinition>().unwrap(),
+            ColumnDefinition { name: "bio".to_owned(), sql_type: "TEXT".to_owned(), nullable: true }
+        );
+        assert_eq!(
+            "price NUMERIC(10, 2)".parse::<ColumnDefinition>().unwrap(),
+            ColumnDefinition { name: "price".to_owned(), sql_type: "NUMERIC(10, 2)".to_owned(), nullable: false }
+        );
+        // A single word after the name is the type, even `null`
+        assert_eq!("deleted null".parse::<ColumnDefinition>().unwrap().sql_type, "null");
+        assert!("email".parse::<ColumnDefinition>().is_err());
+
+        assert_eq!(
+            "team_id, email UNIQUE".parse::<IndexDefinition>().unwrap(),
+            IndexDefinition { columns: vec!["team_id".to_owned(), "email".to_owned()], unique: true }
+        );
+        assert_eq!(
+            "created_at".parse::<IndexDefinition>().unwrap(),
+            IndexDefinition { columns: vec!["created_at".to_owned()], unique: false }
+        );
+        assert_eq!("İd,\tnaïve Unique".parse::<IndexDefinition>().unwrap().columns, vec!["İd", "naïve"]);
+        assert!(", unique".parse::<IndexDefinition>().is_err());
+        assert_eq!("unique".parse::<IndexDefinition>().unwrap().columns, vec!["unique"]);
+    }
+}
@@ -0,0 +1,56 @@
+///! Postgres schema-per-tenant databases, each schema being migrated and tracked on its own
+use url::Url;
+
+use csv_load::quote;
+use drivers::{with_postgres_option, Driver};
+use errors::{redact_urls, Result, ResultExt};
+
+fn schemas_query(pattern: &str) -> String {
+    format!("SELECT nspname FROM pg_namespace WHERE nspname LIKE {} ORDER BY nspname;", quote(pattern))
+}
+
+/// Names of the schemas matching the `LIKE` pattern, such as `tenant_%`, in alphabetical order
+pub fn tenant_schemas(driver: &Driver, pattern: &str) -> Result<Vec<String>> {
+    let rows = driver.query(&schemas_query(pattern))
+        .chain_err(|| format!("Failed to list the schemas matching {}", pattern))?;
+    Ok(rows.into_iter().filter_map(|row| row.into_iter().next().and_then(|v| v)).collect())
+}
+
+/// The Postgres URL with its `search_path` set to the schema, which is where the migrations
+/// and the tracking table end up
+pub fn schema_url(url: &str, schema: &str) -> Result<String> {
+    let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", redact_urls(url)))?;
+    if parsed_url.scheme() != "postgres" {
+        bail!("Tenant schemas are only supported on Postgres");
+    }
+    with_postgres_option(url, &format!("-c search_path={}", schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schema_url, schemas_query, tenant_schemas};
+    use drivers::memory::MemoryDriver;
+
+    #[test]
+    fn test_tenant_schemas() {
+        let driver = MemoryDriver::new();
+        driver.set_query_result(&schemas_query("tenant_%"), vec![
+            vec![Some("tenant_a".to_owned())],
+            vec![Some("tenant_b".to_owned())],
+        ]);
+        assert_eq!(tenant_schemas(&driver, "tenant_%").unwrap(), vec!["tenant_a", "tenant_b"]);
+    }
+
+    #[test]
+    fn test_schema_url() {
+        assert_eq!(
+            schema_url("postgres://app@localhost/app", "tenant_a").unwrap(),
+            "postgres://app@localhost/app?options=-c%20search_path%3Dtenant_a"
+        );
+        assert_eq!(
+            schema_url("postgres://app@localhost/app?sslmode=require&options=-c%20work_mem%3D64MB", "tenant_a").unwrap(),
+            "postgres://app@localhost/app?sslmode=require&options=-c%20work_mem%3D64MB%20-c%20search_path%3Dtenant_a"
+        );
+        assert!(schema_url("mysql://app@localhost/app", "tenant_a").is_err());
+    }
+}
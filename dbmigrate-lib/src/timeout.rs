@@ -0,0 +1,130 @@
+///! Cancelling running migrations, when they take longer than allowed or on request
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use errors::{redact_urls, ErrorKind, Result, ResultExt};
+
+/// Cancels what a driver is running, called from another thread
+pub type Canceller = Box<Fn() -> Result<()> + Send>;
+
+/// Parses durations like `90`, `90s`, `500ms`, `10m` or `2h`, a plain number being seconds
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    let value = s[..split].parse::<u64>().chain_err(|| format!("Invalid duration: {}", s))?;
+    match &s[split..] {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        unit => bail!("Invalid duration unit {} in {}, expected ms, s, m or h", unit, s),
+    }
+}
+
+/// A thread calling the canceller unless stopped before the timeout
+pub struct Watchdog {
+    sender: Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Starts counting
+    pub fn start(timeout: Duration, cancel: Canceller) -> Watchdog {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || match receiver.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(timeout_secs = timeout.as_secs(), "migration timed out, cancelling it");
+                if let Err(e) = cancel() {
+                    error!(error = %redact_urls(&e.to_string()), "failed to cancel the migration");
+                }
+                true
+            },
+            _ => false,
+        });
+        Watchdog { sender: sender, thread: thread }
+    }
+
+    /// Stops the watchdog, returning whether it cancelled what was running
+    pub fn stop(self) -> bool {
+        self.sender.send(()).ok();
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+/// Lets another thread stop a `Migrator`, to shut down gracefully while migrations run at startup.
+/// The migrator checks it before each migration and, on Postgres and MySQL, cancels the migration
+/// running when `cancel` is called. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    in_flight: Arc<Mutex<Option<Canceller>>>,
+}
+
+impl CancellationToken {
+    /// Creates a token that isn't cancelled
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Stops the migrator using the token, which fails with `ErrorKind::Cancelled`
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(ref cancel) = *self.in_flight.lock().unwrap() {
+            if let Err(e) = cancel() {
+                error!(error = %redact_urls(&e.to_string()), "failed to cancel the migration");
+            }
+        }
+    }
+
+    /// Whether `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs `f`, which `token.cancel()` interrupts with the canceller meanwhile
+pub fn with_in_flight<T, F: FnOnce() -> Result<T>>(token: &CancellationToken, cancel: Canceller, f: F) -> Result<T> {
+    *token.in_flight.lock().unwrap() = Some(cancel);
+    // It might have been cancelled before the canceller was there
+    let res = if token.is_cancelled() { Err(ErrorKind::Cancelled.into()) } else { f() };
+    *token.in_flight.lock().unwrap() = None;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse_duration, Watchdog};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let watchdog = Watchdog::start(Duration::from_secs(60), Box::new(move || Ok(flag.store(true, Ordering::SeqCst))));
+        assert!(!watchdog.stop());
+        assert!(!cancelled.load(Ordering::SeqCst));
+
+        let flag = cancelled.clone();
+        let watchdog = Watchdog::start(Duration::from_millis(1), Box::new(move || Ok(flag.store(true, Ordering::SeqCst))));
+        thread::sleep(Duration::from_millis(50));
+        assert!(watchdog.stop());
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+}
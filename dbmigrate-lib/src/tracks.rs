@@ -0,0 +1,261 @@
+///! Independent migration tracks sharing a database, each with its own number and history
+use std::iter;
+use std::path::Path;
+
+use regex::Regex;
+
+use annotations::Annotations;
+use dirty;
+use drivers::{query_value, Driver};
+use errors::{ErrorKind, Result, ResultExt};
+use files::MigrationKind;
+use history::{self, HistoryEntry};
+use timeout::Canceller;
+
+/// A driver tracking its migrations apart from the other tracks of the database, in the
+/// `__dbmigrate_table_<name>` and `__dbmigrate_history_<name>` tables. Components sharing a
+/// database can then number their migrations without coordinating, the lock staying shared.
+/// Migrations are run by the driver, updating the number of the track instead of its own
+pub struct Track {
+    driver: Box<Driver>,
+    name: String,
+    table: String,
+    history_table: String,
+}
+
+impl Track {
+    /// Wraps the driver, creating the table of the track if needed. Names are made of lowercase
+    /// letters, digits and underscores and only SQL databases have tracks
+    pub fn new(driver: Box<Driver>, name: &str) -> Result<Track> {
+        lazy_static! {
+            static ref NAME: Regex = Regex::new(r"^[a-z0-9_]+$").unwrap();
+        }
+        if !NAME.is_match(name) {
+            bail!(ErrorKind::ParseError(format!(
+                "Invalid track name {:?}, only lowercase letters, digits and underscores are allowed", name
+            )));
+        }
+        if driver.migration_kind() != MigrationKind::Sql {
+            bail!("Tracks are only supported by SQL databases");
+        }
+        let track = Track {
+            driver: driver,
+            name: name.to_owned(),
+            table: format!("__dbmigrate_table_{}", name),
+            history_table: format!("__dbmigrate_history_{}", name),
+        };
+        track.create_table()?;
+        track.ensure_history_table()?;
+        Ok(track)
+    }
+
+    /// Name of the track
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn create_table(&self) -> Result<()> {
+        self.driver.execute(&format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER, current INTEGER);", self.table))
+            .chain_err(|| format!("Failed to create the table of track {}", self.name))?;
+        if self.driver.query(&format!("SELECT current FROM {} WHERE id = 1;", self.table))?.is_empty() {
+            self.driver.execute(&format!("INSERT INTO {} (id, current) VALUES (1, 0);", self.table))?;
+        }
+        Ok(())
+    }
+
+    fn current_number(&self) -> Result<i32> {
+        let current = query_value(self, &format!("SELECT current FROM {} WHERE id = 1;", self.table))?;
+        current.parse().chain_err(|| format!("Invalid current migration of track {}: {}", self.name, current))
+    }
+
+    // The statement setting the number of the track, run with the migration for the driver to
+    // handle it like its own. Checked before as the number of updated rows isn't known, the
+    // migration lock keeping other processes from changing it in between
+    fn update_statement(&self, expected: i32, number: i32) -> Result<String> {
+        if self.current_number()? != expected {
+            bail!(ErrorKind::ConcurrentModification(expected));
+        }
+        Ok(format!("UPDATE {} SET current = {} WHERE id = 1 AND current = {}", self.table, number, expected))
+    }
+
+    // The migration followed by the statement setting the number of the track, on a line of its own
+    // in case the migration ends with a comment
+    fn with_update(&self, mut migration: String, expected: i32, number: i32) -> Result<String> {
+        let update = self.update_statement(expected, number)?;
+        let terminated = migration.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with("--"))
+            .last().map_or(true, |line| line.ends_with(';'));
+        migration.push_str(if terminated { "\n" } else { "\n;\n" });
+        migration.push_str(&update);
+        migration.push_str(";\n");
+        Ok(migration)
+    }
+}
+
+impl Driver for Track {
+    fn ensure_migration_table_exists(&self) {
+        self.create_table().unwrap();
+    }
+
+    fn remove_migration_table(&self) {
+        self.driver.execute(&format!("DROP TABLE {};", self.table)).unwrap();
+    }
+
+    fn get_current_number(&self) -> i32 {
+        self.current_number().unwrap()
+    }
+
+    fn set_current_number(&self, expected: i32, number: i32) -> Result<()> {
+        self.driver.execute(&format!("{};", self.update_statement(expected, number)?))
+    }
+
+    // The driver keeps its own number as it is
+    fn migrate(&self, migration: String, expected: i32, number: i32) -> Result<()> {
+        let current = self.driver.get_current_number();
+        self.driver.migrate(self.with_update(migration, expected, number)?, current, current)
+    }
+
+    fn migrate_with_assertions(&self, migration: String, expected: i32, number: i32, assertions: &[String]) -> Result<()> {
+        let current = self.driver.get_current_number();
+        let migration = self.with_update(migration, expected, number)?;
+        self.driver.migrate_with_assertions(migration, current, current, assertions)
+    }
+
+    fn migrate_statements(
+        &self, statements: &mut Iterator<Item = Result<String>>, annotations: &Annotations, expected: i32,
+        number: i32
+    ) -> Result<()> {
+        let current = self.driver.get_current_number();
+        let mut statements = statements.chain(iter::once(self.update_statement(expected, number)));
+        self.driver.migrate_statements(&mut statements, annotations, current, current)
+    }
+
+    fn has_transactional_ddl(&self) -> bool {
+        self.driver.has_transactional_ddl()
+    }
+
+    fn canceller(&self) -> Result<Option<Canceller>> {
+        self.driver.canceller()
+    }
+
+    fn server_version(&self) -> Result<String> {
+        self.driver.server_version()
+    }
+
+    fn read_only(&self) -> Result<Option<String>> {
+        self.driver.read_only()
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.driver.ping()
+    }
+
+    fn execute(&self, sql: &str) -> Result<()> {
+        self.driver.execute(sql)
+    }
+
+    fn query(&self, sql: &str) -> Result<Vec<Vec<Option<String>>>> {
+        self.driver.query(sql)
+    }
+
+    fn schema(&self) -> Result<String> {
+        self.driver.schema()
+    }
+
+    fn load_csv(&self, table: &str, path: &Path) -> Result<()> {
+        self.driver.load_csv(table, path)
+    }
+
+    fn try_lock(&self) -> Result<bool> {
+        self.driver.try_lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.driver.unlock()
+    }
+
+    fn ensure_history_table(&self) -> Result<()> {
+        history::ensure_table(&*self.driver, &self.history_table)
+    }
+
+    fn record_history(&self, entry: &HistoryEntry) -> Result<()> {
+        history::insert_entry(&*self.driver, &self.history_table, entry)
+    }
+
+    fn history(&self) -> Result<Vec<HistoryEntry>> {
+        history::select_entries(&*self.driver, &self.history_table)
+    }
+
+    fn remove_history(&self, number: i32) -> Result<()> {
+        history::delete_entries(&*self.driver, &self.history_table, number)
+    }
+
+    fn schema_snapshot(&self, number: i32) -> Result<Option<String>> {
+        history::select_schema_snapshot(&*self.driver, &self.history_table, number)
+    }
+
+    fn update_checksum(&self, number: i32, checksum: &str) -> Result<()> {
+        history::update_checksum(&*self.driver, &self.history_table, number, checksum)
+    }
+
+    fn dirty(&self) -> Result<Option<i32>> {
+        dirty::select_dirty(&*self.driver, &self.table)
+    }
+
+    fn set_dirty(&self, number: Option<i32>) -> Result<()> {
+        dirty::update_dirty(&*self.driver, &self.table, number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Track;
+    use drivers::Driver;
+    use drivers::memory::MemoryDriver;
+    use errors::ErrorKind;
+
+    #[test]
+    fn test_tracks_have_their_own_table() {
+        let driver = MemoryDriver::new();
+        let select = "SELECT current FROM __dbmigrate_table_billing WHERE id = 1;";
+        driver.set_query_result(select, vec![vec![Some("3".to_owned())]]);
+        let track = Track::new(Box::new(driver.clone()), "billing").unwrap();
+
+        assert_eq!(track.get_current_number(), 3);
+        assert_eq!(driver.get_current_number(), 0);
+        track.set_current_number(3, 4).unwrap();
+        let update = "UPDATE __dbmigrate_table_billing SET current = 4 WHERE id = 1 AND current = 3;";
+        assert!(driver.executed().contains(&update.to_owned()));
+        match track.set_current_number(2, 3).unwrap_err().0 {
+            ErrorKind::ConcurrentModification(2) => (),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_migrations_are_run_by_the_driver() {
+        let driver = MemoryDriver::new();
+        let select = "SELECT current FROM __dbmigrate_table_billing WHERE id = 1;";
+        driver.set_query_result(select, vec![vec![Some("3".to_owned())]]);
+        let track = Track::new(Box::new(driver.clone()), "billing").unwrap();
+
+        track.migrate("CREATE TABLE invoices (id INT)\n-- Done".to_owned(), 3, 4).unwrap();
+        track.migrate("DROP TABLE invoices;".to_owned(), 3, 4).unwrap();
+        let mut statements = vec![Ok("CREATE TABLE invoices (id INT)".to_owned())].into_iter();
+        track.migrate_statements(&mut statements, &Default::default(), 3, 4).unwrap();
+        let update = "UPDATE __dbmigrate_table_billing SET current = 4 WHERE id = 1 AND current = 3";
+        let executed = driver.executed().into_iter().filter(|sql| !sql.starts_with("SELECT")).collect::<Vec<_>>();
+        assert_eq!(executed[executed.len() - 4..].to_vec(), vec![
+            format!("CREATE TABLE invoices (id INT)\n-- Done\n;\n{};\n", update),
+            format!("DROP TABLE invoices;\n{};\n", update),
+            "CREATE TABLE invoices (id INT)".to_owned(),
+            update.to_owned(),
+        ]);
+        assert_eq!(driver.get_current_number(), 0);
+        assert!(track.migrate("SELECT 1;".to_owned(), 2, 3).is_err());
+    }
+
+    #[test]
+    fn test_invalid_track_names_are_refused() {
+        assert!(Track::new(Box::new(MemoryDriver::new()), "Billing; DROP TABLE users").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+///! Server versions and the requirements migrations can put on them
+use std::fmt;
+use std::str::FromStr;
+
+use errors::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/// A constraint on the server version, like `>=8.0`, from `-- dbmigrate:requires-server` annotations.
+/// Versions are compared on the components the requirement gives, so `=8.0` is met by 8.0.33
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionRequirement {
+    operator: Operator,
+    version: Vec<u32>,
+}
+
+impl VersionRequirement {
+    /// Whether the version reported by the server meets the requirement
+    pub fn matches(&self, server_version: &str) -> Result<bool> {
+        let mut server = match parse_version(server_version) {
+            Some(v) => v,
+            None => bail!("Can't read the server version {}", server_version),
+        };
+        server.resize(self.version.len(), 0);
+
+        Ok(match self.operator {
+            Operator::Eq => server == self.version,
+            Operator::Gt => server > self.version,
+            Operator::Ge => server >= self.version,
+            Operator::Lt => server < self.version,
+            Operator::Le => server <= self.version,
+        })
+    }
+}
+
+impl FromStr for VersionRequirement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<VersionRequirement> {
+        let (operator, version) = if s.starts_with(">=") {
+            (Operator::Ge, &s[2..])
+        } else if s.starts_with("<=") {
+            (Operator::Le, &s[2..])
+        } else if s.starts_with('>') {
+            (Operator::Gt, &s[1..])
+        } else if s.starts_with('<') {
+            (Operator::Lt, &s[1..])
+        } else if s.starts_with('=') {
+            (Operator::Eq, &s[1..])
+        } else {
+            (Operator::Eq, s)
+        };
+
+        match parse_version(version) {
+            Some(ref v) if v.len() == version.split('.').count() => Ok(VersionRequirement { operator: operator, version: v.clone() }),
+            _ => bail!("Invalid version requirement {}, expected something like >=8.0", s),
+        }
+    }
+}
+
+impl fmt::Display for VersionRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self.operator {
+            Operator::Eq => "=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+        };
+        let version = self.version.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        write!(f, "{}{}", operator, version.join("."))
+    }
+}
+
+// The leading numbers of versions like `8.0.33-0ubuntu0.22.04.1` or `14.5 (Debian 14.5-1.pgdg110+1)`
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    let end = version.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(version.len());
+    let numbers = version[..end].split('.')
+        .take_while(|n| !n.is_empty())
+        .map(|n| n.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>();
+    numbers.and_then(|n| if n.is_empty() { None } else { Some(n) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, VersionRequirement};
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("8.0.33-0ubuntu0.22.04.1"), Some(vec![8, 0, 33]));
+        assert_eq!(parse_version("14.5 (Debian 14.5-1.pgdg110+1)"), Some(vec![14, 5]));
+        assert_eq!(parse_version("10.5.8-MariaDB"), Some(vec![10, 5, 8]));
+        assert_eq!(parse_version("unknown"), None);
+    }
+
+    #[test]
+    fn test_requirements() {
+        let at_least_8: VersionRequirement = ">=8.0".parse().unwrap();
+        assert!(at_least_8.matches("8.0.33").unwrap());
+        assert!(!at_least_8.matches("5.7.40-log").unwrap());
+        assert!("=8.0".parse::<VersionRequirement>().unwrap().matches("8.0.33").unwrap());
+        assert!("<9".parse::<VersionRequirement>().unwrap().matches("8.0.33").unwrap());
+        assert!(!">8.0".parse::<VersionRequirement>().unwrap().matches("8.0.33").unwrap());
+        assert_eq!(at_least_8.to_string(), ">=8.0");
+        assert!(">=8.x".parse::<VersionRequirement>().is_err());
+        assert!(at_least_8.matches("unknown").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use serde_json;
+
+use errors::{redact_urls, Result, ResultExt};
+use report::Report;
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    started_at: &'a str,
+    finished_at: String,
+    user: Option<String>,
+    /// Database URL, without its password
+    url: Option<String>,
+    #[serde(flatten)]
+    report: &'a Report,
+}
+
+/// Appends the report of an invocation to the audit log, creating the file if needed.
+/// The log is JSON lines: one object per invocation, never rewritten.
+pub fn append(path: &Path, url: Option<&str>, report: &Report, started_at: &str) -> Result<()> {
+    let entry = AuditEntry {
+        started_at: started_at,
+        finished_at: ::dbmigrate_lib::utc_now(),
+        user: ::dbmigrate_lib::current_user(),
+        url: url.map(redact_urls),
+        report: report,
+    };
+    let mut line = serde_json::to_string(&entry).chain_err(|| "Failed to serialize the audit entry")?;
+    line.push('\n');
+
+    OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .chain_err(|| format!("Failed to write the audit log {}", path.display()))
+}
@@ -1,34 +1,59 @@
-use std::path::Path;
-use std::time::Instant;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use dbmigrate_lib::{Driver, create_migration, Migrations, Direction};
-use print;
-use errors::{Result};
+use dbmigrate_lib::{
+    ARCHIVE_DIRECTORY, HistoryEntry, Migrator, OnlineSchemaChange, RetryPolicy, TemporaryDatabase, create_migration,
+    archive_migrations, create_migration_with_content, generate_down, read_migration_files,
+    read_migration_directories, import_state, check_drift, schema_diff, accept_modified, applied_migrations,
+    find_modified, find_orphans, is_settled, prune_orphans, ColumnDefinition, Direction, ImportSource, LintTarget,
+    IndexDefinition, MigrationEvent, MigrationFile, MigrationKind, Migrations, ReadOptions, TableDefinition, Template,
+    checksum,
+};
+use dbmigrate_lib::backup as backup_database;
+use dbmigrate_lib::{sign_bundle, verify_signature, Bundle, BUNDLE_FILENAME};
+use config::{self, Target, CONFIG_FILENAME};
+use git;
+use serde_json::Value;
+use state::Snapshot;
+use print::{self, Format};
+use progress::Progress;
+use report;
+use dbmigrate_lib::errors::ErrorKind as LibErrorKind;
+use errors::{ErrorKind, Result, ResultExt};
 
 
-// Does the whole migration thingy, along with timing and handling errors
+// Does the whole migration thingy, along with timing, progress and handling errors
 macro_rules! migrate {
-    ($driver: ident, $mig_file: ident) => {
-        println!(
-            "Running {} migration #{}: {}",
+    ($migrator: ident, $mig_file: ident, $progress: ident) => {
+        migrate!($migrator, $mig_file, $progress, apply_file)
+    };
+    ($migrator: ident, $mig_file: ident, $progress: ident, $apply: ident) => {
+        let description = format!(
+            "{} migration #{}: {}",
             $mig_file.direction.to_string(), $mig_file.number, $mig_file.name
         );
+        $progress.start(&format!("Running {}", description));
         let res = {
             let start = Instant::now();
 
-            match $driver.migrate(
-                $mig_file.content.clone().unwrap(),
-                if $mig_file.direction == Direction::Up { $mig_file.number } else { $mig_file.number - 1}
-            ) {
+            match $migrator.$apply($mig_file) {
                 Err(e) => Err(e),
                 Ok(_) => {
                     let duration = start.elapsed();
-                    print::success(&format!("> Done in {} second(s)", duration.as_secs()));
+                    report::record($mig_file, duration);
+                    $progress.done(&format!("> Ran {} in {} second(s)", description, duration.as_secs()));
                     Ok(())
                 }
             }
         };
         if res.is_err() {
+            $progress.finish();
             return res.map_err(|e| e.into());
         }
     }
@@ -46,85 +71,1267 @@ pub fn create(migration_files: &Migrations, path: &Path, slug: &str) -> Result<(
     }
 }
 
+/// Same as `create`, the files being filled with the SQL of the template for the database of the URL scheme
+pub fn create_from_template(
+    migration_files: &Migrations, path: &Path, slug: &str, template: Template, scheme: &str
+) -> Result<()> {
+    let number = migration_files.keys().cloned().max().unwrap_or(0) + 1;
+    create_migration_with_content(path, slug, number, &template.up_for(scheme), template.down())?;
+    print::success("Migration files successfully created, replace the placeholders of their SQL");
+    Ok(())
+}
+
+/// Same as `create`, asking for the table the migration creates to write its SQL for the database
+/// of the URL scheme
+pub fn create_interactive(migration_files: &Migrations, path: &Path, slug: &str, scheme: &str) -> Result<()> {
+    println!("Describe the table to create, empty answers keeping the default in brackets");
+    let default_name = slug.trim_left_matches("create_").replace(" ", "_");
+    let name = prompt(&format!("Table name [{}]: ", default_name))?;
+    let mut table = TableDefinition {
+        name: if name.is_empty() { default_name } else { name },
+        id: !prompt("Start with an auto incremented id primary key? [Y/n] ")?.eq_ignore_ascii_case("n"),
+        ..TableDefinition::default()
+    };
+
+    println!("Columns as `name TYPE`, followed by `null` if they can be NULL, an empty line to finish:");
+    loop {
+        let line = prompt("  column: ")?;
+        if line.is_empty() {
+            break;
+        }
+        match line.parse::<ColumnDefinition>() {
+            Ok(column) => table.columns.push(column),
+            Err(e) => print::error(&e.to_string()),
+        }
+    }
+
+    println!("Indexes as comma separated columns, followed by `unique` for unique ones, an empty line to finish:");
+    loop {
+        let line = prompt("  index: ")?;
+        if line.is_empty() {
+            break;
+        }
+        match line.parse::<IndexDefinition>() {
+            Ok(index) => table.indexes.push(index),
+            Err(e) => print::error(&e.to_string()),
+        }
+    }
+    if !table.id && table.columns.is_empty() {
+        bail!("The table needs at least one column");
+    }
+
+    let (up, down) = table.sql(scheme);
+    let number = migration_files.keys().cloned().max().unwrap_or(0) + 1;
+    create_migration_with_content(path, slug, number, &up, &down)?;
+    print::success("Migration files successfully created!");
+    Ok(())
+}
+
+// Asks the question and returns the trimmed answer, empty at the end of the input
+fn prompt(question: &str) -> Result<String> {
+    print!("{}", question);
+    let mut answer = String::new();
+    io::stdout().flush()
+        .and_then(|_| io::stdin().read_line(&mut answer))
+        .chain_err(|| "Failed to read the answer")?;
+    Ok(answer.trim().to_owned())
+}
+
+/// Writes the bundle of the migrations in their folder, signing it with the given gpg key or the default one
+pub fn bundle(migration_files: &Migrations, path: &Path, sign: bool, key: Option<&str>) -> Result<()> {
+    let bundle_path = path.join(BUNDLE_FILENAME);
+    Bundle::create(migration_files)?.write(&bundle_path)?;
+    print::success(&format!("Bundle written to {}", bundle_path.display()));
+    if sign {
+        let signature = sign_bundle(&bundle_path, key)?;
+        print::success(&format!("Signature written to {}", signature.display()));
+    }
+    Ok(())
+}
+
+/// Checks the signature of the bundle of the migrations folder and that the migrations match it
+pub fn verify_bundle(migration_files: &Migrations, path: &Path, keyring: Option<&Path>) -> Result<()> {
+    let bundle_path = path.join(BUNDLE_FILENAME);
+    verify_signature(&bundle_path, keyring)?;
+    Bundle::read(&bundle_path)?.check(migration_files)
+        .chain_err(|| "The migrations don't match the signed bundle, refusing to apply them")?;
+    print::success("The migrations match the signed bundle");
+    Ok(())
+}
+
+/// Lists the uncommitted changes of the migrations folders, failing when `refuse` is set so that
+/// local edits are never applied to production
+pub fn check_git(paths: &[PathBuf], refuse: bool) -> Result<()> {
+    let mut changes = vec![];
+    for path in paths {
+        changes.extend(git::uncommitted_changes(path)?);
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    for change in &changes {
+        print::error(&format!("Not committed: {}", change));
+    }
+    if refuse {
+        bail!("{} file(s) of the migrations aren't committed to git, refusing to apply them", changes.len());
+    }
+    print::error("Warning: the migrations aren't all committed to git");
+    Ok(())
+}
+
+/// Creates a migration whose up SQL is read from stdin and whose down SQL is generated from the
+/// schema changes the up migration makes on an empty scratch database, which is left migrated
+pub fn create_with_auto_down(migration_files: &Migrations, path: &Path, slug: &str, scratch_url: &str) -> Result<()> {
+    let mut up = String::new();
+    io::stdin().read_to_string(&mut up).chain_err(|| "Failed to read the up migration from stdin")?;
+    if up.trim().is_empty() {
+        bail!("The up migration read from stdin is empty");
+    }
+
+    let migrator = Migrator::from_url(scratch_url).chain_err(|| "Failed to get scratch DB connection")?;
+    let driver = migrator.driver();
+    if driver.get_current_number() != 0 {
+        bail!("The scratch database already has migrations applied, it needs to be empty");
+    }
+    println!("Applying the existing migrations to the scratch database");
+    for migration in migration_files.values() {
+        if let Some(ref file) = migration.up {
+            migrator.apply_file(file)?;
+        }
+    }
+
+    let before = driver.schema()?;
+    driver.execute(&up).chain_err(|| "The up migration failed on the scratch database")?;
+    let after = driver.schema()?;
+    if schema_diff(&before, &after).is_empty() {
+        bail!("The up migration doesn't change the schema, there is nothing to generate a down migration from");
+    }
+    let down = generate_down(&before, &after);
+    match driver.execute(&down) {
+        Ok(_) if driver.schema()? == before => print::success("The generated down migration restores the schema"),
+        Ok(_) => print::error("The generated down migration doesn't fully restore the schema, complete its TODOs"),
+        Err(e) => print::error(&format!("The generated down migration fails: {}", e)),
+    }
 
-pub fn status(driver: Box<Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number();
+    let number = migration_files.keys().cloned().max().unwrap_or(0i32) + 1;
+    create_migration_with_content(path, slug, number, &up, &down)?;
+    print::success("Migration files successfully created, review the generated down migration!");
+    Ok(())
+}
+
+pub fn init(path: &Path, slug: Option<&str>) -> Result<()> {
+    if !path.exists() {
+        println!("Creating {}", path.display());
+        fs::create_dir_all(path).chain_err(|| format!("Failed to create {}", path.display()))?;
+    }
+
+    if Path::new(CONFIG_FILENAME).exists() {
+        println!("{} already exists, leaving it untouched", CONFIG_FILENAME);
+    } else {
+        println!("Creating {}", CONFIG_FILENAME);
+        File::create(CONFIG_FILENAME)
+            .and_then(|mut f| f.write_all(config::starter(&path.to_string_lossy()).as_bytes()))
+            .chain_err(|| format!("Failed to create {}", CONFIG_FILENAME))?;
+    }
+
+    if let Some(slug) = slug {
+        let migration_files = read_migration_files(path)?;
+        return create(&migration_files, path, slug);
+    }
+
+    print::success("Project initialized!");
+    Ok(())
+}
+
+
+pub fn status(migrator: &Migrator, migration_files: &Migrations, format: Format) -> Result<()> {
+    let status = status_value(migrator, migration_files)?;
+    if format != Format::Table {
+        print::structured(&status, format);
+        return Ok(());
+    }
+
+    let current = status["current"].as_i64().unwrap_or(0);
     if current == 0 {
         print::success("No migration has been ran");
     }
-    for (number, migration) in migration_files.iter() {
-        let mig_file = migration.up.as_ref().unwrap();
-        if number == &current {
-            print::success(&format!("{} - {} (current)", mig_file.number, mig_file.name));
-        } else {
-            println!("{} - {}", mig_file.number, mig_file.name);
+    let text = |value: &Value| value.as_str().unwrap_or("").to_owned();
+    let rows = status["migrations"].as_array().map_or(vec![], |migrations| migrations.iter().map(|m| {
+        let state = match (m["applied"].as_bool(), m["skipped_in"].as_str()) {
+            (Some(true), Some(environment)) => format!("skipped in {}", environment),
+            (Some(true), None) => "applied".to_owned(),
+            _ => "pending".to_owned(),
+        };
+        let state = if m["number"].as_i64() == Some(current) { format!("{} (current)", state) } else { state };
+        vec![m["number"].to_string(), text(&m["name"]), state, text(&m["applied_at"]), text(&m["applied_by"])]
+    }).collect());
+    print::table(&["NUMBER", "NAME", "STATE", "APPLIED AT", "APPLIED BY"], &rows);
+    if let Some(dirty) = status["dirty"].as_i64() {
+        print::error(&format!("Migration {} failed partway and left the database dirty, see `dbmigrate force`", dirty));
+    }
+
+    for &(key, title) in &[
+        ("orphans", "Applied migrations without matching files, see `dbmigrate prune`:"),
+        ("modified", "Applied migrations modified since:"),
+    ] {
+        let entries = status[key].as_array().cloned().unwrap_or_default();
+        if !entries.is_empty() {
+            print::error(title);
+            for entry in &entries {
+                print::error(&format!("{} - {} (applied {})", entry["number"], text(&entry["name"]), text(&entry["applied_at"])));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The status of every migration, with the applied migrations whose files are missing or were modified,
+/// as printed by `status --format json` and returned by `serve`
+pub fn status_value(migrator: &Migrator, migration_files: &Migrations) -> Result<Value> {
+    let current = migrator.driver().get_current_number();
+    // Migrations applied before the history existed don't have an entry
+    let applied = applied_migrations(migrator.driver())?;
+    let migrations = migration_files.iter().map(|(number, migration)| {
+        let entry = applied.get(number).filter(|_| *number <= current);
+        json!({
+            "number": number,
+            "name": migration.up.as_ref().map(|f| f.name.as_str()),
+            "applied": *number <= current,
+            "applied_at": entry.map(|e| e.applied_at.as_str()),
+            "applied_by": entry.and_then(|e| e.applied_by.as_ref()),
+            "skipped_in": entry.and_then(|e| e.skipped_in.as_ref()),
+        })
+    }).collect::<Vec<_>>();
+    let entry_value = |entry: &HistoryEntry| json!({
+        "number": entry.number,
+        "name": entry.name,
+        "applied_at": entry.applied_at,
+        "applied_by": entry.applied_by,
+    });
+    let orphans = find_orphans(migrator.driver(), migration_files, migrator.baseline())?;
+    let modified = find_modified(migrator.driver(), migration_files, migrator.strict_checksums())?;
+
+    Ok(json!({
+        "current": current,
+        "latest": migration_files.keys().max().cloned().unwrap_or(0),
+        "dirty": migrator.driver().dirty()?,
+        "migrations": migrations,
+        "orphans": orphans.iter().map(&entry_value).collect::<Vec<_>>(),
+        "modified": modified.iter().map(&entry_value).collect::<Vec<_>>(),
+    }))
+}
+
+/// Prints every migration applied or reverted on the database, oldest first
+pub fn history(migrator: &Migrator, format: Format) -> Result<()> {
+    let history = migrator.driver().history()?;
+    let entries = history.iter().map(|entry| json!({
+        "number": entry.number,
+        "name": entry.name,
+        "direction": entry.direction.to_string(),
+        "applied_at": entry.applied_at,
+        "applied_by": entry.applied_by,
+        "hostname": entry.hostname,
+        "git_commit": entry.git_commit,
+        "duration_ms": entry.duration_ms(),
+        "skipped_in": entry.skipped_in,
+        "out_of_order": entry.out_of_order,
+    })).collect::<Vec<_>>();
+    if format != Format::Table {
+        print::structured(&Value::Array(entries), format);
+        return Ok(());
+    }
+
+    if history.is_empty() {
+        print::success("No migration has been ran");
+        return Ok(());
+    }
+    let rows = history.iter().map(|entry| {
+        let duration = match (entry.duration_ms(), entry.skipped_in.as_ref()) {
+            (_, Some(environment)) => format!("skipped in {}", environment),
+            (Some(ms), None) => format!("{}ms", ms),
+            (None, None) => String::new(),
+        };
+        vec![
+            entry.number.to_string(), entry.name.clone(), entry.direction.to_string(), entry.applied_at.clone(),
+            entry.applied_by.clone().unwrap_or_default(), entry.hostname.clone().unwrap_or_default(),
+            // Abbreviated like git does
+            entry.git_commit.as_ref().map_or(String::new(), |c| c.chars().take(7).collect()), duration,
+        ]
+    }).collect::<Vec<_>>();
+    print::table(&["NUMBER", "NAME", "DIRECTION", "AT", "BY", "HOST", "COMMIT", "DURATION"], &rows);
+    Ok(())
+}
+
+/// Prints the migrations applied on one database but not the other, such as staging and production
+pub fn status_diff(migrator: &Migrator, other: &Migrator, migration_files: &Migrations, format: Format) -> Result<()> {
+    let applied = applied_names(migrator, migration_files)?;
+    let other_applied = applied_names(other, migration_files)?;
+    let only_on = |from: &BTreeMap<i32, String>, to: &BTreeMap<i32, String>| from.iter()
+        .filter(|&(number, _)| !to.contains_key(number))
+        .map(|(number, name)| json!({"number": number, "name": name}))
+        .collect::<Vec<_>>();
+    let only_here = only_on(&applied, &other_applied);
+    let only_other = only_on(&other_applied, &applied);
+    let renamed = applied.iter()
+        .filter_map(|(number, name)| other_applied.get(number).filter(|other_name| *other_name != name)
+            .map(|other_name| json!({"number": number, "name": name, "other_name": other_name})))
+        .collect::<Vec<_>>();
+    if format != Format::Table {
+        print::structured(&json!({
+            "current": applied.len(),
+            "other_current": other_applied.len(),
+            "only_here": only_here,
+            "only_other": only_other,
+            "renamed": renamed,
+        }), format);
+        return Ok(());
+    }
+
+    println!("This database is at migration {}, the other one at {}", applied.len(), other_applied.len());
+    for &(label, ref missing) in &[("this", &only_here), ("the other", &only_other)] {
+        if !missing.is_empty() {
+            println!("Only applied on {} database:", label);
+            for entry in missing.iter() {
+                println!("  {} - {}", entry["number"], entry["name"].as_str().unwrap_or(""));
+            }
         }
     }
+    for entry in &renamed {
+        print::error(&format!(
+            "Migration {} is {} on this database and {} on the other",
+            entry["number"], entry["name"].as_str().unwrap_or(""), entry["other_name"].as_str().unwrap_or("")
+        ));
+    }
+
+    if only_here.is_empty() && only_other.is_empty() && renamed.is_empty() {
+        print::success("Both databases have the same migrations applied");
+    }
     Ok(())
 }
 
+// Names of the applied migrations, from the history or the files for the ones applied before it existed
+fn applied_names(migrator: &Migrator, migration_files: &Migrations) -> Result<BTreeMap<i32, String>> {
+    let current = migrator.driver().get_current_number();
+    let history = applied_migrations(migrator.driver())?;
+    Ok((1..current + 1).map(|number| {
+        let name = history.get(&number).map(|entry| entry.name.clone())
+            .or_else(|| migration_files.get(&number).and_then(|m| m.up.as_ref()).map(|f| f.name.clone()))
+            .unwrap_or_default();
+        (number, name)
+    }).collect())
+}
+
+/// Checks the database can be reached and dbmigrate has the permissions it needs, step by step
+pub fn ping(url: &str) -> Result<()> {
+    let start = Instant::now();
+    // Connecting creates the migration table
+    let migrator = Migrator::from_url(url).chain_err(|| "Failed to get DB connection")?;
+    let elapsed = start.elapsed();
+    print::success(&format!(
+        "Connected in {}ms, the migration table exists",
+        elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos()) / 1_000_000
+    ));
+
+    match migrator.driver().server_version() {
+        Ok(version) => print::success(&format!("Server version: {}", version)),
+        Err(e) => println!("Server version unknown: {}", e),
+    }
+
+    // Tracks and the databases without SQL keep the current migration elsewhere
+    let current = migrator.driver().get_current_number();
+    print::success(&format!("Migration table readable, current migration is {}", current));
+
+    migrator.driver().set_current_number(current, current).chain_err(|| "Can't write to the migration table")?;
+    print::success("Migration table writable");
+
+    let history = migrator.driver().history().chain_err(|| "Can't create or read the history table")?;
+    print::success(&format!("History table readable, {} entries", history.len()));
+    Ok(())
+}
+
+/// Prints the current schema dump, or the snapshot taken once the given migration was applied
+pub fn schema(migrator: &Migrator, at: Option<i32>) -> Result<()> {
+    let dump = match at {
+        Some(number) => match migrator.driver().schema_snapshot(number)? {
+            Some(snapshot) => snapshot,
+            None => bail!("No schema snapshot of migration {}, was it applied with schema_snapshots enabled?", number),
+        },
+        None => migrator.driver().schema()?,
+    };
+    println!("{}", dump);
+    Ok(())
+}
+
+/// Whether all migrations are applied
+pub fn check(migrator: &Migrator, migration_files: &Migrations) -> Result<bool> {
+    // Exits with 3, the state being neither up to date nor pending
+    if let Some(number) = migrator.driver().dirty()? {
+        bail!(ErrorKind::DbMigrateLib(LibErrorKind::Dirty(number)));
+    }
+    let current = migrator.driver().get_current_number();
+    let pending = migration_files.range(current + 1..).count();
+    if pending == 0 {
+        print::success("Migrations are up-to-date");
+    } else {
+        println!("{} pending migration(s)", pending);
+    }
+    Ok(pending == 0)
+}
+
+/// Prints the migration files and the problems found reading them, without connecting to the database
+pub fn status_offline(migration_files: &Migrations, format: Format, strict_checksums: bool) -> Result<()> {
+    let mut problems = 0;
+    let migrations = migration_files.iter().map(|(number, migration)| {
+        // The up file is there, reading them would have failed otherwise
+        let up = migration.up.as_ref().unwrap();
+        let mut issues = vec![];
+        for file in migration.up.iter().chain(migration.down.iter()).filter(|f| f.kind != MigrationKind::Script) {
+            if let Err(e) = file.lines().and_then(|mut lines| lines.find(|l| l.is_err()).unwrap_or(Ok(String::new()))) {
+                issues.push(format!("{} can't be read: {}", file.filename, e));
+            }
+        }
+        if issues.is_empty() && up.kind != MigrationKind::Script && up.checksum(false) == Some(checksum("", false)) {
+            issues.push(format!("{} is empty", up.filename));
+        }
+        problems += issues.len();
+        json!({
+            "number": number,
+            "name": up.name,
+            "kind": format!("{:?}", up.kind).to_lowercase(),
+            "checksum": up.checksum(strict_checksums),
+            "issues": issues,
+        })
+    }).collect::<Vec<_>>();
+
+    if format != Format::Table {
+        print::structured(&json!({
+            "latest": migration_files.keys().max().cloned().unwrap_or(0),
+            "migrations": migrations,
+        }), format);
+    } else {
+        let rows = migrations.iter().map(|m| vec![
+            m["number"].to_string(),
+            m["name"].as_str().unwrap_or("").to_owned(),
+            m["kind"].as_str().unwrap_or("").to_owned(),
+            m["checksum"].as_str().unwrap_or("").to_owned(),
+        ]).collect::<Vec<_>>();
+        print::table(&["NUMBER", "NAME", "KIND", "CHECKSUM"], &rows);
+        for issue in migrations.iter().filter_map(|m| m["issues"].as_array()).flat_map(|issues| issues) {
+            print::error(issue.as_str().unwrap_or(""));
+        }
+    }
+    if problems > 0 {
+        bail!("{} problem(s) found in the migration files", problems);
+    }
+    if format == Format::Table {
+        print::success(&format!("{} migration(s), no problem found", migration_files.len()));
+    }
+    Ok(())
+}
+
+/// Prints what the database of the target won't handle well in the migrations, failing if anything was found
+pub fn lint(migration_files: &Migrations, target: LintTarget, format: Format) -> Result<()> {
+    let warnings = dbmigrate_lib::lint(migration_files, target)?;
+    if format != Format::Table {
+        let entries = warnings.iter()
+            .map(|warning| json!({"filename": warning.filename, "message": warning.message}))
+            .collect::<Vec<_>>();
+        print::structured(&Value::Array(entries), format);
+    } else {
+        for warning in &warnings {
+            print::error(&format!("{}: {}", warning.filename, warning.message));
+        }
+    }
+    if warnings.is_empty() {
+        if format == Format::Table {
+            print::success(&format!("No problem found for {}", target));
+        }
+        return Ok(());
+    }
+    bail!("{} problem(s) found for {}", warnings.len(), target)
+}
+
+/// Runs ad hoc SQL, like a hotfix, read from a file or from stdin for `-`, leaving the current migration as is
+pub fn apply_sql(migrator: &Migrator, source: &str) -> Result<()> {
+    let mut sql = String::new();
+    if source == "-" {
+        io::stdin().read_to_string(&mut sql).chain_err(|| "Failed to read the SQL from stdin")?;
+    } else {
+        File::open(source)
+            .and_then(|mut f| f.read_to_string(&mut sql))
+            .chain_err(|| format!("Failed to read {}", source))?;
+    }
+    if sql.trim().is_empty() {
+        bail!("The SQL to apply is empty");
+    }
+    // Recorded before running it, so that the audit log has the SQL that failed too
+    report::record_sql(&sql);
+    migrator.apply_sql(&sql).chain_err(|| "Failed to apply the SQL")?;
+    print::success("SQL applied");
+    Ok(())
+}
+
+/// Sets the current migration and clears the dirty marker, once the database was fixed by hand
+pub fn force(migrator: &Migrator, migration_files: &Migrations, number: i32) -> Result<()> {
+    if number != 0 && !migration_files.contains_key(&number) {
+        bail!("Migration {} doesn't exist", number);
+    }
+    migrator.force(number)?;
+    print::success(&format!("Current migration set to {}, the database is no longer dirty", number));
+    Ok(())
+}
+
+/// Brings the database back in line with the migration files after a failed migration was fixed by hand:
+/// clears the dirty marker, records the checksums of the modified files and forgets the deleted ones
+pub fn repair(migrator: &Migrator, migration_files: &Migrations, confirmed: bool) -> Result<()> {
+    let driver = migrator.driver();
+    let dirty = driver.dirty()?;
+    let modified = find_modified(driver, migration_files, migrator.strict_checksums())?;
+    let orphans = find_orphans(driver, migration_files, migrator.baseline())?;
+    if dirty.is_none() && modified.is_empty() && orphans.is_empty() {
+        print::success("The database matches the migration files, nothing to repair");
+        return Ok(());
+    }
+
+    if let Some(number) = dirty {
+        println!("Migration {} left the database dirty, it will be marked clean", number);
+    }
+    for entry in &modified {
+        println!("{} - {} was modified since it was applied, its checksum will be updated", entry.number, entry.name);
+    }
+    for orphan in &orphans {
+        println!("{} - {} has no file anymore, it will be removed from the history", orphan.number, orphan.name);
+    }
+    if !confirmed && !prompt("Repair the database? [y/N] ")?.eq_ignore_ascii_case("y") {
+        println!("Nothing was repaired");
+        return Ok(());
+    }
+
+    accept_modified(driver, migration_files, &modified, migrator.strict_checksums())?;
+    prune_orphans(driver, &orphans)?;
+    driver.set_dirty(None)?;
+    print::success("The database was repaired");
+    Ok(())
+}
+
+pub fn prune(migrator: &Migrator, migration_files: &Migrations, confirmed: bool) -> Result<()> {
+    let orphans = find_orphans(migrator.driver(), migration_files, migrator.baseline())?;
+    if orphans.is_empty() {
+        print::success("The history matches the migration files, nothing to prune");
+        return Ok(());
+    }
+
+    println!("Applied migrations without matching files:");
+    for orphan in &orphans {
+        println!("{} - {} (applied {})", orphan.number, orphan.name, orphan.applied_at);
+    }
+    if !confirmed {
+        if !prompt("Remove them from the history? [y/N] ")?.eq_ignore_ascii_case("y") {
+            println!("Nothing was removed");
+            return Ok(());
+        }
+    }
+
+    prune_orphans(migrator.driver(), &orphans)?;
+    print::success(&format!("Removed {} migration(s) from the history", orphans.len()));
+    Ok(())
+}
+
+/// Moves the migrations before the baseline to the archive subdirectory, once the database applied them
+pub fn archive(migrator: &Migrator, migration_files: &Migrations, path: &Path, baseline: i32) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    if baseline > current {
+        bail!("Migration {} isn't applied yet, only applied migrations can be the baseline", baseline);
+    }
+    let count = archive_migrations(path, migration_files, baseline)?;
+    if count == 0 {
+        print::success("No migration before the baseline, nothing to archive");
+    } else {
+        print::success(&format!("Moved {} migration(s) to {}", count, path.join(ARCHIVE_DIRECTORY).display()));
+    }
+    Ok(())
+}
+
+
+/// Tags `up --only-tag` and `--skip-tag` select migrations with
+#[derive(Debug, Default)]
+pub struct TagFilter {
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
 
-pub fn up(driver: Box<Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number();
+impl TagFilter {
+    fn allows(&self, file: &MigrationFile) -> bool {
+        let tags = &file.annotations.tags;
+        (self.only.is_empty() || self.only.iter().any(|t| tags.contains(t)))
+            && !self.skip.iter().any(|t| tags.contains(t))
+    }
+}
+
+/// Applies the pending migrations, consecutive ones annotated `-- dbmigrate:parallel` being run
+/// `parallel` at a time
+pub fn up(migrator: &Migrator, migration_files: &Migrations, tags: &TagFilter, parallel: usize) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    migrator.check_unmodified(migration_files)?;
     let max = migration_files.keys().max().unwrap();
     if current == *max {
         print::success("Migrations are up-to-date");
         return Ok(());
     }
 
-    for (number, migration) in migration_files.iter() {
-        if number > &current {
-            let mig_file = migration.up.as_ref().unwrap();
-            migrate!(driver, mig_file);
+    // Migrations are applied in order, those after a filtered out or contract one have to wait for it
+    let pending = migration_files.range(current + 1..).map(|(_, m)| m.up.as_ref().unwrap()).collect::<Vec<_>>();
+    let allowed = pending.iter().take_while(|file| tags.allows(file) && file.annotations.contract.is_none()).count();
+    // Stopping there would silently leave out the migrations selected by the tags after it
+    if let Some(next) = pending.get(allowed).filter(|next| !tags.allows(next)) {
+        let selected = pending[allowed + 1..].iter().filter(|file| tags.allows(file)).count();
+        if selected > 0 {
+            bail!(
+                "Migration {} ({}) is filtered out by its tags but {} migration(s) after it aren't, and migrations \
+                are applied in order. Apply it first, or the later ones with `up --only <number> --allow-out-of-order`",
+                next.number, next.name, selected
+            );
         }
     }
+    if allowed == 0 {
+        let next = pending[0];
+        print::success(&format!("Migration {} ({}) is {}, nothing to apply", next.number, next.name, held_back(next)));
+        return Ok(());
+    }
+
+    let progress = Progress::new(allowed);
+    let mut i = 0;
+    while i < allowed {
+        if i > 0 {
+            migrator.pause_between_migrations();
+        }
+        let batch = if parallel > 1 {
+            pending[i..allowed].iter().take_while(|file| file.annotations.parallel).count()
+        } else {
+            0
+        };
+        if batch > 1 {
+            let files = &pending[i..i + batch];
+            progress.start(&format!(
+                "Running up migrations #{} to #{} in parallel", files[0].number, files[batch - 1].number
+            ));
+            let start = Instant::now();
+            if let Err(e) = migrator.apply_parallel(files, parallel) {
+                progress.finish();
+                return Err(e.into());
+            }
+            let duration = start.elapsed();
+            for file in files {
+                report::record(file, duration);
+                progress.done(&format!(
+                    "> Ran up migration #{}: {} in parallel in {} second(s)", file.number, file.name, duration.as_secs()
+                ));
+            }
+            i += batch;
+        } else {
+            let mig_file = pending[i];
+            migrate!(migrator, mig_file, progress);
+            i += 1;
+        }
+    }
+    progress.finish();
+    if let Some(next) = pending.get(allowed) {
+        print::success(&format!(
+            "Stopped before migration {} ({}), {}, along with the {} migration(s) after it",
+            next.number, next.name, held_back(next), pending.len() - allowed - 1
+        ));
+    }
     Ok(())
 }
 
-pub fn down(driver: Box<Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number();
-    if current == 0 {
+// Why `up` doesn't apply the migration
+fn held_back(file: &MigrationFile) -> String {
+    match file.annotations.contract {
+        Some(expand) => format!("the contract of migration {}, see `dbmigrate contract`", expand),
+        None => "filtered out by its tags".to_owned(),
+    }
+}
+
+/// Applies the contract migrations `up` stopped before, as long as they are next in line and the expand
+/// migration they finish is settled: applied `min_age` ago and followed by `min_deploys` deploys
+pub fn contract(
+    migrator: &Migrator, migration_files: &Migrations, min_age: Option<Duration>, min_deploys: usize
+) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    migrator.check_unmodified(migration_files)?;
+    let history = migrator.driver().history()?;
+    let pending = migration_files.range(current + 1..).map(|(_, m)| m.up.as_ref().unwrap()).collect::<Vec<_>>();
+    let ready = pending.iter()
+        .take_while(|file| file.annotations.contract.map_or(false, |expand| {
+            is_settled(&history, expand, min_age, min_deploys)
+        }))
+        .count();
+    if ready == 0 {
+        match pending.first() {
+            None => print::success("Migrations are up-to-date"),
+            Some(next) => match next.annotations.contract {
+                Some(expand) => print::success(&format!(
+                    "Migration {} ({}) waits for migration {} to settle, nothing to apply",
+                    next.number, next.name, expand
+                )),
+                None => print::success(&format!(
+                    "Migration {} ({}) isn't a contract migration, `dbmigrate up` applies it", next.number, next.name
+                )),
+            },
+        }
+        return Ok(());
+    }
+
+    let progress = Progress::new(ready);
+    for mig_file in pending[..ready].iter().cloned() {
+        migrate!(migrator, mig_file, progress);
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// Backs up the database before the commands running down migrations, and before `up` only
+/// when a pending migration is flagged destructive
+pub fn backup(migrator: &Migrator, migration_files: &Migrations, command: &str, url: &str, dir: &Path) -> Result<()> {
+    if command == "up" {
+        let current = migrator.driver().get_current_number();
+        let destructive = migration_files.range(current + 1..)
+            .filter_map(|(_, migration)| migration.up.as_ref())
+            .any(|file| file.annotations.destructive);
+        if !destructive {
+            return Ok(());
+        }
+    }
+
+    println!("Backing up the database");
+    let path = backup_database(url, dir).chain_err(|| "Backup failed, no migration was run")?;
+    print::success(&format!("Database backed up to {}", path.display()));
+    Ok(())
+}
+
+/// Migrator settings from the command line and config, applied to the migrator of each target
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub strict_checksums: bool,
+    pub allow_gaps: bool,
+    pub baseline: i32,
+    pub git_commit: Option<String>,
+    pub schema_snapshots: bool,
+    pub lock_timeout: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
+    pub timeout: Option<Duration>,
+    pub sleep_between: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    pub statement_progress: bool,
+    pub online: Option<OnlineSchemaChange>,
+    pub environment: Option<String>,
+    pub track: Option<String>,
+}
+
+impl Settings {
+    pub fn apply(&self, migrator: &mut Migrator) {
+        migrator.set_strict_checksums(self.strict_checksums);
+        migrator.set_allow_gaps(self.allow_gaps);
+        migrator.set_baseline(self.baseline);
+        migrator.set_git_commit(self.git_commit.clone());
+        migrator.set_schema_snapshots(self.schema_snapshots);
+        if let Some(timeout) = self.lock_timeout {
+            migrator.set_lock_timeout(timeout);
+        }
+        if let Some(ref policy) = self.retry {
+            migrator.set_retry_policy(policy.clone());
+        }
+        migrator.set_timeout(self.timeout);
+        migrator.set_sleep_between(self.sleep_between);
+        migrator.set_keep_alive(self.keep_alive);
+        migrator.set_statement_progress(self.statement_progress);
+        if self.statement_progress {
+            migrator.add_observer(Box::new(print_statement));
+        }
+        if let Some(ref online) = self.online {
+            migrator.set_online_schema_change(online.clone());
+        }
+        migrator.set_environment(self.environment.clone());
+    }
+}
+
+// Shows which statement a migration is at, the progress bar only counting migrations
+fn print_statement(event: &MigrationEvent) {
+    match *event {
+        MigrationEvent::Statement { number, index, total: Some(total), .. } => {
+            println!("  Migration #{}: statement {}/{}", number, index, total);
+        },
+        MigrationEvent::Statement { number, index, total: None, .. } => {
+            println!("  Migration #{}: statement {}", number, index);
+        },
+        _ => (),
+    }
+}
+
+/// Runs `up` on every target, `parallel` targets at a time, printing the outcome of each as they
+/// finish. Fails if any target failed, after all of them were tried
+pub fn up_targets(targets: &[Target], parallel: usize, up: &(Fn(&str) -> Result<i32> + Sync)) -> Result<()> {
+    let queue = Mutex::new(targets.iter().collect::<VecDeque<_>>());
+    let (sender, receiver) = mpsc::channel();
+
+    let mut failed = vec![];
+    thread::scope(|scope| {
+        for _ in 0..parallel.max(1).min(targets.len()) {
+            let queue = &queue;
+            let sender = sender.clone();
+            scope.spawn(move || loop {
+                let target = match queue.lock().unwrap().pop_front() {
+                    Some(target) => target,
+                    None => break,
+                };
+                println!("Migrating {}", target.name);
+                let res = up(&target.url);
+                sender.send((&target.name, res)).ok();
+            });
+        }
+        // The receiver stops once every worker is done
+        drop(sender);
+
+        for (name, res) in receiver {
+            match res {
+                Ok(_) => print::success(&format!("{}: migrated", name)),
+                Err(e) => {
+                    let causes = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                    print::error(&format!("{}: {}", name, causes.join(": ")));
+                    failed.push(name.as_str());
+                }
+            }
+        }
+    });
+
+    if !failed.is_empty() {
+        bail!("Migrations failed on {} of {} targets: {}", failed.len(), targets.len(), failed.join(", "));
+    }
+    Ok(())
+}
+
+pub fn test(migrator: &Migrator, migration_files: &Migrations) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    let files = migration_files.range(current + 1..)
+        .filter_map(|(_, migration)| migration.up.as_ref())
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        print::success("No pending migrations to test");
+        return Ok(());
+    }
+
+    for file in &files {
+        println!("Testing up migration #{}: {}", file.number, file.name);
+    }
+    migrator.test_files(&files)?;
+    print::success(&format!("{} migration(s) ran successfully and were rolled back", files.len()));
+    Ok(())
+}
+
+/// Runs migration `number` alone in the given direction. It has to be the next one to apply or
+/// revert unless `out_of_order` is set, the migration number then staying as is
+pub fn only(
+    migrator: &Migrator, migration_files: &Migrations, number: i32, direction: Direction, out_of_order: bool
+) -> Result<()> {
+    let migration = migration_files.get(&number).ok_or_else(|| format!("Migration {} doesn't exist", number))?;
+    let mig_file = migration.file(direction)?;
+    let current = migrator.driver().get_current_number();
+    let (next, done) = match direction {
+        Direction::Up => (migration_files.range(current + 1..).next(), "applied"),
+        Direction::Down => (migration_files.range(..current + 1).next_back(), "reverted"),
+    };
+    if is_applied(migrator, number)? != (direction == Direction::Up) {
+        print::success(&format!("Migration {} is already {}", number, done));
+        return Ok(());
+    }
+
+    let progress = Progress::new(1);
+    if next.map(|(n, _)| *n) == Some(number) {
+        migrate!(migrator, mig_file, progress);
+    } else if out_of_order {
+        migrate!(migrator, mig_file, progress, apply_out_of_order);
+    } else {
+        bail!("Migration {} isn't the next one, use --allow-out-of-order to run it out of order", number);
+    }
+    progress.finish();
+    Ok(())
+}
+
+// Whether the migration is applied: those up to the current number are, unless the history shows
+// they were reverted out of order, and those after it aren't unless they were applied out of order
+fn is_applied(migrator: &Migrator, number: i32) -> Result<bool> {
+    let current = migrator.driver().get_current_number();
+    let history = migrator.driver().history()?;
+    Ok(match history.iter().rev().find(|e| e.number == number) {
+        Some(entry) => entry.direction == Direction::Up,
+        None => number <= current,
+    })
+}
+
+pub fn down(migrator: &Migrator, migration_files: &Migrations) -> Result<()> {
+    down_to(migrator, migration_files, 0)
+}
+
+/// Reverts the applied migrations numbered above `target`
+pub fn down_to(migrator: &Migrator, migration_files: &Migrations, target: i32) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    if current <= target {
         print::success("No down migrations to run");
         return Ok(());
     }
 
-    let mut numbers: Vec<i32> = migration_files.keys().cloned().filter(|i| i <= &current).collect();
-    numbers.sort_by(|a, b| b.cmp(a));
+    // Nothing is reverted if one of them is irreversible
+    let files = migration_files.range(target + 1..current + 1).rev()
+        .map(|(_, migration)| migration.file(Direction::Down))
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
 
-    for number in numbers {
-        let migration = migration_files.get(&number).unwrap();
-        let mig_file = migration.down.as_ref().unwrap();
-        migrate!(driver, mig_file);
+    let progress = Progress::new(files.len());
+    for (i, mig_file) in files.into_iter().enumerate() {
+        if i > 0 {
+            migrator.pause_between_migrations();
+        }
+        migrate!(migrator, mig_file, progress);
     }
+    progress.finish();
     Ok(())
 }
 
-pub fn redo(driver: Box<Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number();
+/// Fails if reverting the applied migrations numbered above `target` would revert a protected one
+pub fn check_protected(migrator: &Migrator, protected: &[i32], target: i32) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    let mut reverted = protected.iter().filter(|&&number| number > target && number <= current).collect::<Vec<_>>();
+    if reverted.is_empty() {
+        return Ok(());
+    }
+    reverted.sort();
+    let numbers = reverted.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ");
+    bail!("Protected migration(s) {} would be reverted, use --allow-protected to revert them anyway", numbers)
+}
+
+pub fn redo(migrator: &Migrator, migration_files: &Migrations) -> Result<()> {
+    let current = migrator.driver().get_current_number();
     if current == 0 {
         print::success("No migration to redo");
         return Ok(());
     }
-    let migration = migration_files.get(&current).unwrap();
+    let migration = migration_files.get(&current)
+        .ok_or_else(|| format!("Migration {} has no files, it can't be reverted", current))?;
 
-    let down_file = migration.down.as_ref().unwrap();
+    let down_file = migration.file(Direction::Down)?;
     let up_file = migration.up.as_ref().unwrap();
 
-    migrate!(driver, down_file);
-    migrate!(driver, up_file);
+    let progress = Progress::new(2);
+    migrate!(migrator, down_file, progress);
+    migrate!(migrator, up_file, progress);
+    progress.finish();
     Ok(())
 }
 
 
-pub fn revert(driver: Box<Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number();
+pub fn revert(migrator: &Migrator, migration_files: &Migrations) -> Result<()> {
+    let current = migrator.driver().get_current_number();
     if current == 0 {
         print::success("No migration to revert");
         return Ok(());
     }
-    let migration = migration_files.get(&current).unwrap();
-    let down_file = migration.down.as_ref().unwrap();
+    let migration = migration_files.get(&current)
+        .ok_or_else(|| format!("Migration {} has no files, it can't be reverted", current))?;
+    let down_file = migration.file(Direction::Down)?;
 
-    migrate!(driver, down_file);
+    let progress = Progress::new(1);
+    migrate!(migrator, down_file, progress);
+    progress.finish();
     Ok(())
 }
+
+
+pub fn verify_reversibility(scratch_url: &str, migration_files: &Migrations) -> Result<()> {
+    let migrator = Migrator::from_url(scratch_url).chain_err(|| "Failed to get scratch DB connection")?;
+    let driver = migrator.driver();
+    if driver.get_current_number() != 0 {
+        bail!("The scratch database already has migrations applied, it needs to be empty");
+    }
+
+    let mut failures = 0;
+    for (number, migration) in migration_files.iter() {
+        let up_file = migration.up.as_ref().unwrap();
+        println!("Checking migration #{}: {}", number, up_file.name);
+        let down_file = match migration.file(Direction::Down) {
+            Ok(down_file) => down_file,
+            Err(e) => {
+                migrator.apply_file(up_file)?;
+                println!("> {}, only applied", e);
+                continue;
+            },
+        };
+
+        let before = driver.schema()?;
+        migrator.apply_file(up_file)?;
+        let after_up = driver.schema()?;
+        migrator.apply_file(down_file)?;
+        let after_down = driver.schema()?;
+        migrator.apply_file(up_file)?;
+        let after_redo = driver.schema()?;
+
+        let down_diff = schema_diff(&before, &after_down);
+        let redo_diff = schema_diff(&after_up, &after_redo);
+        if down_diff.is_empty() && redo_diff.is_empty() {
+            print::success("> Reversible");
+            continue;
+        }
+
+        failures += 1;
+        if !down_diff.is_empty() {
+            print::error("> The down migration doesn't restore the previous schema:");
+            for line in &down_diff {
+                println!("  {}", line);
+            }
+        }
+        if !redo_diff.is_empty() {
+            print::error("> Applying the up migration again gives a different schema:");
+            for line in &redo_diff {
+                println!("  {}", line);
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} migration(s) are not reversible", failures);
+    }
+    print::success("All migrations are reversible");
+    Ok(())
+}
+
+/// Replays the migrations on a temporary shadow database created on the server: first the applied ones,
+/// comparing the schema with the database's to detect drift, then the pending ones to catch failures
+/// before they happen on the database
+pub fn shadow(migrator: &Migrator, migration_files: &Migrations, server_url: &str) -> Result<()> {
+    let current = migrator.driver().get_current_number();
+    let database = TemporaryDatabase::create(server_url).chain_err(|| "Failed to create the shadow database")?;
+    println!("Validating migrations on shadow database {}", database.name);
+
+    let res = {
+        // The connection needs to be closed before dropping the database
+        let shadow = Migrator::from_url(&database.url).chain_err(|| "Failed to get shadow DB connection");
+        shadow.and_then(|shadow| replay_on_shadow(migrator, &shadow, migration_files, current))
+    };
+
+    let name = database.name.clone();
+    database.drop_database().chain_err(|| format!("Failed to drop shadow database {}", name))?;
+    res?;
+    print::success("Migrations validated on the shadow database");
+    Ok(())
+}
+
+fn replay_on_shadow(migrator: &Migrator, shadow: &Migrator, migration_files: &Migrations, current: i32) -> Result<()> {
+    for (_, migration) in migration_files.range(..current + 1) {
+        if let Some(ref file) = migration.up {
+            shadow.apply_file(file)
+                .chain_err(|| format!("Applied migration #{} {} fails on the shadow database", file.number, file.name))?;
+        }
+    }
+
+    // Not every database can dump its schema, drift is only checked when it can
+    if let Ok(schema) = migrator.driver().schema() {
+        check_drift(&shadow.driver().schema()?, &schema)?;
+    }
+
+    for (_, migration) in migration_files.range(current + 1..) {
+        if let Some(ref file) = migration.up {
+            shadow.apply_file(file)
+                .chain_err(|| format!("Migration #{} {} fails on the shadow database", file.number, file.name))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn test_db(server_url: &str, migration_files: &Migrations, command: Option<Vec<&str>>) -> Result<()> {
+    let database = TemporaryDatabase::create(server_url).chain_err(|| "Failed to create the temporary database")?;
+    print::success(&format!("Created temporary database {}", database.name));
+
+    let res = {
+        // The connection needs to be closed before dropping the database
+        let migrator = Migrator::from_url(&database.url).chain_err(|| "Failed to get DB connection");
+        migrator.and_then(|m| up(&m, migration_files, &TagFilter::default(), 1))
+    }.and_then(|_| match command {
+        Some(command) => {
+            println!("Running {}", command.join(" "));
+            let status = Command::new(command[0])
+                .args(&command[1..])
+                .env("DBMIGRATE_TEST_URL", &database.url)
+                .env("DATABASE_URL", &database.url)
+                .status()
+                .chain_err(|| format!("Failed to run {}", command[0]))?;
+            if !status.success() {
+                bail!("{} failed: {}", command[0], status);
+            }
+            Ok(())
+        },
+        None => Ok(()),
+    });
+
+    let name = database.name.clone();
+    database.drop_database().chain_err(|| format!("Failed to drop temporary database {}", name))?;
+    print::success(&format!("Dropped temporary database {}", name));
+    res
+}
+
+/// Replaces the current process by the command, or runs it and exits with its status
+/// where that isn't possible
+pub fn exec(command: Vec<&str>) -> Result<()> {
+    println!("Running {}", command.join(" "));
+    let mut cmd = Command::new(command[0]);
+    cmd.args(&command[1..]);
+    exec_command(cmd).chain_err(|| format!("Failed to run {}", command[0]))
+}
+
+#[cfg(unix)]
+fn exec_command(mut cmd: Command) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    // Only returns on failure
+    Err(cmd.exec())
+}
+
+#[cfg(not(unix))]
+fn exec_command(mut cmd: Command) -> io::Result<()> {
+    let status = cmd.status()?;
+    ::std::process::exit(status.code().unwrap_or(1));
+}
+
+pub fn import(migrator: &Migrator, migration_files: &Migrations, source: ImportSource) -> Result<()> {
+    let number = import_state(migrator.driver(), migration_files, source)?;
+    print::success(&format!("Imported {} state, current migration is now {}", source.to_string(), number));
+    Ok(())
+}
+
+pub fn state_export(migrator: &Migrator, migration_files: &Migrations, output: Option<&str>) -> Result<()> {
+    let json = Snapshot::take(migrator.driver(), migration_files).to_json()?;
+    match output {
+        Some(filename) => {
+            File::create(filename)
+                .and_then(|mut f| f.write_all(json.as_bytes()))
+                .chain_err(|| format!("Failed to write {}", filename))?;
+            print::success(&format!("State exported to {}", filename));
+        },
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+pub fn state_import(migrator: &Migrator, migration_files: &Migrations, input: &str) -> Result<()> {
+    let mut json = String::new();
+    if input == "-" {
+        io::stdin().read_to_string(&mut json).chain_err(|| "Failed to read stdin")?;
+    } else {
+        File::open(input)
+            .and_then(|mut f| f.read_to_string(&mut json))
+            .chain_err(|| format!("Failed to read {}", input))?;
+    }
+
+    let snapshot = Snapshot::from_json(&json)?;
+    snapshot.check(migration_files)?;
+    snapshot.restore(migrator.driver())?;
+    print::success(&format!("State imported, current migration is now {}", snapshot.current));
+    Ok(())
+}
+
+/// Renders the migrations above `from` up to `to` (the latest by default) as a Markdown table for release notes,
+/// described by the first comment of their up file
+pub fn changelog(
+    migrator: &Migrator, migration_files: &Migrations, from: i32, to: Option<i32>, output: Option<&str>
+) -> Result<()> {
+    let to = to.unwrap_or_else(|| migration_files.keys().max().cloned().unwrap_or(0));
+    if from > to {
+        bail!("--from ({}) is after --to ({})", from, to);
+    }
+    let current = migrator.driver().get_current_number();
+    let applied = applied_migrations(migrator.driver())?;
+    // Pipes would end the cell early
+    let cell = |text: &str| text.replace('|', "\\|");
+
+    let mut markdown = format!("## Migrations {} to {}\n\n", from + 1, to);
+    markdown.push_str("| Number | Name | Description | Applied |\n");
+    markdown.push_str("|--------|------|-------------|---------|\n");
+    for (number, migration) in migration_files.range(from + 1..to + 1) {
+        let up = migration.up.as_ref();
+        let applied_at = match applied.get(number) {
+            Some(entry) if *number <= current => entry.applied_at.clone(),
+            _ if *number <= current => "yes".to_owned(),
+            _ => "not yet".to_owned(),
+        };
+        markdown.push_str(&format!(
+            "| {} | {} | {} | {} |\n",
+            number,
+            cell(up.map_or("", |f| f.name.as_str())),
+            cell(&up.and_then(|f| f.description()).unwrap_or_default()),
+            applied_at,
+        ));
+    }
+
+    match output {
+        Some(filename) => {
+            File::create(filename)
+                .and_then(|mut f| f.write_all(markdown.as_bytes()))
+                .chain_err(|| format!("Failed to write {}", filename))?;
+            print::success(&format!("Changelog written to {}", filename));
+        },
+        None => print!("{}", markdown),
+    }
+    Ok(())
+}
+
+// Returns the number and content of the up file of the current migration, used by
+// `watch` to notice when it is edited
+fn current_up_content(migrator: &Migrator, migration_files: &Migrations) -> Option<(i32, String)> {
+    let current = migrator.driver().get_current_number();
+    migration_files.get(&current)
+        .and_then(|m| m.up.as_ref())
+        .and_then(|f| f.read_content().ok())
+        .map(|content| content.into_owned())
+        .map(|content| (current, content))
+}
+
+pub fn watch(migrator: &Migrator, paths: &[PathBuf], options: &ReadOptions, interval: Duration) -> Result<()> {
+    let folders = paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+    print::success(&format!("Watching {} for changes, press Ctrl-C to stop", folders.join(", ")));
+    let mut last_applied = read_migration_directories(paths, options).ok()
+        .and_then(|files| current_up_content(migrator, &files));
+    let mut last_error = None;
+
+    loop {
+        // Files can be in an invalid state while being written (eg only the up file exists yet)
+        // so errors are only printed once and we keep watching
+        match read_migration_directories(paths, options) {
+            Err(e) => {
+                let message = format!("{}", e);
+                if last_error.as_ref() != Some(&message) {
+                    print::error(&message);
+                    last_error = Some(message);
+                }
+            },
+            Ok(migration_files) => {
+                last_error = None;
+                let current = migrator.driver().get_current_number();
+                let max = migration_files.keys().cloned().max().unwrap_or(0);
+                let edited = match (&last_applied, current_up_content(migrator, &migration_files)) {
+                    (&Some((number, ref old)), Some((_, ref new))) => number == current && old != new,
+                    _ => false,
+                };
+
+                let res = if max > current {
+                    up(migrator, &migration_files, &TagFilter::default(), 1)
+                } else if edited {
+                    redo(migrator, &migration_files)
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = res {
+                    print::error(&format!("{}", e));
+                }
+                last_applied = current_up_content(migrator, &migration_files);
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
@@ -1,7 +1,83 @@
 use dbmigrate_lib::errors;
+use serde_json::Value;
+
+// Whatever is printed or sent out goes through it, so connection strings never leak their password
+pub use dbmigrate_lib::errors::redact_urls;
 
 error_chain! {
     links {
         DbMigrateLib(errors::Error, errors::ErrorKind);
     }
 }
+
+// The errors of the chain as they were boxed, unlike the ones given by `iter` which can't be downcast
+// to look at the kinds of the lib
+fn chain(e: &Error) -> Vec<&(::std::error::Error + Send + 'static)> {
+    let mut errors: Vec<&(::std::error::Error + Send + 'static)> = vec![e];
+    loop {
+        let last = errors[errors.len() - 1];
+        let next = if let Some(e) = last.downcast_ref::<Error>() {
+            e.1.next_error.as_ref()
+        } else if let Some(e) = last.downcast_ref::<errors::Error>() {
+            e.1.next_error.as_ref()
+        } else {
+            None
+        };
+        match next {
+            Some(next) => errors.push(&**next),
+            None => return errors,
+        }
+    }
+}
+
+// The kind of the lib the error has, whether it was linked in an error of the CLI or given as is
+fn lib_kind<'a>(error: &'a (::std::error::Error + Send + 'static)) -> Option<&'a errors::ErrorKind> {
+    match error.downcast_ref::<Error>().map(Error::kind) {
+        Some(&ErrorKind::DbMigrateLib(ref k)) => Some(k),
+        _ => error.downcast_ref::<errors::Error>().map(errors::Error::kind),
+    }
+}
+
+/// Only the scheme and host of the URL, for the ones like webhooks' which carry their secret in their path
+pub fn url_origin(url: &str) -> String {
+    let (scheme, rest) = match url.find("://") {
+        Some(i) => url.split_at(i + 3),
+        None => ("", url),
+    };
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    format!("{}{}", scheme, authority.rsplit('@').next().unwrap_or(""))
+}
+
+/// Whether the run failed on a database left dirty by a migration that failed partway
+pub fn is_dirty(e: &Error) -> bool {
+    chain(e).into_iter().any(|error| match lib_kind(error) {
+        Some(&errors::ErrorKind::Dirty(..)) => true,
+        _ => false,
+    })
+}
+
+/// The error as a JSON object, for programs deciding what to do about a failed run: `kind` is the
+/// class of the outermost error of the lib which has one, `other` without, and the migration number
+/// and SQLSTATE are null unless a migration failed in the database
+pub fn to_json(e: &Error) -> Value {
+    let mut kind = None;
+    let mut migration = None;
+    let mut sql_state = None;
+    for error in chain(e) {
+        if let Some(k) = lib_kind(error) {
+            kind = kind.or(k.class());
+            if let errors::ErrorKind::MigrationFailed(number, ref name) = *k {
+                migration = migration.or(Some((number, name.clone())));
+            }
+        }
+        sql_state = sql_state.or_else(|| errors::sql_state(error));
+    }
+    json!({
+        "kind": kind.unwrap_or("other"),
+        "migration_number": migration.as_ref().map(|m| m.0),
+        "migration_name": migration.as_ref().map(|m| m.1.clone()),
+        "sql_state": sql_state,
+        "message": redact_urls(&e.to_string()),
+        "causes": e.iter().skip(1).map(|e| redact_urls(&e.to_string())).collect::<Vec<_>>(),
+    })
+}
@@ -0,0 +1,78 @@
+///! Checks of the git repository the migrations folder is in, for `git_check`
+use std::path::Path;
+
+use errors::Result;
+
+/// Files of the migrations folder that are modified, staged or untracked in its git repository
+#[cfg(feature = "git")]
+pub fn uncommitted_changes(path: &Path) -> Result<Vec<String>> {
+    use git2::{Repository, StatusOptions};
+
+    use errors::ResultExt;
+
+    let path = path.canonicalize().chain_err(|| format!("Failed to resolve {}", path.display()))?;
+    let repository = Repository::discover(&path)
+        .chain_err(|| format!("{} isn't in a git repository", path.display()))?;
+    let workdir = match repository.workdir() {
+        Some(workdir) => workdir.canonicalize().chain_err(|| format!("Failed to resolve {}", workdir.display()))?,
+        None => bail!("The git repository of {} has no working tree", path.display()),
+    };
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true).include_ignored(false);
+    // Safe unwrap, the repository was discovered from the folder
+    let relative = path.strip_prefix(&workdir).unwrap();
+    if relative.as_os_str().len() > 0 {
+        options.pathspec(relative);
+    }
+    let statuses = repository.statuses(Some(&mut options))
+        .chain_err(|| format!("Failed to get the git status of {}", path.display()))?;
+    Ok(statuses.iter().filter_map(|entry| entry.path().map(|p| p.to_owned())).collect())
+}
+
+#[cfg(not(feature = "git"))]
+pub fn uncommitted_changes(_path: &Path) -> Result<Vec<String>> {
+    bail!("dbmigrate was built without the `git` feature, the migrations folder can't be checked")
+}
+
+#[cfg(all(test, feature = "git"))]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    use git2::{Repository, Signature};
+    use tempdir::TempDir;
+
+    use super::uncommitted_changes;
+
+    fn commit_all(repository: &Repository) {
+        let mut index = repository.index().unwrap();
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("dbmigrate", "dbmigrate@example.com").unwrap();
+        repository.commit(Some("HEAD"), &signature, &signature, "Migrations", &tree, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_uncommitted_changes() {
+        let dir = TempDir::new("repository").unwrap();
+        let repository = Repository::init(dir.path()).unwrap();
+        let migrations = dir.path().join("migrations");
+        fs::create_dir(&migrations).unwrap();
+        File::create(migrations.join("0001.users.up.sql")).unwrap();
+        File::create(dir.path().join("README.md")).unwrap();
+        commit_all(&repository);
+        assert!(uncommitted_changes(&migrations).unwrap().is_empty());
+
+        // Only the changes of the migrations folder count
+        File::create(migrations.join("0002.posts.up.sql")).unwrap();
+        fs::write(dir.path().join("README.md"), "Changed").unwrap();
+        assert_eq!(uncommitted_changes(&migrations).unwrap(), vec!["migrations/0002.posts.up.sql"]);
+        assert_eq!(uncommitted_changes(dir.path()).unwrap().len(), 2);
+
+        let outside = TempDir::new("migrations").unwrap();
+        assert!(uncommitted_changes(outside.path()).is_err());
+        assert!(uncommitted_changes(Path::new("/does/not/exist")).is_err());
+    }
+}
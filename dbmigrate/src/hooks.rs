@@ -0,0 +1,50 @@
+use std::process::Command;
+
+use errors::{Result, ResultExt};
+use report::Report;
+
+/// Runs the `before_run` hook, whose failure aborts the run
+pub fn before_run(hook: &str, command: &str) -> Result<()> {
+    run_hook(hook, &[("DBMIGRATE_COMMAND", command.to_owned())])
+        .chain_err(|| "before_run hook failed, no migration was run")
+}
+
+/// Runs the `after_run` hook with the outcome of the run in its environment
+pub fn after_run(hook: &str, report: &Report) -> Result<()> {
+    let migrations = report.migrations.iter().map(|m| m.number.to_string()).collect::<Vec<_>>();
+    let env = [
+        ("DBMIGRATE_COMMAND", report.command.clone()),
+        ("DBMIGRATE_OUTCOME", if report.success { "success" } else { "failure" }.to_owned()),
+        ("DBMIGRATE_ERROR", report.error.clone().unwrap_or_default()),
+        ("DBMIGRATE_MIGRATIONS", migrations.join(" ")),
+        ("DBMIGRATE_DURATION", format!("{:.3}", report.duration_secs)),
+    ];
+    run_hook(hook, &env).chain_err(|| "after_run hook failed")
+}
+
+fn run_hook(hook: &str, env: &[(&str, String)]) -> Result<()> {
+    println!("Running {}", hook);
+    let mut cmd = shell(hook);
+    for &(key, ref value) in env {
+        cmd.env(key, value);
+    }
+    let status = cmd.status().chain_err(|| format!("Failed to run {}", hook))?;
+    if !status.success() {
+        bail!("{} failed: {}", hook, status);
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell(hook: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.args(&["/C", hook]);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell(hook: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", hook]);
+    cmd
+}
@@ -3,38 +3,85 @@
 
 #[macro_use] extern crate clap;
 #[macro_use] extern crate error_chain;
+#[macro_use] extern crate serde_derive;
 extern crate dbmigrate_lib;
 extern crate term;
 extern crate dotenv;
+extern crate indicatif;
+extern crate serde;
+#[macro_use] extern crate serde_json;
+extern crate toml;
+extern crate ureq;
+#[macro_use] extern crate tracing;
+#[cfg(feature = "otlp")] extern crate tracing_subscriber;
+#[cfg(feature = "otlp")] extern crate tracing_opentelemetry;
+#[cfg(feature = "otlp")] extern crate opentelemetry;
+#[cfg(feature = "otlp")] extern crate opentelemetry_sdk;
+#[cfg(feature = "otlp")] extern crate opentelemetry_otlp;
+#[cfg(feature = "git")] extern crate git2;
+#[cfg(all(test, feature = "git"))] extern crate tempdir;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
-use std::time::Instant;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod audit;
 mod cmd;
+mod config;
+mod git;
 mod print;
+mod progress;
+mod report;
+mod serve;
+mod state;
+mod telemetry;
 mod errors;
+mod hooks;
+mod metrics;
+mod webhooks;
 
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
+
+use config::{Config, Target, CONFIG_FILENAME};
 use errors::{Result, ResultExt};
-use dbmigrate_lib::{read_migration_files, get_driver};
+use dbmigrate_lib::{
+    baseline, git_commit, parse_duration, read_migration_directories, schema_url, tenant_schemas, Encoding, LintTarget,
+    with_connect_timeout, with_search_path, Direction, ImportSource, Migrations, Migrator, ReadOptions, Template,
+};
+use report::Report;
+
 
+// Exit codes are stable so scripts can branch on them: 0 when everything went fine and
+// migrations are up to date, 1 on errors, 2 when `check` finds pending migrations
+// and 3 for a database left in a dirty state by a failed migration.
+const EXIT_ERROR: i32 = 1;
+const EXIT_PENDING: i32 = 2;
+const EXIT_DIRTY: i32 = 3;
 
 fn main() {
-    if let Err(ref e) = run() {
-        print::error(&format!("{}", e));
-        for e in e.iter().skip(1) {
-            print::error(&format!("caused by: {}", e));
+    dotenv::dotenv().ok();
+
+    let matches = build_cli().get_matches();
+
+    if let Err(ref e) = run(&matches) {
+        if matches.value_of("error_format") == Some("json") {
+            eprintln!("{}", errors::to_json(e));
+        } else {
+            print::error(&format!("{}", e));
+            for e in e.iter().skip(1) {
+                print::error(&format!("caused by: {}", e));
+            }
         }
 
-        ::std::process::exit(1);
+        ::std::process::exit(if errors::is_dirty(e) { EXIT_DIRTY } else { EXIT_ERROR });
     }
 }
 
 
-fn run() -> Result<()> {
-    dotenv::dotenv().ok();
-
-    let matches = clap_app!(dbmigrate =>
+fn build_cli() -> App<'static, 'static> {
+    clap_app!(dbmigrate =>
         (@setting SubcommandRequiredElseHelp)
         (version: &crate_version!()[..])
         (author: "Vincent Prouillet <vincent@wearewizards.io>")
@@ -43,67 +90,811 @@ Handles migrations for databases.
 Each call requires the database url and the path to the directory containing
 the SQL migration files.
 Those can be set using the DBMIGRATE_URL and DBMIGRATE_PATH environment
-variables, via a .env file, or the --url and --path arguments.
-Using arguments will override the environment variables.
+variables, via a .env file, the --url and --path arguments or the url and path
+keys of a dbmigrate.toml file in the current directory.
+Using arguments will override the environment variables, which override the
+config file.
         ")
         (@arg url: -u --url +takes_value "Sets the URL of the database to use.")
-        (@arg path: -p --path +takes_value "Sets the folder containing the migrations")
+        (@arg path: -p --path +takes_value +multiple number_of_values(1) "Sets the folder containing the migrations, \
+            can be used several times to merge the migrations of several folders")
+        (@arg recursive: --recursive "Also reads the migrations of the subfolders of the migrations folder")
+        (@arg optional_down: --("optional-down") "Doesn't require a down file for every migration, reverting one without failing")
+        (@arg allow_gaps: --("allow-gaps") "Accepts gaps in the migration numbers, like when old migrations were squashed \
+            into a baseline and deleted")
+        (@arg encoding: --encoding +takes_value possible_values(&Encoding::variants()) "Sets how to read migration files that aren't valid UTF-8")
+        (@arg strict_checksums: --("strict-checksums") "Doesn't ignore line endings and trailing whitespace when checking applied migrations weren't modified")
+        (@arg timeout: --timeout +takes_value "Sets how long a migration may run before being cancelled, like 90s, 10m or 2h")
+        (@arg timeout_connect: --("timeout-connect") +takes_value "Sets how many seconds to wait for the database to \
+            accept the connection, unless the URL sets connect_timeout (default: 10)")
+        (@arg search_path: --("search-path") visible_alias("default-schema") +takes_value "Sets the schemas \
+            unqualified names refer to, like staging,public, or the database on MySQL (default: DBMIGRATE_SEARCH_PATH)")
+        (@arg environment: --environment +takes_value "Sets the environment, migrations restricted to others being skipped \
+            (default: DBMIGRATE_ENV)")
+        (@arg track: --track +takes_value "Tracks the migrations in a track of their own, for components sharing the database \
+            (default: DBMIGRATE_TRACK)")
+        (@arg sleep_between: --("sleep-between") +takes_value "Sets a pause between migrations, like 30s, letting replication lag catch up")
+        (@arg statement_progress: --("statement-progress") "Runs SQL migrations one statement at a time, printing \
+            which statement each one is at so that a long migration isn't taken for hung")
+        (@arg keep_alive: --("keep-alive") +takes_value "Pings the database this often, like 60s, while waiting for pauses, scripts \
+            and online migrations so its connection isn't closed for being idle")
+        (@arg error_format: --("error-format") +takes_value possible_values(&["text", "json"]) default_value("text")
+            "Sets how failures are printed on stderr, json giving an object with the kind of error, the migration \
+            number and the SQLSTATE")
+        (@subcommand init =>
+            (about: "Creates the migrations folder (defaults to `migrations`) and a starter dbmigrate.toml")
+            (@arg slug: "Also creates a first migration with the given name")
+        )
+        (@subcommand completions =>
+            (about: "Prints the shell completions script for dbmigrate to stdout")
+            (@arg shell: +required possible_values(&Shell::variants()) "Sets the shell to generate completions for")
+        )
         (@subcommand create =>
             (about: "Creates two migration files (up and down) with the given slug")
             (@arg slug: +required "Sets the name of the migration. `.` (dot) is not allowed in the name")
+            (@arg auto_down: --("auto-down") +takes_value "Experimental: reads the up migration from stdin and \
+                generates the down one by applying it to the given empty scratch database and comparing the schemas")
+            (@arg template: --template +takes_value possible_values(&Template::variants()) conflicts_with("auto_down")
+                "Fills the files with the up and down SQL of a common migration, placeholders left to replace")
+            (@arg interactive: -i --interactive conflicts_with[auto_down template]
+                "Asks for the table to create, its columns and indexes and writes the SQL for the database of the url")
         )
         (@subcommand status =>
             (about: "See list of migrations and which ones are applied")
+            (@arg diff: --diff +takes_value conflicts_with("offline") "Compares the applied migrations with the database at the given URL instead")
+            (@arg offline: --offline "Lists the migration files and checks they can be read, without connecting to the database")
+            (@arg format: --format +takes_value possible_values(&print::Format::variants()) default_value("table") "Sets the output format")
+        )
+        (@subcommand history =>
+            (about: "Lists every migration applied or reverted on the database, with who ran it and how long it took")
+            (@arg format: --format +takes_value possible_values(&print::Format::variants()) default_value("table") "Sets the output format")
+        )
+        (@subcommand ping =>
+            (about: "Connects to the database and checks the migration tables can be created, read and written")
+        )
+        (@subcommand schema =>
+            (about: "Prints a dump of the current schema, or of the schema once the given migration was applied")
+            (@arg at: --at +takes_value "Sets the migration whose schema snapshot to print, needs schema_snapshots")
+        )
+        (@subcommand check =>
+            (about: "Exits with 0 if all migrations are applied, 2 if some are pending and 3 if the database \
+                is dirty, for readiness probes")
+        )
+        (@subcommand lint =>
+            (about: "Checks the migrations against a Postgres dialect or MariaDB, without connecting to the database")
+            (@arg dialect: --dialect +takes_value possible_values(&LintTarget::variants()) "Sets the dialect \
+                (default: the flavor of MySQL URLs, the dialect parameter of other database URLs, or postgres)")
+            (@arg format: --format +takes_value possible_values(&print::Format::variants()) default_value("table") "Sets the output format")
         )
         (@subcommand up =>
             (about: "Apply all non-applied migrations")
+            (@arg then: --then requires[command] "Execs the command given after `--` once migrations are applied, \
+                waiting for the database to accept connections first (to be used as a container entrypoint)")
+            (@arg wait: --wait +takes_value "Sets how many seconds to wait for the database (default: 30 with --then, 0 otherwise)")
+            (@arg command: +multiple +last "Sets the command to exec, after `--`")
+            (@arg shadow: --shadow "Applies the migrations to a temporary shadow database first, stopping if they fail \
+                there or if the database schema drifted from them")
+            (@arg parallel: --parallel +takes_value "Sets how many targets or schemas to migrate at once, or for a single \
+                database how many consecutive migrations annotated `-- dbmigrate:parallel` to run at once (default: 1)")
+            (@arg schemas: --schemas +takes_value "Migrates every schema matching the LIKE pattern (such as tenant_%) \
+                separately, each one tracking its own migrations (Postgres only)")
+            (@arg only_tag: --("only-tag") +takes_value "Only applies the migrations with one of the comma separated tags, \
+                stopping before the first one without, and failing if migrations with them come after it")
+            (@arg skip_tag: --("skip-tag") +takes_value "Stops before the first migration with one of the comma \
+                separated tags, failing if migrations without them come after it")
+            (@arg verify_signatures: --("verify-signatures") "Refuses to apply migrations not matching the bundle \
+                of the migrations folder, or whose signature isn't from a trusted key")
+            (@arg only: --only +takes_value conflicts_with[parallel schemas only_tag skip_tag] "Only applies the \
+                migration with the given number")
+            (@arg allow_out_of_order: --("allow-out-of-order") requires[only] "Lets --only apply a migration \
+                which isn't the next one, leaving the migration number as is for up to apply the ones before it")
+        )
+        (@subcommand contract =>
+            (about: "Apply the pending contract migrations, once the expand migrations they finish are settled")
+            (@arg min_days: --("min-days") +takes_value "Sets how many days ago the expand migration must have been applied")
+            (@arg min_deploys: --("min-deploys") +takes_value "Sets how many deploys, counted as the distinct git commits \
+                of the migrations applied since, must have followed the expand migration")
+        )
+        (@subcommand bundle =>
+            (about: "Writes the SHA-256 of every migration file to dbmigrate.bundle in the migrations folder")
+            (@arg sign: --sign "Also signs it with gpg, in a detached dbmigrate.bundle.asc signature")
+            (@arg key: --key +takes_value requires[sign] "Sets the gpg key to sign with (default: the default key of gpg)")
+        )
+        (@subcommand test =>
+            (about: "Apply all non-applied migrations in a transaction and roll it back (Postgres and Sqlite only)")
         )
         (@subcommand down =>
             (about: "Un-apply all applied migrations")
+            (@arg allow_protected: --("allow-protected") "Also reverts the migrations listed as protected in dbmigrate.toml")
+            (@arg only: --only +takes_value "Only reverts the migration with the given number")
+            (@arg allow_out_of_order: --("allow-out-of-order") requires[only] "Lets --only revert a migration \
+                which isn't the current one, leaving the migration number as is")
         )
         (@subcommand redo =>
             (about: "Rollback the current migration and re-run it")
+            (@arg allow_protected: --("allow-protected") "Also reverts the migrations listed as protected in dbmigrate.toml")
         )
         (@subcommand revert =>
             (about: "Revert the current migration")
+            (@arg allow_protected: --("allow-protected") "Also reverts the migrations listed as protected in dbmigrate.toml")
+        )
+        (@subcommand force =>
+            (about: "Sets the current migration and clears the dirty marker, once a failed migration was fixed by hand")
+            (@arg number: +required "Sets the migration the database now matches, 0 for none")
+        )
+        (@subcommand repair =>
+            (about: "Clears the dirty marker, records the checksums of modified migrations and forgets deleted ones")
+            (@arg yes: -y --yes "Doesn't ask for confirmation")
         )
-    ).get_matches();
+        (@subcommand prune =>
+            (about: "Removes applied migrations whose files no longer exist from the history")
+            (@arg yes: -y --yes "Doesn't ask for confirmation")
+        )
+        (@subcommand archive =>
+            (about: "Moves the applied migrations before the baseline to the archive subdirectory")
+            (@arg baseline: +required "Sets the migration to keep as the first one, usually a squash of the ones before it")
+        )
+        (@subcommand import =>
+            (about: "Sets the current migration from the tracking table of another migration tool")
+            (@arg from: --from +required +takes_value possible_values(&ImportSource::variants()) "Sets the tool to import from")
+        )
+        (@subcommand state =>
+            (about: "Exports or restores the migration tracking table as JSON")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand export =>
+                (about: "Writes the current migration state as JSON")
+                (@arg output: -o --output +takes_value "Sets the file to write to (defaults to stdout)")
+            )
+            (@subcommand import =>
+                (about: "Rebuilds the tracking table from a JSON snapshot")
+                (@arg file: +required "Sets the snapshot file to read, `-` for stdin")
+            )
+        )
+        (@subcommand generate =>
+            (about: "Generates documents from the migrations")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand changelog =>
+                (about: "Prints a Markdown changelog of the migrations between two numbers, \
+                    with the first comment of each as description and when it was applied")
+                (@arg from: --from +takes_value "Sets the number of the last migration of the previous release (default: 0)")
+                (@arg to: --to +takes_value "Sets the number of the last migration to include (default: the latest)")
+                (@arg output: -o --output +takes_value "Sets the file to write to (defaults to stdout)")
+            )
+        )
+        (@subcommand watch =>
+            (about: "Watch the migrations folder, applying new migrations and redoing the current one when it changes")
+            (@arg interval: -i --interval +takes_value "Sets the number of seconds between checks (default: 1)")
+        )
+    )
+    // clap_app! only accepts identifiers as subcommand names
+    .subcommand(SubCommand::with_name("verify-reversibility")
+        .about("Runs up, down and up again for each migration against an empty scratch database and compares the schemas")
+        .arg(Arg::with_name("scratch_url").required(true).help("Sets the URL of the scratch database, which will be migrated")))
+    .subcommand(SubCommand::with_name("serve")
+        .about("Serves an HTTP API: GET /status, POST /up and POST /down/<n> reverting the migrations above n. \
+            Requests need the token of DBMIGRATE_SERVE_TOKEN or serve_token in an `Authorization: Bearer` header")
+        .arg(Arg::with_name("listen").long("listen").takes_value(true).default_value("127.0.0.1:8080")
+            .help("Sets the address to listen on")))
+    .subcommand(SubCommand::with_name("test-db")
+        .about("Creates a temporary database on the server of the URL, applies all migrations to it, \
+            runs the optional command with its URL in DBMIGRATE_TEST_URL and DATABASE_URL and drops it")
+        .arg(Arg::with_name("command").multiple(true).last(true).help("Sets the command to run, after `--`")))
+    .subcommand(SubCommand::with_name("apply-sql")
+        .about("Runs the SQL of a file, or of stdin for -, with the migration lock held and the hooks, backups and \
+            notifications of migrations, without changing the current migration")
+        .arg(Arg::with_name("file").required(true).help("Sets the file to run, - reading stdin")))
+    .subcommand(SubCommand::with_name("rollback-to")
+        .about("Reverts the applied migrations numbered above the given one, which stays applied")
+        .arg(Arg::with_name("number").required(true).help("Sets the last migration to keep, 0 reverting them all"))
+        .arg(Arg::with_name("allow_protected").long("allow-protected")
+            .help("Also reverts the migrations listed as protected in dbmigrate.toml")))
+}
 
-    let path_value = match matches.value_of("path").map(|s| s.into()).or(env::var("DBMIGRATE_PATH").ok()) {
-      Some(u) => u,
-      None => bail!("No migration path was provided in the environment or via a command arg.")
+
+fn rollback_target(matches: &ArgMatches) -> Result<i32> {
+    // Safe unwrap, the argument is required
+    let number = matches.value_of("number").unwrap();
+    match number.parse::<i32>() {
+        Ok(target) if target >= 0 => Ok(target),
+        _ => bail!("Invalid migration number: {}", number),
+    }
+}
+
+// The migration `up --only` or `down --only` runs alone
+fn only_number(matches: &ArgMatches) -> Result<Option<i32>> {
+    match matches.value_of("only") {
+        Some(number) => number.parse().map(Some).chain_err(|| format!("Invalid migration number: {}", number)),
+        None => Ok(None),
+    }
+}
+
+// The migration number the command reverts the database to, if it reverts migrations
+fn reverting_to(migrator: &Migrator, command: &str, matches: &ArgMatches) -> Result<Option<i32>> {
+    let current = migrator.driver().get_current_number();
+    Ok(match command {
+        "down" => Some(0),
+        "redo" | "revert" => Some(current - 1),
+        "rollback-to" => Some(rollback_target(matches)?),
+        _ => None,
+    })
+}
+
+
+// The database might still be starting, when run along it in a container for example
+fn connect(url: &str, wait: Duration) -> Result<Migrator> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        match Migrator::from_url(url) {
+            Ok(migrator) => return Ok(migrator),
+            Err(_) if start.elapsed() < wait => {
+                if !waiting {
+                    println!("Waiting for the database to accept connections...");
+                    waiting = true;
+                }
+                thread::sleep(Duration::from_secs(1));
+            },
+            Err(e) => return Err(e).chain_err(|| "Failed to get DB connection"),
+        }
+    }
+}
+
+
+// Connects to the database of the URL, on the track of the settings and with their settings applied
+fn connect_with_settings(url: &str, wait: Duration, settings: &cmd::Settings) -> Result<Migrator> {
+    let mut migrator = connect(url, wait)?;
+    if let Some(ref track) = settings.track {
+        migrator = migrator.on_track(track)?;
+    }
+    settings.apply(&mut migrator);
+    Ok(migrator)
+}
+
+
+// `Driver::get_current_number` panics when the connection is gone after a failure, it is checked first
+fn current_number(migrator: &Migrator) -> Option<i32> {
+    migrator.driver().ping().ok().map(|_| migrator.driver().get_current_number())
+}
+
+
+// Runs the subcommands needing a database connection
+fn run_command(
+    matches: &ArgMatches, migrator: &Migrator, migration_files: &Migrations, paths: &[PathBuf], options: &ReadOptions
+) -> Result<()> {
+    let span = info_span!("dbmigrate", command = matches.subcommand_name().unwrap_or(""));
+    let _enter = span.enter();
+    match matches.subcommand_name() {
+        Some("status") => {
+            let format = matches.subcommand_matches("status").unwrap().value_of("format").unwrap().parse()?;
+            cmd::status(migrator, migration_files, format)?
+        },
+        Some("history") => {
+            let format = matches.subcommand_matches("history").unwrap().value_of("format").unwrap().parse()?;
+            cmd::history(migrator, format)?
+        },
+        Some("up") => {
+            let up_matches = matches.subcommand_matches("up").unwrap();
+            match only_number(up_matches)? {
+                Some(number) => {
+                    let out_of_order = up_matches.is_present("allow_out_of_order");
+                    migrator.with_lock(|| cmd::only(migrator, migration_files, number, Direction::Up, out_of_order))?
+                },
+                None => {
+                    let tags = cmd::TagFilter {
+                        only: tag_list(up_matches, "only_tag"), skip: tag_list(up_matches, "skip_tag"),
+                    };
+                    let parallel = parallel(matches)?;
+                    migrator.with_lock(|| cmd::up(migrator, migration_files, &tags, parallel))?
+                },
+            }
+        },
+        Some("contract") => {
+            let contract_matches = matches.subcommand_matches("contract").unwrap();
+            let min_age = match contract_matches.value_of("min_days") {
+                Some(d) => {
+                    let days = d.parse::<u64>().chain_err(|| format!("Invalid days: {}", d))?;
+                    Some(Duration::from_secs(days * 86400))
+                },
+                None => None,
+            };
+            let min_deploys = match contract_matches.value_of("min_deploys") {
+                Some(d) => d.parse::<usize>().chain_err(|| format!("Invalid deploys: {}", d))?,
+                None => 0,
+            };
+            migrator.with_lock(|| cmd::contract(migrator, migration_files, min_age, min_deploys))?
+        },
+        Some("test") => cmd::test(migrator, migration_files)?,
+        Some("down") => {
+            let down_matches = matches.subcommand_matches("down").unwrap();
+            match only_number(down_matches)? {
+                Some(number) => {
+                    let out_of_order = down_matches.is_present("allow_out_of_order");
+                    migrator.with_lock(|| cmd::only(migrator, migration_files, number, Direction::Down, out_of_order))?
+                },
+                None => migrator.with_lock(|| cmd::down(migrator, migration_files))?,
+            }
+        },
+        Some("redo") => migrator.with_lock(|| cmd::redo(migrator, migration_files))?,
+        Some("revert") => migrator.with_lock(|| cmd::revert(migrator, migration_files))?,
+        Some("rollback-to") => {
+            let target = rollback_target(matches.subcommand_matches("rollback-to").unwrap())?;
+            migrator.with_lock(|| cmd::down_to(migrator, migration_files, target))?
+        },
+        Some("apply-sql") => {
+            let file = matches.subcommand_matches("apply-sql").unwrap().value_of("file").unwrap();
+            migrator.with_lock(|| cmd::apply_sql(migrator, file))?
+        },
+        Some("schema") => {
+            let at = match matches.subcommand_matches("schema").unwrap().value_of("at") {
+                Some(n) => Some(n.parse::<i32>().chain_err(|| format!("Invalid migration number: {}", n))?),
+                None => None,
+            };
+            cmd::schema(migrator, at)?
+        },
+        Some("prune") => {
+            let confirmed = matches.subcommand_matches("prune").unwrap().is_present("yes");
+            cmd::prune(migrator, migration_files, confirmed)?
+        },
+        Some("force") => {
+            let number = matches.subcommand_matches("force").unwrap().value_of("number").unwrap();
+            let number = number.parse::<i32>().chain_err(|| format!("Invalid migration number: {}", number))?;
+            migrator.with_lock(|| cmd::force(migrator, migration_files, number))?
+        },
+        Some("repair") => {
+            let confirmed = matches.subcommand_matches("repair").unwrap().is_present("yes");
+            migrator.with_lock(|| cmd::repair(migrator, migration_files, confirmed))?
+        },
+        Some("archive") => {
+            let baseline = matches.subcommand_matches("archive").unwrap().value_of("baseline").unwrap();
+            let baseline = baseline.parse::<i32>().chain_err(|| format!("Invalid migration number: {}", baseline))?;
+            if paths.len() > 1 {
+                bail!("archive moves the migrations of a single folder, give only one --path");
+            }
+            cmd::archive(migrator, migration_files, &paths[0], baseline)?
+        },
+        Some("import") => {
+            // Safe unwraps, clap validated the value
+            let from = matches.subcommand_matches("import").unwrap().value_of("from").unwrap();
+            cmd::import(migrator, migration_files, from.parse::<ImportSource>()?)?
+        },
+        Some("state") => {
+            let state_matches = matches.subcommand_matches("state").unwrap();
+            match state_matches.subcommand() {
+                ("export", Some(m)) => cmd::state_export(migrator, migration_files, m.value_of("output"))?,
+                ("import", Some(m)) => cmd::state_import(migrator, migration_files, m.value_of("file").unwrap())?,
+                _ => unreachable!(),
+            }
+        },
+        Some("generate") => {
+            let generate_matches = matches.subcommand_matches("generate").unwrap();
+            match generate_matches.subcommand() {
+                ("changelog", Some(m)) => {
+                    let from = match m.value_of("from") {
+                        Some(n) => n.parse::<i32>().chain_err(|| format!("Invalid migration number: {}", n))?,
+                        None => 0,
+                    };
+                    let to = match m.value_of("to") {
+                        Some(n) => Some(n.parse::<i32>().chain_err(|| format!("Invalid migration number: {}", n))?),
+                        None => None,
+                    };
+                    cmd::changelog(migrator, migration_files, from, to, m.value_of("output"))?
+                },
+                _ => unreachable!(),
+            }
+        },
+        Some("watch") => {
+            let interval = match matches.subcommand_matches("watch").unwrap().value_of("interval") {
+                Some(i) => i.parse::<u64>().chain_err(|| format!("Invalid interval: {}", i))?,
+                None => 1,
+            };
+            cmd::watch(migrator, paths, options, Duration::from_secs(interval))?
+        },
+        None => println!("No subcommand was used"),
+        _ => println!("Some other subcommand was used"),
+    }
+    Ok(())
+}
+
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    if let Some(sub_matches) = matches.subcommand_matches("completions") {
+        // Safe unwraps, clap validated the value
+        let shell = sub_matches.value_of("shell").unwrap().parse::<Shell>().unwrap();
+        build_cli().gen_completions_to("dbmigrate", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    let config = Config::load(Path::new(CONFIG_FILENAME))?;
+
+    let started_at = dbmigrate_lib::utc_now();
+    let start = Instant::now();
+    let res = run_with_config(matches, &config);
+
+    let audit_log = env::var("DBMIGRATE_AUDIT_LOG").ok().or(config.audit_log.clone());
+    let logged = match audit_log {
+        Some(ref audit_log) => {
+            // Safe unwrap, a subcommand is required
+            let report = Report::new(matches.subcommand_name().unwrap(), &res, start.elapsed());
+            let url = database_url(matches, &config);
+            audit::append(Path::new(audit_log), url.as_ref().map(String::as_str), &report, &started_at)
+        },
+        None => Ok(()),
+    };
+    let code = res?;
+    logged?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+
+    if let Some(up_matches) = matches.subcommand_matches("up") {
+        if up_matches.is_present("then") {
+            // clap ensures the command is there
+            cmd::exec(up_matches.values_of("command").unwrap().collect())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn database_url(matches: &ArgMatches, config: &Config) -> Option<String> {
+    matches.value_of("url").map(|s| s.into()).or(env::var("DBMIGRATE_URL").ok()).or(config.url.clone())
+}
+
+// Sets the connect_timeout parameter of the URL from --timeout-connect, unless the URL has one
+fn with_timeout_connect(matches: &ArgMatches, url: String) -> Result<String> {
+    match matches.value_of("timeout_connect") {
+        Some(t) => {
+            let secs = t.parse::<u64>().chain_err(|| format!("Invalid connection timeout: {}", t))?;
+            Ok(with_connect_timeout(&url, Duration::from_secs(secs))?)
+        },
+        None => Ok(url),
+    }
+}
+
+// Sets the search path of the URL from --search-path, so that unqualified migrations go to the schema
+// of the environment
+fn with_default_schema(matches: &ArgMatches, config: &Config, url: String) -> Result<String> {
+    let search_path = matches.value_of("search_path").map(|s| s.to_owned())
+        .or(env::var("DBMIGRATE_SEARCH_PATH").ok())
+        .or(config.search_path.clone());
+    match search_path {
+        Some(search_path) => Ok(with_search_path(&url, &search_path)?),
+        None => Ok(url),
+    }
+}
+
+fn tag_list(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches.value_of(name).map_or(vec![], |tags| {
+        tags.split(',').map(|t| t.trim().to_owned()).filter(|t| !t.is_empty()).collect()
+    })
+}
+
+// How many targets `up` migrates at once
+fn parallel(matches: &ArgMatches) -> Result<usize> {
+    match matches.subcommand_matches("up").and_then(|m| m.value_of("parallel")) {
+        Some(p) => p.parse::<usize>().chain_err(|| format!("Invalid parallel: {}", p)),
+        None => Ok(1),
+    }
+}
+
+fn environment(matches: &ArgMatches, config: &Config) -> Option<String> {
+    matches.value_of("environment").map(|e| e.to_owned())
+        .or(env::var("DBMIGRATE_ENV").ok())
+        .or(config.environment.clone())
+}
+
+fn settings(matches: &ArgMatches, config: &Config, paths: &[PathBuf]) -> Result<cmd::Settings> {
+    let timeout = match matches.value_of("timeout").or(config.timeout.as_ref().map(|t| t.as_str())) {
+        Some(t) => Some(parse_duration(t)?),
+        None => None,
+    };
+    let sleep_between = match matches.value_of("sleep_between").or(config.sleep_between.as_ref().map(|s| s.as_str())) {
+        Some(s) => Some(parse_duration(s)?),
+        None => None,
+    };
+    let keep_alive = match matches.value_of("keep_alive").or(config.keep_alive.as_ref().map(|k| k.as_str())) {
+        Some(k) => Some(parse_duration(k)?),
+        None => None,
+    };
+    // The migrations of every folder start after the archived ones of any of them
+    let mut first = 1;
+    for path in paths {
+        first = first.max(baseline(path)?);
+    }
+    Ok(cmd::Settings {
+        strict_checksums: matches.is_present("strict_checksums") || config.strict_checksums.unwrap_or(false),
+        allow_gaps: matches.is_present("allow_gaps") || config.allow_gaps.unwrap_or(false),
+        baseline: first,
+        git_commit: paths.first().and_then(|path| git_commit(path)),
+        schema_snapshots: config.schema_snapshots.unwrap_or(false),
+        lock_timeout: config.lock_timeout.map(Duration::from_secs),
+        retry: config.retry.as_ref().map(|r| r.policy()),
+        timeout: timeout,
+        sleep_between: sleep_between,
+        keep_alive: keep_alive,
+        statement_progress: matches.is_present("statement_progress") || config.statement_progress.unwrap_or(false),
+        environment: environment(matches, config),
+        track: matches.value_of("track").map(|t| t.to_owned())
+            .or(env::var("DBMIGRATE_TRACK").ok())
+            .or(config.track.clone()),
+        online: match config.online {
+            Some(ref online) => Some(online.schema_change()?),
+            None => None,
+        },
+    })
+}
+
+/// Runs the subcommand, returning the exit code
+fn run_with_config(matches: &ArgMatches, config: &Config) -> Result<i32> {
+    // DBMIGRATE_PATH can list several folders like PATH does
+    let paths = match matches.values_of("path") {
+        Some(values) => values.map(PathBuf::from).collect(),
+        None => env::var_os("DBMIGRATE_PATH").map(|p| env::split_paths(&p).collect::<Vec<_>>())
+            .or(config.path.clone().map(|p| vec![PathBuf::from(p)]))
+            .unwrap_or_default(),
+    };
+
+    if let Some(sub_matches) = matches.subcommand_matches("init") {
+        let path = paths.first().cloned().unwrap_or_else(|| PathBuf::from("migrations"));
+        return cmd::init(&path, sub_matches.value_of("slug")).map(|_| 0);
+    }
+
+    // Doesn't need the migration files, only the database
+    if let Some("ping") = matches.subcommand_name() {
+        let url = match database_url(matches, config) {
+            Some(u) => u,
+            None => bail!("No database url was provided in the environment, config file or via a command arg."),
+        };
+        return cmd::ping(&with_timeout_connect(matches, url)?).map(|_| 0);
+    }
+
+    // Files are created in the first folder
+    let path = match paths.first() {
+      Some(p) => p.as_path(),
+      None => bail!("No migration path was provided in the environment, config file or via a command arg.")
     };
-    let path = Path::new(&path_value);
 
-    let migration_files = read_migration_files(path)?;
+    let encoding = match matches.value_of("encoding").map(|s| s.to_owned()).or(config.encoding.clone()) {
+        Some(e) => e.parse::<Encoding>()?,
+        None => Encoding::default(),
+    };
+    let options = ReadOptions {
+        encoding: encoding,
+        optional_down: matches.is_present("optional_down") || config.optional_down.unwrap_or(false),
+        allow_gaps: matches.is_present("allow_gaps") || config.allow_gaps.unwrap_or(false),
+        recursive: matches.is_present("recursive") || config.recursive.unwrap_or(false),
+    };
+    // The content of the files is only read when needed, most commands don't
+    let migration_files = read_migration_directories(&paths, &options)?;
 
-    if let Some("create") = matches.subcommand_name() {
-        // Should be safe unwraps
-        let slug = matches.subcommand_matches("create").unwrap().value_of("slug").unwrap();
-        match cmd::create(&migration_files, path, slug) {
-            Ok(_) => std::process::exit(0),
-            Err(e) => return Err(e)
+    if let Some(sub_matches) = matches.subcommand_matches("create") {
+        // Safe unwrap, the argument is required
+        let slug = sub_matches.value_of("slug").unwrap();
+        // Only the scheme matters, the SQL being generic without one
+        let scheme = database_url(matches, config).and_then(|url| url.split(':').next().map(|s| s.to_owned()));
+        let scheme = scheme.as_ref().map_or("", |s| s.as_str());
+        return match (sub_matches.value_of("auto_down"), sub_matches.value_of("template")) {
+            (Some(scratch_url), _) => cmd::create_with_auto_down(&migration_files, path, slug, scratch_url),
+            (None, Some(template)) => {
+                cmd::create_from_template(&migration_files, path, slug, template.parse()?, scheme)
+            },
+            (None, None) if sub_matches.is_present("interactive") => {
+                cmd::create_interactive(&migration_files, path, slug, scheme)
+            },
+            (None, None) => cmd::create(&migration_files, path, slug),
+        }.map(|_| 0);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("lint") {
+        // Safe unwrap, the argument has a default value
+        let format = sub_matches.value_of("format").unwrap().parse()?;
+        let target = match (sub_matches.value_of("dialect"), database_url(matches, config)) {
+            (Some(target), _) => target.parse::<LintTarget>()?,
+            (None, Some(url)) => LintTarget::from_url(&url)?,
+            (None, None) => LintTarget::default(),
+        };
+        return cmd::lint(&migration_files, target, format).map(|_| 0);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("status").filter(|m| m.is_present("offline")) {
+        // Safe unwrap, the argument has a default value
+        let format = sub_matches.value_of("format").unwrap().parse()?;
+        let strict_checksums = matches.is_present("strict_checksums") || config.strict_checksums.unwrap_or(false);
+        return cmd::status_offline(&migration_files, format, strict_checksums).map(|_| 0);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("bundle") {
+        return cmd::bundle(&migration_files, path, sub_matches.is_present("sign"), sub_matches.value_of("key")).map(|_| 0);
+    }
+
+    let verify_signatures = matches.subcommand_matches("up").map_or(false, |m| m.is_present("verify_signatures"));
+    if matches.subcommand_name() == Some("up") && (verify_signatures || config.verify_signatures.unwrap_or(false)) {
+        cmd::verify_bundle(&migration_files, path, config.trusted_keyring.as_ref().map(Path::new))?;
+    }
+
+    if let Some(git_check) = config.git_check.as_ref().filter(|_| matches.subcommand_name() == Some("up")) {
+        if git_check.applies_to(environment(matches, config).as_ref().map(|e| e.as_str())) {
+            cmd::check_git(&paths, git_check.refuse.unwrap_or(false))?;
         }
     }
 
-    let url = match matches.value_of("url").map(|s| s.into()).or(env::var("DBMIGRATE_URL").ok()) {
+    if let Some(sub_matches) = matches.subcommand_matches("verify-reversibility") {
+        // Safe unwrap, the argument is required
+        let scratch_url = sub_matches.value_of("scratch_url").unwrap();
+        cmd::verify_reversibility(scratch_url, &migration_files)?;
+        return Ok(0);
+    }
+
+    let url = match database_url(matches, config) {
       Some(u) => u,
-      None => bail!("No database url was provided in the environment or via a command arg.")
+      None if !config.targets.is_empty() && matches.subcommand_name() == Some("up") => {
+          if matches.subcommand_matches("up").map_or(false, |m| m.is_present("only")) {
+              bail!("--only runs a migration on a single database, give its url");
+          }
+          let targets = config.targets.iter()
+              .map(|t| {
+                  let url = with_default_schema(matches, config, with_timeout_connect(matches, t.url.clone())?)?;
+                  Ok(Target { url: url, name: t.name.clone() })
+              })
+              .collect::<Result<Vec<_>>>()?;
+          return with_telemetry(config, || {
+              cmd::up_targets(&targets, parallel(matches)?, &|url| {
+                  migrate_target(matches, config, url, &migration_files, &paths, &options)
+              })
+          }).map(|_| 0);
+      },
+      None => bail!("No database url was provided in the environment, config file or via a command arg.")
     };
-    let driver = get_driver(&url).chain_err(|| "Failed to get DB connection")?;
+    let url = with_default_schema(matches, config, with_timeout_connect(matches, url)?)?;
+    if let Some(sub_matches) = matches.subcommand_matches("test-db") {
+        let command = sub_matches.values_of("command").map(|v| v.collect());
+        return cmd::test_db(&url, &migration_files, command).map(|_| 0);
+    }
+
+    if let Some(pattern) = matches.subcommand_matches("up").and_then(|m| m.value_of("schemas")) {
+        let schemas = tenant_schemas(connect(&url, Duration::from_secs(0))?.driver(), pattern)?;
+        let targets = schemas.into_iter()
+            .map(|schema| Ok(Target { url: schema_url(&url, &schema)?, name: schema }))
+            .collect::<Result<Vec<_>>>()?;
+        if targets.is_empty() {
+            bail!("No schema matches {}", pattern);
+        }
+        return with_telemetry(config, || {
+            cmd::up_targets(&targets, parallel(matches)?, &|url| {
+                migrate_target(matches, config, url, &migration_files, &paths, &options)
+            })
+        }).map(|_| 0);
+    }
+
+    with_telemetry(config, || run_on_database(matches, config, &url, &migration_files, &paths, &options))
+}
+
+// Exports the spans of the run to the OTLP endpoint, if one is set
+fn with_telemetry<T, F: FnOnce() -> Result<T>>(config: &Config, f: F) -> Result<T> {
+    let otlp_endpoint = env::var("DBMIGRATE_OTLP_ENDPOINT").ok()
+        .or(config.tracing.as_ref().and_then(|t| t.otlp_endpoint.clone()));
+    if let Some(ref endpoint) = otlp_endpoint {
+        telemetry::init(endpoint)?;
+    }
+    let res = f();
+    if otlp_endpoint.is_some() {
+        telemetry::shutdown();
+    }
+    res
+}
 
+// Runs `up` on one of several targets like on a single database, with its own entry in the audit log
+fn migrate_target(
+    matches: &ArgMatches, config: &Config, url: &str, migration_files: &Migrations, paths: &[PathBuf],
+    options: &ReadOptions
+) -> Result<i32> {
+    report::clear();
+    let started_at = dbmigrate_lib::utc_now();
     let start = Instant::now();
+    let res = run_on_database(matches, config, url, migration_files, paths, options);
+    if let Some(audit_log) = env::var("DBMIGRATE_AUDIT_LOG").ok().or(config.audit_log.clone()) {
+        let report = Report::new("up", &res, start.elapsed());
+        audit::append(Path::new(&audit_log), Some(url), &report, &started_at)?;
+    }
+    res
+}
 
-    match matches.subcommand_name() {
-        Some("status") => cmd::status(driver, &migration_files)?,
-        Some("up") => cmd::up(driver, &migration_files)?,
-        Some("down") => cmd::down(driver, &migration_files)?,
-        Some("redo") => cmd::redo(driver, &migration_files)?,
-        Some("revert") => cmd::revert(driver, &migration_files)?,
-        None => println!("No subcommand was used"),
-        _ => println!("Some other subcommand was used"),
+// Runs the subcommand on the database, with the hooks, backups, notifications and metrics of the
+// commands changing the schema
+fn run_on_database(
+    matches: &ArgMatches, config: &Config, url: &str, migration_files: &Migrations, paths: &[PathBuf],
+    options: &ReadOptions
+) -> Result<i32> {
+    let wait = match matches.subcommand_matches("up") {
+        Some(up_matches) => match up_matches.value_of("wait") {
+            Some(w) => w.parse::<u64>().chain_err(|| format!("Invalid wait: {}", w))?,
+            None if up_matches.is_present("then") => 30,
+            None => 0,
+        },
+        None => 0,
+    };
+    let settings = settings(matches, config, paths)?;
+    let migrator = connect_with_settings(url, Duration::from_secs(wait), &settings)?;
+
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let token = match env::var("DBMIGRATE_SERVE_TOKEN").ok().or(config.serve_token.clone()) {
+            Some(token) => token,
+            None => bail!("serve needs a token, set DBMIGRATE_SERVE_TOKEN or serve_token in {}", CONFIG_FILENAME),
+        };
+        let listen = serve_matches.value_of("listen").unwrap();
+        return serve::serve(&migrator, migration_files, listen, &token, &config.protected).map(|_| 0);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("status").filter(|m| m.is_present("diff")) {
+        // Reached like the database of the URL, on the same track
+        let other_url = sub_matches.value_of("diff").unwrap().to_owned();
+        let other_url = with_default_schema(matches, config, with_timeout_connect(matches, other_url)?)?;
+        let other = connect_with_settings(&other_url, Duration::from_secs(0), &settings)
+            .chain_err(|| "Failed to get the other DB connection")?;
+        // Safe unwrap, the argument has a default value
+        let format = sub_matches.value_of("format").unwrap().parse()?;
+        return cmd::status_diff(&migrator, &other, migration_files, format).map(|_| 0);
     }
 
+    if let Some("check") = matches.subcommand_name() {
+        let up_to_date = cmd::check(&migrator, migration_files)?;
+        return Ok(if up_to_date { 0 } else { EXIT_PENDING });
+    }
+
+    // Refused before the hooks and backups run for nothing
+    if let (command, Some(sub_matches)) = matches.subcommand() {
+        if !sub_matches.is_present("allow_protected") {
+            match (only_number(sub_matches)?, command) {
+                (Some(number), "down") if config.protected.contains(&number) => {
+                    bail!("Protected migration {} would be reverted, use --allow-protected to revert it anyway", number)
+                },
+                (Some(_), "down") => (),
+                _ => if let Some(target) = reverting_to(&migrator, command, sub_matches)? {
+                    cmd::check_protected(&migrator, &config.protected, target)?;
+                },
+            }
+        }
+    }
+
+    // Hooks, notifications and metrics are only about the commands changing the schema
+    let migrating = match matches.subcommand_name() {
+        Some(command @ "up") | Some(command @ "down") | Some(command @ "redo") | Some(command @ "revert")
+            | Some(command @ "rollback-to") | Some(command @ "contract") | Some(command @ "apply-sql") => Some(command),
+        _ => None,
+    };
+    if let Some(up_matches) = matches.subcommand_matches("up") {
+        if up_matches.is_present("shadow") || config.shadow.unwrap_or(false) {
+            let server_url = env::var("DBMIGRATE_SHADOW_URL").ok()
+                .or(config.shadow_url.clone())
+                .unwrap_or_else(|| url.to_owned());
+            cmd::shadow(&migrator, migration_files, &server_url)?;
+        }
+    }
+    if let (Some(command), Some(hook)) = (migrating, config.before_run.as_ref()) {
+        hooks::before_run(hook, command)?;
+    }
+    if let Some(command) = migrating {
+        if let Some(dir) = env::var("DBMIGRATE_BACKUP_DIR").ok().or(config.backup_dir.clone()) {
+            cmd::backup(&migrator, migration_files, command, url, Path::new(&dir))?;
+        }
+    }
+
+    let start = Instant::now();
+    let res = run_command(matches, &migrator, migration_files, paths, options);
+    let mut hook_res = Ok(());
+    if let Some(command) = migrating {
+        let report = Report::new(command, &res, start.elapsed());
+        webhooks::notify(&config.webhooks, &report);
+        if let Some(ref metrics) = config.metrics {
+            metrics::push(metrics, &report, current_number(&migrator));
+        }
+        if let Some(ref hook) = config.after_run {
+            hook_res = hooks::after_run(hook, &report);
+        }
+    }
+    res?;
+    hook_res?;
+
     let duration = start.elapsed();
     let minutes = duration.as_secs() / 60;
     let seconds = duration.as_secs() % 60;
@@ -115,5 +906,5 @@ Using arguments will override the environment variables.
         println!("Operation took {} minutes and {} seconds", minutes, seconds);
     }
 
-    Ok(())
+    Ok(0)
 }
@@ -0,0 +1,85 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use ureq;
+
+use config::Metrics;
+use errors::url_origin;
+use print;
+use report::Report;
+
+/// Sends the metrics of a run to the configured Prometheus pushgateway and/or StatsD server.
+/// Failures are printed but don't fail the run
+pub fn push(metrics: &Metrics, report: &Report, current: Option<i32>) {
+    if let Some(ref url) = metrics.pushgateway {
+        let url = format!("{}/metrics/job/dbmigrate", url.trim_right_matches('/'));
+        let response = ureq::post(&url)
+            .timeout(Duration::from_secs(10))
+            .send_string(&prometheus_text(report, current));
+        if response.error() {
+            let reason = match *response.synthetic_error() {
+                Some(ref e) => format!("{}", e),
+                None => response.status_line().to_owned(),
+            };
+            let origin = url_origin(&url);
+            print::error(&format!("Pushing metrics to {} failed: {}", origin, reason.replace(url.as_str(), &origin)));
+        }
+    }
+
+    if let Some(ref address) = metrics.statsd {
+        let prefix = metrics.prefix.as_ref().map_or("dbmigrate", |p| p.as_str());
+        let res = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+            for line in statsd_lines(prefix, report, current) {
+                socket.send_to(line.as_bytes(), address.as_str())?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            print::error(&format!("Sending metrics to {} failed: {}", address, e));
+        }
+    }
+}
+
+// Pushgateway replaces the metrics of the job on each push, so everything is a gauge
+// describing the last run
+fn prometheus_text(report: &Report, current: Option<i32>) -> String {
+    let mut lines = vec![
+        "# TYPE dbmigrate_last_run_success gauge".to_owned(),
+        format!("dbmigrate_last_run_success{{command=\"{}\"}} {}", report.command, if report.success { 1 } else { 0 }),
+        "# TYPE dbmigrate_last_run_duration_seconds gauge".to_owned(),
+        format!("dbmigrate_last_run_duration_seconds{{command=\"{}\"}} {}", report.command, report.duration_secs),
+        "# TYPE dbmigrate_last_run_migrations gauge".to_owned(),
+        format!("dbmigrate_last_run_migrations{{command=\"{}\"}} {}", report.command, report.migrations.len()),
+        "# TYPE dbmigrate_migration_duration_seconds gauge".to_owned(),
+    ];
+    for migration in &report.migrations {
+        lines.push(format!(
+            "dbmigrate_migration_duration_seconds{{number=\"{}\",name=\"{}\",direction=\"{}\"}} {}",
+            migration.number, migration.name, migration.direction, migration.duration_secs
+        ));
+    }
+    if let Some(current) = current {
+        lines.push("# TYPE dbmigrate_current_version gauge".to_owned());
+        lines.push(format!("dbmigrate_current_version {}", current));
+    }
+    lines.join("\n") + "\n"
+}
+
+fn statsd_lines(prefix: &str, report: &Report, current: Option<i32>) -> Vec<String> {
+    let millis = |secs: f64| (secs * 1000.0).round() as u64;
+    let mut lines = vec![
+        format!("{}.{}.runs:1|c", prefix, report.command),
+        format!("{}.{}.duration:{}|ms", prefix, report.command, millis(report.duration_secs)),
+        format!("{}.{}.migrations:{}|c", prefix, report.command, report.migrations.len()),
+    ];
+    if !report.success {
+        lines.push(format!("{}.{}.failures:1|c", prefix, report.command));
+    }
+    for migration in &report.migrations {
+        lines.push(format!("{}.migration.duration:{}|ms", prefix, millis(migration.duration_secs)));
+    }
+    if let Some(current) = current {
+        lines.push(format!("{}.current_version:{}|g", prefix, current));
+    }
+    lines
+}
@@ -1,9 +1,44 @@
 use std::io;
 use std::io::prelude::*;
+use std::str::FromStr;
 
+use serde_json::{self, Value};
 use term;
 
+use errors::{redact_urls, Error, Result};
+
+/// How commands with structured output print it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Aligned columns, for humans
+    Table,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn variants() -> [&'static str; 3] {
+        ["table", "json", "yaml"]
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            _ => bail!("Unknown format {}, expected table, json or yaml", s),
+        }
+    }
+}
+
+/// Prints the message in red on stderr, the passwords of its URLs hidden since it is often an error
+/// giving a connection string
 pub fn error(message: &str) {
+    let message = redact_urls(message);
     if let Some(mut t) = term::stderr() {
         match t.fg(term::color::BRIGHT_RED) {
             Ok(_) => {
@@ -31,3 +66,102 @@ pub fn success(message: &str) {
         println!("{}", message);
     }
 }
+
+
+/// Prints the rows in columns aligned under the headers
+pub fn table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded = cells.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:1$}", cell, width))
+            .collect::<Vec<_>>();
+        println!("{}", padded.join("  ").trim_right());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(|c| c.as_str()).collect());
+    }
+}
+
+/// Prints the value as JSON or YAML, the formats meant for programs
+pub fn structured(value: &Value, format: Format) {
+    match format {
+        Format::Yaml => print!("{}", to_yaml(value)),
+        _ => println!("{}", serde_json::to_string_pretty(value).unwrap_or_default()),
+    }
+}
+
+// Block style YAML, strings being double-quoted as in JSON which YAML accepts
+fn to_yaml(value: &Value) -> String {
+    let mut out = String::new();
+    match *value {
+        Value::Object(_) | Value::Array(_) if !is_empty(value) => write_yaml(value, 0, &mut out),
+        _ => {
+            out.push_str(&yaml_scalar(value));
+            out.push('\n');
+        },
+    }
+    out
+}
+
+fn write_yaml(value: &Value, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match *value {
+        Value::Object(ref map) => {
+            for (key, value) in map {
+                let key = if key.chars().all(|c| c.is_alphanumeric() || c == '_') && !key.is_empty() {
+                    key.clone()
+                } else {
+                    serde_json::to_string(key).unwrap_or_default()
+                };
+                if is_nested(value) {
+                    out.push_str(&format!("{}{}:\n", pad, key));
+                    write_yaml(value, indent + 2, out);
+                } else {
+                    out.push_str(&format!("{}{}: {}\n", pad, key, yaml_scalar(value)));
+                }
+            }
+        },
+        Value::Array(ref items) => {
+            for item in items {
+                if is_nested(item) {
+                    // The first line of the nested block goes after the dash
+                    let mut nested = String::new();
+                    write_yaml(item, indent + 2, &mut nested);
+                    out.push_str(&format!("{}- {}", pad, &nested[indent + 2..]));
+                } else {
+                    out.push_str(&format!("{}- {}\n", pad, yaml_scalar(item)));
+                }
+            }
+        },
+        _ => out.push_str(&format!("{}{}\n", pad, yaml_scalar(value))),
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match *value {
+        Value::Object(ref map) => map.is_empty(),
+        Value::Array(ref items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+fn is_nested(value: &Value) -> bool {
+    match *value {
+        Value::Object(_) | Value::Array(_) => !is_empty(value),
+        _ => false,
+    }
+}
+
+fn yaml_scalar(value: &Value) -> String {
+    match *value {
+        Value::Object(_) => "{}".to_owned(),
+        Value::Array(_) => "[]".to_owned(),
+        _ => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
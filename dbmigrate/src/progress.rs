@@ -0,0 +1,43 @@
+use indicatif::{ProgressBar, ProgressStyle};
+
+use print;
+
+/// Progress of a sequence of migrations, drawn as a bar with an ETA on terminals
+/// and falling back to plain lines otherwise (in CI logs for example)
+pub struct Progress {
+    bar: ProgressBar,
+}
+
+impl Progress {
+    pub fn new(len: usize) -> Progress {
+        let bar = ProgressBar::new(len as u64);
+        bar.set_style(ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:40}] {pos}/{len} (ETA {eta}) {msg}")
+            .progress_chars("=> "));
+        Progress { bar: bar }
+    }
+
+    /// A migration is starting
+    pub fn start(&self, message: &str) {
+        if self.bar.is_hidden() {
+            println!("{}", message);
+        } else {
+            self.bar.set_message(message);
+        }
+    }
+
+    /// The migration started last is done
+    pub fn done(&self, message: &str) {
+        if self.bar.is_hidden() {
+            print::success(message);
+        } else {
+            self.bar.println(message);
+        }
+        self.bar.inc(1);
+    }
+
+    /// Removes the bar, after all migrations ran or one failed
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,82 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use dbmigrate_lib::{checksum, MigrationFile};
+
+use errors::{redact_urls, Result};
+
+thread_local!(static RAN: RefCell<Vec<RanMigration>> = RefCell::new(Vec::new()));
+thread_local!(static APPLIED_SQL: RefCell<Option<String>> = RefCell::new(None));
+
+/// A migration applied or reverted during this invocation
+#[derive(Debug, Clone, Serialize)]
+pub struct RanMigration {
+    pub number: i32,
+    pub name: String,
+    pub direction: String,
+    pub duration_secs: f64,
+}
+
+/// Records a migration that ran successfully, to be reported at the end
+pub fn record(file: &MigrationFile, duration: Duration) {
+    let ran = RanMigration {
+        number: file.number,
+        name: file.name.clone(),
+        direction: file.direction.to_string(),
+        duration_secs: seconds(duration),
+    };
+    RAN.with(|r| r.borrow_mut().push(ran));
+}
+
+/// Records the checksum of the SQL run by `apply-sql`, to be reported at the end without the SQL itself
+pub fn record_sql(sql: &str) {
+    APPLIED_SQL.with(|a| *a.borrow_mut() = Some(checksum(sql, true)));
+}
+
+/// Forgets the migrations and SQL recorded so far, for processes running several commands
+pub fn clear() {
+    RAN.with(|r| r.borrow_mut().clear());
+    APPLIED_SQL.with(|a| *a.borrow_mut() = None);
+}
+
+/// Summary of a run, sent to notification targets
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub command: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub migrations: Vec<RanMigration>,
+    /// Checksum of the SQL run by `apply-sql`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql_checksum: Option<String>,
+    pub duration_secs: f64,
+}
+
+impl Report {
+    pub fn new<T>(command: &str, res: &Result<T>, duration: Duration) -> Report {
+        Report {
+            command: command.to_owned(),
+            success: res.is_ok(),
+            error: res.as_ref().err().map(|e| redact_urls(&format!("{}", e))),
+            migrations: RAN.with(|r| r.borrow().clone()),
+            sql_checksum: APPLIED_SQL.with(|a| a.borrow().clone()),
+            duration_secs: seconds(duration),
+        }
+    }
+
+    /// One line summary, for chat messages
+    pub fn summary(&self) -> String {
+        let migrations = self.migrations.iter()
+            .map(|m| format!("{} #{} {} ({:.1}s)", m.direction, m.number, m.name, m.duration_secs))
+            .collect::<Vec<_>>();
+        let ran = if migrations.is_empty() { "no migration ran".to_owned() } else { migrations.join(", ") };
+        match self.error {
+            None => format!("dbmigrate {} succeeded in {:.1}s: {}", self.command, self.duration_secs, ran),
+            Some(ref e) => format!("dbmigrate {} failed after {:.1}s: {}. Ran before failing: {}", self.command, self.duration_secs, e, ran),
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
@@ -0,0 +1,145 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+
+use dbmigrate_lib::{Migrations, Migrator};
+use cmd;
+use errors::{redact_urls, Result, ResultExt};
+use print;
+use report::{self, Report};
+
+// What is needed of a request to route it
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+}
+
+/// Serves the HTTP API on the address until killed, handling one request at a time:
+/// `GET /status`, `POST /up` and `POST /down/<n>`, which reverts the migrations above n.
+/// Every request needs the token in an `Authorization: Bearer` header. Protected migrations are never reverted.
+pub fn serve(migrator: &Migrator, migration_files: &Migrations, addr: &str, token: &str, protected: &[i32]) -> Result<()> {
+    let listener = TcpListener::bind(addr).chain_err(|| format!("Failed to listen on {}", addr))?;
+    print::success(&format!("Listening on {}", addr));
+
+    for stream in listener.incoming() {
+        let res = stream.chain_err(|| "Failed to accept a connection")
+            .and_then(|stream| handle(stream, migrator, migration_files, token, protected));
+        if let Err(e) = res {
+            print::error(&format!("Request failed: {}", e));
+        }
+    }
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream, migrator: &Migrator, migration_files: &Migrations, token: &str, protected: &[i32]
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10))).chain_err(|| "Failed to set the read timeout")?;
+    let request = match read_request(&stream)? {
+        Some(request) => request,
+        None => return respond(&mut stream, 400, &json!({ "error": "Bad request" })),
+    };
+    println!("{} {}", request.method, request.path);
+
+    if !request.token.as_ref().map_or(false, |t| constant_time_eq(t, token)) {
+        return respond(&mut stream, 401, &json!({ "error": "Missing or invalid token" }));
+    }
+
+    let segments = request.path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["status"]) => match cmd::status_value(migrator, migration_files) {
+            Ok(status) => respond(&mut stream, 200, &status),
+            Err(e) => respond(&mut stream, 500, &json!({ "error": e.to_string() })),
+        },
+        ("POST", ["up"]) => {
+            let report = run("up", || migrator.with_lock(|| cmd::up(migrator, migration_files, &cmd::TagFilter::default(), 1)));
+            respond_report(&mut stream, &report)
+        },
+        ("POST", ["down", target]) => match target.parse::<i32>() {
+            Ok(target) if target >= 0 => {
+                let report = run("down", || migrator.with_lock(|| {
+                    cmd::check_protected(migrator, protected, target)?;
+                    cmd::down_to(migrator, migration_files, target)
+                }));
+                respond_report(&mut stream, &report)
+            },
+            _ => respond(&mut stream, 400, &json!({ "error": format!("Invalid migration number: {}", target) })),
+        },
+        (_, ["status"]) | (_, ["up"]) | (_, ["down", _]) => {
+            respond(&mut stream, 405, &json!({ "error": "Method not allowed" }))
+        },
+        _ => respond(&mut stream, 404, &json!({ "error": "Not found" })),
+    }
+}
+
+// Runs a command, reporting only the migrations it ran
+fn run<F: FnOnce() -> Result<()>>(command: &str, f: F) -> Report {
+    report::clear();
+    let start = Instant::now();
+    let res = f();
+    Report::new(command, &res, start.elapsed())
+}
+
+// The request line and the token, None if the request isn't valid HTTP
+fn read_request(stream: &TcpStream) -> Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).chain_err(|| "Failed to read the request")?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Ok(None),
+    };
+
+    let mut token = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).chain_err(|| "Failed to read the request")? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next().unwrap_or("").trim().to_lowercase();
+        let value = header.next().unwrap_or("").trim();
+        if name == "authorization" && value.starts_with("Bearer ") {
+            token = Some(value["Bearer ".len()..].trim().to_owned());
+        } else if name == "content-length" {
+            content_length = value.parse().unwrap_or(0);
+        }
+    }
+    // Bodies aren't used but are read so that the client gets the response
+    reader.take(content_length).read_to_end(&mut vec![]).chain_err(|| "Failed to read the request")?;
+
+    Ok(Some(Request { method: method, path: path, token: token }))
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    // Errors in the body may give the database URL
+    let body = redact_urls(&serde_json::to_string(body).chain_err(|| "Failed to serialize the response")?);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body
+    ).chain_err(|| "Failed to send the response")
+}
+
+fn respond_report(stream: &mut TcpStream, report: &Report) -> Result<()> {
+    let body = serde_json::to_value(report).chain_err(|| "Failed to serialize the report")?;
+    respond(stream, if report.success { 200 } else { 500 }, &body)
+}
+
+// Doesn't tell how much of the token was right through timing
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
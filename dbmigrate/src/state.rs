@@ -0,0 +1,66 @@
+use dbmigrate_lib::{Driver, Migrations};
+use serde_json;
+
+use errors::{Result, ResultExt};
+
+/// Portable snapshot of the tracking table
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Current migration number
+    pub current: i32,
+    /// Migrations applied, used to check the snapshot is restored against the same files
+    pub applied: Vec<AppliedMigration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub number: i32,
+    pub name: String,
+}
+
+impl Snapshot {
+    pub fn take(driver: &Driver, migration_files: &Migrations) -> Snapshot {
+        let current = driver.get_current_number();
+        let applied = migration_files.iter()
+            .filter(|&(number, _)| *number <= current)
+            .filter_map(|(number, migration)| migration.up.as_ref().map(|f| AppliedMigration {
+                number: *number,
+                name: f.name.clone(),
+            }))
+            .collect();
+
+        Snapshot { current: current, applied: applied }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).chain_err(|| "Failed to serialize the state")
+    }
+
+    pub fn from_json(json: &str) -> Result<Snapshot> {
+        serde_json::from_str(json).chain_err(|| "Invalid state snapshot")
+    }
+
+    /// Checks the applied migrations of the snapshot are the same as the local ones
+    pub fn check(&self, migration_files: &Migrations) -> Result<()> {
+        for applied in &self.applied {
+            let local_name = migration_files.get(&applied.number)
+                .and_then(|m| m.up.as_ref())
+                .map(|f| f.name.as_str());
+            if local_name != Some(applied.name.as_str()) {
+                bail!(
+                    "Migration {} ({}) from the snapshot doesn't match the local migration files",
+                    applied.number, applied.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the tracking table from the snapshot
+    pub fn restore(&self, driver: &Driver) -> Result<()> {
+        driver.remove_migration_table();
+        driver.ensure_migration_table_exists();
+        driver.set_current_number(0, self.current)?;
+        Ok(())
+    }
+}
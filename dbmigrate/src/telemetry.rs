@@ -0,0 +1,37 @@
+use errors::Result;
+
+/// Sends the spans of the run to the OTLP/HTTP collector at `endpoint`
+/// (`http://localhost:4318` for example)
+#[cfg(feature = "otlp")]
+pub fn init(endpoint: &str) -> Result<()> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{trace, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use errors::ResultExt;
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", "dbmigrate"),
+        ])))
+        // Spans are few and the process is short lived, no need for batching
+        .install_simple()
+        .chain_err(|| "Failed to set up the OTLP exporter")?;
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).chain_err(|| "Failed to set up tracing")
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init(_endpoint: &str) -> Result<()> {
+    bail!("dbmigrate was built without the `otlp` feature, spans can't be exported")
+}
+
+/// Flushes the spans not exported yet
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use ureq;
+
+use config::Webhook;
+use errors::url_origin;
+use print;
+use report::Report;
+
+/// Posts the report to every webhook. Failures are printed but don't fail the run,
+/// the migrations already happened
+pub fn notify(webhooks: &[Webhook], report: &Report) {
+    for webhook in webhooks {
+        let payload = match webhook.format.as_ref().map(|f| f.as_str()) {
+            Some("slack") => json!({ "text": report.summary() }),
+            _ => serde_json::to_value(report).unwrap_or(Value::Null),
+        };
+        let response = ureq::post(&webhook.url)
+            .timeout(Duration::from_secs(10))
+            .send_json(payload);
+        if response.error() {
+            let reason = match *response.synthetic_error() {
+                Some(ref e) => format!("{}", e),
+                None => response.status_line().to_owned(),
+            };
+            let origin = url_origin(&webhook.url);
+            print::error(&format!("Webhook {} failed: {}", origin, reason.replace(webhook.url.as_str(), &origin)));
+        }
+    }
+}